@@ -0,0 +1,251 @@
+use criterion::measurement::{Measurement, ValueFormatter};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use junkfs::cache::MemPool;
+use junkfs::meta::{FileHandle, Itype, Meta};
+use junkfs::store::{FileStore, SharedStore};
+use junkfs::utils::{stats, FS_PAGE_SIZE};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+
+const BENCH_DIR: &str = "/tmp/junkfs_bench";
+const FILE_SIZE: u64 = 64 << 20;
+const IO_SIZES: [u64; 3] = [4 << 10, 64 << 10, 128 << 10];
+const POOL_SIZES: [u64; 2] = [1 << 20, 100 << 20];
+
+fn setup(pool_size: u64) -> Meta {
+    let meta_path = format!("{}/meta", BENCH_DIR);
+    let data_path = format!("{}/data", BENCH_DIR);
+    let _ = std::fs::remove_dir_all(BENCH_DIR);
+    std::fs::create_dir_all(&data_path).unwrap();
+    Meta::format(&meta_path, &data_path, false).unwrap();
+    let mut meta = Meta::load_fs(meta_path, true).unwrap();
+    meta.mknod(0, "/", Itype::Dir, 0o755).unwrap();
+    MemPool::init(pool_size, FS_PAGE_SIZE);
+    meta
+}
+
+fn data_store() -> SharedStore {
+    Rc::new(RefCell::new(FileStore::new()))
+}
+
+fn teardown(meta: Meta) {
+    drop(meta);
+    MemPool::destroy();
+}
+
+// the parameter is the size of each read or write, the block size is `FS_BLK_SIZE` fixed at build time
+fn io_label(io: u64) -> String {
+    format!("io_{}K", io >> 10)
+}
+
+// xorshift, good enough to scatter offsets
+fn next_off(seed: &mut u64, io_size: u64) -> u64 {
+    *seed ^= *seed << 13;
+    *seed ^= *seed >> 7;
+    *seed ^= *seed << 17;
+    (*seed % (FILE_SIZE / io_size)) * io_size
+}
+
+fn bench_write(c: &mut Criterion) {
+    let mut g = c.benchmark_group("write");
+    for pool in POOL_SIZES {
+        for io in IO_SIZES {
+            let buf = vec![0x5au8; io as usize];
+            g.throughput(Throughput::Bytes(io));
+
+            let mut meta = setup(pool);
+            let ino = meta.mknod(1, "seq", Itype::File, 0o644).unwrap().id;
+            let mut h = FileHandle::new(ino, 0, data_store());
+            let mut off = 0;
+            g.bench_function(
+                BenchmarkId::new(format!("seq/pool_{}M", pool >> 20), io_label(io)),
+                |b| {
+                    b.iter(|| {
+                        h.write(&mut meta, off, &buf);
+                        off = (off + io) % FILE_SIZE;
+                    })
+                },
+            );
+            h.flush(&mut meta);
+
+            let ino = meta.mknod(1, "rand", Itype::File, 0o644).unwrap().id;
+            let mut h = FileHandle::new(ino, 1, data_store());
+            let mut seed = 0x2545f4914f6cdd1d;
+            g.bench_function(
+                BenchmarkId::new(format!("rand/pool_{}M", pool >> 20), io_label(io)),
+                |b| b.iter(|| h.write(&mut meta, next_off(&mut seed, io), &buf)),
+            );
+            h.flush(&mut meta);
+            drop(h);
+            teardown(meta);
+        }
+    }
+    g.finish();
+}
+
+fn bench_read(c: &mut Criterion) {
+    let mut g = c.benchmark_group("read");
+    for pool in POOL_SIZES {
+        let mut meta = setup(pool);
+        let ino = meta.mknod(1, "file", Itype::File, 0o644).unwrap().id;
+        let mut h = FileHandle::new(ino, 0, data_store());
+        let chunk = vec![0xa5u8; 128 << 10];
+        let mut off = 0;
+        while off < FILE_SIZE {
+            h.write(&mut meta, off, &chunk);
+            off += chunk.len() as u64;
+        }
+        h.flush(&mut meta);
+
+        for io in IO_SIZES {
+            g.throughput(Throughput::Bytes(io));
+            let mut off = 0;
+            g.bench_function(
+                BenchmarkId::new(format!("seq/pool_{}M", pool >> 20), io_label(io)),
+                |b| {
+                    b.iter(|| {
+                        let _ = h.read(&mut meta, off, io as usize);
+                        off = (off + io) % FILE_SIZE;
+                    })
+                },
+            );
+            let mut seed = 0x2545f4914f6cdd1d;
+            g.bench_function(
+                BenchmarkId::new(format!("rand/pool_{}M", pool >> 20), io_label(io)),
+                |b| b.iter(|| h.read(&mut meta, next_off(&mut seed, io), io as usize)),
+            );
+        }
+        drop(h);
+        teardown(meta);
+    }
+    g.finish();
+}
+
+fn bench_meta(c: &mut Criterion) {
+    let mut meta = setup(1 << 20);
+    let mut i = 0u64;
+    c.bench_function("meta/mknod_unlink", |b| {
+        b.iter(|| {
+            let name = format!("f{}", i);
+            meta.mknod(1, &name, Itype::File, 0o644).unwrap();
+            meta.unlink(1, &name).unwrap();
+            i += 1;
+        })
+    });
+    // creation storm, superblock writes are amortized over many allocations, see `bench_meta_volume` for the bytes
+    c.bench_function("meta/create", |b| {
+        b.iter_custom(|iters| {
+            let start = Instant::now();
+            for n in 0..iters {
+                meta.mknod(1, format!("c{}", n), Itype::File, 0o644).unwrap();
+            }
+            let elapsed = start.elapsed();
+            for n in 0..iters {
+                meta.unlink(1, &format!("c{}", n)).unwrap();
+            }
+            elapsed
+        })
+    });
+    c.bench_function("meta/lookup", |b| {
+        meta.mknod(1, "hot", Itype::File, 0o644).unwrap();
+        b.iter(|| meta.lookup(1, "hot").unwrap())
+    });
+    // what `getattr` does, with and without the decoded inode cache
+    let inos: Vec<u64> = (0..1024)
+        .map(|n| meta.mknod(1, format!("a{}", n), Itype::File, 0o644).unwrap().id)
+        .collect();
+    for cap in [4096, 0] {
+        meta.set_inode_cache(cap);
+        let mut n = 0;
+        c.bench_function(&format!("meta/getattr/cache_{}", cap), |b| {
+            b.iter(|| {
+                n = (n + 1) % inos.len();
+                meta.get_inode(inos[n]).unwrap()
+            })
+        });
+    }
+    teardown(meta);
+}
+
+/// bytes written to the metadata store instead of wall time
+struct MetaBytes;
+
+impl Measurement for MetaBytes {
+    type Intermediate = u64;
+    type Value = u64;
+
+    fn start(&self) -> u64 {
+        stats::snapshot().meta_bytes
+    }
+
+    fn end(&self, start: u64) -> u64 {
+        stats::snapshot().meta_bytes - start
+    }
+
+    fn add(&self, a: &u64, b: &u64) -> u64 {
+        a + b
+    }
+
+    fn zero(&self) -> u64 {
+        0
+    }
+
+    fn to_f64(&self, v: &u64) -> f64 {
+        *v as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        self
+    }
+}
+
+impl ValueFormatter for MetaBytes {
+    fn scale_values(&self, _typical: f64, _values: &mut [f64]) -> &'static str {
+        "B"
+    }
+
+    fn scale_throughputs(&self, _typical: f64, _throughput: &Throughput, _values: &mut [f64]) -> &'static str {
+        "B"
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "B"
+    }
+}
+
+// meta bytes written per create, `synced` writes the superblock with every create as if it weren't written lazily
+fn bench_meta_volume(c: &mut Criterion<MetaBytes>) {
+    let mut meta = setup(1 << 20);
+    let mut g = c.benchmark_group("meta_volume/create");
+    g.sample_size(10);
+    for synced in [false, true] {
+        let name = if synced { "synced" } else { "lazy" };
+        g.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                let start = stats::snapshot().meta_bytes;
+                for n in 0..iters {
+                    meta.mknod(1, format!("c{}", n), Itype::File, 0o644).unwrap();
+                    if synced {
+                        meta.sync().unwrap();
+                    }
+                }
+                let bytes = stats::snapshot().meta_bytes - start;
+                for n in 0..iters {
+                    meta.unlink(1, &format!("c{}", n)).unwrap();
+                }
+                bytes
+            })
+        });
+    }
+    g.finish();
+    teardown(meta);
+}
+
+criterion_group!(benches, bench_write, bench_read, bench_meta);
+criterion_group! {
+    name = volume;
+    config = Criterion::default().with_measurement(MetaBytes);
+    targets = bench_meta_volume
+}
+criterion_main!(benches, volume);
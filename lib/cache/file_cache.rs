@@ -0,0 +1,96 @@
+use crate::cache::LRUCache;
+use crate::meta::Ino;
+
+/// files larger than this are never cached, it's meant for small files read over and over, e.g. configs
+pub const FILE_CACHE_MAX_FILE: u64 = 64 << 10;
+
+/// whole contents of small files keyed by ino, bounded by bytes rather than entries, the least recently read ones
+/// are evicted first, it's separate from `MemPool` which buffers writes
+pub struct FileCache {
+    files: LRUCache<Ino, Vec<u8>>,
+    bytes: u64,
+    budget: u64,
+}
+
+impl FileCache {
+    /// a zero `budget` disables the cache
+    pub fn new(budget: u64) -> Self {
+        Self {
+            files: LRUCache::new(usize::MAX),
+            bytes: 0,
+            budget,
+        }
+    }
+
+    /// whether a file of `len` bytes is worth caching
+    pub fn fits(&self, len: u64) -> bool {
+        self.budget > 0 && len <= FILE_CACHE_MAX_FILE && len <= self.budget
+    }
+
+    pub fn get(&mut self, ino: Ino) -> Option<&[u8]> {
+        self.files.get(&ino).map(|x| x.as_slice())
+    }
+
+    pub fn add(&mut self, ino: Ino, data: Vec<u8>) {
+        if !self.fits(data.len() as u64) {
+            return;
+        }
+        self.del(ino);
+        self.bytes += data.len() as u64;
+        self.files.add(ino, data);
+        while self.bytes > self.budget {
+            match self.files.pop() {
+                None => break,
+                Some((_, x)) => self.bytes -= x.len() as u64,
+            }
+        }
+    }
+
+    /// drop the contents of `ino`, it's called whenever its data may change
+    pub fn del(&mut self, ino: Ino) {
+        if let Some(x) = self.files.get(&ino) {
+            self.bytes -= x.len() as u64;
+            self.files.del(&ino);
+        }
+    }
+
+    #[allow(unused)]
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FileCache, FILE_CACHE_MAX_FILE};
+
+    #[test]
+    fn test_file_cache() {
+        let mut c = FileCache::new(100);
+        c.add(1, vec![1; 40]);
+        c.add(2, vec![2; 40]);
+        assert_eq!(c.bytes(), 80);
+        // 1 is the recent one, so 2 is evicted
+        assert!(c.get(1).is_some());
+        c.add(3, vec![3; 40]);
+        assert_eq!(c.bytes(), 80);
+        assert!(c.get(2).is_none());
+        assert_eq!(c.get(1), Some(&[1u8; 40][..]));
+
+        // replacing and removing keep the byte count
+        c.add(1, vec![1; 10]);
+        assert_eq!(c.bytes(), 50);
+        c.del(3);
+        c.del(4);
+        assert_eq!(c.bytes(), 10);
+
+        // too big for the budget, or for a small file
+        c.add(4, vec![4; 101]);
+        assert!(c.get(4).is_none());
+        let mut c = FileCache::new(1 << 30);
+        assert!(!c.fits(FILE_CACHE_MAX_FILE + 1));
+        c.add(5, vec![5; FILE_CACHE_MAX_FILE as usize + 1]);
+        assert_eq!(c.bytes(), 0);
+        assert!(!FileCache::new(0).fits(0));
+    }
+}
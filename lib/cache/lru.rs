@@ -153,6 +153,23 @@ where
         }
     }
 
+    /// remove the least recently used entry, it's returned rather than passed to the backend
+    pub fn pop(&mut self) -> Option<(K, V)> {
+        if self.size == 0 {
+            return None;
+        }
+        let node = self.front();
+        unsafe {
+            self.remove_node(node);
+            let key = (*node).key.take().unwrap();
+            self.map.remove(&key);
+            let val = (*node).val.take().unwrap();
+            let _ = Box::from_raw(node);
+            self.size -= 1;
+            Some((key, val))
+        }
+    }
+
     #[allow(unused)]
     pub fn len(&self) -> usize {
         self.size
@@ -271,5 +288,12 @@ mod test {
 
         lru.add(5, 5);
         assert_eq!(lru.len(), 1);
+
+        lru.add(6, 6);
+        lru.get(&5);
+        assert_eq!(lru.pop(), Some((6, 6)));
+        assert_eq!(lru.pop(), Some((5, 5)));
+        assert_eq!(lru.pop(), None);
+        assert_eq!(q.borrow().len(), cap);
     }
 }
@@ -1,10 +1,15 @@
+mod file_cache;
 mod lru;
 mod pool;
 
 pub use pool::MemPool;
 
-pub use lru::LRUCache;
+#[cfg(test)]
+pub(crate) use pool::POOL_LOCK;
 
-pub trait Flusher<K, V> {
+pub(crate) use file_cache::FileCache;
+pub(crate) use lru::LRUCache;
+
+pub(crate) trait Flusher<K, V> {
     fn flush(&mut self, key: K, data: V);
 }
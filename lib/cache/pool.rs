@@ -1,19 +1,25 @@
-use crate::utils::{align_up, BitMap, FS_PAGE_SIZE};
+use crate::utils::{align_up, is_power_of2, BitMap};
 use std::mem::ManuallyDrop;
 use std::ptr::NonNull;
 
 static mut G_MEMPOOL: NonNull<MemPool> = NonNull::dangling();
 
+/// the pool is process wide, tests which init or use it must hold this
+#[cfg(test)]
+pub(crate) static POOL_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
 pub struct MemPool {
     ptr: *mut u8,
     cap: usize,
+    /// bytes of every slot, a power of two
+    page: u64,
     dmap: BitMap,
 }
 
 impl MemPool {
-    pub fn init(cap: u64) {
+    pub fn init(cap: u64, page: u64) {
         unsafe {
-            let obj = Box::new(MemPool::new(cap));
+            let obj = Box::new(MemPool::new(cap, page));
             let ptr = Box::into_raw(obj);
             G_MEMPOOL = NonNull::new(ptr).expect("can't create nonnull");
         }
@@ -31,34 +37,46 @@ impl MemPool {
         unsafe { G_MEMPOOL.as_mut() }
     }
 
-    fn new(cap: u64) -> Self {
-        let cap = align_up(cap, FS_PAGE_SIZE);
+    fn new(cap: u64, page: u64) -> Self {
+        assert!(is_power_of2(page));
+        let cap = align_up(cap, page);
         let mut v = ManuallyDrop::new(vec![0u8; cap as usize]);
         Self {
             ptr: v.as_mut_ptr(),
             cap: cap as usize,
-            dmap: BitMap::new(cap / FS_PAGE_SIZE),
+            page,
+            dmap: BitMap::new(cap / page),
         }
     }
 
+    /// bytes of a page returned by `alloc`
+    pub fn page_size(&self) -> u64 {
+        self.page
+    }
+
     pub fn alloc(&mut self) -> *mut u8 {
         if let Some(x) = self.dmap.alloc() {
             unsafe {
-                return self.ptr.add((x * FS_PAGE_SIZE) as usize);
+                return self.ptr.add((x * self.page) as usize);
             }
         } else {
             std::ptr::null_mut()
         }
     }
 
-    pub fn free(&mut self, ptr: *mut u8) {
+    pub(crate) fn free(&mut self, ptr: *mut u8) {
         unsafe {
             let off = ptr.offset_from(self.ptr) as u64;
-            let bit = off / FS_PAGE_SIZE;
+            let bit = off / self.page;
             self.dmap.del(bit);
         }
     }
 
+    /// pages handed out by `alloc` and not freed yet
+    pub fn used(&self) -> u64 {
+        self.dmap.len()
+    }
+
     pub fn full(&self) -> bool {
         self.dmap.full()
     }
@@ -80,7 +98,7 @@ mod test {
 
     #[test]
     fn test_pool() {
-        let mut p = MemPool::new(32 * FS_PAGE_SIZE);
+        let mut p = MemPool::new(32 * FS_PAGE_SIZE, FS_PAGE_SIZE);
         let mut v = Vec::new();
 
         while !p.full() {
@@ -93,11 +111,23 @@ mod test {
         }
 
         assert!(!p.full());
+
+        // the capacity is rounded up to whole pages
+        let page = 16 * FS_PAGE_SIZE;
+        let mut p = MemPool::new(3 * page - 1, page);
+        assert_eq!(p.page_size(), page);
+        let v: Vec<*mut u8> = (0..3).map(|_| p.alloc()).collect();
+        assert!(p.full() && p.alloc().is_null());
+        unsafe {
+            assert_eq!(v[2].offset_from(v[0]), 2 * page as isize);
+        }
+        p.free(v[1]);
+        assert_eq!(p.alloc(), v[1]);
     }
 
     #[test]
     fn test_singleton() {
-        MemPool::init(FS_PAGE_SIZE * 3);
+        MemPool::init(FS_PAGE_SIZE * 3, FS_PAGE_SIZE);
 
         let x = MemPool::get().alloc();
         let y = MemPool::get().alloc();
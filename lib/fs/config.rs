@@ -0,0 +1,437 @@
+use crate::meta::Inode;
+use crate::utils::{is_power_of2, FS_BLK_SIZE, FS_PAGE_SIZE};
+use fuser::consts::{FUSE_ATOMIC_O_TRUNC, FUSE_WRITEBACK_CACHE};
+use fuser::MountOption;
+
+const FUSE_CONF: &str = "/etc/fuse.conf";
+const RELATIME_INTERVAL: u64 = 24 * 3600;
+
+/// a byte count with an optional `K`, `M` or `G` suffix
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (num, shift) = match s.as_bytes().last() {
+        Some(b'K' | b'k') => (&s[..s.len() - 1], 10),
+        Some(b'M' | b'm') => (&s[..s.len() - 1], 20),
+        Some(b'G' | b'g') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    num.parse::<u64>()
+        .ok()
+        .and_then(|x| x.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size {}", s))
+}
+
+/// a page must fit a block, a power of two so pages never straddle a block
+fn parse_page_size(s: &str) -> Result<u64, String> {
+    match parse_size(s)? {
+        x if is_power_of2(x) && x <= FS_BLK_SIZE => Ok(x),
+        _ => Err(format!(
+            "invalid page size {}, it must be a power of two up to {}",
+            s, FS_BLK_SIZE
+        )),
+    }
+}
+
+/// when to update `atime` on read
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Atime {
+    /// update on every read
+    Strict,
+    /// update when `atime` is not newer than `mtime`/`ctime`, or older than 24 hours
+    #[default]
+    Relative,
+    /// never update
+    No,
+}
+
+impl Atime {
+    pub fn need_update(&self, inode: &Inode, now: u64) -> bool {
+        match self {
+            Atime::Strict => inode.atime != now,
+            Atime::Relative => {
+                inode.atime <= inode.mtime || inode.atime <= inode.ctime || inode.atime + RELATIME_INTERVAL <= now
+            }
+            Atime::No => false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    pub meta_path: String,
+    pub mount_point: String,
+    /// where file data is kept if it's not the data path recorded at format
+    pub data_path: Option<String>,
+    pub allow_other: bool,
+    pub allow_root: bool,
+    pub default_permissions: bool,
+    pub atime: Atime,
+    pub strict: bool,
+    /// load the tree under root into the meta cache at mount
+    pub prewarm: bool,
+    /// sync data and meta of a file on close, so a closed file survives a power loss
+    pub sync_on_close: bool,
+    /// bytes of small files kept in memory whole after the first read, 0 disables it
+    pub file_cache: u64,
+    /// create the blocks of a file grown by `truncate` at once rather than on first write
+    pub prealloc: bool,
+    /// write every write to the data store before replying rather than buffering it
+    pub write_through: bool,
+    /// open data files with `O_DSYNC`, so data written to the store is on disk without an fsync
+    pub data_dsync: bool,
+    /// append writes to a journal before replying, so buffered writes survive the process dying
+    pub journal: bool,
+    /// let the kernel cache writes and send them in background
+    pub writeback_cache: bool,
+    /// requests taking at least this many milliseconds are logged as slow, 0 disables it
+    pub slow_op_ms: u64,
+    /// bytes of a page of the memory pool buffering writes, a power of two up to the block size
+    pub page_size: u64,
+    /// file holding the passphrase of a filesystem with encrypted data
+    pub key_file: Option<String>,
+    /// meta path of a copy of the metadata kept up to date in background
+    pub mirror_meta: Option<String>,
+    pub read_only: bool,
+    pub sync: bool,
+    pub dirsync: bool,
+    pub noexec: bool,
+    pub nosuid: bool,
+    pub nodev: bool,
+    /// `-o` options we don't know, they're ignored with a warning
+    pub unknown: Vec<String>,
+}
+
+impl Config {
+    pub fn usage(prog: &str) -> String {
+        format!(
+            "{} [--allow-other | --allow-root] [--default-permissions] [--strictatime | --relatime | --noatime] \
+             [--strict] [--prewarm] [--sync-on-close] [--file-cache bytes[K|M|G]] [--prealloc] [--write-through] [--data-dsync] [--journal] [--writeback-cache] [--data-path dir] [--slow-op-ms ms] [--page-size bytes[K|M]] [--key-file path] [--mirror-meta path] [-o opt[,opt...]] meta_path mount_point",
+            prog
+        )
+    }
+
+    /// parse command line arguments, `args` should not contain the program name
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut cfg = Config {
+            page_size: FS_PAGE_SIZE,
+            ..Default::default()
+        };
+        let mut pos = Vec::new();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-o" => match args.next() {
+                    None => return Err("-o requires an argument".to_string()),
+                    Some(opts) => cfg.apply_options(&opts),
+                },
+                x if x.starts_with("-o") => cfg.apply_options(&x[2..]),
+                "--allow-other" => cfg.allow_other = true,
+                "--allow-root" => cfg.allow_root = true,
+                "--default-permissions" => cfg.default_permissions = true,
+                "--strictatime" => cfg.atime = Atime::Strict,
+                "--relatime" => cfg.atime = Atime::Relative,
+                "--noatime" => cfg.atime = Atime::No,
+                "--strict" => cfg.strict = true,
+                "--prewarm" => cfg.prewarm = true,
+                "--sync-on-close" => cfg.sync_on_close = true,
+                "--prealloc" => cfg.prealloc = true,
+                "--write-through" => cfg.write_through = true,
+                "--data-dsync" => cfg.data_dsync = true,
+                "--journal" => cfg.journal = true,
+                "--writeback-cache" => cfg.writeback_cache = true,
+                "--file-cache" => match args.next() {
+                    None => return Err("--file-cache requires an argument".to_string()),
+                    Some(x) => cfg.file_cache = parse_size(&x)?,
+                },
+                "--slow-op-ms" => match args.next() {
+                    None => return Err("--slow-op-ms requires an argument".to_string()),
+                    Some(x) => cfg.slow_op_ms = x.parse().map_err(|_| format!("invalid milliseconds {}", x))?,
+                },
+                "--page-size" => match args.next() {
+                    None => return Err("--page-size requires an argument".to_string()),
+                    Some(x) => cfg.page_size = parse_page_size(&x)?,
+                },
+                "--mirror-meta" => match args.next() {
+                    None => return Err("--mirror-meta requires an argument".to_string()),
+                    Some(x) => cfg.mirror_meta = Some(x),
+                },
+                "--key-file" => match args.next() {
+                    None => return Err("--key-file requires an argument".to_string()),
+                    Some(x) => cfg.key_file = Some(x),
+                },
+                "--data-path" => match args.next() {
+                    None => return Err("--data-path requires an argument".to_string()),
+                    Some(x) => cfg.data_path = Some(x),
+                },
+                x if x.starts_with("--") => return Err(format!("unknown option {}", x)),
+                _ => pos.push(arg),
+            }
+        }
+
+        if pos.len() != 2 {
+            return Err("require meta_path and mount_point".to_string());
+        }
+        if cfg.allow_other && cfg.allow_root {
+            return Err("--allow-other and --allow-root are mutually exclusive".to_string());
+        }
+        cfg.mount_point = pos.pop().unwrap();
+        cfg.meta_path = pos.pop().unwrap();
+        Ok(cfg)
+    }
+
+    /// apply comma separated options in the form of mount(8) and /etc/fstab
+    fn apply_options(&mut self, opts: &str) {
+        for opt in opts.split(',').filter(|x| !x.is_empty()) {
+            match opt {
+                "ro" => self.read_only = true,
+                "rw" => self.read_only = false,
+                "sync" => self.sync = true,
+                "async" => self.sync = false,
+                "dirsync" => self.dirsync = true,
+                "noatime" => self.atime = Atime::No,
+                "atime" | "relatime" => self.atime = Atime::Relative,
+                "strictatime" => self.atime = Atime::Strict,
+                "allow_other" => self.allow_other = true,
+                "allow_root" => self.allow_root = true,
+                "default_permissions" => self.default_permissions = true,
+                "exec" => self.noexec = false,
+                "noexec" => self.noexec = true,
+                "suid" => self.nosuid = false,
+                "nosuid" => self.nosuid = true,
+                "dev" => self.nodev = false,
+                "nodev" => self.nodev = true,
+                // consumed by mount(8)
+                "defaults" | "auto" | "noauto" | "user" | "users" | "nouser" | "nofail" | "_netdev" => {}
+                x => {
+                    log::warn!("ignore unknown option {}", x);
+                    self.unknown.push(x.to_string());
+                }
+            }
+        }
+    }
+
+    /// `allow_other` and `allow_root` are rejected by fusermount for non-root users unless `user_allow_other` is
+    /// set in /etc/fuse.conf, check it early to give a clear message
+    pub fn check(&self) -> Result<(), String> {
+        if !(self.allow_other || self.allow_root) || unsafe { libc::getuid() } == 0 {
+            return Ok(());
+        }
+        let conf = std::fs::read_to_string(FUSE_CONF).unwrap_or_default();
+        if !Self::user_allow_other(&conf) {
+            return Err(format!(
+                "--allow-other/--allow-root require `user_allow_other` in {} for non-root users",
+                FUSE_CONF
+            ));
+        }
+        Ok(())
+    }
+
+    fn user_allow_other(conf: &str) -> bool {
+        conf.lines().any(|l| l.trim() == "user_allow_other")
+    }
+
+    /// capabilities requested from the kernel in `init`, caching writes in the kernel is only asked for by
+    /// `--writeback-cache`, it's pointless for a read-only mount and defeats `sync`, `O_TRUNC` is handled by `open`
+    pub fn capabilities(&self) -> u32 {
+        let mut caps = FUSE_ATOMIC_O_TRUNC;
+        if self.writeback_cache && !(self.read_only || self.sync) {
+            caps |= FUSE_WRITEBACK_CACHE;
+        }
+        caps
+    }
+
+    pub fn mount_options(&self) -> Vec<MountOption> {
+        let mut options = vec![
+            MountOption::FSName("jfs".to_string()),
+            MountOption::Subtype("jfs".to_string()),
+        ];
+        if self.allow_other {
+            options.push(MountOption::AllowOther);
+        }
+        if self.allow_root {
+            options.push(MountOption::AllowRoot);
+        }
+        if self.default_permissions {
+            options.push(MountOption::DefaultPermissions);
+        }
+        let flags = [
+            (self.read_only, MountOption::RO),
+            (self.sync, MountOption::Sync),
+            (self.dirsync, MountOption::DirSync),
+            (self.noexec, MountOption::NoExec),
+            (self.nosuid, MountOption::NoSuid),
+            (self.nodev, MountOption::NoDev),
+        ];
+        options.extend(flags.into_iter().filter(|x| x.0).map(|x| x.1));
+        options
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Atime, Config, RELATIME_INTERVAL};
+    use crate::meta::{Inode, Itype};
+    use crate::utils::{FS_BLK_SIZE, FS_PAGE_SIZE};
+    use fuser::consts::{FUSE_ATOMIC_O_TRUNC, FUSE_WRITEBACK_CACHE};
+    use fuser::MountOption;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let cfg = Config::parse(args("--allow-other /tmp/meta --default-permissions /mnt")).unwrap();
+        assert_eq!(cfg.meta_path, "/tmp/meta");
+        assert_eq!(cfg.mount_point, "/mnt");
+        let opts = cfg.mount_options();
+        assert!(opts.contains(&MountOption::AllowOther));
+        assert!(opts.contains(&MountOption::DefaultPermissions));
+        assert!(!opts.contains(&MountOption::AllowRoot));
+        assert!(!cfg.strict);
+        assert!(Config::parse(args("--strict /tmp/meta /mnt")).unwrap().strict);
+        assert!(!cfg.prewarm);
+        assert!(Config::parse(args("--prewarm /tmp/meta /mnt")).unwrap().prewarm);
+        assert!(!cfg.sync_on_close);
+        assert!(
+            Config::parse(args("--sync-on-close /tmp/meta /mnt"))
+                .unwrap()
+                .sync_on_close
+        );
+        assert!(!cfg.prealloc);
+        assert!(Config::parse(args("--prealloc /tmp/meta /mnt")).unwrap().prealloc);
+        assert!(!cfg.write_through);
+        assert!(
+            Config::parse(args("--write-through /tmp/meta /mnt"))
+                .unwrap()
+                .write_through
+        );
+        assert!(!cfg.data_dsync);
+        assert!(Config::parse(args("--data-dsync /tmp/meta /mnt")).unwrap().data_dsync);
+        assert!(!cfg.journal);
+        assert!(Config::parse(args("--journal /tmp/meta /mnt")).unwrap().journal);
+        assert!(!cfg.writeback_cache);
+        assert!(
+            Config::parse(args("--writeback-cache /tmp/meta /mnt"))
+                .unwrap()
+                .writeback_cache
+        );
+        assert_eq!(cfg.file_cache, 0);
+        let cache = |s: &str| Config::parse(args(s)).map(|x| x.file_cache);
+        assert_eq!(cache("--file-cache 4M /tmp/meta /mnt"), Ok(4 << 20));
+        assert_eq!(cache("--file-cache 100 /tmp/meta /mnt"), Ok(100));
+        assert!(cache("--file-cache 1X /tmp/meta /mnt").is_err());
+        assert!(cache("/tmp/meta /mnt --file-cache").is_err());
+        assert_eq!(cfg.data_path, None);
+        assert_eq!(cfg.slow_op_ms, 0);
+        let slow = |s: &str| Config::parse(args(s)).map(|x| x.slow_op_ms);
+        assert_eq!(slow("--slow-op-ms 100 /tmp/meta /mnt"), Ok(100));
+        assert!(slow("--slow-op-ms 1s /tmp/meta /mnt").is_err());
+        assert!(slow("/tmp/meta /mnt --slow-op-ms").is_err());
+        assert_eq!(cfg.page_size, FS_PAGE_SIZE);
+        let page = |s: &str| Config::parse(args(s)).map(|x| x.page_size);
+        assert_eq!(page("--page-size 64K /tmp/meta /mnt"), Ok(64 << 10));
+        assert_eq!(page("--page-size 128M /tmp/meta /mnt"), Ok(FS_BLK_SIZE));
+        for bad in ["0", "3000", "256M", "4X"] {
+            assert!(page(&format!("--page-size {} /tmp/meta /mnt", bad)).is_err(), "{}", bad);
+        }
+        assert!(page("/tmp/meta /mnt --page-size").is_err());
+        assert_eq!(cfg.key_file, None);
+        let cfg = Config::parse(args("--key-file /etc/jfs.key /tmp/meta /mnt")).unwrap();
+        assert_eq!(cfg.key_file.as_deref(), Some("/etc/jfs.key"));
+        assert!(Config::parse(args("/tmp/meta /mnt --key-file")).is_err());
+        assert_eq!(cfg.mirror_meta, None);
+        let cfg = Config::parse(args("--mirror-meta /backup/meta /tmp/meta /mnt")).unwrap();
+        assert_eq!(cfg.mirror_meta.as_deref(), Some("/backup/meta"));
+        assert!(Config::parse(args("/tmp/meta /mnt --mirror-meta")).is_err());
+        let cfg = Config::parse(args("--data-path /data /tmp/meta /mnt")).unwrap();
+        assert_eq!(cfg.data_path.as_deref(), Some("/data"));
+        assert_eq!(cfg.meta_path, "/tmp/meta");
+        assert!(Config::parse(args("/tmp/meta /mnt --data-path")).is_err());
+
+        assert!(Config::parse(args("/tmp/meta")).is_err());
+        assert!(Config::parse(args("--allow-other --allow-root /tmp/meta /mnt")).is_err());
+        assert!(Config::parse(args("--bad /tmp/meta /mnt")).is_err());
+    }
+
+    #[test]
+    fn test_user_allow_other() {
+        assert!(Config::user_allow_other("# comment\nuser_allow_other\n"));
+        assert!(!Config::user_allow_other("#user_allow_other\nmount_max = 1000\n"));
+    }
+
+    #[test]
+    fn test_atime() {
+        let mut inode = Inode {
+            id: 2,
+            parent: 1,
+            kind: Itype::File,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            atime: 100,
+            mtime: 50,
+            ctime: 50,
+            length: 0,
+            links: 1,
+        };
+
+        assert!(Atime::Strict.need_update(&inode, 101));
+        assert!(!Atime::Strict.need_update(&inode, 100));
+        assert!(!Atime::No.need_update(&inode, 100 + RELATIME_INTERVAL));
+
+        assert!(!Atime::Relative.need_update(&inode, 101));
+        assert!(Atime::Relative.need_update(&inode, 100 + RELATIME_INTERVAL));
+        inode.mtime = 100;
+        assert!(Atime::Relative.need_update(&inode, 101));
+
+        assert_eq!(Config::parse(args("/a /b")).unwrap().atime, Atime::Relative);
+        assert_eq!(Config::parse(args("--noatime /a /b")).unwrap().atime, Atime::No);
+        assert_eq!(Config::parse(args("--strictatime /a /b")).unwrap().atime, Atime::Strict);
+    }
+
+    #[test]
+    fn test_mount_options() {
+        let cfg = Config::parse(args(
+            "-o ro,noatime,sync,allow_other,default_permissions,noexec,nosuid,defaults,bogus /tmp/meta /mnt",
+        ))
+        .unwrap();
+        assert_eq!(cfg.meta_path, "/tmp/meta");
+        assert_eq!(cfg.mount_point, "/mnt");
+        assert_eq!(cfg.atime, Atime::No);
+        assert!(cfg.allow_other && cfg.default_permissions);
+        assert_eq!(cfg.unknown, vec!["bogus".to_string()]);
+        let opts = cfg.mount_options();
+        for o in [
+            MountOption::RO,
+            MountOption::Sync,
+            MountOption::NoExec,
+            MountOption::NoSuid,
+            MountOption::AllowOther,
+        ] {
+            assert!(opts.contains(&o), "{:?}", o);
+        }
+        assert!(!opts.contains(&MountOption::DirSync));
+
+        // later options win, `-oopt` is the same as `-o opt`
+        let cfg = Config::parse(args("-oro,noexec /tmp/meta -o rw,exec,dirsync /mnt")).unwrap();
+        assert!(!cfg.read_only && !cfg.noexec && cfg.dirsync);
+        assert!(cfg.unknown.is_empty());
+
+        assert!(Config::parse(args("/tmp/meta /mnt -o")).is_err());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let mut cfg = Config::parse(args("/tmp/meta /mnt")).unwrap();
+        assert_eq!(cfg.capabilities() & FUSE_WRITEBACK_CACHE, 0);
+        assert_eq!(cfg.capabilities() & FUSE_ATOMIC_O_TRUNC, FUSE_ATOMIC_O_TRUNC);
+        cfg.writeback_cache = true;
+        assert_eq!(cfg.capabilities() & FUSE_WRITEBACK_CACHE, FUSE_WRITEBACK_CACHE);
+
+        for opt in ["-o ro", "-o sync"] {
+            let cfg = Config::parse(args(&format!("--writeback-cache {} /tmp/meta /mnt", opt))).unwrap();
+            assert_eq!(cfg.capabilities() & FUSE_WRITEBACK_CACHE, 0, "{}", opt);
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use crate::meta::{Ino, Inode, Itype};
+use crate::utils::{to_attr, FS_ROOT_INODE};
+use fuser::FileAttr;
+
+/// a hidden directory in root holding files generated on open rather than stored, it's not listed by readdir, and
+/// the name can't be taken by a regular entry
+pub const CTL_DIR: &str = ".junkfs";
+pub const HEALTH: &str = "health";
+pub const HANDLES: &str = "handles";
+pub const LABEL: &str = "label";
+
+/// `ioctl` on an open directory removing everything below it, the directory itself is kept, it's what `rm -rf` of
+/// its entries does but with far fewer writes to the metadata store, `_IO('J', 1)`
+pub const CLEAR_DIR: u32 = 0x4a01;
+/// `ioctl` on any file of the filesystem changing its label to the argument, a label of up to `LABEL_MAX` bytes padded
+/// with NUL, `_IOW('J', 2, [u8; LABEL_MAX])`
+pub const SET_LABEL: u32 = 0x40104a02;
+
+// far beyond the inode map, so they never name a stored inode
+pub const CTL_DIR_INO: Ino = u64::MAX - 1;
+pub const HEALTH_INO: Ino = u64::MAX - 2;
+pub const HANDLES_INO: Ino = u64::MAX - 3;
+pub const LABEL_INO: Ino = u64::MAX - 4;
+
+pub fn is_ctl(ino: Ino) -> bool {
+    ino == CTL_DIR_INO || ino == HEALTH_INO || ino == HANDLES_INO || ino == LABEL_INO
+}
+
+/// whether `name` in `parent` is a control entry, which is what it resolves to then
+pub fn lookup(parent: Ino, name: &str) -> Option<Ino> {
+    match (parent, name) {
+        (FS_ROOT_INODE, CTL_DIR) => Some(CTL_DIR_INO),
+        (CTL_DIR_INO, HEALTH) => Some(HEALTH_INO),
+        (CTL_DIR_INO, HANDLES) => Some(HANDLES_INO),
+        (CTL_DIR_INO, LABEL) => Some(LABEL_INO),
+        _ => None,
+    }
+}
+
+/// attributes of control entry `ino`, it's read only and owned by the user running the filesystem
+pub fn attr(ino: Ino, size: u64, started: u64) -> FileAttr {
+    let (parent, kind, mode) =
+        if ino == CTL_DIR_INO { (FS_ROOT_INODE, Itype::Dir, 0o555) } else { (CTL_DIR_INO, Itype::File, 0o444) };
+    to_attr(&Inode {
+        id: ino,
+        parent,
+        kind,
+        mode,
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+        atime: started,
+        mtime: started,
+        ctime: started,
+        length: size,
+        links: 1,
+    })
+}
+
+/// what `/.junkfs/health` reads, one `name value` pair per line, so a monitoring agent can poll the mount without
+/// touching any stored file
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Health {
+    /// seconds since mount
+    pub uptime: u64,
+    /// open files and directories
+    pub open_handles: u64,
+    /// bytes written but not flushed to the data store yet
+    pub dirty_bytes: u64,
+    /// decoded inodes in the meta cache
+    pub cached_inodes: u64,
+    /// unix seconds of the last flush, 0 if there's none yet
+    pub last_flush: u64,
+}
+
+impl Health {
+    pub fn render(&self) -> String {
+        format!(
+            "uptime {}\nopen_handles {}\ndirty_bytes {}\ncached_inodes {}\nlast_flush {}\n",
+            self.uptime, self.open_handles, self.dirty_bytes, self.cached_inodes, self.last_flush
+        )
+    }
+
+    /// names not known are skipped, so an older agent can read a newer status
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut h = Health::default();
+        for line in s.lines() {
+            let (name, val) = line.split_once(' ').ok_or_else(|| format!("invalid line {}", line))?;
+            let val = val
+                .parse::<u64>()
+                .map_err(|e| format!("invalid value of {}, error {}", name, e))?;
+            match name {
+                "uptime" => h.uptime = val,
+                "open_handles" => h.open_handles = val,
+                "dirty_bytes" => h.dirty_bytes = val,
+                "cached_inodes" => h.cached_inodes = val,
+                "last_flush" => h.last_flush = val,
+                _ => {}
+            }
+        }
+        Ok(h)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandleKind {
+    File,
+    Dir,
+    Ctl,
+}
+
+impl HandleKind {
+    fn name(&self) -> &'static str {
+        match self {
+            HandleKind::File => "file",
+            HandleKind::Dir => "dir",
+            HandleKind::Ctl => "ctl",
+        }
+    }
+}
+
+/// an open handle as listed by `/.junkfs/handles`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenHandle {
+    pub ino: Ino,
+    pub fh: u64,
+    pub kind: HandleKind,
+    /// bytes written through the handle but not flushed yet
+    pub dirty: u64,
+}
+
+/// the label given to `SET_LABEL`, which is padded with NUL
+pub fn parse_label(data: &[u8]) -> Result<&str, libc::c_int> {
+    let end = data.iter().position(|&c| c == 0).unwrap_or(data.len());
+    std::str::from_utf8(&data[..end]).map_err(|_| libc::EINVAL)
+}
+
+/// what `/.junkfs/handles` reads, one `fh ino kind dirty` per handle, ordered by fh
+pub fn render_handles(handles: &[OpenHandle]) -> String {
+    handles
+        .iter()
+        .map(|h| format!("{} {} {} {}\n", h.fh, h.ino, h.kind.name(), h.dirty))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        lookup, parse_label, render_handles, HandleKind, Health, OpenHandle, CTL_DIR_INO, HANDLES_INO, HEALTH_INO,
+        LABEL_INO,
+    };
+
+    #[test]
+    fn test_health() {
+        let h = Health {
+            uptime: 12,
+            open_handles: 3,
+            dirty_bytes: 4096,
+            cached_inodes: 7,
+            last_flush: 1700000000,
+        };
+        assert_eq!(Health::parse(&h.render()).unwrap(), h);
+        assert_eq!(Health::parse("uptime 1\nnew_field 2\n").unwrap().uptime, 1);
+        assert!(Health::parse("uptime x\n").is_err());
+        assert!(Health::parse("uptime\n").is_err());
+
+        assert_eq!(lookup(1, ".junkfs"), Some(CTL_DIR_INO));
+        assert_eq!(lookup(CTL_DIR_INO, "health"), Some(HEALTH_INO));
+        assert_eq!(lookup(CTL_DIR_INO, "handles"), Some(HANDLES_INO));
+        assert_eq!(lookup(CTL_DIR_INO, "label"), Some(LABEL_INO));
+        assert_eq!(lookup(2, ".junkfs"), None);
+        assert_eq!(lookup(1, "health"), None);
+
+        let v = [
+            OpenHandle {
+                ino: 2,
+                fh: 1,
+                kind: HandleKind::File,
+                dirty: 4096,
+            },
+            OpenHandle {
+                ino: 1,
+                fh: 3,
+                kind: HandleKind::Dir,
+                dirty: 0,
+            },
+        ];
+        assert_eq!(render_handles(&v), "1 2 file 4096\n3 1 dir 0\n");
+        assert_eq!(render_handles(&[]), "");
+
+        assert_eq!(parse_label(b"data\0\0\0\0"), Ok("data"));
+        assert_eq!(parse_label(b"full-sixteen-byt"), Ok("full-sixteen-byt"));
+        assert_eq!(parse_label(&[0; 16]), Ok(""));
+        assert_eq!(parse_label(&[0xff, 0]), Err(libc::EINVAL));
+    }
+}
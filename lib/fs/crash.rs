@@ -0,0 +1,108 @@
+//! context for a panic in a request handler, the panic unwinds out of the session loop, which drops `Fs` and so
+//! flushes open handles and meta before the process exits, what's added here is the request it hit in the log, and
+//! how long each request takes, so a hang can be told from a slow request
+
+use crate::utils::fault::{self, Fault};
+use crate::utils::stats;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+thread_local! {
+    static OP: Cell<(&'static str, u64)> = const { Cell::new(("", 0)) };
+}
+
+// requests taking at least this long are logged, 0 disables it
+static SLOW_OP_NS: AtomicU64 = AtomicU64::new(0);
+
+/// log requests taking at least `ms` milliseconds as slow, 0 disables it, the latency is recorded anyway
+pub fn set_slow_op(ms: u64) {
+    SLOW_OP_NS.store(ms.saturating_mul(1_000_000), Ordering::Relaxed);
+}
+
+/// a request being served, its latency is recorded when it's dropped at the end of the handler
+#[must_use]
+pub struct Span {
+    op: &'static str,
+    ino: u64,
+    start: Instant,
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let ns = self.start.elapsed().as_nanos() as u64;
+        let limit = SLOW_OP_NS.load(Ordering::Relaxed);
+        let slow = limit > 0 && ns >= limit;
+        if slow {
+            log::warn!("slow {} ino {} took {}ms", self.op, self.ino, ns / 1_000_000);
+        }
+        stats::record_op(self.op, ns, slow);
+    }
+}
+
+/// remember the request served on this thread, it's what a panic is reported with, and start timing it
+pub fn enter(op: &'static str, ino: u64) -> Span {
+    OP.set((op, ino));
+    if fault::hit(Fault::Panic) {
+        panic!("injected panic in {} ino {}", op, ino);
+    }
+    let span = Span {
+        op,
+        ino,
+        start: Instant::now(),
+    };
+    if fault::hit(Fault::Slow) {
+        std::thread::sleep(Duration::from_nanos(SLOW_OP_NS.load(Ordering::Relaxed).max(1_000_000)));
+    }
+    span
+}
+
+/// the request last served on this thread, if any
+pub fn current() -> Option<(&'static str, u64)> {
+    let (op, ino) = OP.get();
+    (!op.is_empty()).then_some((op, ino))
+}
+
+/// log panics with the request they hit, then report them as before
+pub fn install_panic_hook() {
+    let prev = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        match current() {
+            Some((op, ino)) => log::error!("panic in {} ino {}: {}", op, ino, info),
+            None => log::error!("panic: {}", info),
+        }
+        log::logger().flush();
+        prev(info);
+    }));
+}
+
+#[cfg(test)]
+mod test {
+    use super::{enter, set_slow_op};
+    use crate::utils::fault::{self, Fault};
+    use crate::utils::stats::{self, OpStats};
+
+    #[test]
+    fn test_slow_op() {
+        let op = |name: &str| {
+            stats::ops()
+                .into_iter()
+                .find(|x| x.0 == name)
+                .map_or(OpStats::default(), |x| x.1)
+        };
+        let before = stats::snapshot().slow_ops;
+        set_slow_op(5);
+        drop(enter("test_fast", 1));
+        fault::arm(Fault::Slow, 0);
+        drop(enter("test_slow", 2));
+        fault::disarm(Fault::Slow);
+        set_slow_op(0);
+        drop(enter("test_slow", 2));
+
+        let (fast, slow) = (op("test_fast"), op("test_slow"));
+        assert_eq!((fast.calls, fast.slow), (1, 0));
+        assert_eq!((slow.calls, slow.slow), (2, 1));
+        assert!(slow.max_ns >= 5_000_000 && slow.ns >= slow.max_ns);
+        assert_eq!(stats::snapshot().slow_ops - before, 1);
+    }
+}
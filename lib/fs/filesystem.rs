@@ -1,63 +1,424 @@
-use crate::cache::MemPool;
-use crate::meta::{DirHandle, FileHandle, HandleCmp, Ino, Itype, Meta};
-use crate::store::FileStore;
-use crate::utils::{to_attr, to_filetype, BitMap, FS_BLK_SIZE, FS_FUSE_MAX_IO_SIZE};
+use crate::cache::{FileCache, LRUCache, MemPool};
+use crate::fs::control::{self, HandleKind, Health, OpenHandle, CTL_DIR_INO, HEALTH_INO, LABEL_INO};
+use crate::fs::crash;
+use crate::fs::journal::{self, Journal};
+use crate::fs::Config;
+use crate::meta::{DirHandle, FileHandle, HandleCmp, Ino, Inode, Itype, Layout, Meta, MetaError};
+use crate::store::{read_passphrase, FileStore, PackedStore, SharedStore, Xts};
+use crate::utils::{
+    epoch_secs, from_systime, get_data_path, stats, to_attr, to_filetype, BitMap, FS_BLK_SIZE, FS_FUSE_MAX_IO_SIZE,
+    FS_ROOT_INODE,
+};
+use fuser::consts::{FOPEN_DIRECT_IO, FUSE_WRITEBACK_CACHE};
 use fuser::{
-    Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite,
-    Request, TimeOrNow,
+    fuse_forget_one, FileAttr, FileType, Filesystem, MountOption, Notifier, PollHandle, ReplyAttr, ReplyCreate,
+    ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLseek, ReplyOpen, ReplyPoll, ReplyWrite,
+    ReplyXattr, Request, TimeOrNow,
+};
+use libc::{
+    E2BIG, EACCES, EBADF, EEXIST, EFAULT, EINVAL, EISDIR, ENFILE, ENOENT, ENOSYS, ENOTDIR, ENXIO, S_IFMT, S_IFREG,
 };
-use libc::{E2BIG, EEXIST, EFAULT, ENOENT, ENOSYS, ENOTDIR, S_IFMT, S_IFREG};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 use std::time;
 use std::time::SystemTime;
 
 type HashTable<T> = RefCell<HashMap<Ino, Vec<Rc<RefCell<T>>>>>;
 
+static COMPACT_REQUESTED: AtomicBool = AtomicBool::new(false);
+// set once the session is created, `Fs` itself is moved into the session before a notifier exists
+static NOTIFIER: Mutex<Option<Invalidator>> = Mutex::new(None);
+
+// at most this many files and directories open at same time, including the reserved fh 0
+const FS_MAX_HANDLES: u64 = 1024;
+// allocated sizes of files kept for `getattr`, see `Fs::attr`
+const USAGE_CACHE_SIZE: usize = 4096;
+// `FUSE_POLL_SCHEDULE_NOTIFY`, the kernel waits for a notification of the poll handle
+const POLL_SCHEDULE_NOTIFY: u32 = 1;
+// events a regular file is always ready for
+const POLL_READY: u32 = (libc::POLLIN | libc::POLLOUT | libc::POLLRDNORM | libc::POLLWRNORM) as u32;
+
+// the journal is emptied once it grows past this, after everything buffered is flushed
+const JOURNAL_LIMIT: u64 = 64 << 20;
+
+/// tells the kernel a file polled is worth polling again, see `Fs::poll_file`
+type PollWaker = Box<dyn FnOnce() -> std::io::Result<()>>;
+/// tells the kernel to drop what it caches of an ino, see `Fs::invalidate`
+type Invalidator = Box<dyn Fn(Ino) -> std::io::Result<()> + Send>;
+
+/// fh 0 is reserved, it's what a request without a handle carries, so it must never name an open file
+fn handle_map(cap: u64) -> BitMap {
+    let mut m = BitMap::new(cap);
+    m.add(0);
+    m
+}
+
+/// the part of `buf` a read of `size` bytes at `off` gets
+fn window(buf: &[u8], off: u64, size: usize) -> &[u8] {
+    let start = (off as usize).min(buf.len());
+    &buf[start..(start + size).min(buf.len())]
+}
+
+/// `size` 0 asks for the length only, a buffer too small for the value gets `ERANGE`
+fn reply_xattr(buf: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(buf.len() as u32);
+    } else if (size as usize) < buf.len() {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(buf);
+    }
+}
+
+/// the file offset of a read or write, it must not be negative and the end must be representable as `off_t`,
+/// otherwise it would wrap into a nonsensical block id
+fn io_offset(offset: i64, size: usize) -> Result<u64, libc::c_int> {
+    if offset < 0 || offset.checked_add(size as i64).is_none() {
+        return Err(EINVAL);
+    }
+    Ok(offset as u64)
+}
+
+/// data is only read, written or resized through a regular file, the target of a symlink is kept in meta rather than
+/// data blocks and must not be cut or written, `symlink` is the error for one
+fn check_file(inode: &Inode, symlink: libc::c_int) -> Result<(), libc::c_int> {
+    match inode.kind {
+        Itype::File => Ok(()),
+        Itype::Dir => Err(EISDIR),
+        Itype::Symlink => Err(symlink),
+    }
+}
+
 pub struct Fs {
+    cfg: Config,
     meta: Meta,
     store: HashTable<FileHandle>,
     dirs: HashTable<DirHandle>,
     hmap: BitMap,
+    hmap_warned: bool,
+    data: SharedStore,
+    /// the kernel caches writes, it handles `O_APPEND` itself then
+    writeback: bool,
+    /// ino => bytes allocated in the data store, only for files without open handles
+    usage: LRUCache<Ino, u64>,
+    /// unix seconds of mount
+    started: u64,
+    /// fh => ino and content of an open control file, see `control`
+    ctl_files: HashMap<u64, (Ino, Vec<u8>)>,
+    /// whole contents of small files recently read, see `--file-cache`
+    file_cache: FileCache,
+    /// ino => lookups the kernel holds, every entry replied counts one until it's given back by `forget`
+    lookups: HashMap<Ino, u64>,
+    /// files removed while open, their inode and data are kept until they're closed and forgotten, see `reclaim`
+    unlinked: HashSet<Ino>,
+    /// files a write back failed for since their last fsync, kept after the failed handle is closed
+    write_errors: HashSet<Ino>,
+    /// a write back of any file failed since the last `fsyncdir`
+    sync_error: bool,
+    /// ino => pollers waiting for the file to change, woken once by the next write
+    pollers: HashMap<Ino, Vec<PollWaker>>,
+    /// writes buffered since the last checkpoint, see `--journal`
+    journal: Option<Journal>,
 }
 
 unsafe impl Send for Fs {}
 
 impl Fs {
-    pub fn new(path: String) -> Result<Self, String> {
-        let meta = Meta::load_fs(path);
+    pub fn new(cfg: &Config) -> Result<Self, String> {
+        Self::load(cfg, None)
+    }
+
+    /// same as `new` but keep file data in `data` instead of the data directory
+    pub fn with_store(cfg: &Config, data: SharedStore) -> Result<Self, String> {
+        Self::load(cfg, Some(data))
+    }
+
+    /// the data store is chosen by the layout recorded in superblock unless `data` is given
+    fn load(cfg: &Config, data: Option<SharedStore>) -> Result<Self, String> {
+        let meta = Meta::load_fs(cfg.meta_path.clone(), !cfg.read_only);
         if meta.is_err() {
             return Err(meta.err().unwrap());
         }
 
-        MemPool::init(100 << 20);
+        let mut meta = meta.unwrap();
+        if let Some(path) = &cfg.data_path {
+            meta.set_data_path(path)?;
+        }
+        if let Some(path) = &cfg.mirror_meta {
+            let real = |x: &str| std::fs::canonicalize(x).ok();
+            if real(path).is_some() && real(path) == real(&cfg.meta_path) {
+                return Err(format!("mirror {} is the meta store itself", path));
+            }
+            meta.mirror_to(path);
+        }
+        let issues = meta.check();
+        for i in &issues {
+            log::warn!("self check: {}", i);
+        }
+        if cfg.strict && !issues.is_empty() {
+            return Err(format!(
+                "self check found {} issue(s): {}",
+                issues.len(),
+                issues.join(", ")
+            ));
+        }
+        if cfg.prewarm {
+            log::warn!("prewarm meta cache, {} keys loaded", meta.prewarm());
+        }
+        let mut key = Self::load_key(cfg, &meta)?;
+        let data = data.unwrap_or_else(|| -> SharedStore {
+            match meta.layout() {
+                Layout::Block => {
+                    let mut s = FileStore::new();
+                    s.set_dsync(cfg.data_dsync);
+                    if let Some(xts) = key.take() {
+                        s.set_key(xts);
+                    }
+                    Rc::new(RefCell::new(s))
+                }
+                Layout::Packed => {
+                    let mut s = PackedStore::new(get_data_path());
+                    s.set_dsync(cfg.data_dsync);
+                    Rc::new(RefCell::new(s))
+                }
+            }
+        });
+        data.borrow_mut().init()?;
+        if !cfg.read_only && meta.unclean() {
+            Self::reap_orphans(&mut meta, &data);
+        }
+
+        MemPool::init(100 << 20, cfg.page_size);
+        crash::set_slow_op(cfg.slow_op_ms);
+        let journal = Self::load_journal(cfg, &mut meta, &data)?;
 
         Ok(Fs {
-            meta: meta.unwrap(),
+            cfg: cfg.clone(),
+            meta,
             dirs: RefCell::new(HashMap::new()),
             store: RefCell::new(HashMap::new()),
-            hmap: BitMap::new(1024), // at most 1024 files open at same time
+            hmap: handle_map(FS_MAX_HANDLES),
+            hmap_warned: false,
+            data,
+            writeback: false,
+            usage: LRUCache::new(USAGE_CACHE_SIZE),
+            started: epoch_secs(),
+            ctl_files: HashMap::new(),
+            file_cache: FileCache::new(cfg.file_cache),
+            lookups: HashMap::new(),
+            unlinked: HashSet::new(),
+            write_errors: HashSet::new(),
+            sync_error: false,
+            pollers: HashMap::new(),
+            journal,
         })
     }
 
+    fn journal_path() -> String {
+        format!("{}/.journal", get_data_path())
+    }
+
+    /// the writes left in the journal were acknowledged but may never have reached the store, e.g. the process died
+    /// with them buffered, so they're written back whether the journal is still on or not, a read-only mount leaves
+    /// them for the next writable one
+    fn load_journal(cfg: &Config, meta: &mut Meta, data: &SharedStore) -> Result<Option<Journal>, String> {
+        let path = Self::journal_path();
+        let records = journal::records(&path)?;
+        if cfg.read_only {
+            if !records.is_empty() {
+                log::warn!(
+                    "{} writes in journal {} are left to a writable mount",
+                    records.len(),
+                    path
+                );
+            }
+            return Ok(None);
+        }
+        if !records.is_empty() {
+            Self::replay(meta, data, &records)?;
+        }
+        if !cfg.journal {
+            let _ = std::fs::remove_file(&path);
+            return Ok(None);
+        }
+        Journal::open(&path)
+            .map(Some)
+            .map_err(|e| format!("can't open journal {}, error {}", path, e))
+    }
+
+    /// write the journal back in order and sync what it wrote, a write to a file removed since is skipped
+    fn replay(meta: &mut Meta, data: &SharedStore, records: &[(Ino, u64, Vec<u8>)]) -> Result<(), String> {
+        let mut inos = HashSet::new();
+        for (ino, off, buf) in records {
+            if !meta.get_inode(*ino).is_ok_and(|x| x.kind == Itype::File) {
+                log::warn!("journal: ino {} is gone, skip {} bytes at {}", ino, buf.len(), off);
+                continue;
+            }
+            let mut h = FileHandle::new(*ino, 0, data.clone());
+            let n = h.write(meta, *off, buf);
+            h.flush(meta);
+            if n != buf.len() {
+                return Err(format!(
+                    "can't replay journal, ino {} {} of {} bytes written at {}",
+                    ino,
+                    n,
+                    buf.len(),
+                    off
+                ));
+            }
+            inos.insert(*ino);
+        }
+        for ino in &inos {
+            data.borrow_mut()
+                .fsync(*ino, false)
+                .map_err(|e| format!("can't sync ino {} replayed from journal, error {}", ino, e))?;
+        }
+        meta.sync().map_err(|e| e.to_string())?;
+        log::warn!("replay {} writes to {} files from journal", records.len(), inos.len());
+        Ok(())
+    }
+
+    /// journal a write buffered in `h`, one which can't be journaled is flushed at once instead
+    fn journal_write(&mut self, ino: Ino, h: &Rc<RefCell<FileHandle>>, offset: u64, data: &[u8]) {
+        let Some(j) = self.journal.as_mut() else {
+            return;
+        };
+        if let Err(e) = j.append(ino, offset, data) {
+            log::error!(
+                "can't journal write ino {} offset {} error {}, flush it",
+                ino,
+                offset,
+                e
+            );
+            h.borrow_mut().flush(&mut self.meta);
+            return;
+        }
+        if j.len() >= JOURNAL_LIMIT {
+            self.checkpoint();
+        }
+    }
+
+    /// flush every handle and empty the journal, it's done before data is changed other than by a write, e.g. a
+    /// truncate, or else replaying the writes before it would undo the change
+    fn checkpoint(&mut self) {
+        if self.journal.as_ref().is_none_or(|j| j.is_empty()) {
+            return;
+        }
+        self.flush_all();
+        if let Some(Err(e)) = self.journal.as_mut().map(|j| j.clear()) {
+            log::error!("can't clear journal, error {}", e);
+        }
+    }
+
+    /// the key of file data derived from the passphrase in `--key-file`, a wrong passphrase is refused, so data is
+    /// never written with another key
+    fn load_key(cfg: &Config, meta: &Meta) -> Result<Option<Xts>, String> {
+        match (meta.key_params(), &cfg.key_file) {
+            (None, None) => Ok(None),
+            (None, Some(_)) => Err("data is not encrypted, --key-file is not expected".to_string()),
+            (Some(_), None) => Err("data is encrypted, --key-file is required".to_string()),
+            (Some(params), Some(path)) => {
+                let xts = Xts::derive(&read_passphrase(path)?, &params.salt);
+                if xts.check() != params.check {
+                    return Err(format!("wrong key in {}", path));
+                }
+                Ok(Some(xts))
+            }
+        }
+    }
+
+    /// reclaim inodes left unreachable by a crash, e.g. between removing an entry and its inode, and their data, it
+    /// scans every entry, so it's only done when the last mount wasn't clean
+    fn reap_orphans(meta: &mut Meta, data: &SharedStore) {
+        match meta.reap_orphans() {
+            Err(e) => log::error!("can't reap orphans, error {}", e),
+            Ok(orphans) => {
+                for inode in orphans.iter().filter(|x| x.kind == Itype::File) {
+                    data.borrow_mut().unlink(inode.id, inode.blocks());
+                }
+                if !orphans.is_empty() {
+                    log::warn!("reaped {} orphan inode(s)", orphans.len());
+                }
+            }
+        }
+    }
+
+    /// ask the mounted filesystem to compact its metadata, it's safe to call from any thread, the work is done by
+    /// the next `lookup`, `getattr` or `readdir`
+    pub fn request_compact() {
+        COMPACT_REQUESTED.store(true, Ordering::Relaxed);
+    }
+
+    /// let `invalidate` reach the kernel through the session serving this filesystem
+    pub fn set_notifier(n: Notifier) {
+        Self::set_invalidator(Some(Box::new(move |ino| n.inval_inode(ino, 0, 0))));
+    }
+
+    fn set_invalidator(f: Option<Invalidator>) {
+        *NOTIFIER.lock().unwrap() = f;
+    }
+
+    /// drop the cached attributes and data of `ino` in the kernel, so the next access calls `getattr` again rather
+    /// than waiting for the ttl, it's meant for background tasks changing the store behind the kernel's back, it
+    /// must not be called from a request handler, which would wait for the kernel waiting for the handler.
+    /// return false if there's no session or the kernel doesn't know `ino`
+    pub fn invalidate(ino: Ino) -> bool {
+        let n = NOTIFIER.lock().unwrap();
+        match n.as_ref().map(|f| f(ino)) {
+            None => false,
+            Some(Err(e)) => {
+                log::warn!("invalidate ino {} fail, error {}", ino, e);
+                false
+            }
+            Some(Ok(_)) => true,
+        }
+    }
+
+    fn poll_compact(&mut self) {
+        if COMPACT_REQUESTED.swap(false, Ordering::Relaxed) {
+            match self.meta.compact() {
+                Err(e) => log::error!("compact metadata fail, error {}", e),
+                Ok(_) => log::warn!("compact metadata done"),
+            }
+        }
+    }
+
     pub fn flush_sb(&self) {
         self.meta.flush_sb().expect("can't flush sb");
     }
 
-    fn new_file_handle(&mut self, ino: Ino) -> Option<Rc<RefCell<FileHandle>>> {
-        if self.hmap.full() {
-            log::warn!("too many open files");
-            return None;
-        }
-        let r = self.hmap.alloc().unwrap();
-        let entry = Rc::new(RefCell::new(FileHandle::new(ino, r)));
-        if self.store.borrow().contains_key(&ino) {
-            self.store.borrow_mut().get_mut(&ino).unwrap().push(entry.clone());
-        } else {
-            self.store.borrow_mut().insert(ino, vec![entry.clone()]);
+    /// warn once until a handle is released, rather than flooding the log while the table stays full
+    fn alloc_fh(&mut self) -> Option<u64> {
+        let r = self.hmap.alloc_next();
+        if r.is_none() && !self.hmap_warned {
+            log::warn!("too many open files, limit {}", self.hmap.cap() - 1);
+            self.hmap_warned = true;
+        }
+        r
+    }
+
+    /// callers free a fh only once its handle is gone, a fh that isn't allocated is a bug of ours, it's logged
+    /// rather than bringing the mount down
+    fn free_fh(&mut self, fh: u64) {
+        if !self.hmap.free(fh) {
+            log::error!("free fh {} which is not allocated", fh);
         }
+        self.hmap_warned = false;
+    }
+
+    fn new_file_handle(&mut self, ino: Ino) -> Option<Rc<RefCell<FileHandle>>> {
+        let r = self.alloc_fh()?;
+        let entry = Rc::new(RefCell::new(FileHandle::new(ino, r, self.data.clone())));
+        entry.borrow_mut().write_through = self.cfg.write_through;
+        self.usage.del(&ino);
+        self.store.borrow_mut().entry(ino).or_default().push(entry.clone());
+        self.meta.set_open(ino, true);
         Some(entry)
     }
 
@@ -88,453 +449,3127 @@ impl Fs {
         Self::find_handle(ino, fh, &self.store)
     }
 
-    fn remove_file_handle(&mut self, ino: Ino, fh: u64) {
-        let h = Self::find_handle(ino, fh, &self.store).expect("fh not found");
+    fn remove_file_handle(&mut self, ino: Ino, fh: u64) -> Result<(), libc::c_int> {
+        let h = Self::find_handle(ino, fh, &self.store).ok_or_else(|| self.bad_handle("release", ino, fh))?;
         h.borrow_mut().flush(&mut self.meta);
+        self.note_write_error(ino, &h);
         Self::remove_handle(ino, fh, &self.store);
-        let ok = self.hmap.free(fh);
-        assert!(ok);
+        self.free_fh(fh);
+        if self.store.borrow().get(&ino).is_none_or(|v| v.is_empty()) {
+            self.pollers.remove(&ino);
+            self.meta.set_open(ino, false);
+            self.reclaim(ino);
+        }
+        // nothing is buffered once the last file is closed
+        if self.store.borrow().values().all(|v| v.is_empty()) {
+            self.checkpoint();
+        }
+        Ok(())
     }
 
-    fn new_dir_handle(&mut self, ino: Ino) -> Option<Rc<RefCell<DirHandle>>> {
-        if self.hmap.full() {
-            log::warn!("too many open files");
-            return None;
+    /// the `events` a file is ready for, a regular file is always readable and writable, `wake` is kept if the
+    /// kernel asks to be notified, and called once the file is written, e.g. `tail -f` waiting for it to grow
+    fn poll_file(&mut self, ino: Ino, fh: u64, events: u32, wake: Option<PollWaker>) -> Result<u32, libc::c_int> {
+        if !control::is_ctl(ino) && self.find_file_handle(ino, fh).is_none() {
+            return Err(self.bad_handle("poll", ino, fh));
         }
-        let entry = Rc::new(RefCell::new(DirHandle::new(self.hmap.alloc().unwrap())));
-        if self.dirs.borrow().contains_key(&ino) {
-            self.dirs.borrow_mut().get_mut(&ino).unwrap().push(entry.clone());
-        } else {
-            self.dirs.borrow_mut().insert(ino, vec![entry.clone()]);
+        if let Some(w) = wake {
+            self.pollers.entry(ino).or_default().push(w);
         }
-        Some(entry)
+        Ok(events & POLL_READY)
     }
 
-    fn find_dir_handle(&self, ino: Ino, fh: u64) -> Option<Rc<RefCell<DirHandle>>> {
-        Self::find_handle(ino, fh, &self.dirs)
+    /// notify the pollers of `ino` it changed, each once, the kernel polls again and asks anew
+    fn wake_pollers(&mut self, ino: Ino) {
+        for w in self.pollers.remove(&ino).unwrap_or_default() {
+            if let Err(e) = w() {
+                log::warn!("notify poll of ino {} fail, error {}", ino, e);
+            }
+        }
     }
 
-    fn remove_dir_handle(&mut self, ino: Ino, fh: u64) {
-        Self::remove_handle(ino, fh, &self.dirs).expect("fn not found");
-        let ok = self.hmap.free(fh);
-        assert!(ok);
+    /// a request naming a handle not open on `ino` is a client bug, it's refused rather than bringing the mount down
+    fn bad_handle(&self, op: &str, ino: Ino, fh: u64) -> libc::c_int {
+        if fh < self.hmap.cap() && self.hmap.test(fh) {
+            log::error!("{} ino {} fh {} is open on another inode", op, ino, fh);
+        } else {
+            log::error!("{} ino {} fh {} is not open", op, ino, fh);
+        }
+        EBADF
     }
-}
 
-impl Filesystem for Fs {
-    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let mut name = name.to_str().unwrap().to_string();
-        let ttl = time::Duration::new(1, 0);
+    /// flush dirty buffers of every handle opened on `ino`, so a following read (including the ones servicing
+    /// mmap page faults) observes data written through other handles
+    fn flush_inode(&mut self, ino: Ino) {
+        let handles = self.store.borrow().get(&ino).cloned();
+        if let Some(v) = handles {
+            for h in v {
+                h.borrow_mut().flush(&mut self.meta);
+            }
+        }
+    }
 
-        if name == ".." {
-            if parent == 1 {
-                name = ".".to_string();
-            } else {
-                if let Some(inode) = self.meta.load_inode(parent) {
-                    assert_ne!(inode.parent, 0);
-                    if let Some(inode) = self.meta.load_inode(inode.parent) {
-                        if inode.kind != Itype::Dir {
-                            log::warn!("lookup parent {} name {} ino {} not dir", inode.parent, name, inode.id);
-                            reply.error(ENOTDIR);
-                        } else {
-                            let attr = &to_attr(&inode);
-                            reply.entry(&ttl, &attr, 0);
-                        }
-                        return;
-                    }
-                }
-                log::warn!("can't load parent {} name {}", parent, name);
-                reply.error(EFAULT);
-                return;
+    /// buffer `data` in handle `h` of `ino`, a handle flushes itself when the page pool is full, if the pages are
+    /// held by other handles they're flushed too, requests are served one at a time, so waiting for them to free
+    /// pages would never end, a write is short only if flushing every handle frees nothing
+    ///
+    /// other handles of `ino` holding buffered data are flushed first, so the writes through different fds reach the
+    /// store in the order they're made, a read through any fd flushes them all, so it sees the latest, the pollers of
+    /// `ino` are woken once something is written
+    fn write_file(&mut self, ino: Ino, h: &Rc<RefCell<FileHandle>>, offset: u64, data: &[u8]) -> usize {
+        if data.is_empty() {
+            return 0;
+        }
+        let others: Vec<_> = self
+            .store
+            .borrow()
+            .get(&ino)
+            .into_iter()
+            .flatten()
+            .filter(|x| !Rc::ptr_eq(x, h) && x.borrow().dirty() > 0)
+            .cloned()
+            .collect();
+        for x in others {
+            x.borrow_mut().flush(&mut self.meta);
+        }
+        let mut n = h.borrow_mut().write(&mut self.meta, offset, data);
+        while n < data.len() && MemPool::get().full() {
+            log::info!("write ino {} page pool is full, flush all handles", ino);
+            self.flush_all();
+            let m = h.borrow_mut().write(&mut self.meta, offset + n as u64, &data[n..]);
+            if m == 0 {
+                log::error!(
+                    "write ino {} can't get pages, {} of {} bytes written",
+                    ino,
+                    n,
+                    data.len()
+                );
+                break;
             }
+            n += m;
+        }
+        if n > 0 && !h.borrow().write_through {
+            self.journal_write(ino, h, offset, &data[..n]);
         }
+        if n > 0 {
+            self.wake_pollers(ino);
+        }
+        n
+    }
 
-        if let Some(inode) = self.meta.lookup(parent, &name) {
-            let attr = to_attr(&inode);
-            reply.entry(&ttl, &attr, 0);
-        } else {
-            log::info!("lookup fail parent {} name {}", parent, name);
-            reply.error(ENOENT);
+    /// keep the failed write back of handle `h` of `ino`, it's reported by the next fsync of the file or any
+    /// `fsyncdir`, return whether there's one
+    fn note_write_error(&mut self, ino: Ino, h: &Rc<RefCell<FileHandle>>) -> bool {
+        let failed = h.borrow_mut().take_error();
+        if failed {
+            self.write_errors.insert(ino);
+            self.sync_error = true;
         }
+        failed
     }
 
-    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
-        log::info!("getattr ino {}", ino);
-        match self.meta.load_inode(ino) {
-            None => {
-                log::error!("can't load inode by Ino {ino}");
-                reply.error(EEXIST);
-            }
-            Some(inode) => {
-                let attr = to_attr(&inode);
-                log::info!("getattr ino {} size {}", ino, inode.length);
-                let ttl = time::Duration::new(1, 0);
-                reply.attr(&ttl, &attr);
-            }
+    /// whether a write back of `ino` failed since its last fsync, the error is reported once
+    fn take_write_error(&mut self, ino: Ino) -> bool {
+        let handles = self.store.borrow().get(&ino).cloned().unwrap_or_default();
+        for h in &handles {
+            self.note_write_error(ino, h);
         }
+        self.write_errors.remove(&ino)
     }
 
-    fn init(&mut self, req: &fuser::Request<'_>, _cfg: &mut fuser::KernelConfig) -> Result<(), i32> {
-        log::info!(
-            "unique {}, uid {}, gid {}, pid {}",
-            req.unique(),
-            req.uid(),
-            req.gid(),
-            req.pid()
-        );
-        // NOTE: the root Ino is 1, in this function we must create a root if not exist
-        if let Some(inode) = self.meta.load_inode(1) {
-            log::info!("load root inode {} ok", inode.id);
-            Ok(())
-        } else {
-            match self.meta.mknod(0, "/".to_string(), Itype::Dir, 0o755) {
-                Err(e) => {
-                    log::error!("create root inode fail, error {}", e);
-                    Err(e)
-                }
-                Ok(_) => {
-                    log::info!("create root inode ok");
-                    Ok(())
-                }
-            }
+    /// whether a write back of any file failed since the last `fsyncdir`
+    fn take_sync_error(&mut self) -> bool {
+        let handles: Vec<_> = self
+            .store
+            .borrow()
+            .iter()
+            .flat_map(|(ino, v)| v.iter().map(|h| (*ino, h.clone())))
+            .collect();
+        for (ino, h) in &handles {
+            self.note_write_error(*ino, h);
         }
+        std::mem::take(&mut self.sync_error)
     }
 
-    fn setattr(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        mode: Option<u32>,
-        uid: Option<u32>,
-        gid: Option<u32>,
-        _size: Option<u64>,
-        _atime: Option<TimeOrNow>,
-        _mtime: Option<TimeOrNow>,
-        _ctime: Option<SystemTime>,
-        _fh: Option<u64>,
-        _crtime: Option<SystemTime>,
-        _chgtime: Option<SystemTime>,
-        _bkuptime: Option<SystemTime>,
-        _flags: Option<u32>,
-        reply: ReplyAttr,
-    ) {
-        log::info!("setattr ino {}", ino);
-        match self.meta.load_inode(ino) {
-            None => {
-                log::error!("can't load inode Ino {ino}");
-                reply.error(EEXIST);
-            }
-            Some(mut inode) => {
-                if mode.is_some() {
-                    inode.mode = mode.unwrap() as u16;
-                }
-                if uid.is_some() {
-                    inode.uid = uid.unwrap();
-                }
-                if gid.is_some() {
-                    inode.gid = gid.unwrap();
-                }
-                // FIXME: how to handle `size` change, truncate ???
-                match self.meta.store_inode(&inode) {
-                    Ok(()) => {
-                        let ttl = time::Duration::new(1, 0);
-                        let attr = &to_attr(&inode);
-                        reply.attr(&ttl, &attr);
-                    }
-                    Err(e) => {
-                        log::error!("can't store inode {} error {}", inode.id, e);
-                        reply.error(EFAULT);
-                    }
-                }
-            }
+    fn flush_all(&mut self) {
+        let handles: Vec<_> = self.store.borrow().values().flatten().cloned().collect();
+        for h in handles {
+            h.borrow_mut().flush(&mut self.meta);
         }
     }
 
-    /// TODO: handle `flags`
-    /// - truncate
-    /// - append
-    fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: ReplyOpen) {
-        log::info!("open ino {} flags {}", _ino, _flags);
-        let r = self.new_file_handle(_ino);
-        match r {
-            None => {
-                log::warn!("open fail, can't create handle for ino {}", _ino);
-                reply.error(EFAULT)
-            }
-            Some(handle) => {
-                log::info!("opened ino {} fh {}", _ino, handle.borrow().fh);
-                reply.opened(handle.borrow().fh, 0);
+    /// flush every handle of `ino` and persist its data, and meta too unless `datasync` and the size is unchanged,
+    /// it's EIO if a write back of the file failed since its last fsync, what's left is synced still
+    fn sync_file(&mut self, ino: Ino, datasync: bool) -> Result<(), libc::c_int> {
+        let before = self.meta.get_inode(ino).map(|x| x.length).unwrap_or(0);
+        self.flush_inode(ino);
+        let failed = self.take_write_error(ino);
+        let length = self.meta.get_inode(ino)?.length;
+        if let Err(e) = self.data.borrow_mut().fsync(ino, datasync) {
+            log::error!("fsync ino {} can't sync data, error {}", ino, e);
+            return Err(e.raw_os_error().unwrap_or(libc::EIO));
+        }
+        // the file size lives in meta, so it's needed by fdatasync too once it's changed
+        if !datasync || before != length {
+            if let Err(e) = self.meta.sync() {
+                log::error!("fsync ino {} can't sync meta, error {}", ino, e);
+                return Err(libc::EIO);
             }
         }
+        if failed {
+            log::error!("fsync ino {} write back failed since the last fsync", ino);
+            return Err(libc::EIO);
+        }
+        Ok(())
     }
 
-    fn read(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyData,
-    ) {
-        log::info!("read ino {} fh {} offset {} size {}", ino, fh, offset, size);
-        if size as u64 > FS_FUSE_MAX_IO_SIZE {
-            log::error!("IO request too big, limit to {} bytes", FS_FUSE_MAX_IO_SIZE);
-            reply.error(E2BIG);
-            return;
+    /// a full fsync of `ino` if `--sync-on-close` is given
+    fn sync_on_close(&mut self, ino: Ino) -> Result<(), libc::c_int> {
+        if !self.cfg.sync_on_close {
+            return Ok(());
         }
-        let file = self.find_file_handle(ino, fh);
+        self.sync_file(ino, false)
+    }
 
-        match file {
-            None => {
-                log::error!("can't find handle of {fh}");
-                reply.error(EEXIST);
-            }
-            Some(h) => {
-                let mut f = h.borrow_mut();
-                let buf = f.read(&mut self.meta, offset as u64, size as usize);
-                match buf {
-                    None => {
-                        log::error!("read fail");
-                        reply.error(EFAULT);
-                    }
-                    Some(buf) => {
-                        log::info!("read ino {} fh {} nbytes {}", ino, fh, buf.len());
-                        reply.data(&buf);
-                    }
-                }
+    /// what `close(2)` waits for, flush handle `h` of `ino` and sync it if `--sync-on-close` is given, it's EIO if
+    /// the write back of the handle failed, the next fsync reports it too
+    fn close_file(&mut self, ino: Ino, h: &Rc<RefCell<FileHandle>>) -> Result<(), libc::c_int> {
+        h.borrow_mut().flush(&mut self.meta);
+        if self.cfg.sync_on_close {
+            return self.sync_file(ino, false);
+        }
+        if self.note_write_error(ino, h) {
+            log::error!("close ino {} write back failed", ino);
+            return Err(libc::EIO);
+        }
+        Ok(())
+    }
+
+    fn update_atime(&mut self, ino: Ino) {
+        // a read-only mount writes nothing, whatever `--atime` says
+        if self.cfg.read_only {
+            return;
+        }
+        if let Some(inode) = self.meta.load_inode(ino) {
+            let now = epoch_secs();
+            if self.cfg.atime.need_update(&inode, now) {
+                self.meta.touch_atime(ino, now);
             }
         }
     }
 
-    fn release(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        _fh: u64,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        _flush: bool,
-        reply: ReplyEmpty,
-    ) {
-        log::info!("release ino {} fh {}", _ino, _fh);
-        self.remove_file_handle(_ino, _fh);
-        reply.ok();
+    fn new_dir_handle(&mut self, ino: Ino) -> Option<Rc<RefCell<DirHandle>>> {
+        let fh = self.alloc_fh()?;
+        let entry = Rc::new(RefCell::new(DirHandle::new(fh)));
+        self.dirs.borrow_mut().entry(ino).or_default().push(entry.clone());
+        Some(entry)
     }
 
-    fn write(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyWrite,
-    ) {
-        log::info!("write ino {} fh {} offset {} size {}", ino, fh, offset, data.len());
+    /// attributes of `inode`, `st_blocks` of a file counts the data actually allocated rather than its size, so
+    /// `du` of a sparse file is small
+    fn attr(&mut self, inode: &Inode) -> FileAttr {
+        let mut attr = to_attr(inode);
+        if inode.kind == Itype::File {
+            attr.blocks = self.allocated(inode).div_ceil(512);
+        }
+        attr
+    }
 
-        match self.find_file_handle(ino, fh) {
-            None => {
-                log::error!("can't find file by ino {} fh {}", ino, fh);
-                reply.error(ENOENT);
+    /// asking the store may stat every block, so the result is cached, unless a handle is open, whose writes
+    /// change it at any time, other changes go through `truncate`, `reflink` and `drop_data`
+    fn allocated(&mut self, inode: &Inode) -> u64 {
+        if let Some(x) = self.usage.get(&inode.id) {
+            return *x;
+        }
+        let n = self.data.borrow().allocated(inode.id, inode.blocks());
+        if self.store.borrow().get(&inode.id).is_none_or(|v| v.is_empty()) {
+            self.usage.add(inode.id, n);
+        }
+        n
+    }
+
+    /// a snapshot of every open handle ordered by fh, served by `/.junkfs/handles`, a handle leaked by a client
+    /// or by a missed release stays listed
+    pub fn debug_handles(&self) -> Vec<OpenHandle> {
+        let mut v = Vec::new();
+        for (&ino, hs) in self.store.borrow().iter() {
+            for h in hs {
+                let h = h.borrow();
+                v.push(OpenHandle {
+                    ino,
+                    fh: h.fh,
+                    kind: HandleKind::File,
+                    dirty: h.dirty(),
+                });
             }
-            Some(h) => {
-                let mut f = h.borrow_mut();
-                let nbytes = f.write(&mut self.meta, offset as u64, data);
-                reply.written(nbytes as u32);
+        }
+        for (&ino, hs) in self.dirs.borrow().iter() {
+            for h in hs {
+                v.push(OpenHandle {
+                    ino,
+                    fh: h.borrow().fh,
+                    kind: HandleKind::Dir,
+                    dirty: 0,
+                });
             }
         }
+        for (&fh, (ino, _)) in &self.ctl_files {
+            v.push(OpenHandle {
+                ino: *ino,
+                fh,
+                kind: HandleKind::Ctl,
+                dirty: 0,
+            });
+        }
+        v.sort_by_key(|h| h.fh);
+        v
     }
 
-    fn flush(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        log::info!("flush ino {} fh {}", ino, fh);
-        if let Some(h) = self.find_file_handle(ino, fh) {
-            h.borrow_mut().flush(&mut self.meta);
-            reply.ok();
+    /// the status served by `/.junkfs/health`
+    pub fn health(&self) -> Health {
+        let s = stats::snapshot();
+        Health {
+            uptime: epoch_secs().saturating_sub(self.started),
+            open_handles: self.debug_handles().len() as u64,
+            dirty_bytes: s.dirty_bytes(),
+            cached_inodes: self.meta.cached_inodes() as u64,
+            last_flush: s.last_flush,
+        }
+    }
+
+    /// permission bits `access` checks against, setuid and setgid are dropped on a `nosuid` mount and the execute bits
+    /// of non-directories on a `noexec` one, what's stored and reported by `getattr` is left alone
+    fn effective_mode(&self, attr: &FileAttr) -> u16 {
+        let mut mode = attr.perm;
+        if self.cfg.nosuid {
+            mode &= !(libc::S_ISUID | libc::S_ISGID) as u16;
+        }
+        if self.cfg.noexec && attr.kind != FileType::Directory {
+            mode &= !0o111;
+        }
+        mode
+    }
+
+    /// whether `uid` in group `gid` may access `attr` as `mask` of `access(2)`, the bits of the owner, the group or
+    /// the others apply, root may read and write anything and execute what has an execute bit
+    fn check_access(&self, attr: &FileAttr, uid: u32, gid: u32, mask: i32) -> Result<(), libc::c_int> {
+        if mask & libc::W_OK != 0 && self.cfg.read_only {
+            return Err(libc::EROFS);
+        }
+        let mode = self.effective_mode(attr);
+        let want = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u16;
+        // root may search any directory, it executes a file only if anyone may
+        let ok = if uid == 0 {
+            want & libc::X_OK as u16 == 0 || attr.kind == FileType::Directory || mode & 0o111 != 0
         } else {
-            log::error!("flush fail ino {} fh {}", ino, fh);
-            reply.error(ENOENT);
+            let bits = if uid == attr.uid {
+                mode >> 6
+            } else if gid == attr.gid {
+                mode >> 3
+            } else {
+                mode
+            };
+            bits & want == want
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(EACCES)
         }
     }
 
-    fn opendir(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
-        log::info!("opendir ino {} flags {}", ino, flags);
-        let r = self.new_dir_handle(ino);
-        match r {
-            None => {
-                log::warn!("can't create new dir handle for ino {}", ino);
-                reply.error(EFAULT)
+    /// the kernel was replied with an entry of `ino`, root and control files are never forgotten
+    fn remember(&mut self, ino: Ino) {
+        if ino != FS_ROOT_INODE && !control::is_ctl(ino) {
+            *self.lookups.entry(ino).or_default() += 1;
+        }
+    }
+
+    /// the kernel drops `n` lookups of `ino`, once none is left and the file isn't open what's cached for it goes
+    fn forget_ino(&mut self, ino: Ino, n: u64) {
+        let left = match self.lookups.get_mut(&ino) {
+            None => return,
+            Some(x) => {
+                *x = x.saturating_sub(n);
+                *x
             }
-            Some(handle) => {
-                log::info!("opened ino {} fh {}", ino, handle.borrow().fh);
-                self.meta.load_dentry(ino, &handle);
-                reply.opened(handle.borrow().fh, 0);
+        };
+        if left != 0 {
+            return;
+        }
+        self.lookups.remove(&ino);
+        if self.store.borrow().get(&ino).is_none_or(|v| v.is_empty()) {
+            log::info!("forget ino {}", ino);
+            self.usage.del(&ino);
+            self.file_cache.del(ino);
+            self.reclaim(ino);
+        }
+    }
+
+    /// drop the inode and data of a file removed while it was open, once it's closed and the kernel forgot it
+    fn reclaim(&mut self, ino: Ino) {
+        if !self.unlinked.contains(&ino)
+            || self.lookups.contains_key(&ino)
+            || self.store.borrow().get(&ino).is_some_and(|v| !v.is_empty())
+        {
+            return;
+        }
+        self.unlinked.remove(&ino);
+        self.store.borrow_mut().remove(&ino);
+        match self.meta.drop_unlinked(ino) {
+            Err(e) => log::error!("can't drop unlinked ino {} error {}", ino, e),
+            Ok(inode) => {
+                log::info!("reclaim unlinked ino {}", ino);
+                self.free_data(&inode);
             }
         }
     }
 
-    fn releasedir(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
-        log::info!("releasedir ino {} fh {}", ino, fh);
-        self.remove_dir_handle(ino, fh);
-        reply.ok();
+    fn ctl_attr(&self, ino: Ino) -> FileAttr {
+        let size = if ino == CTL_DIR_INO { 0 } else { self.ctl_content(ino).len() as u64 };
+        control::attr(ino, size, self.started)
     }
 
-    fn readdir(&mut self, _req: &Request<'_>, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) {
-        log::info!("readdir ino {} fh {} offset {}", ino, fh, offset);
-        if let Some(h) = self.find_dir_handle(ino, fh) {
-            let mut off = h.borrow().off() as i64;
-            while let Some(i) = h.borrow_mut().next() {
-                if reply.add(ino, off, to_filetype(i.kind), &i.name) {
-                    log::info!("add dentry buffer full, current entry {} offset {}", i.name, off);
-                    break;
-                }
-                off += 1;
-            }
-            reply.ok();
-        } else {
-            log::warn!("this is impossible, since a directory at least has . and ..");
-            reply.error(ENOENT);
+    fn ctl_content(&self, ino: Ino) -> Vec<u8> {
+        match ino {
+            HEALTH_INO => self.health().render().into_bytes(),
+            LABEL_INO => format!("{}\n", self.meta.label()).into_bytes(),
+            _ => control::render_handles(&self.debug_handles()).into_bytes(),
         }
     }
 
-    fn mknod(
-        &mut self,
-        _req: &Request<'_>,
-        parent: u64,
-        name: &OsStr,
-        mode: u32,
-        _umask: u32,
-        _rdev: u32,
-        reply: ReplyEntry,
-    ) {
-        let name = name.to_str().unwrap().to_string();
-        log::info!("mknod parent {} name {}", parent, name);
+    /// a control file is read only, its content is generated once on open, so reads at any offset are consistent
+    fn open_ctl(&mut self, ino: Ino, flags: i32) -> Result<u64, libc::c_int> {
+        if ino == CTL_DIR_INO {
+            return Err(EISDIR);
+        }
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            return Err(EACCES);
+        }
+        let fh = self.alloc_fh().ok_or(ENFILE)?;
+        let buf = self.ctl_content(ino);
+        self.ctl_files.insert(fh, (ino, buf));
+        Ok(fh)
+    }
 
-        if mode & S_IFMT != S_IFREG {
-            log::warn!("non-file node is not support");
-            reply.error(ENOSYS);
+    /// content of control file `ino` opened as `fh`
+    fn ctl_file(&self, ino: Ino, fh: u64) -> Result<&[u8], libc::c_int> {
+        match self.ctl_files.get(&fh) {
+            Some((x, buf)) if *x == ino => Ok(buf),
+            _ => Err(self.bad_handle("read", ino, fh)),
+        }
+    }
+
+    fn release_ctl(&mut self, ino: Ino, fh: u64) -> Result<(), libc::c_int> {
+        self.ctl_file(ino, fh)
+            .map_err(|_| self.bad_handle("release", ino, fh))?;
+        self.ctl_files.remove(&fh);
+        self.free_fh(fh);
+        Ok(())
+    }
+
+    /// read through handle `fh`, a small file is read whole and kept in `file_cache` if it's enabled, so reading it
+    /// again doesn't reach the data store
+    fn read_file(&mut self, ino: Ino, fh: u64, offset: u64, size: usize) -> Result<Vec<u8>, libc::c_int> {
+        let h = self
+            .find_file_handle(ino, fh)
+            .ok_or_else(|| self.bad_handle("read", ino, fh))?;
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        if let Some(buf) = self.file_cache.get(ino) {
+            stats::record_file_cache_hit();
+            return Ok(window(buf, offset, size).to_vec());
+        }
+        self.flush_inode(ino);
+        let length = self.meta.get_inode(ino)?.length;
+        let whole = self.file_cache.fits(length);
+        let (off, len) = if whole { (0, length as usize) } else { (offset, size) };
+        let buf = h
+            .borrow_mut()
+            .read(&mut self.meta, off, len)
+            .inspect_err(|e| log::error!("read fail ino {} fh {} error {}", ino, fh, e))?;
+        if !whole {
+            return Ok(buf);
+        }
+        let r = window(&buf, offset, size).to_vec();
+        self.file_cache.add(ino, buf);
+        Ok(r)
+    }
+
+    /// release the data of a removed inode, an open file kept with no links by `Meta` keeps it until `reclaim`
+    fn drop_data(&mut self, inode: &Inode) {
+        if inode.kind != Itype::File {
+            return;
+        }
+        if inode.links == 0 {
+            self.unlinked.insert(inode.id);
             return;
         }
+        self.free_data(inode);
+    }
 
-        match self.meta.mknod(parent, name, Itype::File, mode) {
-            Err(e) => {
-                log::warn!("mknod fail, errno {}", e);
-                reply.error(e);
+    fn free_data(&mut self, inode: &Inode) {
+        // the ino may be reused, the writes journaled for it must not land in another file
+        self.checkpoint();
+        self.data.borrow_mut().unlink(inode.id, inode.blocks());
+        self.usage.del(&inode.id);
+        self.file_cache.del(inode.id);
+        self.write_errors.remove(&inode.id);
+    }
+
+    /// a handle of `ino` opened with `flags`, it's allocated before `O_TRUNC` cuts the file, so an open refused for
+    /// lack of handles leaves the file as it is, the handle is freed if the truncate fails
+    fn open_file(&mut self, ino: Ino, flags: i32) -> Result<Rc<RefCell<FileHandle>>, libc::c_int> {
+        let h = self.new_file_handle(ino).ok_or_else(|| {
+            log::info!("open fail, can't create handle for ino {}", ino);
+            ENFILE
+        })?;
+        if flags & libc::O_TRUNC != 0 {
+            if let Err(e) = self.truncate(ino) {
+                let fh = h.borrow().fh;
+                self.remove_file_handle(ino, fh)?;
+                return Err(e);
             }
-            Ok(inode) => {
-                let attr = to_attr(&inode);
-                let ttl = time::Duration::new(1, 0);
-                reply.entry(&ttl, &attr, 0);
+        }
+        h.borrow_mut().append = flags & libc::O_APPEND != 0 && !self.writeback;
+        Ok(h)
+    }
+
+    /// the inode `create` opens, the kernel usually looks the name up first, but it may be created in between,
+    /// without `O_EXCL` an existing file is simply opened, anything else is refused as `open` does
+    fn create_file(&mut self, parent: Ino, name: &str, mode: u32, flags: i32) -> Result<Inode, libc::c_int> {
+        let inode = match self.meta.mknod(parent, name, Itype::File, mode) {
+            Err(MetaError::Exists) if flags & libc::O_EXCL == 0 => self.meta.lookup(parent, name).ok_or(ENOENT)?,
+            Err(e) => {
+                log::warn!("create fail, error {}", e);
+                return Err(e.errno());
             }
+            Ok(inode) => inode,
+        };
+        check_file(&inode, libc::ELOOP)?;
+        if inode.length > 0 && flags & libc::O_TRUNC != 0 {
+            self.truncate(inode.id)?;
         }
+        Ok(self.meta.get_inode(inode.id)?)
     }
 
-    fn mkdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, _umask: u32, reply: ReplyEntry) {
-        let name = name.to_str().unwrap().to_string();
+    /// drop all data of a file and make it empty, data buffered by open handles is flushed first, so none of it is
+    /// written after the truncation
+    fn truncate(&mut self, ino: Ino) -> Result<(), libc::c_int> {
+        self.checkpoint();
+        self.flush_inode(ino);
+        let mut inode = self.meta.get_inode(ino)?;
+        check_file(&inode, EINVAL)?;
+        self.data.borrow_mut().unlink(ino, inode.blocks());
+        self.meta.release_blocks(ino, inode.blocks());
+        self.usage.del(&ino);
+        self.file_cache.del(ino);
+        let now = epoch_secs();
+        inode.length = 0;
+        inode.mtime = now;
+        inode.ctime = now;
+        self.meta.store_inode(&inode).map_err(|e| {
+            log::error!("can't truncate ino {} error {}", ino, e);
+            libc::EIO
+        })
+    }
 
-        log::info!("mkdir parent {} name {}", parent, name);
-        match self.meta.mknod(parent, &name, Itype::Dir, mode) {
-            Ok(inode) => {
-                let attr = to_attr(&inode);
-                let ttl = time::Duration::new(1, 0);
-                reply.entry(&ttl, &attr, 0);
+    /// give `ino` a private copy of block `blk` if it's shared, before it's cut or extended
+    fn unshare_block(&mut self, ino: Ino, blk: u64) -> Result<(), libc::c_int> {
+        if !self.meta.is_shared(ino, blk) {
+            return Ok(());
+        }
+        self.data.borrow_mut().unshare(ino, blk).map_err(|e| {
+            log::error!("can't unshare block {}_{} error {}", ino, blk, e);
+            e.raw_os_error().unwrap_or(libc::EIO)
+        })?;
+        self.meta.unshare_block(ino, blk).map_err(|e| {
+            log::error!("can't drop reference of block {}_{} error {}", ino, blk, e);
+            libc::EIO
+        })
+    }
+
+    /// change the size of a file like `ftruncate`, the data past `size` is dropped and a grown file reads zeros up
+    /// to it, with `--prealloc` the blocks up to `size` are created at once, so writes scattered over a file grown
+    /// up front don't each create one
+    fn resize(&mut self, ino: Ino, size: u64) -> Result<(), libc::c_int> {
+        self.checkpoint();
+        self.flush_inode(ino);
+        let mut inode = self.meta.get_inode(ino)?;
+        check_file(&inode, EINVAL)?;
+        let io_err = |e: std::io::Error| {
+            log::error!("can't resize ino {} to {} error {}", ino, size, e);
+            e.raw_os_error().unwrap_or(libc::EIO)
+        };
+        if size < inode.length {
+            // the block holding the new end is cut, the ones after it emptied
+            for blk in size / FS_BLK_SIZE..inode.blocks() {
+                if blk * FS_BLK_SIZE >= size {
+                    self.meta.clear_data(ino, blk);
+                }
+                if !self.data.borrow().exists(ino, blk) {
+                    continue;
+                }
+                self.unshare_block(ino, blk)?;
+                let len = size.saturating_sub(blk * FS_BLK_SIZE);
+                self.data.borrow_mut().set_len(ino, blk, len).map_err(io_err)?;
             }
-            Err(e) => {
-                log::error!("can't create dir {}, errno {}", name, e);
-                reply.error(e);
+        } else if size > inode.length {
+            // the store may hold bytes past the old end, e.g. written before a crash lost the new length, they're cut
+            // off the blocks, so the grown part reads as zeros rather than stale data
+            for blk in inode.length / FS_BLK_SIZE..size.div_ceil(FS_BLK_SIZE) {
+                let exists = self.data.borrow().exists(ino, blk);
+                if exists || self.cfg.prealloc {
+                    self.unshare_block(ino, blk)?;
+                }
+                if exists {
+                    let keep = inode.length.saturating_sub(blk * FS_BLK_SIZE);
+                    if keep == 0 {
+                        self.meta.clear_data(ino, blk);
+                    }
+                    self.data.borrow_mut().set_len(ino, blk, keep).map_err(io_err)?;
+                }
+                if self.cfg.prealloc {
+                    let len = (size - blk * FS_BLK_SIZE).min(FS_BLK_SIZE);
+                    self.data.borrow_mut().preallocate(ino, blk, len).map_err(io_err)?;
+                }
             }
         }
+        self.usage.del(&ino);
+        self.file_cache.del(ino);
+        let now = epoch_secs();
+        inode.length = size;
+        inode.mtime = now;
+        inode.ctime = now;
+        self.meta.store_inode(&inode).map_err(|e| {
+            log::error!("can't store ino {} error {}", ino, e);
+            libc::EIO
+        })
     }
 
-    // `create` is a fuse operation of `mknod` and `open`, which is used to create regular file
-    fn create(
-        &mut self,
-        _req: &Request<'_>,
-        parent: u64,
-        name: &OsStr,
-        mode: u32,
-        umask: u32,
-        flags: i32,
-        reply: ReplyCreate,
-    ) {
-        let name = name.to_str().unwrap().to_string();
-        log::info!("create parent {} name {} flags {} mask {}", parent, name, flags, umask);
-        let r = self.meta.mknod(parent, &name, Itype::File, mode);
-        if r.is_err() {
-            let e = r.err().unwrap();
-            log::warn!("create fail, errno {}", e);
-            reply.error(e);
-            return;
+    /// run the `ioctl` `cmd` on `ino` on behalf of `uid`, a command junkfs doesn't know gets `ENOTTY` as `ioctl(2)`
+    /// on a file which doesn't support it, e.g. `FS_IOC_GETFLAGS` of `lsattr`
+    fn ioctl_cmd(&mut self, ino: Ino, cmd: u32, in_data: &[u8], uid: u32) -> Result<(), libc::c_int> {
+        match cmd {
+            control::CLEAR_DIR => self.clear_dir(ino, uid),
+            control::SET_LABEL => self.set_label(in_data, uid),
+            _ => Err(libc::ENOTTY),
         }
+    }
 
-        let inode = r.unwrap();
-        let r = self.new_file_handle(inode.id);
+    /// remove everything below directory `ino` on behalf of `uid`, see `control::CLEAR_DIR`, the permissions of the
+    /// entries aren't checked, so only root and the user running the filesystem may do it
+    fn clear_dir(&mut self, ino: Ino, uid: u32) -> Result<(), libc::c_int> {
+        if self.cfg.read_only {
+            return Err(libc::EROFS);
+        }
+        if control::is_ctl(ino) || uid != 0 && uid != unsafe { libc::getuid() } {
+            return Err(libc::EPERM);
+        }
+        let mut removed = Vec::new();
+        let r = self.meta.clear_dir(ino, &mut removed);
+        for inode in &removed {
+            self.drop_data(inode);
+        }
+        r.map_err(|e| {
+            log::error!("can't clear dir {} error {}", ino, e);
+            e.errno()
+        })
+    }
 
-        match r {
-            None => {
-                log::error!("create fail parent {} name {} ino {}", parent, name, inode.id);
-                reply.error(EFAULT)
+    /// change the label on behalf of `uid`, see `control::SET_LABEL`, it's a property of the whole filesystem, so
+    /// only root and the user running the filesystem may do it
+    fn set_label(&mut self, data: &[u8], uid: u32) -> Result<(), libc::c_int> {
+        if self.cfg.read_only {
+            return Err(libc::EROFS);
+        }
+        if uid != 0 && uid != unsafe { libc::getuid() } {
+            return Err(libc::EPERM);
+        }
+        let label = control::parse_label(data)?;
+        self.meta.set_label(label).map_err(|e| {
+            log::error!("can't set label {} error {}", label, e);
+            e.errno()
+        })
+    }
+
+    /// the mount options of `cfg`, named by the label if there's one, so it's what `mount` and `df` show
+    pub fn mount_options(&self) -> Vec<MountOption> {
+        let mut options = self.cfg.mount_options();
+        let label = self.meta.label();
+        if !label.is_empty() {
+            for o in options.iter_mut() {
+                if let MountOption::FSName(name) = o {
+                    *name = label.to_string();
+                }
             }
-            Some(handle) => {
-                let ttl = time::Duration::new(1, 0);
-                let attr = to_attr(&inode);
-                let fh = handle.borrow().fh;
-                log::info!(
-                    "created file parent {} name {} ino {} fh {}",
-                    parent,
-                    name,
-                    inode.id,
-                    fh
-                );
-                reply.created(&ttl, &attr, 0, fh, 0);
+        }
+        options
+    }
+
+    /// `lseek` of `fh` on `ino`, the store finds the data or hole for `SEEK_DATA`/`SEEK_HOLE`
+    fn seek_file(&mut self, ino: Ino, fh: u64, offset: i64, whence: i32) -> Result<u64, libc::c_int> {
+        if self.find_file_handle(ino, fh).is_none() {
+            return Err(self.bad_handle("lseek", ino, fh));
+        }
+        if offset < 0 {
+            return Err(ENXIO);
+        }
+        // buffered data has no block file yet
+        self.flush_inode(ino);
+        let inode = self.meta.get_inode(ino)?;
+        self.data.borrow().seek(ino, inode.length, offset as u64, whence)
+    }
+
+    /// `fallocate` of `len` bytes from `off`, the blocks of the range are created up front, `FALLOC_FL_ZERO_RANGE`
+    /// zeros the range too, the file grows to the end of the range unless `FALLOC_FL_KEEP_SIZE` is given, punching
+    /// holes and moving ranges aren't supported
+    fn allocate(&mut self, ino: Ino, off: u64, len: u64, mode: i32) -> Result<(), libc::c_int> {
+        if mode & !(libc::FALLOC_FL_KEEP_SIZE | libc::FALLOC_FL_ZERO_RANGE) != 0 {
+            return Err(libc::EOPNOTSUPP);
+        }
+        let end = off.checked_add(len).ok_or(libc::EFBIG)?;
+        if len == 0 {
+            return Err(EINVAL);
+        }
+        self.checkpoint();
+        self.flush_inode(ino);
+        let inode = self.meta.get_inode(ino)?;
+        check_file(&inode, EINVAL)?;
+        let zero = mode & libc::FALLOC_FL_ZERO_RANGE != 0;
+        if mode & libc::FALLOC_FL_KEEP_SIZE == 0 && end > inode.length {
+            self.resize(ino, end)?;
+        }
+        let io_err = |e: std::io::Error| {
+            log::error!("can't allocate ino {} off {} len {} error {}", ino, off, len, e);
+            e.raw_os_error().unwrap_or(libc::EIO)
+        };
+        for blk in off / FS_BLK_SIZE..end.div_ceil(FS_BLK_SIZE) {
+            let base = blk * FS_BLK_SIZE;
+            let (from, to) = (off.max(base) - base, end.min(base + FS_BLK_SIZE) - base);
+            self.unshare_block(ino, blk)?;
+            if zero {
+                self.data.borrow_mut().zero(ino, blk, from, to - from).map_err(io_err)?;
+                self.meta.mark_data(ino, blk).map_err(|e| {
+                    log::error!("can't record data of block {}_{} error {}", ino, blk, e);
+                    libc::EIO
+                })?;
+            } else {
+                self.data.borrow_mut().preallocate(ino, blk, to).map_err(io_err)?;
             }
         }
+        self.usage.del(&ino);
+        self.file_cache.del(ino);
+        if !zero {
+            return Ok(());
+        }
+        // zeroing is a data modification
+        let mut inode = self.meta.get_inode(ino)?;
+        let now = epoch_secs();
+        inode.mtime = now;
+        inode.ctime = now;
+        self.meta.store_inode(&inode).map_err(|e| {
+            log::error!("can't store ino {} error {}", ino, e);
+            libc::EIO
+        })
     }
 
-    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let name = name.to_string_lossy().to_string();
-        match self.meta.unlink(parent, &name) {
-            Err(e) => {
-                log::error!("can't find parent {} name {}", parent, name);
-                reply.error(e);
+    /// change of mode, owner or times is a change of the inode, so `ctime` is updated unless given, `mtime` is only
+    /// changed if asked, it's the time of the last data modification, a new size is applied by `resize` first
+    #[allow(clippy::too_many_arguments)]
+    fn set_attr(
+        &mut self,
+        ino: Ino,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+    ) -> Result<Inode, libc::c_int> {
+        self.file_cache.del(ino);
+        let mut inode = self
+            .meta
+            .get_inode(ino)
+            .inspect_err(|_| log::error!("can't load inode Ino {ino}"))?;
+        let now = epoch_secs();
+        let secs = |t: TimeOrNow| match t {
+            TimeOrNow::Now => now,
+            TimeOrNow::SpecificTime(x) => from_systime(x),
+        };
+        if let Some(mode) = mode {
+            inode.set_mode(mode);
+        }
+        if let Some(uid) = uid {
+            inode.uid = uid;
+        }
+        if let Some(gid) = gid {
+            inode.gid = gid;
+        }
+        if let Some(t) = atime {
+            self.meta.drop_atime(ino);
+            inode.atime = secs(t);
+        }
+        if let Some(t) = mtime {
+            inode.mtime = secs(t);
+        }
+        inode.ctime = ctime.map_or(now, from_systime);
+        self.meta.store_inode(&inode).map_err(|e| {
+            log::error!("can't store inode {} error {}", inode.id, e);
+            EFAULT
+        })?;
+        Ok(inode)
+    }
+
+    /// make the empty regular file `dst` a copy of `src` sharing its blocks, a shared block is copied by the first
+    /// write to it, so the files diverge as they're modified. it's the building block of reflink copies, which
+    /// can't reach us through FUSE yet
+    pub fn reflink(&mut self, src: Ino, dst: Ino) -> Result<(), libc::c_int> {
+        self.flush_inode(src);
+        self.flush_inode(dst);
+        let from = self.meta.get_inode(src)?;
+        let mut to = self.meta.get_inode(dst)?;
+        if from.kind == Itype::Dir || to.kind == Itype::Dir {
+            return Err(EISDIR);
+        }
+        if from.kind != Itype::File || to.kind != Itype::File || to.length != 0 || src == dst {
+            return Err(EINVAL);
+        }
+        let blocks = from.blocks();
+        for blk in 0..blocks {
+            if !self.data.borrow().exists(src, blk) {
+                continue;
             }
-            Ok(inode) => {
-                if inode.kind == Itype::File {
-                    let mut i = 0;
-                    while i <= inode.length {
-                        FileStore::unlink(inode.id, i / FS_BLK_SIZE);
-                        i += FS_BLK_SIZE;
-                    }
-                    self.store.borrow_mut().remove(&inode.id);
-                }
-                reply.ok();
+            let r = self.data.borrow_mut().share(src, dst, blk).map_err(|e| {
+                log::error!("can't share block {}_{} with ino {} error {}", src, blk, dst, e);
+                e.raw_os_error().unwrap_or(libc::EIO)
+            });
+            let r = r.and_then(|_| {
+                self.meta.share_block(src, dst, blk).map_err(|e| {
+                    log::error!("can't record shared block {}_{} error {}", src, blk, e);
+                    libc::EIO
+                })
+            });
+            // a block of zeros may be left a hole by the copy
+            let r = r.and_then(|_| match self.data.borrow().exists(dst, blk) {
+                false => Ok(()),
+                true => self.meta.mark_data(dst, blk).map_err(|e| {
+                    log::error!("can't record data of block {}_{} error {}", dst, blk, e);
+                    libc::EIO
+                }),
+            });
+            if let Err(e) = r {
+                // `dst` is still empty, drop what's shared so far
+                self.data.borrow_mut().unlink(dst, blocks);
+                self.meta.release_blocks(dst, blocks);
+                return Err(e);
             }
         }
+        self.usage.del(&dst);
+        self.file_cache.del(dst);
+        let now = epoch_secs();
+        to.length = from.length;
+        to.mtime = now;
+        to.ctime = now;
+        self.meta.store_inode(&to).map_err(|e| {
+            log::error!("can't store ino {} error {}", dst, e);
+            libc::EIO
+        })
     }
 
-    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let name = name.to_string_lossy().to_string();
-        match self.meta.unlink(parent, &name) {
-            Err(e) => {
-                log::error!("rmdir fail parent {} name {} errno {}", parent, name, e);
-                reply.error(e);
+    fn find_dir_handle(&self, ino: Ino, fh: u64) -> Option<Rc<RefCell<DirHandle>>> {
+        Self::find_handle(ino, fh, &self.dirs)
+    }
+
+    fn remove_dir_handle(&mut self, ino: Ino, fh: u64) -> Result<(), libc::c_int> {
+        Self::remove_handle(ino, fh, &self.dirs).ok_or_else(|| self.bad_handle("releasedir", ino, fh))?;
+        self.free_fh(fh);
+        Ok(())
+    }
+}
+
+impl Filesystem for Fs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let _span = crash::enter("lookup", parent);
+        self.poll_compact();
+        let mut name = name.to_str().unwrap().to_string();
+        let ttl = time::Duration::new(1, 0);
+
+        if let Some(ino) = control::lookup(parent, &name) {
+            reply.entry(&ttl, &self.ctl_attr(ino), 0);
+            return;
+        }
+
+        if name == ".." {
+            if parent == 1 {
+                name = ".".to_string();
+            } else {
+                if let Some(inode) = self.meta.load_inode(parent) {
+                    assert_ne!(inode.parent, 0);
+                    if let Some(inode) = self.meta.load_inode(inode.parent) {
+                        if inode.kind != Itype::Dir {
+                            log::warn!("lookup parent {} name {} ino {} not dir", inode.parent, name, inode.id);
+                            reply.error(ENOTDIR);
+                        } else {
+                            let attr = &self.attr(&inode);
+                            self.remember(inode.id);
+                            reply.entry(&ttl, &attr, 0);
+                        }
+                        return;
+                    }
+                }
+                log::warn!("can't load parent {} name {}", parent, name);
+                reply.error(ENOENT);
+                return;
             }
+        }
+
+        match self.meta.resolve(parent, &name) {
             Ok(inode) => {
-                log::info!("rmdir ok parent {} ino {} name {}", parent, inode.id, name);
-                reply.ok();
+                let attr = self.attr(&inode);
+                self.remember(inode.id);
+                reply.entry(&ttl, &attr, 0);
+            }
+            Err(e) => {
+                log::info!("lookup fail parent {} name {} error {}", parent, name, e);
+                reply.error(e.errno());
             }
         }
     }
-}
 
-impl Drop for Fs {
-    fn drop(&mut self) {
-        self.meta.close();
-        MemPool::destroy();
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        let _span = crash::enter("forget", ino);
+        self.forget_ino(ino, nlookup);
+    }
+
+    fn batch_forget(&mut self, _req: &Request<'_>, nodes: &[fuse_forget_one]) {
+        let _span = crash::enter("batch_forget", 0);
+        for node in nodes {
+            self.forget_ino(node.nodeid, node.nlookup);
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        let _span = crash::enter("getattr", ino);
+        self.poll_compact();
+        log::info!("getattr ino {}", ino);
+        if control::is_ctl(ino) {
+            reply.attr(&time::Duration::new(1, 0), &self.ctl_attr(ino));
+            return;
+        }
+        match self.meta.get_inode(ino) {
+            Err(e) => {
+                log::error!("can't load inode by Ino {ino}");
+                reply.error(e.errno());
+            }
+            Ok(inode) => {
+                let attr = self.attr(&inode);
+                log::info!("getattr ino {} size {}", ino, inode.length);
+                let ttl = time::Duration::new(1, 0);
+                reply.attr(&ttl, &attr);
+            }
+        }
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let _span = crash::enter("access", ino);
+        log::info!(
+            "access ino {} mask {:#o} uid {} gid {}",
+            ino,
+            mask,
+            req.uid(),
+            req.gid()
+        );
+        let attr = if control::is_ctl(ino) {
+            self.ctl_attr(ino)
+        } else {
+            match self.meta.get_inode(ino) {
+                Err(e) => {
+                    reply.error(e.errno());
+                    return;
+                }
+                Ok(inode) => to_attr(&inode),
+            }
+        };
+        match self.check_access(&attr, req.uid(), req.gid(), mask) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn init(&mut self, req: &fuser::Request<'_>, cfg: &mut fuser::KernelConfig) -> Result<(), i32> {
+        log::info!(
+            "unique {}, uid {}, gid {}, pid {}",
+            req.unique(),
+            req.uid(),
+            req.gid(),
+            req.pid()
+        );
+        let caps = self.cfg.capabilities();
+        // all or nothing, retry without the ones the kernel lacks
+        let caps = match cfg.add_capabilities(caps) {
+            Ok(_) => caps,
+            Err(unsupported) => {
+                log::warn!("kernel doesn't support capabilities {:#x}", unsupported);
+                let caps = caps & !unsupported;
+                cfg.add_capabilities(caps).map(|_| caps).unwrap_or(0)
+            }
+        };
+        self.writeback = caps & FUSE_WRITEBACK_CACHE != 0;
+        // NOTE: the root Ino is 1, in this function we must create a root if not exist
+        if let Some(inode) = self.meta.load_inode(1) {
+            log::info!("load root inode {} ok", inode.id);
+            Ok(())
+        } else {
+            match self.meta.mknod(0, "/".to_string(), Itype::Dir, 0o755) {
+                Err(e) => {
+                    log::error!("create root inode fail, error {}", e);
+                    Err(e.errno())
+                }
+                Ok(_) => {
+                    log::info!("create root inode ok");
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let _span = crash::enter("setattr", ino);
+        log::info!("setattr ino {}", ino);
+        if control::is_ctl(ino) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        if let Some(size) = size {
+            if let Err(e) = self.resize(ino, size) {
+                reply.error(e);
+                return;
+            }
+        }
+        match self.set_attr(ino, mode, uid, gid, atime, mtime, ctime) {
+            Err(e) => reply.error(e),
+            Ok(inode) => {
+                let ttl = time::Duration::new(1, 0);
+                reply.attr(&ttl, &self.attr(&inode));
+            }
+        }
+    }
+
+    /// `O_TRUNC` reaches here since `FUSE_ATOMIC_O_TRUNC` is negotiated in `init`
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let _span = crash::enter("open", ino);
+        log::info!("open ino {} flags {}", ino, flags);
+        if control::is_ctl(ino) {
+            // the size changes with every open, so the page cache must not be used
+            match self.open_ctl(ino, flags) {
+                Err(e) => reply.error(e),
+                Ok(fh) => reply.opened(fh, FOPEN_DIRECT_IO),
+            }
+            return;
+        }
+        // the kernel follows symlinks, it only opens one by mistake
+        if let Err(e) = self
+            .meta
+            .get_inode(ino)
+            .map_err(|e| e.errno())
+            .and_then(|x| check_file(&x, libc::ELOOP))
+        {
+            reply.error(e);
+            return;
+        }
+        match self.open_file(ino, flags) {
+            Err(e) => reply.error(e),
+            Ok(handle) => {
+                log::info!("opened ino {} fh {}", ino, handle.borrow().fh);
+                reply.opened(handle.borrow().fh, 0);
+            }
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let _span = crash::enter("read", ino);
+        log::info!("read ino {} fh {} offset {} size {}", ino, fh, offset, size);
+        // nothing is read, so nothing is flushed or accessed
+        if size == 0 {
+            reply.data(&[]);
+            return;
+        }
+        if size as u64 > FS_FUSE_MAX_IO_SIZE {
+            log::error!("IO request too big, limit to {} bytes", FS_FUSE_MAX_IO_SIZE);
+            reply.error(E2BIG);
+            return;
+        }
+        let offset = match io_offset(offset, size as usize) {
+            Err(e) => {
+                log::error!("read ino {} invalid offset {}", ino, offset);
+                reply.error(e);
+                return;
+            }
+            Ok(x) => x,
+        };
+        if control::is_ctl(ino) {
+            match self.ctl_file(ino, fh) {
+                Err(e) => reply.error(e),
+                Ok(buf) => reply.data(window(buf, offset, size as usize)),
+            }
+            return;
+        }
+        match self.read_file(ino, fh, offset, size as usize) {
+            Err(e) => reply.error(e),
+            Ok(buf) => {
+                log::info!("read ino {} fh {} nbytes {}", ino, fh, buf.len());
+                reply.data(&buf);
+                self.update_atime(ino);
+            }
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let _span = crash::enter("release", _ino);
+        log::info!("release ino {} fh {}", _ino, _fh);
+        let r = if control::is_ctl(_ino) {
+            self.release_ctl(_ino, _fh)
+        } else {
+            self.remove_file_handle(_ino, _fh)
+                .and_then(|_| self.sync_on_close(_ino))
+        };
+        match r {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let _span = crash::enter("write", ino);
+        log::info!("write ino {} fh {} offset {} size {}", ino, fh, offset, data.len());
+        // nothing is written, so the file isn't modified
+        if data.is_empty() {
+            reply.written(0);
+            return;
+        }
+        let offset = match io_offset(offset, data.len()) {
+            Err(e) => {
+                log::error!("write ino {} invalid offset {}", ino, offset);
+                reply.error(e);
+                return;
+            }
+            Ok(x) => x,
+        };
+
+        match self.find_file_handle(ino, fh) {
+            None => reply.error(self.bad_handle("write", ino, fh)),
+            Some(h) => {
+                self.file_cache.del(ino);
+                let offset = if h.borrow().append {
+                    // the length is only up to date once buffered writes are flushed
+                    self.flush_inode(ino);
+                    match self.meta.get_inode(ino) {
+                        Err(e) => {
+                            reply.error(e.errno());
+                            return;
+                        }
+                        Ok(inode) => inode.length,
+                    }
+                } else {
+                    offset
+                };
+                let nbytes = self.write_file(ino, &h, offset, data);
+                reply.written(nbytes as u32);
+            }
+        }
+    }
+
+    fn flush(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        let _span = crash::enter("flush", ino);
+        log::info!("flush ino {} fh {}", ino, fh);
+        if let Some(h) = self.find_file_handle(ino, fh) {
+            // release is sent after close(2) returned, the caller only waits for flush
+            match self.close_file(ino, &h) {
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(e),
+            }
+        } else {
+            reply.error(self.bad_handle("flush", ino, fh));
+        }
+    }
+
+    // `msync` on a shared writable mapping ends up here after the kernel wrote back dirty pages
+    fn fsync(&mut self, _req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let _span = crash::enter("fsync", ino);
+        log::info!("fsync ino {} fh {} datasync {}", ino, fh, datasync);
+        if self.find_file_handle(ino, fh).is_none() {
+            reply.error(self.bad_handle("fsync", ino, fh));
+            return;
+        }
+        match self.sync_file(ino, datasync) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn fsyncdir(&mut self, _req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let _span = crash::enter("fsyncdir", ino);
+        log::info!("fsyncdir ino {} fh {} datasync {}", ino, fh, datasync);
+        if self.find_dir_handle(ino, fh).is_none() {
+            reply.error(self.bad_handle("fsyncdir", ino, fh));
+            return;
+        }
+        // entries of a directory are all in meta
+        if let Err(e) = self.meta.sync() {
+            log::error!("fsyncdir ino {} can't sync meta, error {}", ino, e);
+            reply.error(libc::EIO);
+            return;
+        }
+        if self.take_sync_error() {
+            log::error!("fsyncdir ino {} write back failed since the last fsyncdir", ino);
+            reply.error(libc::EIO);
+            return;
+        }
+        reply.ok();
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        _out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        let _span = crash::enter("ioctl", ino);
+        log::info!("ioctl ino {} fh {} cmd {:#x}", ino, fh, cmd);
+        match self.ioctl_cmd(ino, cmd, in_data, req.uid()) {
+            Ok(_) => reply.ioctl(0, &[]),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn poll(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        ph: PollHandle,
+        events: u32,
+        flags: u32,
+        reply: ReplyPoll,
+    ) {
+        let _span = crash::enter("poll", ino);
+        log::info!("poll ino {} fh {} events {:#x} flags {:#x}", ino, fh, events, flags);
+        let wake = (flags & POLL_SCHEDULE_NOTIFY != 0).then(|| Box::new(move || ph.notify()) as PollWaker);
+        match self.poll_file(ino, fh, events, wake) {
+            Ok(revents) => reply.poll(revents),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn fallocate(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        let _span = crash::enter("fallocate", ino);
+        log::info!(
+            "fallocate ino {} fh {} offset {} length {} mode {:#x}",
+            ino,
+            fh,
+            offset,
+            length,
+            mode
+        );
+        if self.find_file_handle(ino, fh).is_none() {
+            reply.error(self.bad_handle("fallocate", ino, fh));
+            return;
+        }
+        if offset < 0 || length <= 0 {
+            reply.error(EINVAL);
+            return;
+        }
+        match self.allocate(ino, offset as u64, length as u64, mode) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn lseek(&mut self, _req: &Request<'_>, ino: u64, fh: u64, offset: i64, whence: i32, reply: ReplyLseek) {
+        let _span = crash::enter("lseek", ino);
+        log::info!("lseek ino {} fh {} offset {} whence {}", ino, fh, offset, whence);
+        match self.seek_file(ino, fh, offset, whence) {
+            Err(e) => reply.error(e),
+            Ok(off) => reply.offset(off as i64),
+        }
+    }
+
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let _span = crash::enter("opendir", ino);
+        log::info!("opendir ino {} flags {}", ino, flags);
+        let r = self.new_dir_handle(ino);
+        match r {
+            None => {
+                log::info!("can't create new dir handle for ino {}", ino);
+                reply.error(ENFILE)
+            }
+            Some(handle) => {
+                log::info!("opened ino {} fh {}", ino, handle.borrow().fh);
+                reply.opened(handle.borrow().fh, 0);
+            }
+        }
+    }
+
+    fn releasedir(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        let _span = crash::enter("releasedir", ino);
+        log::info!("releasedir ino {} fh {}", ino, fh);
+        match self.remove_dir_handle(ino, fh) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request<'_>, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let _span = crash::enter("readdir", ino);
+        self.poll_compact();
+        log::info!("readdir ino {} fh {} offset {}", ino, fh, offset);
+        if let Some(h) = self.find_dir_handle(ino, fh) {
+            // `offset` is the cookie of the last entry taken, it's 0 for a new listing or after rewinddir
+            self.meta.seek_dentry(ino, &h, offset);
+            loop {
+                if h.borrow().need_load() {
+                    self.meta.load_dentry(ino, &h);
+                }
+                let mut hb = h.borrow_mut();
+                let Some(i) = hb.peek() else {
+                    break;
+                };
+                if reply.add(ino, i.off, to_filetype(i.kind), &i.name) {
+                    log::info!("add dentry buffer full, current entry {} offset {}", i.name, i.off);
+                    break;
+                }
+                hb.next();
+            }
+            reply.ok();
+        } else {
+            reply.error(self.bad_handle("readdir", ino, fh));
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let _span = crash::enter("mknod", parent);
+        let name = name.to_str().unwrap().to_string();
+        log::info!("mknod parent {} name {}", parent, name);
+        if control::lookup(parent, &name).is_some() {
+            reply.error(EEXIST);
+            return;
+        }
+
+        if mode & S_IFMT != S_IFREG {
+            log::warn!("non-file node is not support");
+            reply.error(ENOSYS);
+            return;
+        }
+
+        match self.meta.mknod(parent, name, Itype::File, mode) {
+            Err(e) => {
+                log::warn!("mknod fail, error {}", e);
+                reply.error(e.errno());
+            }
+            Ok(inode) => {
+                let attr = self.attr(&inode);
+                let ttl = time::Duration::new(1, 0);
+                self.remember(inode.id);
+                reply.entry(&ttl, &attr, 0);
+            }
+        }
+    }
+
+    fn mkdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, _umask: u32, reply: ReplyEntry) {
+        let _span = crash::enter("mkdir", parent);
+        let name = name.to_str().unwrap().to_string();
+
+        log::info!("mkdir parent {} name {}", parent, name);
+        if control::lookup(parent, &name).is_some() {
+            reply.error(EEXIST);
+            return;
+        }
+        match self.meta.mknod(parent, &name, Itype::Dir, mode) {
+            Ok(inode) => {
+                let attr = self.attr(&inode);
+                let ttl = time::Duration::new(1, 0);
+                self.remember(inode.id);
+                reply.entry(&ttl, &attr, 0);
+            }
+            Err(e) => {
+                log::error!("can't create dir {}, error {}", name, e);
+                reply.error(e.errno());
+            }
+        }
+    }
+
+    fn symlink(&mut self, _req: &Request<'_>, parent: u64, link_name: &OsStr, target: &Path, reply: ReplyEntry) {
+        let _span = crash::enter("symlink", parent);
+        let name = link_name.to_str().unwrap().to_string();
+        log::info!("symlink parent {} name {} target {}", parent, name, target.display());
+        if control::lookup(parent, &name).is_some() {
+            reply.error(EEXIST);
+            return;
+        }
+        match self.meta.symlink(parent, &name, target.as_os_str().as_bytes()) {
+            Err(e) => {
+                log::error!("can't create symlink {}, error {}", name, e);
+                reply.error(e.errno());
+            }
+            Ok(inode) => {
+                let attr = self.attr(&inode);
+                let ttl = time::Duration::new(1, 0);
+                self.remember(inode.id);
+                reply.entry(&ttl, &attr, 0);
+            }
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        let _span = crash::enter("readlink", ino);
+        log::info!("readlink ino {}", ino);
+        match self.meta.readlink(ino) {
+            Err(e) => {
+                log::error!("readlink ino {} fail, error {}", ino, e);
+                reply.error(e.errno());
+            }
+            Ok(target) => reply.data(&target),
+        }
+    }
+
+    // `create` is a fuse operation of `mknod` and `open`, which is used to create regular file
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let _span = crash::enter("create", parent);
+        let name = name.to_str().unwrap().to_string();
+        log::info!("create parent {} name {} flags {} mask {}", parent, name, flags, umask);
+        if control::lookup(parent, &name).is_some() {
+            reply.error(EEXIST);
+            return;
+        }
+        let inode = match self.create_file(parent, &name, mode, flags) {
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+            Ok(x) => x,
+        };
+
+        let r = self.new_file_handle(inode.id);
+
+        match r {
+            None => {
+                log::info!("create fail parent {} name {} ino {}", parent, name, inode.id);
+                reply.error(ENFILE)
+            }
+            Some(handle) => {
+                let ttl = time::Duration::new(1, 0);
+                let attr = self.attr(&inode);
+                let fh = handle.borrow().fh;
+                log::info!(
+                    "created file parent {} name {} ino {} fh {}",
+                    parent,
+                    name,
+                    inode.id,
+                    fh
+                );
+                self.remember(inode.id);
+                reply.created(&ttl, &attr, 0, fh, 0);
+            }
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _span = crash::enter("unlink", parent);
+        let name = name.to_string_lossy().to_string();
+        match self.meta.unlink(parent, &name) {
+            Err(e) => {
+                log::error!("can't find parent {} name {}", parent, name);
+                reply.error(e.errno());
+            }
+            Ok(inode) => {
+                self.drop_data(&inode);
+                reply.ok();
+            }
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _span = crash::enter("rmdir", parent);
+        let name = name.to_string_lossy().to_string();
+        match self.meta.rmdir(parent, &name) {
+            Err(e) => {
+                log::error!("rmdir fail parent {} name {} error {}", parent, name, e);
+                reply.error(e.errno());
+            }
+            Ok(inode) => {
+                log::info!("rmdir ok parent {} ino {} name {}", parent, inode.id, name);
+                reply.ok();
+            }
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let _span = crash::enter("rename", parent);
+        let name = name.to_string_lossy().to_string();
+        let newname = newname.to_string_lossy().to_string();
+        log::info!(
+            "rename parent {} name {} to parent {} name {}",
+            parent,
+            name,
+            newparent,
+            newname
+        );
+        if control::lookup(newparent, &newname).is_some() {
+            reply.error(libc::EPERM);
+            return;
+        }
+        match self.meta.rename(parent, &name, newparent, &newname, flags) {
+            Err(e) => {
+                log::error!("rename fail parent {} name {} error {}", parent, name, e);
+                reply.error(e.errno());
+            }
+            Ok(replaced) => {
+                if let Some(inode) = replaced {
+                    self.drop_data(&inode);
+                }
+                reply.ok();
+            }
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let _span = crash::enter("setxattr", ino);
+        let name = name.to_string_lossy();
+        log::info!(
+            "setxattr ino {} name {} size {} flags {}",
+            ino,
+            name,
+            value.len(),
+            flags
+        );
+        if control::is_ctl(ino) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        match self.meta.set_xattr(ino, &name, value, flags) {
+            Err(e) => reply.error(e.errno()),
+            Ok(_) => reply.ok(),
+        }
+    }
+
+    fn getxattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let _span = crash::enter("getxattr", ino);
+        let name = name.to_string_lossy();
+        log::info!("getxattr ino {} name {} size {}", ino, name, size);
+        if control::is_ctl(ino) {
+            reply.error(libc::ENODATA);
+            return;
+        }
+        match self.meta.get_xattr(ino, &name) {
+            Err(e) => reply.error(e.errno()),
+            Ok(value) => reply_xattr(&value, size, reply),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let _span = crash::enter("listxattr", ino);
+        log::info!("listxattr ino {} size {}", ino, size);
+        if !control::is_ctl(ino) {
+            if let Err(e) = self.meta.get_inode(ino) {
+                reply.error(e.errno());
+                return;
+            }
+        }
+        // every name is followed by a nul
+        let mut buf = Vec::new();
+        for name in self.meta.list_xattr(ino) {
+            buf.extend_from_slice(name.as_bytes());
+            buf.push(0);
+        }
+        reply_xattr(&buf, size, reply);
+    }
+
+    fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _span = crash::enter("removexattr", ino);
+        let name = name.to_string_lossy();
+        log::info!("removexattr ino {} name {}", ino, name);
+        if control::is_ctl(ino) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        match self.meta.remove_xattr(ino, &name) {
+            Err(e) => reply.error(e.errno()),
+            Ok(_) => reply.ok(),
+        }
+    }
+}
+
+impl Drop for Fs {
+    fn drop(&mut self) {
+        // handles still open when the session ends abruptly hold dirty pages of the pool and may grow files, so
+        // they're flushed before meta is closed and the pool is gone
+        if std::thread::panicking() {
+            let n: usize = self.store.borrow().values().map(|v| v.len()).sum();
+            log::error!("flush {} open handles and meta after a panic", n);
+        }
+        self.flush_all();
+        self.checkpoint();
+        self.store.borrow_mut().clear();
+        // files removed while open are closed and forgotten by the unmount
+        self.lookups.clear();
+        let unlinked: Vec<Ino> = self.unlinked.iter().copied().collect();
+        for ino in unlinked {
+            self.meta.set_open(ino, false);
+            self.reclaim(ino);
+        }
+        self.meta.close();
+        MemPool::destroy();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{handle_map, io_offset, Fs, PollWaker, POLL_READY};
+    use crate::cache::{FileCache, MemPool, POOL_LOCK};
+    use crate::fs::control::{self, HandleKind, CTL_DIR_INO, HANDLES_INO, HEALTH_INO, LABEL_INO};
+    use crate::fs::crash;
+    use crate::fs::Health;
+    use crate::fs::{Atime, Config};
+    use crate::meta::{DirLimits, Ino, Inode, Itype, KeyParams, Layout, Meta, LABEL_MAX};
+    use crate::store::{DataStore, Entry, MemStore, PackedStore, SharedStore, Xts};
+    use crate::utils::fault::{self, Fault};
+    use crate::utils::{epoch_secs, init_data_path, stats, to_attr, to_systime, FS_BLK_SIZE, FS_PAGE_SIZE};
+    use fuser::{MountOption, TimeOrNow};
+    use libc::{EINVAL, EISDIR};
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashSet;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_handle_map() {
+        let mut m = handle_map(16);
+        let mut seen = HashSet::new();
+        while let Some(fh) = m.alloc_next() {
+            assert_ne!(fh, 0);
+            assert!(seen.insert(fh), "duplicated fh {}", fh);
+        }
+        assert_eq!(seen.len(), 15);
+        assert!(m.alloc_next().is_none());
+
+        assert!(m.free(7));
+        assert_eq!(m.alloc_next(), Some(7));
+        assert!(m.alloc_next().is_none());
+
+        // opened and closed over and over, every slot is handed out in turn before one is reused
+        assert!(m.free(3) && m.free(7) && m.free(11));
+        let fhs: Vec<u64> = (0..6)
+            .map(|_| {
+                let fh = m.alloc_next().unwrap();
+                assert!(m.free(fh));
+                fh
+            })
+            .collect();
+        assert_eq!(fhs, vec![11, 3, 7, 11, 3, 7]);
+    }
+
+    #[test]
+    fn test_io_offset() {
+        assert_eq!(io_offset(0, 0), Ok(0));
+        assert_eq!(io_offset(4096, 4096), Ok(4096));
+        assert_eq!(io_offset(i64::MAX - 10, 10), Ok(i64::MAX as u64 - 10));
+        assert_eq!(io_offset(-1, 0), Err(libc::EINVAL));
+        assert_eq!(io_offset(i64::MIN, 4096), Err(libc::EINVAL));
+        assert_eq!(io_offset(i64::MAX - 10, 11), Err(libc::EINVAL));
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        // not mounted, nothing to invalidate
+        assert!(!Fs::invalidate(1));
+
+        let (mut fs, _, _) = new_fs("/tmp/test_fs_invalidate", Some(Rc::new(RefCell::new(MemStore::new()))));
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+        assert_eq!(fs.write_file(f.id, &h, 0, b"hello"), 5);
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let s = seen.clone();
+        Fs::set_invalidator(Some(Box::new(move |ino| {
+            s.lock().unwrap().push(ino);
+            match ino {
+                1000 => Err(std::io::Error::from_raw_os_error(libc::ENOENT)),
+                _ => Ok(()),
+            }
+        })));
+
+        h.borrow_mut().flush(&mut fs.meta);
+        // changed behind the kernel's back while the file is open and written
+        let mut inode = fs.meta.get_inode(f.id).unwrap();
+        inode.set_mode(0o600);
+        fs.meta.store_inode(&inode).unwrap();
+        assert!(Fs::invalidate(f.id));
+        // unknown to the kernel
+        assert!(!Fs::invalidate(1000));
+        Fs::set_invalidator(None);
+        assert_eq!(*seen.lock().unwrap(), vec![f.id, 1000]);
+
+        // what the getattr the kernel sends next gets
+        let inode = fs.meta.get_inode(f.id).unwrap();
+        let attr = fs.attr(&inode);
+        assert_eq!((attr.perm, attr.size), (0o600, 5));
+        let fh = h.borrow().fh;
+        fs.remove_file_handle(f.id, fh).unwrap();
+    }
+
+    /// a formatted filesystem under `path` with root created, return it with paths of meta and data
+    fn new_fs(path: &str, data_store: Option<SharedStore>) -> (Fs, String, String) {
+        let (meta, data, mnt) = (
+            format!("{}/meta", path),
+            format!("{}/data", path),
+            format!("{}/mnt", path),
+        );
+        let _ = std::fs::remove_dir_all(path);
+        std::fs::create_dir_all(&data).unwrap();
+        std::fs::create_dir_all(&mnt).unwrap();
+        Meta::format(&meta, &data, false).unwrap();
+
+        let cfg = Config::parse(vec![meta.clone(), mnt]).unwrap();
+        let mut fs = match data_store {
+            None => Fs::new(&cfg).unwrap(),
+            Some(x) => Fs::with_store(&cfg, x).unwrap(),
+        };
+        fs.meta.mknod(0, "/", Itype::Dir, 0o755).unwrap();
+        (fs, meta, data)
+    }
+
+    #[test]
+    fn test_write_full_pool() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut fs, _, _) = new_fs(
+            "/tmp/test_fs_write_full_pool",
+            Some(Rc::new(RefCell::new(MemStore::new()))),
+        );
+        // a pool of 8 pages, two handles with dirty pages use it all up
+        MemPool::destroy();
+        MemPool::init(8 * FS_PAGE_SIZE, FS_PAGE_SIZE);
+
+        let chunk = 4 * FS_PAGE_SIZE as usize;
+        let mut hs = Vec::new();
+        for i in 0..4u8 {
+            let f = fs.meta.mknod(1, &format!("f{}", i), Itype::File, 0o644).unwrap();
+            hs.push((f.id, fs.new_file_handle(f.id).unwrap()));
+        }
+        for round in 0..16 {
+            for (i, (ino, h)) in hs.iter().enumerate() {
+                let data = vec![(round * 4 + i) as u8; chunk];
+                assert_eq!(fs.write_file(*ino, h, (round * chunk) as u64, &data), chunk);
+            }
+        }
+        // larger than the whole pool
+        let (ino, h) = &hs[0];
+        let big = vec![0xffu8; 3 * 8 * FS_PAGE_SIZE as usize];
+        assert_eq!(fs.write_file(*ino, h, 0, &big), big.len());
+
+        fs.flush_all();
+        for (i, (ino, h)) in hs.iter().enumerate().skip(1) {
+            assert_eq!(fs.meta.get_inode(*ino).unwrap().length, 16 * chunk as u64);
+            let buf = h.borrow_mut().read(&mut fs.meta, 15 * chunk as u64, chunk).unwrap();
+            assert_eq!(buf, vec![(15 * 4 + i) as u8; chunk]);
+        }
+        let buf = h.borrow_mut().read(&mut fs.meta, 0, big.len()).unwrap();
+        assert_eq!(buf, big);
+    }
+
+    #[test]
+    fn test_page_size() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let store = Rc::new(RefCell::new(MemStore::new()));
+        let (mut fs, meta, _) = new_fs("/tmp/test_fs_page_size", Some(store.clone()));
+        // the pool goes with the filesystem, the next one makes a pool of larger pages
+        drop(fs);
+        let page = 64 * FS_PAGE_SIZE;
+        let cfg = Config::parse(vec![
+            "--page-size".to_string(),
+            "256K".to_string(),
+            meta,
+            "/tmp/test_fs_page_size/mnt".to_string(),
+        ])
+        .unwrap();
+        fs = Fs::with_store(&cfg, store).unwrap();
+        assert_eq!(MemPool::get().page_size(), page);
+
+        // unaligned writes across pages and a block boundary, then small ones sharing a page
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+        let data: Vec<u8> = (0..3 * page as usize + 100).map(|i| (i % 251) as u8).collect();
+        let off = FS_BLK_SIZE - page - 7;
+        for c in data.chunks(100_000) {
+            let pos = off + (c.as_ptr() as usize - data.as_ptr() as usize) as u64;
+            assert_eq!(fs.write_file(f.id, &h, pos, c), c.len());
+        }
+        for i in 0..100u64 {
+            assert_eq!(fs.write_file(f.id, &h, i * 10, b"0123456789"), 10);
+        }
+        assert!(h.borrow().dirty() > 0);
+        h.borrow_mut().flush(&mut fs.meta);
+        assert_eq!(h.borrow().dirty(), 0);
+
+        // every page went back to the pool
+        let mut pages = Vec::new();
+        while !MemPool::get().full() {
+            pages.push(MemPool::get().alloc());
+        }
+        assert_eq!(pages.len() as u64, (100 << 20) / page);
+        for p in pages {
+            MemPool::get().free(p);
+        }
+
+        let mut h = h.borrow_mut();
+        let mut buf = Vec::new();
+        while buf.len() < data.len() {
+            let r = h
+                .read(&mut fs.meta, off + buf.len() as u64, data.len() - buf.len())
+                .unwrap();
+            assert!(!r.is_empty());
+            buf.extend(r);
+        }
+        assert_eq!(buf, data);
+        assert_eq!(h.read(&mut fs.meta, 0, 1000).unwrap(), b"0123456789".repeat(100));
+    }
+
+    #[test]
+    fn test_write_through() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = "/tmp/test_fs_write_through";
+        let store = Rc::new(RefCell::new(PackedStore::new(&format!("{}/data", path))));
+        let (mut fs, _, data) = new_fs(path, Some(store));
+        fs.cfg.write_through = true;
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+
+        let file = format!("{}/{}", data, f.id);
+        assert_eq!(fs.write_file(f.id, &h, 0, b"hello"), 5);
+        assert_eq!(std::fs::read(&file).unwrap(), b"hello");
+        assert_eq!(fs.write_file(f.id, &h, 5, b" world"), 6);
+        assert_eq!(std::fs::read(&file).unwrap(), b"hello world");
+        assert_eq!(h.borrow().dirty(), 0);
+        assert_eq!(fs.meta.get_inode(f.id).unwrap().length, 11);
+
+        // buffered by default
+        fs.cfg.write_through = false;
+        let g = fs.meta.mknod(1, "g", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(g.id).unwrap();
+        assert_eq!(fs.write_file(g.id, &h, 0, b"hello"), 5);
+        assert!(!std::path::Path::new(&format!("{}/{}", data, g.id)).exists());
+    }
+
+    #[test]
+    fn test_data_path() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = "/tmp/test_fs_data_path";
+        let (meta, old, moved) = (
+            format!("{}/meta", path),
+            format!("{}/data", path),
+            format!("{}/moved", path),
+        );
+        let _ = std::fs::remove_dir_all(path);
+        std::fs::create_dir_all(&old).unwrap();
+        Meta::format_with(&meta, &old, Layout::Packed, DirLimits::default(), "", None, false).unwrap();
+        std::fs::rename(&old, &moved).unwrap();
+
+        let cfg = |data: &str| {
+            let args = vec!["--strict", "--data-path", data, meta.as_str(), path];
+            Config::parse(args.into_iter().map(String::from)).unwrap()
+        };
+        let plain = Config::parse(vec!["--strict".to_string(), meta.clone(), path.to_string()]).unwrap();
+        assert!(Fs::new(&plain).err().unwrap().contains("inaccessible"));
+        assert!(Fs::new(&cfg(&old)).err().unwrap().contains("inaccessible"));
+        let file = format!("{}/file", path);
+        std::fs::write(&file, b"").unwrap();
+        assert!(Fs::new(&cfg(&file)).err().unwrap().contains("not a directory"));
+
+        let mut fs = Fs::new(&cfg(&format!("{}/", moved))).unwrap();
+        fs.meta.mknod(0, "/", Itype::Dir, 0o755).unwrap();
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+        assert_eq!(fs.write_file(f.id, &h, 0, b"hello"), 5);
+        h.borrow_mut().flush(&mut fs.meta);
+        assert_eq!(std::fs::read(format!("{}/{}", moved, f.id)).unwrap(), b"hello");
+        assert!(!std::path::Path::new(&old).exists());
+    }
+
+    #[test]
+    fn test_drop_flush() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut fs, meta, data) = new_fs("/tmp/test_fs_drop_flush", None);
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+        assert_eq!(h.borrow_mut().write(&mut fs.meta, 0, b"hello"), 5);
+        drop(h);
+
+        // the data path is process wide, other tests may have changed it in the meantime
+        init_data_path(&data);
+        drop(fs);
+        assert_eq!(std::fs::read(format!("{}/{}/0", data, f.id)).unwrap(), b"hello");
+        let m = Meta::load_fs(meta, true).unwrap();
+        assert_eq!(m.load_inode(f.id).unwrap().length, 5);
+    }
+
+    #[test]
+    fn test_journal_replay() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = "/tmp/test_fs_journal_replay";
+        let store = Rc::new(RefCell::new(MemStore::new()));
+        let (fs, meta, data) = new_fs(path, Some(store.clone()));
+        drop(fs);
+        let mut cfg = Config::parse(vec![meta.clone(), format!("{}/mnt", path)]).unwrap();
+        cfg.journal = true;
+        let journal = format!("{}/.journal", data);
+        // the data path is process wide, other tests may have changed it in the meantime
+        let mount = |cfg: &Config| {
+            init_data_path(&data);
+            Fs::with_store(cfg, store.clone()).unwrap()
+        };
+        // the process dies, what's buffered is lost, the journal is left as it was
+        let crash = |mut fs: Fs| {
+            let j = fs.journal.take();
+            fs.store.borrow_mut().clear();
+            drop(fs);
+            drop(j);
+        };
+
+        let mut fs = mount(&cfg);
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+        assert_eq!(fs.write_file(f.id, &h, 0, b"acknowledged"), 12);
+        assert_eq!(fs.write_file(f.id, &h, FS_BLK_SIZE - 2, b"tail"), 4);
+        assert_eq!(fs.write_file(f.id, &h, 0, b"A"), 1);
+        drop(h);
+        crash(fs);
+        assert!(!store.borrow().exists(f.id, 0));
+
+        // replayed by a mount without the journal too, which removes it
+        cfg.journal = false;
+        let mut fs = mount(&cfg);
+        assert!(!std::path::Path::new(&journal).exists());
+        assert_eq!(fs.meta.get_inode(f.id).unwrap().length, FS_BLK_SIZE + 2);
+        let h = fs.new_file_handle(f.id).unwrap();
+        assert_eq!(h.borrow_mut().read(&mut fs.meta, 0, 12).unwrap(), b"Acknowledged");
+        // across the block boundary
+        assert_eq!(h.borrow_mut().read(&mut fs.meta, FS_BLK_SIZE - 2, 10).unwrap(), b"ta");
+        assert_eq!(h.borrow_mut().read(&mut fs.meta, FS_BLK_SIZE, 10).unwrap(), b"il");
+        drop(h);
+        drop(fs);
+
+        // a truncate empties the journal, the writes before it are not replayed over it
+        cfg.journal = true;
+        let mut fs = mount(&cfg);
+        let h = fs.new_file_handle(f.id).unwrap();
+        assert_eq!(fs.write_file(f.id, &h, 0, b"gone"), 4);
+        assert!(std::fs::metadata(&journal).unwrap().len() > 0);
+        fs.resize(f.id, 2).unwrap();
+        assert_eq!(std::fs::metadata(&journal).unwrap().len(), 0);
+        assert_eq!(fs.write_file(f.id, &h, 1, b"x"), 1);
+        drop(h);
+        crash(fs);
+        let mut fs = mount(&cfg);
+        assert_eq!(fs.meta.get_inode(f.id).unwrap().length, 2);
+        let h = fs.new_file_handle(f.id).unwrap();
+        assert_eq!(h.borrow_mut().read(&mut fs.meta, 0, 10).unwrap(), b"gx");
+        let fh = h.borrow().fh;
+        drop(h);
+        // nothing is buffered once every file is closed
+        assert_eq!(fs.write_file(f.id, &fs.find_file_handle(f.id, fh).unwrap(), 0, b"y"), 1);
+        fs.remove_file_handle(f.id, fh).unwrap();
+        assert_eq!(std::fs::metadata(&journal).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut fs, _, _) = new_fs("/tmp/test_fs_truncate", Some(Rc::new(RefCell::new(MemStore::new()))));
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let (a, b) = (fs.new_file_handle(f.id).unwrap(), fs.new_file_handle(f.id).unwrap());
+        a.borrow_mut().write(&mut fs.meta, 0, &[1u8; 8192]);
+        a.borrow_mut().flush(&mut fs.meta);
+        // still buffered when the file is truncated
+        a.borrow_mut().write(&mut fs.meta, 8192, b"tail");
+
+        fs.truncate(f.id).unwrap();
+        assert_eq!(fs.meta.get_inode(f.id).unwrap().length, 0);
+        assert!(b.borrow_mut().read(&mut fs.meta, 0, 100).unwrap().is_empty());
+
+        // the old data doesn't come back when the file grows again
+        b.borrow_mut().write(&mut fs.meta, 10, b"x");
+        assert_eq!(
+            b.borrow_mut().read(&mut fs.meta, 0, 100).unwrap(),
+            b"\0\0\0\0\0\0\0\0\0\0x"
+        );
+
+        let d = fs.meta.mknod(1, "d", Itype::Dir, 0o755).unwrap();
+        assert_eq!(fs.truncate(d.id), Err(EISDIR));
+        assert_eq!(fs.truncate(4096), Err(libc::ENOENT));
+    }
+
+    #[test]
+    fn test_reflink() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut fs, _, _) = new_fs("/tmp/test_fs_reflink", Some(Rc::new(RefCell::new(MemStore::new()))));
+        let src = fs.meta.mknod(1, "src", Itype::File, 0o644).unwrap();
+        let dst = fs.meta.mknod(1, "dst", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(src.id).unwrap();
+        h.borrow_mut().write(&mut fs.meta, 0, b"hello world");
+        // the buffered data is shared too
+        fs.reflink(src.id, dst.id).unwrap();
+        assert_eq!(fs.meta.block_refs(src.id, 0), 2);
+        assert_eq!(fs.meta.get_inode(dst.id).unwrap().length, 11);
+        let d = fs.new_file_handle(dst.id).unwrap();
+        assert_eq!(d.borrow_mut().read(&mut fs.meta, 0, 100).unwrap(), b"hello world");
+        assert_eq!(fs.reflink(src.id, dst.id), Err(libc::EINVAL));
+
+        // share then write, the writer gets its own copy
+        d.borrow_mut().write(&mut fs.meta, 0, b"HELLO");
+        assert_eq!(d.borrow_mut().read(&mut fs.meta, 0, 100).unwrap(), b"HELLO world");
+        assert_eq!(h.borrow_mut().read(&mut fs.meta, 0, 100).unwrap(), b"hello world");
+        assert_eq!(fs.meta.block_refs(src.id, 0), 1);
+        assert_eq!(fs.meta.block_refs(dst.id, 0), 1);
+
+        // share then unlink one, the other keeps the data
+        let cp = fs.meta.mknod(1, "cp", Itype::File, 0o644).unwrap();
+        fs.reflink(src.id, cp.id).unwrap();
+        let inode = fs.meta.unlink(1, "src").unwrap();
+        fs.drop_data(&inode);
+        // it's still open, so it shares the block until it's closed
+        assert_eq!(fs.meta.block_refs(cp.id, 0), 2);
+        let fh = h.borrow().fh;
+        fs.remove_file_handle(src.id, fh).unwrap();
+        assert_eq!(fs.meta.block_refs(cp.id, 0), 1);
+        let c = fs.new_file_handle(cp.id).unwrap();
+        assert_eq!(c.borrow_mut().read(&mut fs.meta, 0, 100).unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn test_st_blocks() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut fs, _, _) = new_fs("/tmp/test_fs_st_blocks", Some(Rc::new(RefCell::new(MemStore::new()))));
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+        // 4097 bytes at the end of a 3 blocks file
+        h.borrow_mut().write(&mut fs.meta, 3 * FS_BLK_SIZE - 4097, &[1u8; 4097]);
+        h.borrow_mut().flush(&mut fs.meta);
+        let inode = fs.meta.get_inode(f.id).unwrap();
+        assert_eq!(inode.length, 3 * FS_BLK_SIZE);
+        // `MemStore` allocates the whole block up to the last byte written
+        assert_eq!(fs.attr(&inode).blocks, FS_BLK_SIZE / 512);
+        assert!(fs.usage.get(&f.id).is_none());
+
+        // cached once no handle is open, until the data is changed
+        let fh = h.borrow().fh;
+        fs.remove_file_handle(f.id, fh).unwrap();
+        assert_eq!(fs.attr(&inode).blocks, FS_BLK_SIZE / 512);
+        assert_eq!(fs.usage.get(&f.id).copied(), Some(FS_BLK_SIZE));
+        fs.truncate(f.id).unwrap();
+        let inode = fs.meta.get_inode(f.id).unwrap();
+        assert_eq!(fs.attr(&inode).blocks, 0);
+
+        let d = fs.meta.mknod(1, "d", Itype::Dir, 0o755).unwrap();
+        assert_eq!(fs.attr(&d).blocks, 0);
+    }
+
+    #[test]
+    fn test_health() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut fs, _, _) = new_fs("/tmp/test_fs_health", Some(Rc::new(RefCell::new(MemStore::new()))));
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let _h = fs.new_file_handle(f.id).unwrap();
+
+        let fh = fs.open_ctl(HEALTH_INO, libc::O_RDONLY).unwrap();
+        let h = Health::parse(std::str::from_utf8(&fs.ctl_files[&fh].1).unwrap()).unwrap();
+        assert_eq!(h.open_handles, 1);
+        assert!(h.cached_inodes > 0);
+        assert_eq!(fs.health().open_handles, 2);
+
+        assert_eq!(fs.open_ctl(HEALTH_INO, libc::O_WRONLY), Err(libc::EACCES));
+        assert_eq!(fs.open_ctl(CTL_DIR_INO, libc::O_RDONLY), Err(EISDIR));
+    }
+
+    #[test]
+    fn test_label() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = "/tmp/test_fs_label";
+        let (meta, data) = (format!("{}/meta", path), format!("{}/data", path));
+        let _ = std::fs::remove_dir_all(path);
+        std::fs::create_dir_all(&data).unwrap();
+        let e = Meta::format_with(
+            &meta,
+            &data,
+            Layout::Block,
+            DirLimits::default(),
+            "no space",
+            None,
+            false,
+        );
+        assert!(e.err().unwrap().starts_with("invalid label"));
+        Meta::format_with(&meta, &data, Layout::Block, DirLimits::default(), "photos", None, false).unwrap();
+
+        let cfg = Config::parse(vec![meta.clone(), path.to_string()]).unwrap();
+        let store = || Rc::new(RefCell::new(MemStore::new()));
+        let mut fs = Fs::with_store(&cfg, store()).unwrap();
+        let label = |fs: &mut Fs| {
+            let fh = fs.open_ctl(LABEL_INO, libc::O_RDONLY).unwrap();
+            let s = String::from_utf8(fs.ctl_file(LABEL_INO, fh).unwrap().to_vec()).unwrap();
+            fs.release_ctl(LABEL_INO, fh).unwrap();
+            s
+        };
+        assert_eq!(label(&mut fs), "photos\n");
+        assert!(fs.mount_options().contains(&MountOption::FSName("photos".to_string())));
+
+        let me = unsafe { libc::getuid() };
+        let mut arg = [0u8; LABEL_MAX];
+        arg[..7].copy_from_slice(b"archive");
+        assert_eq!(fs.set_label(&arg, me.wrapping_add(1)), Err(libc::EPERM));
+        assert_eq!(fs.set_label(b"seventeen-bytes!!", me), Err(libc::ENAMETOOLONG));
+        assert_eq!(fs.set_label(b"a b", me), Err(libc::EINVAL));
+        fs.set_label(&arg, me).unwrap();
+        assert_eq!(label(&mut fs), "archive\n");
+        drop(fs);
+
+        // it's in the store, and an empty one names the mount as before
+        let mut fs = Fs::with_store(&cfg, store()).unwrap();
+        assert_eq!(label(&mut fs), "archive\n");
+        fs.set_label(&[0; LABEL_MAX], me).unwrap();
+        assert!(fs.mount_options().contains(&MountOption::FSName("jfs".to_string())));
+        fs.cfg.read_only = true;
+        assert_eq!(fs.set_label(&arg, me), Err(libc::EROFS));
+    }
+
+    #[test]
+    fn test_key() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = "/tmp/test_fs_key";
+        let (meta, data) = (format!("{}/meta", path), format!("{}/data", path));
+        let _ = std::fs::remove_dir_all(path);
+        std::fs::create_dir_all(&data).unwrap();
+        let (right, wrong) = (format!("{}/right", path), format!("{}/wrong", path));
+        std::fs::write(&right, "open sesame\n").unwrap();
+        std::fs::write(&wrong, "open says me\n").unwrap();
+        let salt = [9u8; 16];
+        let key = KeyParams {
+            salt,
+            check: Xts::derive(b"open sesame", &salt).check(),
+        };
+        let e = Meta::format_with(&meta, &data, Layout::Packed, DirLimits::default(), "", Some(key), false);
+        assert!(e.unwrap_err().contains("block layout"));
+        Meta::format_with(&meta, &data, Layout::Block, DirLimits::default(), "", Some(key), false).unwrap();
+
+        let mount = |key_file: Option<&str>| {
+            let mut cfg = Config::parse(vec![meta.clone(), path.to_string()]).unwrap();
+            cfg.key_file = key_file.map(|x| x.to_string());
+            Fs::with_store(&cfg, Rc::new(RefCell::new(MemStore::new()))).map(|_| ())
+        };
+        assert!(mount(None).unwrap_err().contains("--key-file is required"));
+        assert!(mount(Some(&wrong)).unwrap_err().contains("wrong key"));
+        mount(Some(&right)).unwrap();
+
+        // a plain filesystem refuses a key rather than leave it unused
+        let _ = std::fs::remove_dir_all(&meta);
+        Meta::format_with(&meta, &data, Layout::Block, DirLimits::default(), "", None, true).unwrap();
+        assert!(mount(Some(&right)).unwrap_err().contains("not encrypted"));
+        mount(None).unwrap();
+    }
+
+    #[test]
+    fn test_debug_handles() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut fs, _, _) = new_fs(
+            "/tmp/test_fs_debug_handles",
+            Some(Rc::new(RefCell::new(MemStore::new()))),
+        );
+        assert!(fs.debug_handles().is_empty());
+
+        let mut fhs = Vec::new();
+        for i in 0..5 {
+            let f = fs.meta.mknod(1, &format!("f{}", i), Itype::File, 0o644).unwrap();
+            let h = fs.new_file_handle(f.id).unwrap();
+            h.borrow_mut().write(&mut fs.meta, 0, &[1u8; 100]);
+            fhs.push((f.id, h.borrow().fh));
+        }
+        let d = fs.new_dir_handle(1).unwrap().borrow().fh;
+        let v = fs.debug_handles();
+        assert_eq!(v.len(), 6);
+        assert!(v.windows(2).all(|w| w[0].fh < w[1].fh));
+        assert!(v.iter().filter(|h| h.kind == HandleKind::File).all(|h| h.dirty == 100));
+        assert!(v.iter().any(|h| h.kind == HandleKind::Dir && h.ino == 1 && h.fh == d));
+
+        let fh = fs.open_ctl(HANDLES_INO, libc::O_RDONLY).unwrap();
+        let s = String::from_utf8(fs.ctl_files[&fh].1.clone()).unwrap();
+        assert_eq!(s, control::render_handles(&v));
+        assert_eq!(fs.health().open_handles, 7);
+        fs.ctl_files.remove(&fh);
+        fs.free_fh(fh);
+
+        for (n, &(ino, fh)) in fhs.iter().enumerate() {
+            fs.remove_file_handle(ino, fh).unwrap();
+            assert_eq!(fs.debug_handles().len(), 5 - n);
+        }
+        fs.remove_dir_handle(1, d).unwrap();
+        assert!(fs.debug_handles().is_empty());
+        assert_eq!(fs.health().open_handles, 0);
+    }
+
+    #[test]
+    fn test_read_other_handle() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut fs, _, _) = new_fs(
+            "/tmp/test_fs_read_other_handle",
+            Some(Rc::new(RefCell::new(MemStore::new()))),
+        );
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let a = fs.new_file_handle(f.id).unwrap();
+        let b = fs.new_file_handle(f.id).unwrap();
+        let (fa, fb) = (a.borrow().fh, b.borrow().fh);
+
+        // buffered in a, read through b without a flush
+        assert_eq!(fs.write_file(f.id, &a, 0, b"aaaa"), 4);
+        assert!(a.borrow().dirty() > 0);
+        assert_eq!(fs.read_file(f.id, fb, 0, 10).unwrap(), b"aaaa");
+
+        // the later write wins whichever handle is flushed first
+        assert_eq!(fs.write_file(f.id, &b, 0, b"bb"), 2);
+        assert_eq!(fs.write_file(f.id, &a, 0, b"c"), 1);
+        assert_eq!(b.borrow().dirty(), 0);
+        assert_eq!(fs.read_file(f.id, fb, 0, 10).unwrap(), b"cbaa");
+        assert_eq!(fs.read_file(f.id, fa, 1, 10).unwrap(), b"baa");
+    }
+
+    #[test]
+    fn test_poll() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut fs, _, _) = new_fs("/tmp/test_fs_poll", Some(Rc::new(RefCell::new(MemStore::new()))));
+        let f = fs.meta.mknod(1, "log", Itype::File, 0o644).unwrap();
+        let writer = fs.new_file_handle(f.id).unwrap();
+        let reader = fs.new_file_handle(f.id).unwrap().borrow().fh;
+        let woken = Rc::new(Cell::new(0));
+        let waker = || -> PollWaker {
+            let w = woken.clone();
+            Box::new(move || {
+                w.set(w.get() + 1);
+                Ok(())
+            })
+        };
+
+        let all = POLL_READY | libc::POLLPRI as u32;
+        assert_eq!(fs.poll_file(f.id, reader, all, Some(waker())), Ok(POLL_READY));
+        assert_eq!(fs.poll_file(f.id, 1000, all, None), Err(libc::EBADF));
+        assert_eq!(woken.get(), 0);
+
+        // the writer appends, the reader is notified once and polls again
+        assert_eq!(fs.write_file(f.id, &writer, 0, b"line 1\n"), 7);
+        assert_eq!(woken.get(), 1);
+        assert_eq!(fs.write_file(f.id, &writer, 7, b"line 2\n"), 7);
+        assert_eq!(woken.get(), 1);
+        fs.poll_file(f.id, reader, libc::POLLIN as u32, Some(waker())).unwrap();
+        assert_eq!(fs.write_file(f.id, &writer, 14, b"line 3\n"), 7);
+        assert_eq!(woken.get(), 2);
+        assert_eq!(fs.read_file(f.id, reader, 7, 100).unwrap(), b"line 2\nline 3\n");
+
+        // a poller left when the file is closed is dropped
+        fs.poll_file(f.id, reader, libc::POLLIN as u32, Some(waker())).unwrap();
+        let fh = writer.borrow().fh;
+        fs.remove_file_handle(f.id, fh).unwrap();
+        assert!(fs.pollers.contains_key(&f.id));
+        fs.remove_file_handle(f.id, reader).unwrap();
+        assert!(!fs.pollers.contains_key(&f.id));
+        assert_eq!(woken.get(), 2);
+    }
+
+    #[test]
+    fn test_bad_handle() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut fs, _, _) = new_fs("/tmp/test_fs_bad_handle", Some(Rc::new(RefCell::new(MemStore::new()))));
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let g = fs.meta.mknod(1, "g", Itype::File, 0o644).unwrap();
+        let fh = fs.new_file_handle(f.id).unwrap().borrow().fh;
+
+        // never opened, and opened on another file
+        assert_eq!(fs.remove_file_handle(f.id, 1000), Err(libc::EBADF));
+        assert_eq!(fs.remove_file_handle(f.id, u64::MAX), Err(libc::EBADF));
+        assert_eq!(fs.remove_file_handle(g.id, fh), Err(libc::EBADF));
+        assert_eq!(fs.remove_dir_handle(1, fh), Err(libc::EBADF));
+
+        // the right pair still works, once
+        fs.remove_file_handle(f.id, fh).unwrap();
+        assert_eq!(fs.remove_file_handle(f.id, fh), Err(libc::EBADF));
+        // a late request on the released handle
+        assert_eq!(fs.read_file(f.id, fh, 0, 10), Err(libc::EBADF));
+        assert!(fs.find_file_handle(f.id, fh).is_none());
+        fs.free_fh(fh);
+
+        // a control file opened as another one, and released twice
+        let fh = fs.open_ctl(HEALTH_INO, libc::O_RDONLY).unwrap();
+        assert_eq!(fs.ctl_file(HANDLES_INO, fh).err(), Some(libc::EBADF));
+        assert_eq!(fs.release_ctl(HANDLES_INO, fh), Err(libc::EBADF));
+        assert!(fs.ctl_file(HEALTH_INO, fh).is_ok());
+        fs.release_ctl(HEALTH_INO, fh).unwrap();
+        assert_eq!(fs.ctl_file(HEALTH_INO, fh).err(), Some(libc::EBADF));
+        assert_eq!(fs.release_ctl(HEALTH_INO, fh), Err(libc::EBADF));
+        assert_eq!(fs.health().open_handles, 0);
+    }
+
+    #[test]
+    fn test_create_existing() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut fs, _, _) = new_fs(
+            "/tmp/test_fs_create_existing",
+            Some(Rc::new(RefCell::new(MemStore::new()))),
+        );
+        let f = fs.create_file(1, "f", 0o644, 0).unwrap();
+        fs.meta.mknod(1, "d", Itype::Dir, 0o755).unwrap();
+        fs.meta.symlink(1, "l", b"f").unwrap();
+
+        // raced with another create, the file is opened unless `O_EXCL`
+        assert_eq!(fs.create_file(1, "f", 0o644, 0).unwrap().id, f.id);
+        assert_eq!(fs.create_file(1, "f", 0o644, libc::O_EXCL).err(), Some(libc::EEXIST));
+        // never opened as a file
+        assert_eq!(fs.create_file(1, "d", 0o644, 0).err(), Some(EISDIR));
+        assert_eq!(fs.create_file(1, "l", 0o644, 0).err(), Some(libc::ELOOP));
+        assert_eq!(fs.create_file(1, "l", 0o644, libc::O_TRUNC).err(), Some(libc::ELOOP));
+        assert_eq!(fs.meta.lookup(1, "l").unwrap().kind, Itype::Symlink);
+    }
+
+    #[test]
+    fn test_open_trunc() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut fs, _, _) = new_fs("/tmp/test_fs_open_trunc", Some(Rc::new(RefCell::new(MemStore::new()))));
+        fs.hmap = handle_map(2);
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let h = fs.open_file(f.id, libc::O_WRONLY).unwrap();
+        assert_eq!(fs.write_file(f.id, &h, 0, b"hello"), 5);
+        h.borrow_mut().flush(&mut fs.meta);
+
+        // no handle left, the file isn't cut
+        assert_eq!(fs.open_file(f.id, libc::O_TRUNC).err(), Some(libc::ENFILE));
+        assert_eq!(fs.meta.get_inode(f.id).unwrap().length, 5);
+        let fh = h.borrow().fh;
+        fs.remove_file_handle(f.id, fh).unwrap();
+
+        // the truncate fails, the handle is given back
+        fault::arm(Fault::StoreInode, 0);
+        assert_eq!(fs.open_file(f.id, libc::O_TRUNC).err(), Some(libc::EIO));
+        fault::disarm(Fault::StoreInode);
+        assert!(!fs.hmap.test(fh));
+
+        let h = fs.open_file(f.id, libc::O_TRUNC | libc::O_APPEND).unwrap();
+        assert!(h.borrow().append);
+        assert_eq!(fs.meta.get_inode(f.id).unwrap().length, 0);
+        let fh = h.borrow().fh;
+        fs.remove_file_handle(f.id, fh).unwrap();
+    }
+
+    #[test]
+    fn test_handle_limit() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut fs, _, _) = new_fs(
+            "/tmp/test_fs_handle_limit",
+            Some(Rc::new(RefCell::new(MemStore::new()))),
+        );
+        fs.hmap = handle_map(4);
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let fhs: Vec<u64> = (0..3).map(|_| fs.new_file_handle(f.id).unwrap().borrow().fh).collect();
+        assert_eq!(fhs, vec![1, 2, 3]);
+
+        // the table is full for files, directories and control files alike, which `open` answers with `ENFILE`
+        assert!(fs.new_file_handle(f.id).is_none());
+        assert!(fs.new_dir_handle(1).is_none());
+        assert_eq!(fs.open_ctl(HEALTH_INO, libc::O_RDONLY), Err(libc::ENFILE));
+        assert!(fs.hmap_warned);
+
+        // a handle released is opened again
+        fs.remove_file_handle(f.id, fhs[1]).unwrap();
+        assert!(!fs.hmap_warned);
+        let h = fs.new_file_handle(f.id).unwrap();
+        assert_eq!(h.borrow().fh, fhs[1]);
+        assert!(fs.new_file_handle(f.id).is_none());
+        for fh in [fhs[0], fhs[1], fhs[2]] {
+            fs.remove_file_handle(f.id, fh).unwrap();
+        }
+        let d = fs.new_dir_handle(1).unwrap().borrow().fh;
+        fs.remove_dir_handle(1, d).unwrap();
+    }
+
+    #[test]
+    fn test_zero_length_io() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let store = Rc::new(RefCell::new(MemStore::new()));
+        let (mut fs, _, _) = new_fs("/tmp/test_fs_zero_length_io", Some(store.clone()));
+        fs.file_cache = FileCache::new(1 << 20);
+        let mut f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+        let fh = h.borrow().fh;
+        assert_eq!(fs.write_file(f.id, &h, 0, b"hello"), 5);
+        h.borrow_mut().flush(&mut fs.meta);
+        // back in time, so a change is seen within the same second
+        f = fs.meta.get_inode(f.id).unwrap();
+        f.mtime -= 100;
+        f.ctime -= 100;
+        fs.meta.store_inode(&f).unwrap();
+
+        // past the end too, it neither grows the file nor buffers anything
+        assert_eq!(fs.write_file(f.id, &h, 0, &[]), 0);
+        assert_eq!(fs.write_file(f.id, &h, 1 << 20, &[]), 0);
+        assert_eq!(h.borrow().dirty(), 0);
+        h.borrow_mut().flush(&mut fs.meta);
+        let g = fs.meta.get_inode(f.id).unwrap();
+        assert_eq!((g.length, g.mtime, g.ctime), (5, f.mtime, f.ctime));
+        assert!(!store.borrow().exists(f.id, 1));
+
+        // it isn't read into the file cache either, and a released handle is still refused
+        assert_eq!(fs.read_file(f.id, fh, 0, 0), Ok(Vec::new()));
+        assert_eq!(fs.read_file(f.id, fh, 1 << 20, 0), Ok(Vec::new()));
+        assert!(fs.file_cache.get(f.id).is_none());
+        assert_eq!(fs.read_file(f.id, fh, 0, 5), Ok(b"hello".to_vec()));
+        fs.remove_file_handle(f.id, fh).unwrap();
+        assert_eq!(fs.read_file(f.id, fh, 0, 0), Err(libc::EBADF));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = "/tmp/test_fs_snapshot";
+        let store = Rc::new(RefCell::new(MemStore::new()));
+        let (mut fs, _, _) = new_fs(path, Some(store.clone()));
+        let snap = format!("{}/snap", path);
+        let d = fs.meta.mknod(1, "d", Itype::Dir, 0o755).unwrap();
+        let f = fs.meta.mknod(d.id, "f", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+        assert_eq!(fs.write_file(f.id, &h, 0, b"before"), 6);
+        h.borrow_mut().flush(&mut fs.meta);
+        fs.meta.set_xattr(f.id, "user.k", b"v", 0).unwrap();
+        let atime = epoch_secs() + 1000;
+        fs.meta.touch_atime(f.id, atime);
+
+        let n = fs.meta.snapshot(&snap, false).unwrap();
+        assert!(fs.meta.snapshot(&snap, false).unwrap_err().contains("--force"));
+        assert_eq!(fs.meta.snapshot(&snap, true).unwrap(), n);
+        // what's done afterwards is not in the snapshot
+        fs.meta.mknod(1, "after", Itype::File, 0o644).unwrap();
+        fs.meta.rename(d.id, "f", 1, "g", 0).unwrap();
+        drop(fs);
+
+        let mut cfg = Config::parse(vec![snap.clone(), format!("{}/mnt", path)]).unwrap();
+        cfg.read_only = true;
+        let mut fs = Fs::with_store(&cfg, store.clone()).unwrap();
+        assert!(fs.meta.check().is_empty());
+        assert!(fs.meta.lookup(1, "after").is_none());
+        assert!(fs.meta.lookup(1, "g").is_none());
+        let g = fs.meta.lookup(d.id, "f").unwrap();
+        assert_eq!(g.atime, atime);
+        assert_eq!(fs.meta.get_xattr(f.id, "user.k").unwrap(), b"v");
+        let h = fs.new_file_handle(f.id).unwrap();
+        assert_eq!(h.borrow_mut().read(&mut fs.meta, 0, 6).unwrap(), b"before");
+        let attr = to_attr(&g);
+        assert_eq!(fs.check_access(&attr, 0, 0, libc::W_OK), Err(libc::EROFS));
+    }
+
+    #[test]
+    fn test_read_only_unchanged() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let store = Rc::new(RefCell::new(MemStore::new()));
+        let (mut fs, meta, _) = new_fs("/tmp/test_fs_read_only_unchanged", Some(store.clone()));
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+        assert_eq!(fs.write_file(f.id, &h, 0, b"hello"), 5);
+        let fh = h.borrow().fh;
+        fs.remove_file_handle(f.id, fh).unwrap();
+        drop(fs);
+        let dump = || {
+            let db = sled::open(&meta).unwrap();
+            db.iter().map(|x| x.unwrap()).collect::<Vec<_>>()
+        };
+        let before = dump();
+
+        let mut cfg = Config::parse(vec![meta.clone(), "/tmp/test_fs_read_only_unchanged/mnt".to_string()]).unwrap();
+        cfg.read_only = true;
+        cfg.atime = Atime::Strict;
+        let mut fs = Fs::with_store(&cfg, store.clone()).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+        let fh = h.borrow().fh;
+        assert_eq!(fs.read_file(f.id, fh, 0, 100), Ok(b"hello".to_vec()));
+        fs.update_atime(f.id);
+        // even if it's asked for
+        fs.meta.touch_atime(f.id, epoch_secs() + 1000);
+        fs.sync_file(f.id, false).unwrap();
+        fs.remove_file_handle(f.id, fh).unwrap();
+        drop(fs);
+        assert_eq!(dump(), before);
+    }
+
+    #[test]
+    fn test_reap_orphans() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let store = Rc::new(RefCell::new(MemStore::new()));
+        let (mut fs, meta, _) = new_fs("/tmp/test_fs_reap_orphans", Some(store.clone()));
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let keep = fs.meta.mknod(1, "keep", Itype::File, 0o644).unwrap();
+        for ino in [f.id, keep.id] {
+            let h = fs.new_file_handle(ino).unwrap();
+            assert_eq!(fs.write_file(ino, &h, 0, b"data"), 4);
+            h.borrow_mut().flush(&mut fs.meta);
+        }
+        assert!(store.borrow().exists(f.id, 0));
+        // the process died after the entry was removed, before the meta store was closed
+        fs.meta.orphan(1, "f").unwrap();
+        fault::arm(Fault::Flush, 0);
+        drop(fs);
+        fault::disarm(Fault::Flush);
+
+        let mut cfg = Config::parse(vec![meta.clone(), "/tmp/test_fs_reap_orphans/mnt".to_string()]).unwrap();
+        cfg.read_only = true;
+        let fs = Fs::with_store(&cfg, store.clone()).unwrap();
+        assert!(fs.meta.unclean());
+        assert!(fs.meta.get_inode(f.id).is_ok());
+        drop(fs);
+
+        cfg.read_only = false;
+        let fs = Fs::with_store(&cfg, store.clone()).unwrap();
+        assert!(fs.meta.get_inode(f.id).is_err());
+        assert!(!store.borrow().exists(f.id, 0));
+        assert!(store.borrow().exists(keep.id, 0));
+        assert!(fs.meta.orphans().unwrap().is_empty());
+        drop(fs);
+
+        // entries aren't scanned after a clean unmount, an orphan is then left to `fsck --repair`
+        let mut fs = Fs::with_store(&cfg, store.clone()).unwrap();
+        assert!(!fs.meta.unclean());
+        let g = fs.meta.mknod(1, "g", Itype::File, 0o644).unwrap();
+        fs.meta.orphan(1, "g").unwrap();
+        drop(fs);
+        let fs = Fs::with_store(&cfg, store.clone()).unwrap();
+        assert!(fs.meta.get_inode(g.id).is_ok());
+        drop(fs);
+        let mut m = Meta::load_fs(meta.clone(), false).unwrap();
+        assert!(!m.unclean());
+        m.mark_unclean().unwrap();
+        drop(m);
+        let fs = Fs::with_store(&cfg, store.clone()).unwrap();
+        assert!(fs.meta.get_inode(g.id).is_err());
+    }
+
+    /// a `MemStore` whose writes fail while `fail` is set
+    struct FlakyStore {
+        inner: MemStore,
+        fail: Rc<Cell<bool>>,
+    }
+
+    impl DataStore for FlakyStore {
+        fn write(&mut self, ino: Ino, group: &[Entry]) -> std::io::Result<()> {
+            if self.fail.get() {
+                return Err(std::io::Error::from_raw_os_error(libc::EIO));
+            }
+            self.inner.write(ino, group)
+        }
+
+        fn read(&mut self, ino: Ino, blk: u64, off: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.inner.read(ino, blk, off, buf)
+        }
+
+        fn set_len(&mut self, ino: Ino, blk: u64, len: u64) -> std::io::Result<()> {
+            self.inner.set_len(ino, blk, len)
+        }
+
+        fn fsync(&mut self, ino: Ino, datasync: bool) -> std::io::Result<()> {
+            self.inner.fsync(ino, datasync)
+        }
+
+        fn unlink(&mut self, ino: Ino, blocks: u64) {
+            self.inner.unlink(ino, blocks)
+        }
+
+        fn exists(&self, ino: Ino, blk: u64) -> bool {
+            self.inner.exists(ino, blk)
+        }
+    }
+
+    #[test]
+    fn test_sync_error() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let fail = Rc::new(Cell::new(false));
+        let store = FlakyStore {
+            inner: MemStore::new(),
+            fail: fail.clone(),
+        };
+        let (mut fs, _, _) = new_fs("/tmp/test_fs_sync_error", Some(Rc::new(RefCell::new(store))));
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+        assert_eq!(fs.write_file(f.id, &h, 0, b"hello"), 5);
+        assert_eq!(fs.sync_file(f.id, false), Ok(()));
+
+        // the write back fails, fsync reports it once, so does fsyncdir
+        fail.set(true);
+        assert_eq!(fs.write_file(f.id, &h, 5, b" world"), 6);
+        assert_eq!(fs.sync_file(f.id, false), Err(libc::EIO));
+        assert_eq!(fs.sync_file(f.id, true), Ok(()));
+        assert!(fs.take_sync_error());
+        assert!(!fs.take_sync_error());
+
+        // failed on close, the error is kept for the next fsync through another handle
+        assert_eq!(fs.write_file(f.id, &h, 5, b" world"), 6);
+        let other = fs.new_file_handle(f.id).unwrap();
+        let fh = h.borrow().fh;
+        fs.remove_file_handle(f.id, fh).unwrap();
+        fail.set(false);
+        assert_eq!(fs.sync_file(f.id, false), Err(libc::EIO));
+        assert_eq!(fs.sync_file(f.id, false), Ok(()));
+        assert!(fs.take_sync_error());
+        assert_eq!(fs.meta.get_inode(f.id).unwrap().length, 5);
+
+        // close reports it, fsync does again
+        fail.set(true);
+        assert_eq!(fs.write_file(f.id, &other, 5, b" world"), 6);
+        assert_eq!(fs.close_file(f.id, &other), Err(libc::EIO));
+        assert_eq!(fs.sync_file(f.id, false), Err(libc::EIO));
+        assert_eq!(fs.close_file(f.id, &other), Ok(()));
+        // synced on close, it's reported once
+        fs.cfg.sync_on_close = true;
+        assert_eq!(fs.write_file(f.id, &other, 5, b" world"), 6);
+        assert_eq!(fs.close_file(f.id, &other), Err(libc::EIO));
+        fail.set(false);
+        assert_eq!(fs.close_file(f.id, &other), Ok(()));
+        let fh = other.borrow().fh;
+        fs.remove_file_handle(f.id, fh).unwrap();
+    }
+
+    #[test]
+    fn test_unlink_open() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let store = Rc::new(RefCell::new(MemStore::new()));
+        let (mut fs, meta, _) = new_fs("/tmp/test_fs_unlink_open", Some(store.clone()));
+        let pages = MemPool::get().used();
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        fs.remember(f.id);
+        let h = fs.new_file_handle(f.id).unwrap();
+        let fh = h.borrow().fh;
+        assert_eq!(fs.write_file(f.id, &h, 0, b"hello"), 5);
+
+        // what `unlink` does, the open file keeps its inode and data
+        let inode = fs.meta.unlink(1, "f").unwrap();
+        fs.drop_data(&inode);
+        assert!(fs.meta.lookup(1, "f").is_none());
+        assert_eq!(fs.meta.get_inode(f.id).unwrap().links, 0);
+        assert_eq!(fs.read_file(f.id, fh, 0, 100), Ok(b"hello".to_vec()));
+        assert_eq!(fs.write_file(f.id, &h, 5, b" world"), 6);
+        // buffered
+        assert!(MemPool::get().used() > pages);
+
+        // closed, the kernel still knows the ino
+        fs.remove_file_handle(f.id, fh).unwrap();
+        assert_eq!(MemPool::get().used(), pages);
+        assert!(fs.meta.get_inode(f.id).is_ok());
+        assert!(store.borrow().exists(f.id, 0));
+
+        // forgotten, it's gone with its data, and the fh is free
+        fs.forget_ino(f.id, 1);
+        assert!(fs.meta.get_inode(f.id).is_err());
+        assert!(!store.borrow().exists(f.id, 0));
+        assert!(fs.unlinked.is_empty());
+        assert!(!fs.hmap.test(fh));
+
+        // removed by rename while open, and not closed before the unmount
+        let a = fs.meta.mknod(1, "a", Itype::File, 0o644).unwrap();
+        let b = fs.meta.mknod(1, "b", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(b.id).unwrap();
+        assert_eq!(fs.write_file(b.id, &h, 0, b"b"), 1);
+        let replaced = fs.meta.rename(1, "a", 1, "b", 0).unwrap().unwrap();
+        fs.drop_data(&replaced);
+        assert_eq!(fs.meta.lookup(1, "b").unwrap().id, a.id);
+        assert!(fs.unlinked.contains(&b.id));
+        drop(fs);
+        let m = Meta::load_fs(meta, false).unwrap();
+        assert!(m.get_inode(b.id).is_err());
+        assert!(!store.borrow().exists(b.id, 0));
+        assert!(m.orphans().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_forget() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut fs, _, _) = new_fs("/tmp/test_fs_forget", Some(Rc::new(RefCell::new(MemStore::new()))));
+        fs.file_cache = FileCache::new(1 << 20);
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        for _ in 0..3 {
+            fs.remember(f.id);
+        }
+        fs.remember(1);
+        fs.remember(HEALTH_INO);
+        assert_eq!(fs.lookups.len(), 1);
+        let h = fs.new_file_handle(f.id).unwrap();
+        let fh = h.borrow().fh;
+        assert_eq!(fs.write_file(f.id, &h, 0, b"hello"), 5);
+        assert_eq!(fs.read_file(f.id, fh, 0, 5), Ok(b"hello".to_vec()));
+        assert!(fs.file_cache.get(f.id).is_some());
+
+        fs.forget_ino(f.id, 2);
+        assert_eq!(fs.lookups[&f.id], 1);
+        // an ino never replied is ignored, so is forgetting more than was looked up
+        fs.forget_ino(f.id + 1, 1);
+        assert!(!fs.lookups.contains_key(&(f.id + 1)));
+
+        // it's still open, so the cache stays
+        fs.forget_ino(f.id, 5);
+        assert!(fs.lookups.is_empty());
+        assert!(fs.file_cache.get(f.id).is_some());
+
+        fs.remember(f.id);
+        fs.remove_file_handle(f.id, fh).unwrap();
+        fs.forget_ino(f.id, 1);
+        assert!(fs.file_cache.get(f.id).is_none());
+    }
+
+    #[test]
+    fn test_file_cache() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        // not `FileStore`, its fds are cached process wide by ino, which other tests use too
+        let path = "/tmp/test_fs_file_cache";
+        let store = Rc::new(RefCell::new(PackedStore::new(&format!("{}/data", path))));
+        let (mut fs, _, _) = new_fs(path, Some(store));
+        fs.file_cache = FileCache::new(1 << 20);
+        let f = fs.meta.mknod(1, "conf", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+        let fh = h.borrow().fh;
+        h.borrow_mut().write(&mut fs.meta, 0, b"key = value\n");
+
+        let before = stats::snapshot();
+        assert_eq!(fs.read_file(f.id, fh, 4, 5).unwrap(), b"= val");
+        let s = stats::snapshot();
+        assert!(s.pread_calls > before.pread_calls);
+        assert_eq!(s.file_cache_hits, before.file_cache_hits);
+
+        let before = stats::snapshot();
+        assert_eq!(fs.read_file(f.id, fh, 0, 100).unwrap(), b"key = value\n");
+        assert_eq!(fs.read_file(f.id, fh, 100, 10).unwrap(), b"");
+        let s = stats::snapshot();
+        assert_eq!(s.pread_calls, before.pread_calls);
+        assert_eq!(s.file_cache_hits - before.file_cache_hits, 2);
+
+        // the cached copy is dropped with the data
+        fs.truncate(f.id).unwrap();
+        assert_eq!(fs.read_file(f.id, fh, 0, 100).unwrap(), b"");
+        assert_eq!(fs.file_cache.bytes(), 0);
+    }
+
+    #[test]
+    fn test_panic_flush() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut fs, meta, _) = new_fs("/tmp/test_fs_panic_flush", Some(Rc::new(RefCell::new(MemStore::new()))));
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+        h.borrow_mut().write(&mut fs.meta, 0, b"hello");
+        drop(h);
+
+        // `fs` is owned by the closure like by the session loop, so it's dropped while unwinding
+        fault::arm(Fault::Panic, 0);
+        let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+            let _fs = fs;
+            let _span = crash::enter("write", f.id);
+        }));
+        fault::disarm(Fault::Panic);
+        assert!(r.is_err());
+        assert_eq!(crash::current(), Some(("write", f.id)));
+
+        // the buffered write made it to meta
+        let mut m = Meta::load_fs(meta, true).unwrap();
+        assert_eq!(m.lookup(1, "f").unwrap().length, 5);
+    }
+
+    #[test]
+    fn test_access() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut fs, _, _) = new_fs("/tmp/test_fs_access", Some(Rc::new(RefCell::new(MemStore::new()))));
+        let bin = to_attr(&fs.meta.mknod(1, "bin", Itype::File, 0o6755).unwrap());
+        let dir = to_attr(&fs.meta.mknod(1, "dir", Itype::Dir, 0o755).unwrap());
+        let (uid, gid) = (bin.uid, bin.gid);
+        assert_eq!(fs.effective_mode(&bin), 0o6755);
+        fs.check_access(&bin, uid, gid, libc::R_OK | libc::W_OK | libc::X_OK)
+            .unwrap();
+        assert_eq!(fs.check_access(&bin, uid + 1, gid + 1, libc::W_OK), Err(libc::EACCES));
+        fs.check_access(&bin, uid + 1, gid + 1, libc::R_OK | libc::X_OK)
+            .unwrap();
+        fs.check_access(&bin, 0, 0, libc::W_OK | libc::X_OK).unwrap();
+        // searched by root whatever its mode, a file isn't executed by root unless anyone may
+        let locked = to_attr(&fs.meta.mknod(1, "locked", Itype::Dir, 0o600).unwrap());
+        fs.check_access(&locked, 0, 0, libc::R_OK | libc::W_OK | libc::X_OK)
+            .unwrap();
+        assert_eq!(
+            fs.check_access(&locked, uid + 1, gid + 1, libc::X_OK),
+            Err(libc::EACCES)
+        );
+        let plain = to_attr(&fs.meta.mknod(1, "plain", Itype::File, 0o600).unwrap());
+        assert_eq!(fs.check_access(&plain, 0, 0, libc::X_OK), Err(libc::EACCES));
+
+        fs.cfg.nosuid = true;
+        assert_eq!(fs.effective_mode(&bin), 0o755);
+        fs.check_access(&bin, uid, gid, libc::X_OK).unwrap();
+
+        // nothing is executable, even by root, a directory can still be searched
+        fs.cfg.noexec = true;
+        assert_eq!(fs.effective_mode(&bin), 0o644);
+        assert_eq!(fs.check_access(&bin, uid, gid, libc::X_OK), Err(libc::EACCES));
+        assert_eq!(fs.check_access(&bin, 0, 0, libc::X_OK), Err(libc::EACCES));
+        fs.check_access(&bin, uid, gid, libc::R_OK | libc::W_OK).unwrap();
+        fs.check_access(&dir, uid + 1, gid + 1, libc::X_OK).unwrap();
+        fs.check_access(&bin, uid, gid, libc::F_OK).unwrap();
+
+        fs.cfg.read_only = true;
+        assert_eq!(fs.check_access(&bin, 0, 0, libc::W_OK), Err(libc::EROFS));
+        let stored = fs.meta.get_inode(bin.ino).unwrap();
+        assert_eq!(stored.mode, 0o6755);
+    }
+
+    #[test]
+    fn test_resize() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut fs, _, _) = new_fs("/tmp/test_fs_resize", Some(Rc::new(RefCell::new(MemStore::new()))));
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let g = fs.meta.mknod(1, "g", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+        h.borrow_mut().write(&mut fs.meta, 0, b"hello world");
+        fs.reflink(f.id, g.id).unwrap();
+
+        // cutting the copy leaves the shared data alone
+        fs.resize(g.id, 5).unwrap();
+        assert_eq!(fs.meta.get_inode(g.id).unwrap().length, 5);
+        let c = fs.new_file_handle(g.id).unwrap();
+        assert_eq!(c.borrow_mut().read(&mut fs.meta, 0, 100).unwrap(), b"hello");
+        assert_eq!(h.borrow_mut().read(&mut fs.meta, 0, 100).unwrap(), b"hello world");
+
+        // grown back, the cut off part reads as zeros
+        fs.resize(g.id, 8).unwrap();
+        assert_eq!(c.borrow_mut().read(&mut fs.meta, 0, 100).unwrap(), b"hello\0\0\0");
+        fs.cfg.prealloc = true;
+        fs.resize(f.id, 2 * FS_BLK_SIZE + 1).unwrap();
+        assert_eq!(fs.meta.get_inode(f.id).unwrap().length, 2 * FS_BLK_SIZE + 1);
+        assert_eq!(h.borrow_mut().read(&mut fs.meta, FS_BLK_SIZE - 2, 2).unwrap(), b"\0\0");
+        assert_eq!(h.borrow_mut().read(&mut fs.meta, 2 * FS_BLK_SIZE, 10).unwrap(), b"\0");
+        assert_eq!(fs.resize(1, 0), Err(EISDIR));
+
+        // a symlink is neither cut nor truncated, its target stays
+        let l = fs.meta.symlink(1, "l", b"f").unwrap();
+        assert_eq!(fs.resize(l.id, 0), Err(EINVAL));
+        assert_eq!(fs.truncate(l.id), Err(EINVAL));
+        assert_eq!(fs.meta.readlink(l.id).unwrap(), b"f");
+        assert_eq!(fs.meta.get_inode(l.id).unwrap().length, 1);
+    }
+
+    #[test]
+    fn test_clear_dir() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let store = Rc::new(RefCell::new(MemStore::new()));
+        let (mut fs, _, _) = new_fs("/tmp/test_fs_clear_dir", Some(store.clone()));
+        let dir = fs.meta.mknod(1, "dir", Itype::Dir, 0o755).unwrap();
+        let sub = fs.meta.mknod(dir.id, "sub", Itype::Dir, 0o755).unwrap();
+        let mut files = Vec::new();
+        for (parent, name) in [(dir.id, "a"), (dir.id, "b"), (sub.id, "c")] {
+            let f = fs.meta.mknod(parent, name, Itype::File, 0o644).unwrap();
+            let h = fs.new_file_handle(f.id).unwrap();
+            h.borrow_mut().write(&mut fs.meta, 0, b"junk");
+            let fh = h.borrow().fh;
+            fs.remove_file_handle(f.id, fh).unwrap();
+            files.push(f.id);
+        }
+        assert!(files.iter().all(|&f| store.borrow().exists(f, 0)));
+
+        let me = unsafe { libc::getuid() };
+        assert_eq!(fs.clear_dir(dir.id, me.wrapping_add(1)), Err(libc::EPERM));
+        assert_eq!(fs.clear_dir(CTL_DIR_INO, 0), Err(libc::EPERM));
+        assert_eq!(fs.clear_dir(files[0], me), Err(libc::ENOTDIR));
+        fs.cfg.read_only = true;
+        assert_eq!(fs.clear_dir(dir.id, me), Err(libc::EROFS));
+        fs.cfg.read_only = false;
+
+        // a command junkfs doesn't know fails at once, e.g. `FS_IOC_GETFLAGS` of `lsattr`
+        assert_eq!(fs.ioctl_cmd(files[0], 0x80086601, &[], me), Err(libc::ENOTTY));
+        assert_eq!(fs.ioctl_cmd(dir.id, 0x4a7f, &[], me), Err(libc::ENOTTY));
+        assert!(fs.meta.lookup(dir.id, "sub").is_some());
+        fs.ioctl_cmd(dir.id, control::CLEAR_DIR, &[], me).unwrap();
+        assert!(fs.meta.lookup(1, "dir").is_some());
+        assert!(fs.meta.lookup(dir.id, "sub").is_none());
+        assert!(files.iter().all(|&f| !store.borrow().exists(f, 0)));
+        fs.meta.rmdir(1, "dir").unwrap();
+    }
+
+    #[test]
+    fn test_seek_file() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        // the default block layout, a `FileStore`
+        let (mut fs, _, _) = new_fs("/tmp/test_fs_seek_file", None);
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+        let fh = h.borrow().fh;
+        // blocks 1 and 3 hold data, still buffered when seeking
+        assert_eq!(fs.write_file(f.id, &h, FS_BLK_SIZE, b"one"), 3);
+        assert_eq!(fs.write_file(f.id, &h, 3 * FS_BLK_SIZE, b"three"), 5);
+        let len = 3 * FS_BLK_SIZE + 5;
+        let mut seek = |off: u64, whence| fs.seek_file(f.id, fh, off as i64, whence);
+
+        assert_eq!(seek(0, libc::SEEK_DATA), Ok(FS_BLK_SIZE));
+        assert_eq!(seek(FS_BLK_SIZE + 1, libc::SEEK_DATA), Ok(FS_BLK_SIZE + 1));
+        assert_eq!(seek(2 * FS_BLK_SIZE, libc::SEEK_DATA), Ok(3 * FS_BLK_SIZE));
+        assert_eq!(seek(0, libc::SEEK_HOLE), Ok(0));
+        assert_eq!(seek(FS_BLK_SIZE, libc::SEEK_HOLE), Ok(2 * FS_BLK_SIZE));
+        assert_eq!(seek(3 * FS_BLK_SIZE, libc::SEEK_HOLE), Ok(len));
+        assert_eq!(seek(len, libc::SEEK_DATA), Err(libc::ENXIO));
+        assert_eq!(seek(len, libc::SEEK_HOLE), Err(libc::ENXIO));
+        assert_eq!(fs.seek_file(f.id, fh, -1, libc::SEEK_DATA), Err(libc::ENXIO));
+        assert_eq!(fs.seek_file(f.id, fh + 1, 0, libc::SEEK_DATA), Err(libc::EBADF));
+        fs.remove_file_handle(f.id, fh).unwrap();
+    }
+
+    #[test]
+    fn test_allocate() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = "/tmp/test_fs_allocate";
+        let store = Rc::new(RefCell::new(PackedStore::new(&format!("{}/data", path))));
+        let (mut fs, _, _) = new_fs(path, Some(store));
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+        let read = |fs: &mut Fs, off: u64, size: usize| h.borrow_mut().read(&mut fs.meta, off, size).unwrap();
+        let len = |fs: &mut Fs| fs.meta.get_inode(f.id).unwrap().length;
+        let (keep, zero) = (libc::FALLOC_FL_KEEP_SIZE, libc::FALLOC_FL_ZERO_RANGE);
+        h.borrow_mut().write(&mut fs.meta, 0, &[0xff; 100]);
+
+        // plain allocation keeps the content and grows the file, with KEEP_SIZE it grows nothing
+        fs.allocate(f.id, 50, 100, 0).unwrap();
+        assert_eq!(len(&mut fs), 150);
+        assert_eq!(read(&mut fs, 0, 200), [[0xff; 100], [0; 100]].concat()[..150]);
+        fs.allocate(f.id, 100, 1000, keep).unwrap();
+        assert_eq!(len(&mut fs), 150);
+        assert_eq!(read(&mut fs, 0, 100), [0xff; 100]);
+
+        // zeroing inside the content, the size stays
+        fs.allocate(f.id, 10, 20, zero).unwrap();
+        assert_eq!(len(&mut fs), 150);
+        let buf = read(&mut fs, 0, 150);
+        assert_eq!(&buf[..10], &[0xff; 10]);
+        assert_eq!(&buf[10..30], &[0; 20]);
+        assert_eq!(&buf[30..100], &[0xff; 70]);
+
+        // zeroing across the end grows the file unless KEEP_SIZE, and spans blocks
+        fs.allocate(f.id, 90, 100, zero | keep).unwrap();
+        assert_eq!(len(&mut fs), 150);
+        assert_eq!(read(&mut fs, 80, 20), [[0xff; 10], [0; 10]].concat());
+        fs.allocate(f.id, 95, FS_BLK_SIZE, zero).unwrap();
+        assert_eq!(len(&mut fs), FS_BLK_SIZE + 95);
+        assert_eq!(read(&mut fs, 80, 20), [[0xff; 10], [0; 10]].concat());
+        assert_eq!(read(&mut fs, FS_BLK_SIZE - 5, 5), [0; 5]);
+        assert_eq!(read(&mut fs, FS_BLK_SIZE, 200), [0; 95]);
+        assert!(fs.meta.has_data(f.id, 1));
+
+        // the rest is refused
+        assert_eq!(
+            fs.allocate(f.id, 0, 10, libc::FALLOC_FL_PUNCH_HOLE | keep),
+            Err(libc::EOPNOTSUPP)
+        );
+        assert_eq!(fs.allocate(f.id, 0, 0, 0), Err(EINVAL));
+        assert_eq!(fs.allocate(f.id, u64::MAX, 2, 0), Err(libc::EFBIG));
+        assert_eq!(fs.allocate(1, 0, 10, 0), Err(EISDIR));
+        let l = fs.meta.symlink(1, "l", b"f").unwrap();
+        assert_eq!(fs.allocate(l.id, 0, 10, zero), Err(EINVAL));
+        assert_eq!(read(&mut fs, 0, 10), [0xff; 10]);
+    }
+
+    #[test]
+    fn test_resize_zero_fill() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = "/tmp/test_fs_resize_zero_fill";
+        let store = Rc::new(RefCell::new(PackedStore::new(&format!("{}/data", path))));
+        let (mut fs, _, _) = new_fs(path, Some(store));
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+        let read = |fs: &mut Fs, off: u64, size: usize| h.borrow_mut().read(&mut fs.meta, off, size).unwrap();
+
+        // shrink then grow
+        h.borrow_mut().write(&mut fs.meta, 0, &[0xff; 100]);
+        fs.resize(f.id, 10).unwrap();
+        fs.resize(f.id, 100).unwrap();
+        let buf = read(&mut fs, 0, 100);
+        assert_eq!(&buf[..10], &[0xff; 10]);
+        assert_eq!(&buf[10..], &[0u8; 90]);
+
+        // bytes past the end left in the store, in the last block and in a block after it
+        h.borrow_mut().write(&mut fs.meta, 0, &[0xee; 100]);
+        h.borrow_mut().write(&mut fs.meta, FS_BLK_SIZE + 10, &[0xee; 10]);
+        h.borrow_mut().flush(&mut fs.meta);
+        let mut inode = fs.meta.get_inode(f.id).unwrap();
+        inode.length = 50;
+        fs.meta.store_inode(&inode).unwrap();
+        fs.resize(f.id, FS_BLK_SIZE + 100).unwrap();
+        let buf = read(&mut fs, 0, 100);
+        assert_eq!(&buf[..50], &[0xee; 50]);
+        assert_eq!(&buf[50..], &[0u8; 50]);
+        assert_eq!(read(&mut fs, FS_BLK_SIZE, 100), vec![0u8; 100]);
+    }
+
+    #[test]
+    fn test_lost_block() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let store = Rc::new(RefCell::new(MemStore::new()));
+        let (mut fs, _, _) = new_fs("/tmp/test_fs_lost_block", Some(store.clone()));
+        let f = fs.meta.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let h = fs.new_file_handle(f.id).unwrap();
+
+        // the first block is a hole
+        h.borrow_mut().write(&mut fs.meta, FS_BLK_SIZE, b"hello");
+        assert_eq!(h.borrow_mut().read(&mut fs.meta, 0, 5).unwrap(), vec![0u8; 5]);
+        assert_eq!(h.borrow_mut().read(&mut fs.meta, FS_BLK_SIZE, 5).unwrap(), b"hello");
+        assert!(!fs.meta.has_data(f.id, 0));
+        assert!(fs.meta.has_data(f.id, 1));
+
+        // the written one is gone from the store
+        store.borrow_mut().unlink(f.id, 2);
+        assert_eq!(h.borrow_mut().read(&mut fs.meta, FS_BLK_SIZE, 5), Err(libc::EIO));
+        assert_eq!(h.borrow_mut().read(&mut fs.meta, 0, 5).unwrap(), vec![0u8; 5]);
+
+        // cut off, it's a hole again once the file grows back
+        fs.resize(f.id, 10).unwrap();
+        assert!(!fs.meta.has_data(f.id, 1));
+        fs.resize(f.id, FS_BLK_SIZE + 5).unwrap();
+        assert_eq!(h.borrow_mut().read(&mut fs.meta, FS_BLK_SIZE, 5).unwrap(), vec![0u8; 5]);
+    }
+
+    #[test]
+    fn test_times() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut fs, _, _) = new_fs("/tmp/test_fs_times", Some(Rc::new(RefCell::new(MemStore::new()))));
+        let d = fs.meta.mknod(1, "d", Itype::Dir, 0o755).unwrap();
+        let mut f = fs.meta.mknod(d.id, "f", Itype::File, 0o644).unwrap();
+        let old = |fs: &mut Fs, mut inode: Inode| {
+            inode.mtime = 1;
+            inode.ctime = 1;
+            fs.meta.store_inode(&inode).unwrap();
+        };
+
+        // chmod changes the inode only
+        old(&mut fs, f);
+        f = fs.set_attr(f.id, Some(0o600), None, None, None, None, None).unwrap();
+        assert_eq!(f.mtime, 1);
+        assert!(f.ctime > 1);
+
+        // write changes both
+        old(&mut fs, f);
+        let h = fs.new_file_handle(f.id).unwrap();
+        h.borrow_mut().write(&mut fs.meta, 0, b"x");
+        h.borrow_mut().flush(&mut fs.meta);
+        f = fs.meta.get_inode(f.id).unwrap();
+        assert!(f.mtime > 1 && f.ctime > 1);
+
+        // utimensat sets what's given, atime may go backward
+        fs.meta.touch_atime(f.id, epoch_secs());
+        let t = |x: u64| Some(TimeOrNow::SpecificTime(to_systime(x)));
+        f = fs.set_attr(f.id, None, None, None, t(50), t(100), None).unwrap();
+        assert_eq!((f.atime, f.mtime), (50, 100));
+        assert!(f.ctime > 100);
+        let f = fs.meta.get_inode(f.id).unwrap();
+        assert_eq!((f.atime, f.mtime), (50, 100));
+
+        // so does a new entry to its directory
+        let d = fs.meta.get_inode(d.id).unwrap();
+        old(&mut fs, d);
+        fs.meta.mknod(d.id, "g", Itype::File, 0o644).unwrap();
+        let d = fs.meta.get_inode(d.id).unwrap();
+        assert!(d.mtime > 1 && d.ctime > 1);
     }
 }
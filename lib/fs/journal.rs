@@ -0,0 +1,139 @@
+use crate::meta::Ino;
+use std::io::Write;
+
+// ino, offset, length and crc32 of a record
+const HEADER_LEN: usize = 24;
+
+fn checksum(header: &[u8], data: &[u8]) -> u32 {
+    let mut h = crc32fast::Hasher::new();
+    h.update(header);
+    h.update(data);
+    h.finalize()
+}
+
+/// writes appended before they're acknowledged, so the ones still buffered when the process dies are replayed by the
+/// next mount, see `--journal`, a record is `ino | offset | length | crc32 | data`, all little endian
+pub struct Journal {
+    file: std::fs::File,
+    len: u64,
+}
+
+impl Journal {
+    /// open the journal at `path` for appending, what it holds is dropped, so it must be replayed first
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        let file = std::fs::File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .inspect_err(|e| log::error!("can't open journal {} error {}", path, e))?;
+        let mut j = Self { file, len: 0 };
+        j.clear()?;
+        Ok(j)
+    }
+
+    /// bytes in the journal
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// record the write of `data` at `off` of `ino`, it's in the host page cache once this returns, so it survives
+    /// the process but not the host
+    pub fn append(&mut self, ino: Ino, off: u64, data: &[u8]) -> std::io::Result<()> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + data.len());
+        buf.extend_from_slice(&ino.to_le_bytes());
+        buf.extend_from_slice(&off.to_le_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        let sum = checksum(&buf, data);
+        buf.extend_from_slice(&sum.to_le_bytes());
+        buf.extend_from_slice(data);
+        if let Err(e) = self.file.write_all(&buf) {
+            // a torn record would end the replay, the ones appended after it would be lost
+            let _ = self.file.set_len(self.len);
+            return Err(e);
+        }
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+
+    /// empty the journal once what it holds is in the data store
+    pub fn clear(&mut self) -> std::io::Result<()> {
+        self.file.set_len(0)?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+/// the writes in the journal at `path` in the order they were made, a missing journal holds none, a torn or corrupt
+/// record ends it, it was never acknowledged
+pub fn records(path: &str) -> Result<Vec<(Ino, u64, Vec<u8>)>, String> {
+    let buf = match std::fs::read(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("can't read journal {}, error {}", path, e)),
+        Ok(x) => x,
+    };
+    let mut r = Vec::new();
+    let mut pos = 0;
+    while buf.len() - pos >= HEADER_LEN {
+        let header = &buf[pos..pos + HEADER_LEN];
+        let ino = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let off = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let len = u32::from_le_bytes(header[16..20].try_into().unwrap()) as usize;
+        let sum = u32::from_le_bytes(header[20..].try_into().unwrap());
+        let data = match buf.get(pos + HEADER_LEN..pos + HEADER_LEN + len) {
+            Some(x) if checksum(&header[..20], x) == sum => x,
+            _ => break,
+        };
+        r.push((ino, off, data.to_vec()));
+        pos += HEADER_LEN + len;
+    }
+    if pos != buf.len() {
+        log::warn!("journal {} ends with {} bytes of a torn record", path, buf.len() - pos);
+    }
+    Ok(r)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{records, Journal};
+
+    #[test]
+    fn test_journal() {
+        let path = "/tmp/test_journal";
+        let _ = std::fs::remove_file(path);
+        assert!(records(path).unwrap().is_empty());
+
+        let mut j = Journal::open(path).unwrap();
+        j.append(2, 100, b"hello").unwrap();
+        j.append(3, 0, b"").unwrap();
+        j.append(2, 7, b"world").unwrap();
+        let all = vec![
+            (2, 100, b"hello".to_vec()),
+            (3, 0, Vec::new()),
+            (2, 7, b"world".to_vec()),
+        ];
+        assert_eq!(records(path).unwrap(), all);
+
+        // the process died in the middle of the last record, or it's corrupt
+        let good = std::fs::read(path).unwrap();
+        std::fs::write(path, &good[..good.len() - 2]).unwrap();
+        assert_eq!(records(path).unwrap(), all[..2]);
+        let mut bad = good.clone();
+        bad[26] ^= 1;
+        std::fs::write(path, &bad).unwrap();
+        assert!(records(path).unwrap().is_empty());
+
+        // reopened, what it held is dropped
+        let mut j = Journal::open(path).unwrap();
+        assert!(records(path).unwrap().is_empty());
+        j.append(5, 1, b"x").unwrap();
+        j.clear().unwrap();
+        assert_eq!(j.len(), 0);
+        j.append(6, 2, b"y").unwrap();
+        assert_eq!(records(path).unwrap(), vec![(6, 2, b"y".to_vec())]);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,3 +1,12 @@
+mod config;
+mod control;
+mod crash;
 mod filesystem;
+mod journal;
+mod mount;
 
+pub use config::{Atime, Config};
+pub use control::{HandleKind, Health, OpenHandle, CLEAR_DIR, SET_LABEL};
+pub use crash::install_panic_hook;
 pub use filesystem::Fs;
+pub use mount::{mount, MountHandle};
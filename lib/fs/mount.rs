@@ -0,0 +1,70 @@
+use crate::fs::{Config, Fs};
+use std::thread::JoinHandle;
+
+/// a filesystem mounted by `mount`, requests are served by a background thread until it's unmounted, dropping the
+/// handle unmounts too
+pub struct MountHandle {
+    mount_point: String,
+    unmounter: fuser::SessionUnmounter,
+    thread: Option<JoinHandle<std::io::Result<()>>>,
+}
+
+/// load the filesystem of `cfg.meta_path` and mount it on `cfg.mount_point`, it returns once the mount is in place,
+/// so a program can embed junkfs without running the binary
+pub fn mount(cfg: &Config) -> Result<MountHandle, String> {
+    let fs = Fs::new(cfg)?;
+    let options = fs.mount_options();
+    let mut session = fuser::Session::new(fs, &cfg.mount_point, &options)
+        .map_err(|e| format!("can't mount {}, error {}", cfg.mount_point, e))?;
+    Fs::set_notifier(session.notifier());
+    let unmounter = session.unmount_callable();
+    let thread = std::thread::Builder::new()
+        .name("junkfs".to_string())
+        .spawn(move || session.run())
+        .map_err(|e| format!("can't start session, error {}", e))?;
+    Ok(MountHandle {
+        mount_point: cfg.mount_point.clone(),
+        unmounter,
+        thread: Some(thread),
+    })
+}
+
+impl MountHandle {
+    pub fn mount_point(&self) -> &str {
+        &self.mount_point
+    }
+
+    /// unmount and wait for the session to exit, open files and metadata are flushed before it returns
+    pub fn unmount(mut self) -> Result<(), String> {
+        self.unmounter
+            .unmount()
+            .map_err(|e| format!("can't unmount {}, error {}", self.mount_point, e))?;
+        self.wait()
+    }
+
+    /// wait for the filesystem to be unmounted by others, e.g. `fusermount -u`
+    pub fn join(mut self) -> Result<(), String> {
+        self.wait()
+    }
+
+    fn wait(&mut self) -> Result<(), String> {
+        match self.thread.take().map(|t| t.join()) {
+            None => Ok(()),
+            Some(Err(_)) => Err(format!("session of {} panicked", self.mount_point)),
+            Some(Ok(r)) => r.map_err(|e| format!("session of {} failed, error {}", self.mount_point, e)),
+        }
+    }
+}
+
+impl Drop for MountHandle {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            if let Err(e) = self.unmounter.unmount() {
+                log::error!("can't unmount {}, error {}", self.mount_point, e);
+            }
+            if let Err(e) = self.wait() {
+                log::error!("{}", e);
+            }
+        }
+    }
+}
@@ -1,6 +1,8 @@
-mod cache;
+pub mod cache;
 pub mod fs;
 pub mod logger;
 pub mod meta;
 pub mod store;
 pub mod utils;
+
+pub use fs::{mount, MountHandle};
@@ -1,8 +1,9 @@
 use log::{LevelFilter, Metadata, Record};
 use std::cell::{Cell, RefCell};
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::ptr::addr_of_mut;
+use std::str::FromStr;
 use std::sync::Mutex;
 
 thread_local! {
@@ -75,13 +76,30 @@ impl Console {
     }
 }
 
-/// NOTE: file rolling is not support at present
+/// when `max_size` is not 0, the file is rolled once it grows beyond `max_size` bytes, `path` is renamed to
+/// `path.1`, `path.1` to `path.2` and so on, at most `keep` rolled files are preserved
 struct File {
     w: std::fs::File,
+    path: PathBuf,
+    size: u64,
+    max_size: u64,
+    keep: usize,
 }
 
 impl File {
-    fn new(path: impl AsRef<Path>, trunc: bool) -> Result<Self, std::io::Error> {
+    fn new(path: impl AsRef<Path>, trunc: bool, max_size: u64, keep: usize) -> Result<Self, std::io::Error> {
+        let w = Self::open(path.as_ref(), trunc)?;
+        let size = w.metadata()?.len();
+        Ok(Self {
+            w,
+            path: path.as_ref().to_path_buf(),
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    fn open(path: &Path, trunc: bool) -> Result<std::fs::File, std::io::Error> {
         let mut ops = std::fs::File::options();
         ops.write(true).create(true);
         if trunc {
@@ -89,10 +107,31 @@ impl File {
         } else {
             ops.append(true);
         }
-        match ops.open(path) {
-            Err(e) => Err(e),
-            Ok(f) => Ok(Self { w: f }),
+        ops.open(path)
+    }
+
+    fn rolled_path(&self, idx: usize) -> PathBuf {
+        let mut p = self.path.clone().into_os_string();
+        p.push(format!(".{}", idx));
+        PathBuf::from(p)
+    }
+
+    fn roll(&mut self) -> Result<(), std::io::Error> {
+        self.w.flush()?;
+        if self.keep == 0 {
+            let _ = std::fs::remove_file(&self.path);
+        } else {
+            for i in (1..self.keep).rev() {
+                let from = self.rolled_path(i);
+                if from.exists() {
+                    std::fs::rename(&from, self.rolled_path(i + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rolled_path(1))?;
         }
+        self.w = Self::open(&self.path, true)?;
+        self.size = 0;
+        Ok(())
     }
 }
 
@@ -112,7 +151,13 @@ impl Sink for Console {
 
 impl Sink for File {
     fn sink(&mut self, str: &String) {
+        if self.max_size > 0 && self.size + str.len() as u64 > self.max_size {
+            if let Err(e) = self.roll() {
+                eprintln!("can't roll {}, error {}", self.path.display(), e);
+            }
+        }
         self.w.write(str.as_bytes()).unwrap();
+        self.size += str.len() as u64;
     }
 
     fn flush(&mut self) {
@@ -131,6 +176,19 @@ impl Logger {
         return Self::get();
     }
 
+    /// parse a log level like `WARN` or `debug` from the first line of `path`
+    pub fn read_level(path: impl AsRef<Path>) -> Option<LevelFilter> {
+        let s = std::fs::read_to_string(path).ok()?;
+        LevelFilter::from_str(s.lines().next()?.trim()).ok()
+    }
+
+    /// change the max level at runtime, return the new level or `None` when `path` doesn't hold a valid level
+    pub fn reload_level(path: impl AsRef<Path>) -> Option<LevelFilter> {
+        let level = Self::read_level(path)?;
+        log::set_max_level(level);
+        Some(level)
+    }
+
     pub fn get() -> &'static mut Self {
         unsafe {
             let a = addr_of_mut!(G_LOGGER);
@@ -155,8 +213,19 @@ impl Logger {
     }
 
     pub fn add_file(&mut self, path: impl AsRef<Path>, trunc: bool) -> Option<&mut Self> {
+        self.add_rolling_file(path, trunc, 0, 0)
+    }
+
+    /// same as `add_file`, but roll the file when it exceeds `max_size` bytes and keep at most `keep` old files
+    pub fn add_rolling_file(
+        &mut self,
+        path: impl AsRef<Path>,
+        trunc: bool,
+        max_size: u64,
+        keep: usize,
+    ) -> Option<&mut Self> {
         if self.exist(G_FILE).is_none() {
-            match File::new(&path, trunc) {
+            match File::new(&path, trunc, max_size, keep) {
                 Err(e) => {
                     eprintln!(
                         "can't open {}, error {}",
@@ -194,6 +263,7 @@ impl Logger {
 
 #[cfg(test)]
 mod test {
+    use crate::logger::logger::{File, Sink};
     use crate::logger::Logger;
 
     #[test]
@@ -204,4 +274,71 @@ mod test {
         let q = &*l as *const dyn log::Log;
         assert!(std::ptr::addr_eq(p, q));
     }
+
+    #[test]
+    fn test_roll() {
+        let dir = "/tmp/test_log_roll";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        let path = format!("{}/junkfs.log", dir);
+        let line = "0123456789abcdef\n".to_string();
+
+        let mut f = File::new(&path, true, 4 * line.len() as u64, 2).unwrap();
+        for _ in 0..4 {
+            f.sink(&line);
+        }
+        f.flush();
+        assert!(!std::path::Path::new(&format!("{}.1", path)).exists());
+
+        // the 5th line exceeds the limit
+        f.sink(&line);
+        f.flush();
+        let old = std::fs::read_to_string(format!("{}.1", path)).unwrap();
+        assert_eq!(old, line.repeat(4));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), line);
+
+        for _ in 0..8 {
+            f.sink(&line);
+        }
+        f.flush();
+        assert!(std::path::Path::new(&format!("{}.2", path)).exists());
+        assert!(!std::path::Path::new(&format!("{}.3", path)).exists());
+    }
+
+    #[test]
+    fn test_read_level() {
+        let path = "/tmp/test_log_level";
+        std::fs::write(path, "debug\n").unwrap();
+        assert_eq!(Logger::read_level(path), Some(log::LevelFilter::Debug));
+        std::fs::write(path, "ERROR").unwrap();
+        assert_eq!(Logger::read_level(path), Some(log::LevelFilter::Error));
+        std::fs::write(path, "verbose").unwrap();
+        assert_eq!(Logger::read_level(path), None);
+        let _ = std::fs::remove_file(path);
+        assert_eq!(Logger::read_level(path), None);
+    }
+
+    #[test]
+    fn test_reload_level() {
+        let path = "/tmp/test_log_reload_level";
+        let old = log::max_level();
+        std::fs::write(path, "warn").unwrap();
+        assert_eq!(Logger::reload_level(path), Some(log::LevelFilter::Warn));
+        assert_eq!(log::max_level(), log::LevelFilter::Warn);
+        // what's below the level is dropped before it reaches the logger
+        assert!(log::Level::Debug > log::max_level());
+        assert!(log::Level::Warn <= log::max_level());
+
+        std::fs::write(path, "debug").unwrap();
+        assert_eq!(Logger::reload_level(path), Some(log::LevelFilter::Debug));
+        assert!(log::Level::Debug <= log::max_level());
+        assert!(log::Level::Trace > log::max_level());
+
+        // a bad level keeps the current one
+        std::fs::write(path, "verbose").unwrap();
+        assert_eq!(Logger::reload_level(path), None);
+        assert_eq!(log::max_level(), log::LevelFilter::Debug);
+        log::set_max_level(old);
+        let _ = std::fs::remove_file(path);
+    }
 }
@@ -0,0 +1,135 @@
+use crate::meta::meta_store::{MetaIter, MetaStore};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+/// a store keeping writes in memory until `commit`, which hands them to the wrapped store in a single `apply`,
+/// reads see the pending writes, so a series of operations runs on it as on the wrapped store
+pub struct BatchStore {
+    inner: Box<dyn MetaStore>,
+    /// key => value to insert, or `None` to remove
+    pending: RefCell<BTreeMap<String, Option<Vec<u8>>>>,
+}
+
+impl BatchStore {
+    pub fn new(inner: Box<dyn MetaStore>) -> Self {
+        Self {
+            inner,
+            pending: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// write the pending keys to the wrapped store and give it back, nothing is written if it fails
+    pub fn commit(self) -> (Box<dyn MetaStore>, Result<(), String>) {
+        let ops: Vec<(String, Option<Vec<u8>>)> = self.pending.into_inner().into_iter().collect();
+        let r = if ops.is_empty() { Ok(()) } else { self.inner.apply(&ops) };
+        (self.inner, r)
+    }
+}
+
+impl MetaStore for BatchStore {
+    fn insert(&self, key: &str, val: &[u8]) -> Result<(), String> {
+        self.pending.borrow_mut().insert(key.to_string(), Some(val.to_vec()));
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        match self.pending.borrow().get(key) {
+            Some(v) => Ok(v.clone()),
+            None => self.inner.get(key),
+        }
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> MetaIter {
+        let v: Vec<Option<Vec<u8>>> = self
+            .scan_prefix_from(prefix, "", usize::MAX)
+            .into_iter()
+            .map(|(_, v)| Some(v))
+            .collect();
+        MetaIter {
+            iter: Box::new(v.into_iter()),
+        }
+    }
+
+    fn scan_prefix_from(&self, prefix: &str, start: &str, limit: usize) -> Vec<(String, Vec<u8>)> {
+        let from = if start.is_empty() { Bound::Included(prefix) } else { Bound::Excluded(start) };
+        let pending = self.pending.borrow();
+        let ops: Vec<_> = pending
+            .range::<str, _>((from, Bound::Unbounded))
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .collect();
+        // every pending key may hide a stored one, so fetch as many more, what's beyond them sorts after the result
+        let mut r: BTreeMap<String, Vec<u8>> = self
+            .inner
+            .scan_prefix_from(prefix, start, limit.saturating_add(ops.len()))
+            .into_iter()
+            .collect();
+        for (k, v) in ops {
+            match v {
+                Some(v) => r.insert(k.clone(), v.clone()),
+                None => r.remove(k),
+            };
+        }
+        r.into_iter().take(limit).collect()
+    }
+
+    fn remove(&self, key: &str) -> Result<(), String> {
+        self.pending.borrow_mut().insert(key.to_string(), None);
+        Ok(())
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool, String> {
+        match self.pending.borrow().get(key) {
+            Some(v) => Ok(v.is_some()),
+            None => self.inner.contains_key(key),
+        }
+    }
+
+    /// the pending keys are written by `commit`
+    fn flush(&self) {}
+
+    fn compact(&mut self) -> Result<(), String> {
+        Err("can't compact in a batch".to_string())
+    }
+
+    fn cache_stats(&self) -> (u64, u64) {
+        self.inner.cache_stats()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BatchStore;
+    use crate::meta::mem::MemMetaStore;
+    use crate::meta::meta_store::MetaStore;
+
+    #[test]
+    fn test_batch_store() {
+        let inner = MemMetaStore::new();
+        for k in ["a_1", "a_2", "a_3", "a_4", "b_1"] {
+            inner.insert(k, k.as_bytes()).unwrap();
+        }
+        let b = BatchStore::new(Box::new(inner));
+        b.remove("a_1").unwrap();
+        b.remove("a_2").unwrap();
+        b.insert("a_5", b"new").unwrap();
+        b.insert("a_3", b"changed").unwrap();
+
+        assert_eq!(b.get("a_1").unwrap(), None);
+        assert_eq!(b.get("a_3").unwrap(), Some(b"changed".to_vec()));
+        assert_eq!(b.get("a_4").unwrap(), Some(b"a_4".to_vec()));
+        assert!(!b.contains_key("a_2").unwrap());
+        assert!(b.contains_key("a_5").unwrap());
+        let keys = |v: Vec<(String, Vec<u8>)>| v.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+        assert_eq!(keys(b.scan_prefix_from("a_", "", 2)), ["a_3", "a_4"]);
+        assert_eq!(keys(b.scan_prefix_from("a_", "a_3", 10)), ["a_4", "a_5"]);
+        let mut it = b.scan_prefix("a_");
+        assert_eq!(it.next(), Some(b"changed".to_vec()));
+
+        let (inner, r) = b.commit();
+        r.unwrap();
+        assert_eq!(inner.get("a_1").unwrap(), None);
+        assert_eq!(inner.get("a_5").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(inner.get("b_1").unwrap(), Some(b"b_1".to_vec()));
+    }
+}
@@ -1,12 +1,14 @@
+use crate::meta::record::{self, Tag};
 use crate::meta::{Ino, MetaKV};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 #[derive(Serialize, Deserialize)]
 pub struct Dentry {
     parent: Ino,
     pub ino: Ino,
     pub name: String,
-    size: usize, // TODO: calculate total size of directory
+    size: usize, // unused, the size of a directory is kept in its inode, see `Meta::resize_dir`
 }
 
 impl Dentry {
@@ -19,16 +21,54 @@ impl Dentry {
         }
     }
 
+    pub fn parent(&self) -> Ino {
+        self.parent
+    }
+
+    /// the parent is fixed width, so a name containing `_` or digits can't be confused with another parent
     pub fn key(parent: Ino, name: &str) -> String {
-        format!("d_{}_{}", parent, name)
+        format!("d_{:016x}_{}", parent, name)
+    }
+
+    /// the `readdir` offset of entry `name`, a listing resumes right after the entry whose cookie it's given however
+    /// the directory changed, 0 starts a listing, `.` and `..` are 1 and 2, other names hash to 62 bits above them
+    pub fn cookie(name: &str) -> i64 {
+        match name {
+            "." => 1,
+            ".." => 2,
+            _ => {
+                let h = Sha256::digest(name.as_bytes());
+                (u64::from_le_bytes(h[..8].try_into().unwrap()) >> 2) as i64 + 3
+            }
+        }
+    }
+
+    /// key used before superblock version 2, only for migration
+    pub fn legacy_key(&self) -> String {
+        format!("d_{}_{}", self.parent, self.name)
+    }
+
+    /// prefix of all dentries regardless of key scheme
+    pub fn all_prefix() -> &'static str {
+        "d_"
     }
 
     pub fn val(this: &Self) -> Vec<u8> {
-        bincode::serialize(this).expect("can't serialize dentry")
+        record::seal(Tag::Dentry, this)
     }
 
     pub fn prefix(parent: Ino) -> String {
-        format!("d_{}_", parent)
+        format!("d_{:016x}_", parent)
+    }
+
+    /// key of all entries of a small directory, it doesn't start with `all_prefix`
+    pub fn inline_key(parent: Ino) -> String {
+        format!("dir_{}", parent)
+    }
+
+    /// prefix of the inline keys of all directories
+    pub fn inline_prefix() -> &'static str {
+        "dir_"
     }
 }
 
@@ -41,3 +81,23 @@ impl MetaKV for Dentry {
         Self::val(self)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::meta::dentry::Dentry;
+
+    #[test]
+    fn test_dentry_key() {
+        assert_eq!(Dentry::key(3, "5_foo"), "d_0000000000000003_5_foo");
+        assert_ne!(Dentry::key(3, "5_foo"), Dentry::key(35, "foo"));
+        assert!(!Dentry::key(12, "x").starts_with(&Dentry::prefix(1)));
+        assert!(!Dentry::key(1, "2_x").starts_with(&Dentry::prefix(12)));
+
+        assert_eq!((Dentry::cookie("."), Dentry::cookie("..")), (1, 2));
+        assert!(["a", "b", "...", ""].iter().all(|x| Dentry::cookie(x) > 2));
+        assert_ne!(Dentry::cookie("a"), Dentry::cookie("b"));
+        assert_eq!(Dentry::cookie("a"), Dentry::cookie("a"));
+        assert!(Dentry::key(1, "2_x").starts_with(&Dentry::prefix(1)));
+        assert_eq!(Dentry::new(3, 9, "5_foo").legacy_key(), "d_3_5_foo");
+    }
+}
@@ -0,0 +1,97 @@
+use thiserror::Error;
+
+/// why a metadata operation failed, a failure of the store keeps its message so it can be told from a refused
+/// request, `errno` is what the kernel is replied with
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum MetaError {
+    #[error("no such entry")]
+    NotFound,
+    #[error("entry exists")]
+    Exists,
+    #[error("not a directory")]
+    NotDir,
+    #[error("is a directory")]
+    IsDir,
+    #[error("directory not empty")]
+    NotEmpty,
+    #[error("entry is busy")]
+    Busy,
+    #[error("invalid argument")]
+    Invalid,
+    #[error("too many links")]
+    TooManyLinks,
+    #[error("directory is full")]
+    DirFull,
+    #[error("no free inode")]
+    NoInode,
+    #[error("name too long")]
+    NameTooLong,
+    #[error("no such attribute")]
+    NoAttr,
+    #[error("attribute name out of range")]
+    AttrName,
+    #[error("attribute value too big")]
+    AttrValue,
+    #[error("corrupt {0}")]
+    Corrupt(String),
+    #[error("store error, {0}")]
+    Store(String),
+}
+
+impl MetaError {
+    pub fn errno(&self) -> libc::c_int {
+        match self {
+            MetaError::NotFound => libc::ENOENT,
+            MetaError::Exists => libc::EEXIST,
+            MetaError::NotDir => libc::ENOTDIR,
+            MetaError::IsDir => libc::EISDIR,
+            MetaError::NotEmpty => libc::ENOTEMPTY,
+            MetaError::Busy => libc::EBUSY,
+            MetaError::Invalid => libc::EINVAL,
+            MetaError::TooManyLinks => libc::EMLINK,
+            MetaError::DirFull | MetaError::NoInode => libc::ENOSPC,
+            MetaError::NameTooLong => libc::ENAMETOOLONG,
+            MetaError::NoAttr => libc::ENODATA,
+            MetaError::AttrName => libc::ERANGE,
+            MetaError::AttrValue => libc::E2BIG,
+            MetaError::Corrupt(_) | MetaError::Store(_) => libc::EIO,
+        }
+    }
+}
+
+/// errors of `MetaStore` are the messages of the backend
+impl From<String> for MetaError {
+    fn from(e: String) -> Self {
+        MetaError::Store(e)
+    }
+}
+
+impl From<MetaError> for libc::c_int {
+    fn from(e: MetaError) -> Self {
+        e.errno()
+    }
+}
+
+impl From<MetaError> for String {
+    fn from(e: MetaError) -> Self {
+        e.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MetaError;
+
+    #[test]
+    fn test_errno() {
+        assert_eq!(MetaError::NotFound.errno(), libc::ENOENT);
+        assert_eq!(MetaError::NoInode.errno(), libc::ENOSPC);
+        assert_eq!(MetaError::AttrName.errno(), libc::ERANGE);
+        assert_eq!(libc::c_int::from(MetaError::Store("io".to_string())), libc::EIO);
+        assert_eq!(String::from(MetaError::Store("io".to_string())), "store error, io");
+        assert_eq!(
+            MetaError::from("gone".to_string()),
+            MetaError::Store("gone".to_string())
+        );
+    }
+}
@@ -1,14 +1,16 @@
+use super::record::{self, Tag};
 use super::{Ino, MetaKV};
-use crate::utils::FS_BLK_SIZE;
+use crate::utils::{FS_BLK_SIZE, FS_MODE_MASK};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 pub enum Itype {
     File,
     Dir,
+    Symlink,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub struct Inode {
     pub id: Ino,
     pub parent: Ino,
@@ -28,12 +30,47 @@ impl Inode {
         self.length / FS_BLK_SIZE + (if self.length % FS_BLK_SIZE > 0 { 1 } else { 0 })
     }
 
+    /// only permission bits are stored, the file type comes from `kind` and can't be changed by chmod
+    pub fn set_mode(&mut self, mode: u32) {
+        self.mode = (mode & FS_MODE_MASK) as u16;
+    }
+
     pub fn key(ino: Ino) -> String {
         format!("i_{}", ino)
     }
 
+    pub fn prefix() -> &'static str {
+        "i_"
+    }
+
+    /// key of a symlink's target
+    pub fn link_key(ino: Ino) -> String {
+        format!("l_{}", ino)
+    }
+
+    /// key of the other inodes sharing block `blk` of `ino`, it exists only while the block is shared
+    pub fn bref_key(ino: Ino, blk: u64) -> String {
+        format!("bref_{}_{}", ino, blk)
+    }
+
+    /// key marking block `blk` of `ino` as written, a block without it is a hole, so a block missing from the data
+    /// store is told apart from one never written
+    pub fn data_key(ino: Ino, blk: u64) -> String {
+        format!("data_{}_{}", ino, blk)
+    }
+
+    /// key of extended attribute `name` of `ino`
+    pub fn xattr_key(ino: Ino, name: &str) -> String {
+        format!("x_{}_{}", ino, name)
+    }
+
+    /// prefix of all extended attributes of `ino`, the trailing `_` keeps `x_1_` from matching `x_12_`
+    pub fn xattr_prefix(ino: Ino) -> String {
+        format!("x_{}_", ino)
+    }
+
     pub fn val(this: &Self) -> Vec<u8> {
-        bincode::serialize(this).expect("can't serialize inode")
+        record::seal(Tag::Inode, this)
     }
 }
 
@@ -0,0 +1,78 @@
+use crate::meta::meta_store::{MetaIter, MetaStore, Scanner};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+
+/// keep all keys in memory, nothing survives the process, it's meant for tests and to stand in for a store moved out
+/// for a moment, keys are ordered so `scan_prefix` behaves like the on disk stores
+#[derive(Default)]
+pub struct MemMetaStore {
+    map: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl MemMetaStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl MetaStore for MemMetaStore {
+    fn insert(&self, key: &str, val: &[u8]) -> Result<(), String> {
+        self.map.lock().unwrap().insert(key.to_string(), val.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.map.lock().unwrap().get(key).cloned())
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> MetaIter {
+        // the map can't be borrowed by the iterator, callers may modify the store while iterating
+        let v: Vec<Option<Vec<u8>>> = self
+            .map
+            .lock()
+            .unwrap()
+            .range(prefix.to_string()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(_, v)| Some(v.clone()))
+            .collect();
+        MetaIter {
+            iter: Box::new(v.into_iter()),
+        }
+    }
+
+    fn scan_prefix_from(&self, prefix: &str, start: &str, limit: usize) -> Vec<(String, Vec<u8>)> {
+        scan_from(&self.map.lock().unwrap(), prefix, start, limit)
+    }
+
+    fn remove(&self, key: &str) -> Result<(), String> {
+        self.map.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool, String> {
+        Ok(self.map.lock().unwrap().contains_key(key))
+    }
+
+    fn flush(&self) {}
+
+    fn compact(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn scanner(&self) -> Option<Scanner> {
+        let map = self.map.clone();
+        Some(Box::new(move |start, limit| {
+            scan_from(&map.lock().unwrap(), "", start, limit)
+        }))
+    }
+}
+
+fn scan_from(map: &BTreeMap<String, Vec<u8>>, prefix: &str, start: &str, limit: usize) -> Vec<(String, Vec<u8>)> {
+    let from = if start.is_empty() { Bound::Included(prefix) } else { Bound::Excluded(start) };
+    map.range::<str, _>((from, Bound::Unbounded))
+        .take_while(|(k, _)| k.starts_with(prefix))
+        .take(limit)
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
@@ -1,69 +1,770 @@
+use crate::cache::LRUCache;
+use crate::meta::batch::BatchStore;
 use crate::meta::dentry::Dentry;
+use crate::meta::error::MetaError;
 use crate::meta::inode::{Inode, Itype};
+use crate::meta::mem::MemMetaStore;
+use crate::meta::mirror::MirrorStore;
+use crate::meta::record::{self, Tag};
 use crate::meta::sled::SledStore;
-use crate::meta::super_block::SuperBlock;
+use crate::meta::super_block::{DirLimits, KeyParams, Layout, SuperBlock, FS_VERSION};
 use crate::meta::{DirHandle, MetaKV, MetaStore};
-use crate::utils::{init_data_path, FS_META_CACHE_SIZE};
-use libc::{EEXIST, EFAULT, ENOENT, ENOTEMPTY};
-use std::cell::RefCell;
+use crate::utils::fault::{self, Fault};
+use crate::utils::{epoch_secs, init_data_path, stats, FS_BLK_SIZE, FS_META_CACHE_SIZE, FS_MODE_MASK, FS_ROOT_INODE};
+use libc::PATH_MAX;
+use serde::de::DeserializeOwned;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::rc::Rc;
-use std::time::{SystemTime, UNIX_EPOCH};
 
 pub type Ino = u64;
 
+fn is_dot(name: &str) -> bool {
+    name == "." || name == ".."
+}
+
+/// a corrupted value is logged and treated as missing, so it fails the request touching it rather than the mount
+fn decode<T: DeserializeOwned>(key: &str, bytes: &[u8]) -> Option<T> {
+    if fault::hit(Fault::Deserialize) {
+        log::error!("can't decode {}, injected fault", key);
+        return None;
+    }
+    match bincode::deserialize::<T>(bytes) {
+        Err(e) => {
+            log::error!("can't decode {}, {} bytes, error {}", key, bytes.len(), e);
+            None
+        }
+        Ok(x) => Some(x),
+    }
+}
+
+/// the superblock stored at `key`
+fn load_sb(meta: &dyn MetaStore, key: &str) -> Result<SuperBlock, String> {
+    match meta.get(key)? {
+        None => Err("not formated".to_string()),
+        Some(v) => SuperBlock::decode(&v),
+    }
+}
+
+/// a record of type `tag`, a corrupt one is logged and treated as missing like an undecodable value
+fn decode_record<T: DeserializeOwned>(tag: Tag, key: &str, bytes: &[u8]) -> Option<T> {
+    match record::open(tag, bytes) {
+        Err(e) => {
+            log::error!("can't decode {}, {}", key, e);
+            None
+        }
+        Ok(body) => decode::<T>(key, body),
+    }
+}
+
+/// why `uri` can't hold file data, if it can't
+fn data_path_issue(uri: &str) -> Option<String> {
+    match std::fs::metadata(uri) {
+        Err(e) => Some(format!("data path {} is inaccessible, error {}", uri, e)),
+        Ok(m) if !m.is_dir() => Some(format!("data path {} is not a directory", uri)),
+        Ok(_) => {
+            let path = std::ffi::CString::new(uri).unwrap_or_default();
+            if unsafe { libc::access(path.as_ptr(), libc::R_OK | libc::W_OK | libc::X_OK) } != 0 {
+                Some(format!("data path {} is not writable", uri))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// what a non-empty meta store holds, as told by `format_with`
+fn describe_store(db: &sled::Db) -> Result<String, String> {
+    Ok(match db.get(SuperBlock::key()).map_err(|e| e.to_string())? {
+        None => format!("{} unknown keys", db.len()),
+        Some(sb) => match SuperBlock::decode(&sb) {
+            Ok(sb) => format!("a filesystem of {} inodes with data in {}", sb.used_inodes(), sb.uri()),
+            Err(e) => format!("{} keys, superblock: {}", db.len(), e),
+        },
+    })
+}
+
+/// open the meta store `dst` to copy a filesystem into, one which is not empty is cleared only if `force` is true
+fn clear_store(dst: &str, force: bool) -> Result<(), String> {
+    let db = SledStore::open(dst).map_err(|e| e.to_string())?;
+    if !db.is_empty() {
+        let what = describe_store(&db)?;
+        if !force {
+            return Err(format!("{} contains {}, use --force to overwrite", dst, what));
+        }
+        log::warn!("overwrite {}, destroy {}", dst, what);
+        db.clear().map_err(|e| e.to_string())?;
+    }
+    db.flush().map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// copy all keys of `src` to `dst` in key order, return how many
+fn copy_keys(src: &dyn MetaStore, dst: &dyn MetaStore) -> Result<u64, String> {
+    let mut n = 0;
+    let mut cursor = String::new();
+    loop {
+        let batch = src.scan_prefix_from("", &cursor, COPY_BATCH);
+        for (k, v) in &batch {
+            dst.insert(k, v)?;
+        }
+        n += batch.len() as u64;
+        match batch.last() {
+            Some((k, _)) if batch.len() == COPY_BATCH => cursor = k.clone(),
+            _ => return Ok(n),
+        }
+    }
+}
+
+/// compare `dst` with `src` key by key, return the number of keys
+fn verify_copy(src: &dyn MetaStore, dst: &dyn MetaStore) -> Result<u64, String> {
+    let mut n = 0;
+    let mut cursor = String::new();
+    loop {
+        let a = src.scan_prefix_from("", &cursor, COPY_BATCH);
+        let b = dst.scan_prefix_from("", &cursor, COPY_BATCH);
+        if let Some(i) = (0..a.len().max(b.len())).find(|&i| a.get(i) != b.get(i)) {
+            let key = a.get(i).or(b.get(i)).map(|x| x.0.as_str()).unwrap_or_default();
+            return Err(format!("copy differs at key {}", key));
+        }
+        n += a.len() as u64;
+        match a.last() {
+            Some((k, _)) if a.len() == COPY_BATCH => cursor = k.clone(),
+            _ => return Ok(n),
+        }
+    }
+}
+
+/// the result of `Meta::verify_format`, the superblock `format_with` would write and what stops it
+#[derive(Debug)]
+pub struct FormatCheck {
+    pub version: u32,
+    pub inodes: u64,
+    pub block_size: u64,
+    pub data_path: String,
+    pub layout: Layout,
+    /// what the meta path holds now, `None` if it's empty
+    pub existing: Option<String>,
+    /// reasons the format would fail
+    pub issues: Vec<String>,
+}
+
 pub struct NameT {
     pub name: String,
     pub kind: Itype,
+    /// the `readdir` offset of the entry, see `Dentry::cookie`
+    pub off: i64,
 }
 
+// the superblock is written after this many inode allocations or frees, a crash in between is recovered by the
+// `repair` run by the next writable load, see `unclean`
+const SB_FLUSH_INTERVAL: u64 = 64;
+// the backup superblock is written every this many superblock writes and on close
+const SB_BACKUP_INTERVAL: u64 = 16;
+
+// pending atime updates are written after this many seconds or once this many inodes are pending, whichever comes
+// first, a crash loses at most that window of atime which is fine for a timestamp nobody relies on
+const ATIME_FLUSH_INTERVAL: u64 = 30;
+const ATIME_FLUSH_LIMIT: usize = 1024;
+// decoded inodes kept by default, see `set_inode_cache`
+const INODE_CACHE_SIZE: usize = 4096;
+// entries loaded per `load_dentry`, it bounds the memory a listing of a huge directory holds
+const DENTRY_BATCH: usize = 1024;
+// a directory of at most this many entries keeps them in a single key, it's split into a key per entry once it
+// grows beyond and merged back once it shrinks to half, so adding and removing one at the limit doesn't flip it
+const INLINE_DIR_MAX: usize = 8;
+// keys read at a time by `copy_store` and the first copy of a mirror
+pub(super) const COPY_BATCH: usize = 1024;
+// entries removed in a single write of the store by `clear_dir`
+const UNLINK_BATCH: usize = 1024;
+
+// the limits of Linux, longer names get `ERANGE` and bigger values `E2BIG`
+const XATTR_NAME_MAX: usize = 255;
+const XATTR_SIZE_MAX: usize = 65536;
+
 pub struct Meta {
     pub meta: Box<dyn MetaStore>,
     sb: SuperBlock,
+    /// where file data is kept, the data path of superblock unless it's overridden by `set_data_path`
+    data_path: String,
+    sb_dirty: Cell<u64>,
+    /// superblock writes since the backup was written, the `SB_BACKUP_INTERVAL`th one updates it too
+    sb_flushes: Cell<u64>,
+    /// ino => atime not yet written to the store
+    atimes: RefCell<HashMap<Ino, u64>>,
+    atime_flushed: Cell<u64>,
+    /// inode key => decoded inode as stored, it saves the decode on top of the read cache of the store
+    inodes: RefCell<LRUCache<String, Inode>>,
+    /// loaded by `load_fs` for writing, otherwise it's neither migrated nor repaired, nor the backup superblock
+    /// rewritten on close
+    writable: bool,
+    /// the last writable mount didn't end with `close`, see `unclean`
+    unclean: bool,
+    /// inos of open files, see `set_open`
+    open: HashSet<Ino>,
 }
 
 impl Meta {
-    // write superblock
-    pub fn format(meta_path: &str, store_path: &str) -> Result<(), String> {
-        let db = sled::open(meta_path);
-        if db.is_err() {
-            return Err(db.err().unwrap().to_string());
+    /// check the parameters of `format_with` without writing anything, an existing meta store is opened to tell
+    /// what's in it but never modified
+    pub fn verify_format(meta_path: &str, store_path: &str, layout: Layout, force: bool) -> FormatCheck {
+        let sb = SuperBlock::new(store_path, layout);
+        let mut issues = Vec::new();
+        let mut existing = None;
+        // opening a store creates it
+        if std::path::Path::new(meta_path).exists() {
+            match SledStore::open(meta_path) {
+                Err(e) => issues.push(format!("can't open {}, error {}", meta_path, e)),
+                Ok(db) if db.is_empty() => {}
+                Ok(db) => match describe_store(&db) {
+                    Err(e) => issues.push(format!("can't read {}, error {}", meta_path, e)),
+                    Ok(what) => {
+                        if !force {
+                            issues.push(format!("{} contains {}, use --force to reformat", meta_path, what));
+                        }
+                        existing = Some(what);
+                    }
+                },
+            }
+        }
+        if let Some(e) = data_path_issue(store_path) {
+            issues.push(e);
+        }
+        FormatCheck {
+            version: sb.version(),
+            inodes: sb.total_inodes(),
+            block_size: FS_BLK_SIZE,
+            data_path: sb.uri().to_string(),
+            layout: sb.layout(),
+            existing,
+            issues,
+        }
+    }
+
+    /// write superblock, an existing store is only overwritten if `force` is true, and then all of its keys are
+    /// removed, so nothing of the old filesystem is left behind
+    pub fn format(meta_path: &str, store_path: &str, force: bool) -> Result<(), String> {
+        Self::format_with(
+            meta_path,
+            store_path,
+            Layout::Block,
+            DirLimits::default(),
+            "",
+            None,
+            force,
+        )
+    }
+
+    /// same as `format`, with file data laid out as `layout`, directories capped by `limits`, labeled `label` and
+    /// encrypted with the key `key` tells if it's given
+    pub fn format_with(
+        meta_path: &str,
+        store_path: &str,
+        layout: Layout,
+        limits: DirLimits,
+        label: &str,
+        key: Option<KeyParams>,
+        force: bool,
+    ) -> Result<(), String> {
+        let mut sb = SuperBlock::new(store_path, layout);
+        sb.set_limits(limits);
+        sb.set_label(label)
+            .map_err(|e| format!("invalid label {}, {}", label, e))?;
+        if key.is_some() && layout != Layout::Block {
+            return Err("encryption requires the block layout".to_string());
+        }
+        sb.set_key_params(key);
+        let db = SledStore::open(meta_path).map_err(|e| e.to_string())?;
+
+        if !db.is_empty() {
+            let what = describe_store(&db)?;
+            if !force {
+                return Err(format!("{} contains {}, use --force to reformat", meta_path, what));
+            }
+            log::warn!("reformat {}, destroy {}", meta_path, what);
+            db.clear().map_err(|e| e.to_string())?;
         }
 
-        let db = db.unwrap();
-        let sb = SuperBlock::new(store_path);
-        let r = db.insert(SuperBlock::key(), sb.val());
+        let r = db
+            .insert(SuperBlock::key(), sb.val())
+            .and_then(|_| db.insert(SuperBlock::backup_key(), sb.val()));
 
         match r {
             Err(e) => Err(e.to_string()),
-            Ok(_) => Ok(()),
+            // wait for background writes, which hold the db lock, before it's reopened by `load_fs`
+            Ok(_) => db.flush().map(|_| ()).map_err(|e| e.to_string()),
         }
     }
 
-    pub fn load_fs(path: String) -> Result<Self, String> {
+    /// a superblock lost or corrupted is replaced by the backup, whose inode map is rebuilt by `repair`, a backup
+    /// missing or describing another filesystem is rewritten
+    ///
+    /// unless `writable` nothing is written, e.g. for `fsck` or a read-only mount, the superblock is then taken as
+    /// it is, its inode map left to an explicit `repair`, and a store of an older version is refused
+    pub fn load_fs(path: String, writable: bool) -> Result<Self, String> {
         let meta = Box::new(SledStore::new(&path, FS_META_CACHE_SIZE));
-        let sb = meta.get(&SuperBlock::key());
-        match sb {
-            Err(e) => Err(e),
-            Ok(sb) => match sb {
-                None => Err("not formated".to_string()),
-                Some(sb) => {
-                    let sb = bincode::deserialize::<SuperBlock>(&sb);
-
-                    match sb {
-                        Err(e) => Err(e.to_string()),
-                        Ok(sb) => {
-                            // TODO: check consistency
-                            sb.check();
-                            init_data_path(sb.uri());
-                            Ok(Meta { meta, sb })
-                        }
+        let (sb, from_backup) = match load_sb(meta.as_ref(), &SuperBlock::key()) {
+            Ok(sb) => (sb, false),
+            Err(e) => match load_sb(meta.as_ref(), &SuperBlock::backup_key()) {
+                Err(_) => return Err(e),
+                Ok(sb) => {
+                    log::warn!("superblock: {}, mount from the backup", e);
+                    (sb, true)
+                }
+            },
+        };
+        init_data_path(sb.uri());
+        let mut m = Self::open(meta, sb);
+        m.writable = writable;
+        m.unclean = from_backup || !m.sb.clean();
+        if !writable {
+            if m.sb.version() != FS_VERSION {
+                return Err(format!(
+                    "metadata of version {} must be migrated to {} by a writable mount first",
+                    m.sb.version(),
+                    FS_VERSION
+                ));
+            }
+            if let Some(e) = m.backup_issue() {
+                log::warn!("{}, left to a writable mount", e);
+            }
+            return Ok(m);
+        }
+        m.migrate()?;
+        // a clean unmount wrote the inode map last, it's only stale after a crash
+        if m.unclean {
+            m.repair(true)?;
+        }
+        if from_backup {
+            m.flush_sb()?;
+        }
+        if let Some(e) = m.backup_issue() {
+            log::warn!("{}, rewrite it", e);
+            m.flush_sb_backup()?;
+        }
+        // written before anything else, so a crash from now on is told by the next mount
+        m.sb.set_clean(false);
+        m.flush_sb()?;
+        m.meta.flush();
+        Ok(m)
+    }
+
+    /// the last writable mount ended without `close`, e.g. it crashed, or `fsck --repair` found orphans, so there may
+    /// be orphans to reap, see `orphans`
+    pub fn unclean(&self) -> bool {
+        self.unclean
+    }
+
+    /// have the next writable mount reap orphans, see `unclean`
+    pub fn mark_unclean(&mut self) -> Result<(), MetaError> {
+        self.sb.set_clean(false);
+        self.write_sb()?;
+        self.meta.flush();
+        self.unclean = true;
+        Ok(())
+    }
+
+    /// copy the unmounted filesystem in meta store `src` to a new meta store `dst`, e.g. to move it to another disk
+    /// or backend, `src` is only read, `dst` must be empty unless `force` is true, then all of its keys are removed
+    /// first, the copy is compared with `src` key by key afterwards, return the number of keys copied, the data path
+    /// is kept, records of an older version are migrated on the first mount of `dst`
+    pub fn copy_store(src: &str, dst: &str, force: bool) -> Result<u64, String> {
+        // opening a store creates it
+        let src_dir = std::fs::canonicalize(src).map_err(|e| format!("can't open {}, error {}", src, e))?;
+        if std::fs::canonicalize(dst).is_ok_and(|x| x == src_dir) {
+            return Err(format!("{} and {} are the same store", src, dst));
+        }
+        let from = SledStore::new(src, FS_META_CACHE_SIZE);
+        if let Err(e) = load_sb(&from, &SuperBlock::key()) {
+            load_sb(&from, &SuperBlock::backup_key()).map_err(|_| format!("{} is not a filesystem, {}", src, e))?;
+        }
+
+        clear_store(dst, force)?;
+        let to = SledStore::new(dst, FS_META_CACHE_SIZE);
+        let n = copy_keys(&from, &to)?;
+        to.flush();
+        let m = verify_copy(&from, &to)?;
+        if m != n {
+            return Err(format!("copied {} keys, {} found", n, m));
+        }
+        Ok(n)
+    }
+
+    /// copy the whole keyspace of this filesystem to a new meta store `dst` as it is now, pending atimes and
+    /// superblock included, the keys are written in a single batch, so `dst` holds all or none of them, its data path
+    /// is the one of this filesystem, the data is not copied, `dst` must be empty unless `force` is true, return the
+    /// number of keys copied
+    pub fn snapshot(&self, dst: &str, force: bool) -> Result<u64, String> {
+        self.sync().map_err(|e| e.to_string())?;
+        let mut ops = Vec::new();
+        let mut cursor = String::new();
+        loop {
+            let batch = self.meta.scan_prefix_from("", &cursor, COPY_BATCH);
+            let full = batch.len() == COPY_BATCH;
+            ops.extend(batch.into_iter().map(|(k, v)| (k, Some(v))));
+            match ops.last() {
+                Some((k, _)) if full => cursor = k.clone(),
+                _ => break,
+            }
+        }
+        clear_store(dst, force)?;
+        let to = SledStore::new(dst, FS_META_CACHE_SIZE);
+        to.apply(&ops)?;
+        to.flush();
+        let n = verify_copy(self.meta.as_ref(), &to)?;
+        if n != ops.len() as u64 {
+            return Err(format!("copied {} keys, {} found", ops.len(), n));
+        }
+        Ok(n)
+    }
+
+    /// write every change to the sled store at `path` too, in background, it starts as a copy of this filesystem, see
+    /// `MirrorStore`
+    pub fn mirror_to(&mut self, path: &str) {
+        let inner = std::mem::replace(&mut self.meta, Box::new(MemMetaStore::new()));
+        self.meta = Box::new(MirrorStore::new(inner, path));
+    }
+
+    fn open(meta: Box<dyn MetaStore>, sb: SuperBlock) -> Self {
+        Meta {
+            meta,
+            data_path: sb.uri().to_string(),
+            sb,
+            sb_dirty: Cell::new(0),
+            sb_flushes: Cell::new(0),
+            atimes: RefCell::new(HashMap::new()),
+            atime_flushed: Cell::new(epoch_secs()),
+            inodes: RefCell::new(LRUCache::new(INODE_CACHE_SIZE)),
+            writable: true,
+            unclean: false,
+            open: HashSet::new(),
+        }
+    }
+
+    /// keep at most `cap` decoded inodes, 0 disables the cache
+    pub fn set_inode_cache(&mut self, cap: usize) {
+        self.inodes = RefCell::new(LRUCache::new(cap));
+    }
+
+    /// number of decoded inodes in cache
+    pub fn cached_inodes(&self) -> usize {
+        self.inodes.borrow().len()
+    }
+
+    fn cache_inode(&self, key: &str, inode: &Inode) {
+        let mut c = self.inodes.borrow_mut();
+        if c.cap() > 0 {
+            c.add(key.to_string(), *inode);
+        }
+    }
+
+    fn uncache_inode(&self, key: &str) {
+        self.inodes.borrow_mut().del(&key.to_string());
+    }
+
+    /// a freshly formatted filesystem kept in memory, `store_path` is only recorded in the superblock, the global
+    /// data path is left alone
+    #[cfg(test)]
+    pub(crate) fn in_memory(store_path: &str) -> Self {
+        let sb = SuperBlock::new(store_path, Layout::Block);
+        let meta = Box::new(MemMetaStore::new());
+        meta.insert(&SuperBlock::key(), &sb.val()).unwrap();
+        meta.insert(&SuperBlock::backup_key(), &sb.val()).unwrap();
+        Self::open(meta, sb)
+    }
+
+    /// upgrade a store written by an older version, every step must be idempotent since it's redone if we crash
+    /// before the superblock is updated
+    fn migrate(&mut self) -> Result<(), String> {
+        let version = self.sb.version();
+        if version == FS_VERSION {
+            return Ok(());
+        }
+        log::warn!("migrate metadata from version {} to {}", version, FS_VERSION);
+
+        if version < 2 {
+            let mut dentries = Vec::new();
+            let mut it = self.meta.scan_prefix(Dentry::all_prefix());
+            while let Some(v) = it.next() {
+                let de =
+                    bincode::deserialize::<Dentry>(record::open_legacy(Tag::Dentry, &v)).map_err(|e| e.to_string())?;
+                dentries.push((de.legacy_key(), de.key(), v));
+            }
+            for (old, new, v) in dentries {
+                if old != new {
+                    self.meta.insert(&new, &v)?;
+                    self.meta.remove(&old)?;
+                }
+            }
+        }
+        if version < 4 {
+            // a directory had a single link, now it has one more per subdirectory, whose inodes name it as parent
+            let mut dirs = Vec::new();
+            let mut subdirs: HashMap<Ino, u32> = HashMap::new();
+            {
+                let mut it = self.meta.scan_prefix(Inode::prefix());
+                while let Some(v) = it.next() {
+                    let inode = bincode::deserialize::<Inode>(record::open_legacy(Tag::Inode, &v))
+                        .map_err(|e| e.to_string())?;
+                    if inode.kind == Itype::Dir {
+                        *subdirs.entry(inode.parent).or_default() += 1;
+                        dirs.push(inode);
+                    }
+                }
+            }
+            for mut inode in dirs {
+                let links = 2 + subdirs.get(&inode.id).copied().unwrap_or(0);
+                if inode.links != links {
+                    inode.links = links;
+                    self.store_inode(&inode)?;
+                }
+            }
+        }
+        if version < 5 {
+            // inodes and dentries were stored bare, the superblock is sealed when it's written below
+            let mut bare = Vec::new();
+            {
+                let mut it = self.meta.scan_prefix(Inode::prefix());
+                while let Some(v) = it.next() {
+                    if record::open(Tag::Inode, &v).is_err() {
+                        let inode = bincode::deserialize::<Inode>(&v).map_err(|e| e.to_string())?;
+                        bare.push((inode.key(), inode.val()));
+                    }
+                }
+                let mut it = self.meta.scan_prefix(Dentry::all_prefix());
+                while let Some(v) = it.next() {
+                    if record::open(Tag::Dentry, &v).is_err() {
+                        let de = bincode::deserialize::<Dentry>(&v).map_err(|e| e.to_string())?;
+                        bare.push((de.key(), de.val()));
+                    }
+                }
+            }
+            for (key, val) in bare {
+                self.meta.insert(&key, &val)?;
+            }
+        }
+        // version 3 only adds the data layout to superblock, which is written below, version 6 only adds inline
+        // directories, the existing ones keep a key per entry until they shrink, version 7 only adds the label to
+        // superblock, version 8 only adds key parameters to superblock, the data of an older filesystem is plain,
+        // version 9 only adds the clean flag to superblock, an older filesystem is taken as not cleanly unmounted
+        self.sb.upgrade();
+        self.flush_sb()?;
+        self.meta.flush();
+        Ok(())
+    }
+
+    /// rebuild the inode map of superblock from inode keys, so a crash can't leave a live ino free to be allocated
+    /// again, return the number of leaked and lost inos, nothing is written unless `apply` is true, then a superblock
+    /// or backup which is lost or differs is rewritten too
+    pub fn repair(&mut self, apply: bool) -> Result<(u64, u64), String> {
+        let mut live = Vec::new();
+        {
+            let mut it = self.meta.scan_prefix(Inode::prefix());
+            while let Some(v) = it.next() {
+                let inode = record::decode::<Inode>(Tag::Inode, &v)?;
+                live.push(inode.id);
+            }
+        }
+        let r = self.sb.rebuild_imap(&live, apply);
+        if !apply {
+            return Ok(r);
+        }
+        if r != (0, 0) {
+            log::warn!("repair inode map, leaked {} lost {}", r.0, r.1);
+            self.write_sb()?;
+        } else if let Err(e) = load_sb(self.meta.as_ref(), &SuperBlock::key()) {
+            log::warn!("superblock: {}, rewrite it", e);
+            self.write_sb()?;
+        }
+        if let Some(e) = self.backup_issue() {
+            log::warn!("{}, rewrite it", e);
+            self.flush_sb_backup()?;
+        }
+        self.meta.flush();
+        Ok(r)
+    }
+
+    /// inodes which can't be reached from root, e.g. a crash between creating an inode and its entry or removing an
+    /// entry and its inode, entries of an unreachable directory don't make its children reachable, a broken entry is
+    /// an error, since what's below it would be taken for orphans
+    pub fn orphans(&self) -> Result<Vec<Inode>, String> {
+        // root is created on first mount, nothing can be told before
+        if self.load_inode(FS_ROOT_INODE).is_none() {
+            return Ok(Vec::new());
+        }
+        let mut children: HashMap<Ino, Vec<Ino>> = HashMap::new();
+        {
+            let mut it = self.meta.scan_prefix(Dentry::all_prefix());
+            while let Some(v) = it.next() {
+                let de = record::decode::<Dentry>(Tag::Dentry, &v)?;
+                children.entry(de.parent()).or_default().push(de.ino);
+            }
+            let mut it = self.meta.scan_prefix(Dentry::inline_prefix());
+            while let Some(v) = it.next() {
+                for de in record::decode::<Vec<Dentry>>(Tag::Dir, &v)? {
+                    children.entry(de.parent()).or_default().push(de.ino);
+                }
+            }
+        }
+        let mut reached = HashSet::from([FS_ROOT_INODE]);
+        let mut dirs = vec![FS_ROOT_INODE];
+        while let Some(dir) = dirs.pop() {
+            for &ino in children.get(&dir).into_iter().flatten() {
+                if reached.insert(ino) {
+                    dirs.push(ino);
+                }
+            }
+        }
+        let mut orphans = Vec::new();
+        let mut it = self.meta.scan_prefix(Inode::prefix());
+        while let Some(v) = it.next() {
+            let inode = record::decode::<Inode>(Tag::Inode, &v)?;
+            if !reached.contains(&inode.id) {
+                orphans.push(inode);
+            }
+        }
+        Ok(orphans)
+    }
+
+    /// remove the inodes found by `orphans` and free their inos, the entries of an orphaned directory are removed
+    /// too, return the removed inodes, their data is left to the caller
+    pub fn reap_orphans(&mut self) -> Result<Vec<Inode>, String> {
+        let orphans = self.orphans()?;
+        for inode in &orphans {
+            log::warn!(
+                "reap orphan ino {} kind {:?} length {}",
+                inode.id,
+                inode.kind,
+                inode.length
+            );
+            if inode.kind == Itype::Dir {
+                for (k, _) in self.meta.scan_prefix_from(&Dentry::prefix(inode.id), "", usize::MAX) {
+                    self.delete_key(&k)?;
+                }
+                self.delete_key(&Dentry::inline_key(inode.id))?;
+            }
+            self.drop_inode(inode)?;
+        }
+        if !orphans.is_empty() {
+            self.flush_sb()?;
+            self.meta.flush();
+        }
+        Ok(orphans)
+    }
+
+    /// load the tree under root breadth first into the read cache, so the first operations after mount don't miss,
+    /// it stops once the cache is full, return the number of loaded keys
+    pub fn prewarm(&self) -> usize {
+        let limit = FS_META_CACHE_SIZE;
+        let mut n = 0;
+        let mut dirs = VecDeque::new();
+        if self.load_inode(FS_ROOT_INODE).is_some() {
+            n += 1;
+            dirs.push_back(FS_ROOT_INODE);
+        }
+        while let Some(dir) = dirs.pop_front() {
+            let prefix = Dentry::prefix(dir);
+            let mut entries = Vec::new();
+            {
+                let mut it = self.meta.scan_prefix(&prefix);
+                while let Some(v) = it.next() {
+                    if let Some(de) = decode_record::<Dentry>(Tag::Dentry, &prefix, &v) {
+                        entries.push((de, true));
+                    }
+                }
+            }
+            // loading the inline entries is what warms them
+            if let Ok(Some(v)) = self.inline_dentries(dir) {
+                n += 1;
+                entries.extend(v.into_iter().map(|de| (de, false)));
+            }
+            for (de, own_key) in entries {
+                if n + 2 > limit {
+                    return n;
+                }
+                // both the dentry and the inode are needed by a lookup
+                if own_key {
+                    let _ = self.meta.get(&Dentry::key(dir, &de.name));
+                    n += 1;
+                }
+                if let Some(inode) = self.load_inode(de.ino) {
+                    n += 1;
+                    if inode.kind == Itype::Dir {
+                        dirs.push_back(inode.id);
                     }
                 }
+            }
+        }
+        n
+    }
+
+    /// keep file data in `path` rather than the data path recorded at format, e.g. the data directory was moved to
+    /// another disk, it lasts for this mount only, the superblock is left alone
+    pub fn set_data_path(&mut self, path: &str) -> Result<(), String> {
+        let path = path.trim_end_matches('/');
+        if let Some(e) = data_path_issue(path) {
+            return Err(e);
+        }
+        if path != self.sb.uri() {
+            log::warn!("data path {} overrides {} recorded in superblock", path, self.sb.uri());
+        }
+        init_data_path(path);
+        self.data_path = path.to_string();
+        Ok(())
+    }
+
+    pub fn label(&self) -> &str {
+        self.sb.label()
+    }
+
+    /// what tells the key of file data, `None` unless it's encrypted
+    pub fn key_params(&self) -> Option<KeyParams> {
+        self.sb.key_params()
+    }
+
+    /// change the label, see `SuperBlock::set_label`, both superblocks are written at once, so it's never lost
+    pub fn set_label(&mut self, label: &str) -> Result<(), MetaError> {
+        self.sb.set_label(label)?;
+        self.flush_sb()?;
+        self.flush_sb_backup()
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.sb.layout()
+    }
+
+    /// a lightweight consistency check run at mount time, return the found issues
+    pub fn check(&self) -> Vec<String> {
+        let mut issues = self.sb.check();
+
+        match self.load_inode(FS_ROOT_INODE) {
+            // root is created on first mount, so it's only missing in a fresh filesystem
+            None if self.sb.used_inodes() > 1 => issues.push("root inode is missing".to_string()),
+            Some(root) if root.kind != Itype::Dir => issues.push("root inode is not a directory".to_string()),
+            _ => {}
+        }
+
+        if let Some(e) = data_path_issue(&self.data_path) {
+            issues.push(e);
+        }
+        issues.extend(self.backup_issue());
+        issues
+    }
+
+    /// what's wrong with the backup superblock, if anything
+    fn backup_issue(&self) -> Option<String> {
+        match self.meta.get(&SuperBlock::backup_key()) {
+            Err(e) => Some(format!("can't read backup superblock, error {}", e)),
+            Ok(None) => Some("backup superblock is missing".to_string()),
+            Ok(Some(v)) => match SuperBlock::decode(&v) {
+                Err(e) => Some(format!("backup superblock: {}", e)),
+                Ok(b) if !b.same_fs(&self.sb) => Some("backup superblock differs from the primary".to_string()),
+                Ok(_) => None,
             },
         }
     }
 
     pub fn store(&mut self, key: &str, value: &[u8]) {
+        self.uncache_inode(key);
         match self.meta.insert(key, value) {
             Ok(_) => {}
             Err(e) => {
@@ -82,123 +783,814 @@ impl Meta {
         }
     }
 
+    /// reclaim space of the metadata store, e.g. after a bulk delete
+    pub fn compact(&mut self) -> Result<(), MetaError> {
+        self.flush_sb()?;
+        Ok(self.meta.compact()?)
+    }
+
+    /// write everything, a writable mount is marked clean unless it's closed by a panic, which may have stopped an
+    /// operation halfway, e.g. between removing an entry and its inode
     pub fn close(&mut self) {
+        if !self.writable || self.sync().is_err() {
+            return;
+        }
+        if !std::thread::panicking() {
+            self.sb.set_clean(true);
+            let _ = self.flush_sb();
+        }
+        let _ = self.flush_sb_backup();
+        self.meta.flush();
+    }
+
+    /// persist pending atime, superblock if it changed and flush the store
+    pub fn sync(&self) -> Result<(), MetaError> {
+        if fault::hit(Fault::Flush) {
+            return Err(MetaError::Store("injected flush fault".to_string()));
+        }
+        self.flush_atime()?;
+        if self.sb_dirty.get() != 0 {
+            self.flush_sb()?;
+        }
         self.meta.flush();
+        Ok(())
+    }
+
+    /// write the superblock, unless it's loaded read-only, see `write_sb`
+    pub fn flush_sb(&self) -> Result<(), MetaError> {
+        if !self.writable {
+            return Ok(());
+        }
+        self.write_sb()
     }
 
-    pub fn flush_sb(&self) -> Result<(), String> {
-        match self.meta.insert(&SuperBlock::key(), &self.sb.val()) {
+    /// write the superblock even if it's loaded read-only, only for an explicit repair, e.g. by `fsck --repair`
+    fn write_sb(&self) -> Result<(), MetaError> {
+        let val = self.sb.val();
+        match self.meta.insert(&SuperBlock::key(), &val) {
             Err(e) => {
                 log::error!("can't flush superblock, error {}", e);
-                Err(e.to_string())
+                Err(e.into())
             }
-            Ok(_) => Ok(()),
+            Ok(_) => {
+                self.sb_dirty.set(0);
+                stats::record_sb_flush(val.len() as u64);
+                let n = self.sb_flushes.get() + 1;
+                self.sb_flushes.set(n);
+                if n >= SB_BACKUP_INTERVAL && self.flush_sb_backup().is_ok() {
+                    self.sb_flushes.set(0);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// write the superblock to the backup key
+    pub fn flush_sb_backup(&self) -> Result<(), MetaError> {
+        self.meta
+            .insert(&SuperBlock::backup_key(), &self.sb.val())
+            .inspect_err(|e| log::error!("can't flush backup superblock, error {}", e))
+            .map_err(MetaError::Store)
+    }
+
+    /// record a new atime of `ino`, it's visible through `load_inode` at once but written to the store in batch
+    pub fn touch_atime(&self, ino: Ino, atime: u64) {
+        let n = {
+            let mut atimes = self.atimes.borrow_mut();
+            let e = atimes.entry(ino).or_insert(atime);
+            *e = (*e).max(atime);
+            atimes.len()
+        };
+        if n >= ATIME_FLUSH_LIMIT || epoch_secs() >= self.atime_flushed.get() + ATIME_FLUSH_INTERVAL {
+            let _ = self.flush_atime();
+        }
+    }
+
+    /// forget the pending atime of `ino`, e.g. it's set explicitly, which may go backward
+    pub fn drop_atime(&self, ino: Ino) {
+        self.atimes.borrow_mut().remove(&ino);
+    }
+
+    /// write all pending atime, inodes removed in the meantime are skipped, nothing is written if it's loaded
+    /// read-only
+    pub fn flush_atime(&self) -> Result<(), MetaError> {
+        if !self.writable {
+            return Ok(());
+        }
+        let pending = std::mem::take(&mut *self.atimes.borrow_mut());
+        self.atime_flushed.set(epoch_secs());
+        for (ino, atime) in pending {
+            if let Some(mut inode) = self.load_stored_inode(ino) {
+                if inode.atime >= atime {
+                    continue;
+                }
+                inode.atime = atime;
+                if let Err(e) = self.meta.insert(&Inode::key(ino), &inode.val()) {
+                    log::error!("can't flush atime of ino {} error {}", ino, e);
+                    return Err(e.into());
+                }
+                self.cache_inode(&Inode::key(ino), &inode);
+                stats::record_atime_write();
+            }
+        }
+        Ok(())
+    }
+
+    /// the inode map in memory is authoritative during a mount, so the superblock is written lazily
+    fn sb_changed(&self) {
+        let n = self.sb_dirty.get() + 1;
+        self.sb_dirty.set(n);
+        if n >= SB_FLUSH_INTERVAL {
+            let _ = self.flush_sb();
+        }
+    }
+
+    /// same as `lookup`, but tell a missing entry (`ENOENT`) from a `parent` that is not a directory (`ENOTDIR`)
+    pub fn resolve(&mut self, parent: Ino, name: &str) -> Result<Inode, MetaError> {
+        if self.get_inode(parent)?.kind != Itype::Dir {
+            return Err(MetaError::NotDir);
         }
+        self.lookup(parent, name).ok_or(MetaError::NotFound)
     }
 
-    /// - use `parent` and `name` to build dentry key
-    /// - load value of dentry key
+    /// - load the dentry of `parent` and `name`, see `get_dentry`
     /// - if existed, load Inode from database
     /// - or else, return None
-    pub fn lookup(&mut self, parent: Ino, name: &String) -> Option<Inode> {
-        let parent = Dentry::key(parent, &name);
-        match self.meta.get(&parent) {
+    pub fn lookup(&mut self, parent: Ino, name: &str) -> Option<Inode> {
+        match self.get_dentry(parent, name) {
             Err(e) => {
-                log::error!("can't load dentry {}, error {}", parent, e.to_string());
+                log::error!("can't load dentry {}, error {}", Dentry::key(parent, name), e);
                 None
             }
-            Ok(dentry) => {
-                if dentry.is_none() {
-                    log::info!("can't find dentry {}", parent);
-                    return None;
-                }
-                let dentry = dentry.unwrap();
-                let dentry = bincode::deserialize::<Dentry>(&dentry).expect("can't deserialize dentry");
-                self.load_inode(dentry.ino)
+            Ok(None) => {
+                log::info!("can't find dentry {}", Dentry::key(parent, name));
+                None
             }
+            Ok(Some(dentry)) => self.load_inode(dentry.ino),
         }
     }
 
-    pub fn mknod(&mut self, parent: u64, name: impl AsRef<str>, ftype: Itype, mode: u32) -> Result<Inode, libc::c_int> {
-        if self.dentry_exist(parent, name.as_ref()) {
-            log::error!("node existed dentry {}", Dentry::key(parent, name.as_ref()));
-            return Err(EEXIST);
+    /// the entry `name` of `parent` whether it's inline or has a key of its own, the form the size of `parent`
+    /// suggests is tried first, a corrupted entry is missing
+    fn get_dentry(&self, parent: Ino, name: &str) -> Result<Option<Dentry>, MetaError> {
+        let inline = || -> Result<Option<Dentry>, MetaError> {
+            Ok(self
+                .inline_dentries(parent)?
+                .and_then(|v| v.into_iter().find(|d| d.name == name)))
+        };
+        let own = || -> Result<Option<Dentry>, MetaError> {
+            let key = Dentry::key(parent, name);
+            Ok(self
+                .meta
+                .get(&key)?
+                .and_then(|v| decode_record::<Dentry>(Tag::Dentry, &key, &v)))
+        };
+        let small = self
+            .load_inode(parent)
+            .is_none_or(|dir| dir.length <= INLINE_DIR_MAX as u64);
+        if small {
+            inline()?.map_or_else(own, |d| Ok(Some(d)))
+        } else {
+            own()?.map_or_else(inline, |d| Ok(Some(d)))
         }
+    }
 
-        let epoch = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("can't get unix timestamp")
-            .as_secs();
+    /// entries of `dir` kept in its inline key ordered by name, `None` if there's no such key
+    fn inline_dentries(&self, dir: Ino) -> Result<Option<Vec<Dentry>>, MetaError> {
+        let key = Dentry::inline_key(dir);
+        match self.meta.get(&key)? {
+            None => Ok(None),
+            Some(v) => record::decode::<Vec<Dentry>>(Tag::Dir, &v)
+                .map(Some)
+                .map_err(|e| MetaError::Corrupt(format!("{}, {}", key, e))),
+        }
+    }
 
-        // NOTE: for superblock, we skip slot 0 in bitmap
-        if parent == 0 {
-            self.sb.alloc_ino().unwrap();
+    fn store_inline(&mut self, dir: Ino, entries: &[Dentry]) -> Result<(), MetaError> {
+        let key = Dentry::inline_key(dir);
+        if entries.is_empty() {
+            return self.delete_key(&key);
         }
-        if let Some(ino) = self.sb.alloc_ino() {
-            if parent == 0 {
-                assert_eq!(ino, 1);
+        Ok(self.meta.insert(&key, &record::seal(Tag::Dir, &entries))?)
+    }
+
+    /// whether `dir` keeps a key per entry
+    fn has_dentry_keys(&self, dir: Ino) -> bool {
+        !self.meta.scan_prefix_from(&Dentry::prefix(dir), "", 1).is_empty()
+    }
+
+    fn dir_empty(&self, dir: Ino) -> bool {
+        !self.has_dentry_keys(dir) && matches!(self.inline_dentries(dir), Ok(None))
+    }
+
+    /// remove only the entry `name`, as a crash before its inode is removed leaves it
+    #[cfg(test)]
+    pub(crate) fn orphan(&mut self, parent: Ino, name: &str) -> Result<(), MetaError> {
+        self.remove_dentry(parent, name)
+    }
+
+    /// remove the entry `name` of `parent` in whichever form it's kept, a directory with a key per entry is merged
+    /// into the inline key once it's down to half of `INLINE_DIR_MAX`
+    fn remove_dentry(&mut self, parent: Ino, name: &str) -> Result<(), MetaError> {
+        self.delete_key(&Dentry::key(parent, name))?;
+        if let Some(mut entries) = self.inline_dentries(parent)? {
+            let n = entries.len();
+            entries.retain(|d| d.name != name);
+            if entries.len() != n {
+                self.store_inline(parent, &entries)?;
             }
+            return Ok(());
+        }
+        let rest = self
+            .meta
+            .scan_prefix_from(&Dentry::prefix(parent), "", INLINE_DIR_MAX / 2 + 1);
+        if rest.is_empty() || rest.len() > INLINE_DIR_MAX / 2 {
+            return Ok(());
+        }
+        let entries: Vec<Dentry> = rest
+            .iter()
+            .filter_map(|(k, v)| decode_record::<Dentry>(Tag::Dentry, k, v))
+            .collect();
+        // a broken entry stays where it is
+        if entries.len() != rest.len() {
+            return Ok(());
+        }
+        // the inline key is written before the keys are removed, a crash in between leaves the entries twice
+        self.store_inline(parent, &entries)?;
+        for (k, _) in rest {
+            self.delete_key(&k)?;
+        }
+        Ok(())
+    }
+
+    pub fn mknod(&mut self, parent: u64, name: impl AsRef<str>, ftype: Itype, mode: u32) -> Result<Inode, MetaError> {
+        if self.dentry_exist(parent, name.as_ref()) {
+            log::error!("node existed dentry {}", Dentry::key(parent, name.as_ref()));
+            return Err(MetaError::Exists);
+        }
+        self.check_room(parent, ftype == Itype::Dir)?;
+
+        let epoch = epoch_secs();
+
+        // NOTE: slot 0 and root are reserved in the inode map, root is never allocated
+        let ino = if parent == 0 {
+            self.sb.reserve();
+            Some(FS_ROOT_INODE)
+        } else {
+            self.sb.alloc_ino()
+        };
+        if let Some(ino) = ino {
             let inode = Inode {
                 id: ino,
                 parent,
                 kind: ftype,
-                mode: mode as u16,
+                mode: (mode & FS_MODE_MASK) as u16,
                 uid: unsafe { libc::getuid() },
                 gid: unsafe { libc::getgid() },
                 atime: epoch,
                 mtime: epoch,
                 ctime: epoch,
                 length: 0,
-                links: 1,
+                links: if ftype == Itype::Dir { 2 } else { 1 },
             };
 
-            let r = self.store_inode(&inode);
-            if r.is_err() {
-                log::error!("can't store inode {}", ino);
+            if let Err(e) = self.store_inode(&inode) {
+                log::error!("can't store inode {} error {}", ino, e);
                 self.sb.free_ino(ino);
-                return Err(EFAULT);
+                return Err(e);
             }
 
-            let r = self.store_dentry(parent, &name, ino);
-            if r.is_err() {
+            if let Err(e) = self.store_dentry(parent, &name, ino) {
                 self.sb.free_ino(ino);
                 let key = Inode::key(ino);
-                self.delete_key(&key).expect("can't remove key");
-                return Err(EFAULT);
+                let _ = self.delete_key(&key);
+                return Err(e);
             }
 
-            let _ = self.flush_sb();
+            self.resize_dir(parent, true, ftype == Itype::Dir);
+            self.sb_changed();
             Ok(inode)
         } else {
-            Err(ENOENT)
+            Err(MetaError::NoInode)
         }
     }
 
-    pub fn unlink(&mut self, parent: Ino, name: &String) -> Result<Inode, libc::c_int> {
-        let key = self.lookup(parent, &name);
+    /// whether `dir` can take another entry, which is a subdirectory if `subdir`, see `DirLimits`
+    fn check_room(&self, dir: Ino, subdir: bool) -> Result<(), MetaError> {
+        let Some(inode) = self.load_inode(dir) else {
+            return Ok(());
+        };
+        let limits = self.sb.limits();
+        if subdir && inode.links >= limits.max_links {
+            log::warn!(
+                "dir {} has {} links, the limit is {}",
+                dir,
+                inode.links,
+                limits.max_links
+            );
+            return Err(MetaError::TooManyLinks);
+        }
+        if limits.max_entries != 0 && inode.length >= limits.max_entries {
+            log::warn!(
+                "dir {} has {} entries, the limit is {}",
+                dir,
+                inode.length,
+                limits.max_entries
+            );
+            return Err(MetaError::DirFull);
+        }
+        Ok(())
+    }
 
-        if key.is_none() {
-            return Err(ENOENT);
+    /// the size of a directory is the number of its entries, excluding `.` and `..`, and its links are 2 plus its
+    /// subdirectories, adding or removing one is a modification of the directory
+    fn resize_dir(&mut self, dir: Ino, add: bool, subdir: bool) {
+        // parent of root
+        if dir == 0 {
+            return;
         }
-        let inode = key.unwrap();
-        if inode.kind == Itype::Dir {
-            let prefix = Dentry::prefix(inode.id);
-            let mut it = self.meta.scan_prefix(&prefix);
-            if it.next().is_some() {
-                return Err(ENOTEMPTY);
+        if let Some(mut inode) = self.load_inode(dir) {
+            inode.length = if add { inode.length + 1 } else { inode.length.saturating_sub(1) };
+            if subdir {
+                inode.links = if add { inode.links + 1 } else { inode.links.saturating_sub(1) };
+            }
+            // the entries are the content of a directory
+            let now = epoch_secs();
+            inode.mtime = now;
+            inode.ctime = now;
+            if let Err(e) = self.store_inode(&inode) {
+                log::error!("can't update size of dir {} error {}", dir, e);
             }
         }
-        let ikey = Inode::key(inode.id);
-        let dkey = Dentry::key(parent, name);
-        self.delete_key(&ikey).unwrap();
-        self.delete_key(&dkey).unwrap();
-        self.sb.free_ino(inode.id);
+    }
+
+    /// remove a non-directory entry, directories must be removed by `rmdir`
+    pub fn unlink(&mut self, parent: Ino, name: &str) -> Result<Inode, MetaError> {
+        if is_dot(name) {
+            return Err(MetaError::IsDir);
+        }
+        let key = self.lookup(parent, name);
+
+        if key.is_none() {
+            return Err(MetaError::NotFound);
+        }
+        let inode = key.unwrap();
+        if inode.kind == Itype::Dir {
+            return Err(MetaError::IsDir);
+        }
+        self.remove(parent, name, inode)
+    }
+
+    /// the target is kept in a single key rather than data blocks, so it's stored and loaded in full or not at all
+    pub fn symlink(&mut self, parent: Ino, name: &str, target: &[u8]) -> Result<Inode, MetaError> {
+        if target.is_empty() {
+            return Err(MetaError::NotFound);
+        }
+        if target.len() >= PATH_MAX as usize {
+            return Err(MetaError::NameTooLong);
+        }
+        let mut inode = self.mknod(parent, name, Itype::Symlink, 0o777)?;
+        inode.length = target.len() as u64;
+        let r = self
+            .meta
+            .insert(&Inode::link_key(inode.id), target)
+            .map_err(MetaError::from)
+            .and_then(|_| self.store_inode(&inode));
+        if let Err(e) = r {
+            log::error!("can't store symlink {} error {}", inode.id, e);
+            let _ = self.remove(parent, name, inode);
+            return Err(e);
+        }
+        Ok(inode)
+    }
+
+    pub fn readlink(&self, ino: Ino) -> Result<Vec<u8>, MetaError> {
+        let inode = self.get_inode(ino)?;
+        if inode.kind != Itype::Symlink {
+            return Err(MetaError::Invalid);
+        }
+        match self.load(&Inode::link_key(ino)) {
+            Some(target) if target.len() as u64 == inode.length => Ok(target),
+            _ => {
+                log::error!("missing or truncated symlink target of {}", ino);
+                Err(MetaError::Corrupt(format!("symlink target of {}", ino)))
+            }
+        }
+    }
+
+    /// remove an empty directory
+    pub fn rmdir(&mut self, parent: Ino, name: &str) -> Result<Inode, MetaError> {
+        match name {
+            "." => return Err(MetaError::Invalid),
+            ".." => return Err(MetaError::NotEmpty),
+            _ => {}
+        }
+        let key = self.lookup(parent, name);
+
+        if key.is_none() {
+            return Err(MetaError::NotFound);
+        }
+        let inode = key.unwrap();
+        if inode.kind != Itype::Dir {
+            return Err(MetaError::NotDir);
+        }
+        if inode.id == FS_ROOT_INODE {
+            return Err(MetaError::Busy);
+        }
+        if !self.dir_empty(inode.id) {
+            return Err(MetaError::NotEmpty);
+        }
+        self.remove(parent, name, inode)
+    }
+
+    /// move `name` in `parent` to `newname` in `newparent`, only the dentry moves, the ino and so the data blocks are
+    /// kept, an existing target is replaced and returned, the caller is responsible for its data
+    pub fn rename(
+        &mut self,
+        parent: Ino,
+        name: &str,
+        newparent: Ino,
+        newname: &str,
+        flags: u32,
+    ) -> Result<Option<Inode>, MetaError> {
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            return Err(MetaError::Invalid);
+        }
+        if is_dot(name) || is_dot(newname) {
+            return Err(MetaError::Busy);
+        }
+        let mut inode = self.lookup(parent, name).ok_or(MetaError::NotFound)?;
+        if parent == newparent && name == newname {
+            return Ok(None);
+        }
+        if self.get_inode(newparent)?.kind != Itype::Dir {
+            return Err(MetaError::NotDir);
+        }
+        // a directory can't be moved into itself or its descendants
+        if inode.kind == Itype::Dir {
+            let mut p = newparent;
+            while p != 0 {
+                if p == inode.id {
+                    return Err(MetaError::Invalid);
+                }
+                p = self.get_inode(p)?.parent;
+            }
+        }
+
+        let target = self.lookup(newparent, newname);
+        if target.is_none() && parent != newparent {
+            self.check_room(newparent, inode.kind == Itype::Dir)?;
+        }
+        let mut replaced = None;
+        if let Some(old) = target {
+            if flags & libc::RENAME_NOREPLACE != 0 {
+                return Err(MetaError::Exists);
+            }
+            if old.kind == Itype::Dir {
+                if inode.kind != Itype::Dir {
+                    return Err(MetaError::IsDir);
+                }
+                if !self.dir_empty(old.id) {
+                    return Err(MetaError::NotEmpty);
+                }
+            } else if inode.kind == Itype::Dir {
+                return Err(MetaError::NotDir);
+            }
+            replaced = Some(self.remove(newparent, newname, old)?);
+        }
+
+        // add the new entry before the old one is gone, a crash in between leaves two entries rather than none
+        self.store_dentry(newparent, newname, inode.id)?;
+        self.resize_dir(newparent, true, inode.kind == Itype::Dir);
+        self.remove_dentry(parent, name)?;
+        self.resize_dir(parent, false, inode.kind == Itype::Dir);
+        // both directories are modified by `resize_dir`, the moved inode is changed but its content isn't
+        inode.parent = newparent;
+        inode.ctime = epoch_secs();
+        self.store_inode(&inode)?;
+        Ok(replaced)
+    }
+
+    /// remove the entries `names` of `parent` as `unlink` does, but in a single write of the store along with the
+    /// superblock rather than a few writes each, an entry that can't be removed is skipped with its error
+    pub fn unlink_batch(&mut self, parent: Ino, names: &[&str]) -> Result<Vec<Result<Inode, MetaError>>, MetaError> {
+        self.batch(|m| names.iter().map(|name| m.unlink(parent, name)).collect())
+    }
+
+    /// remove everything below `dir`, which is kept, e.g. for a fast `rm -rf`, the entries of a directory are removed
+    /// `UNLINK_BATCH` at a time, see `unlink_batch`, the files removed are added to `removed` so their data can be
+    /// dropped, also when it fails half way
+    pub fn clear_dir(&mut self, dir: Ino, removed: &mut Vec<Inode>) -> Result<(), MetaError> {
+        if self.get_inode(dir)?.kind != Itype::Dir {
+            return Err(MetaError::NotDir);
+        }
+        // a directory is emptied before it's removed along with its siblings
+        let mut stack = vec![dir];
+        while let Some(&top) = stack.last() {
+            let entries = self.children(top, UNLINK_BATCH)?;
+            if entries.is_empty() {
+                stack.pop();
+                continue;
+            }
+            let mut full = None;
+            for (_, inode) in &entries {
+                if inode.kind == Itype::Dir && !self.children(inode.id, 1)?.is_empty() {
+                    full = Some(inode.id);
+                    break;
+                }
+            }
+            if let Some(sub) = full {
+                stack.push(sub);
+                continue;
+            }
+            let r = self.batch(|m| {
+                entries
+                    .iter()
+                    .map(
+                        |(name, inode)| {
+                            if inode.kind == Itype::Dir {
+                                m.rmdir(top, name)
+                            } else {
+                                m.unlink(top, name)
+                            }
+                        },
+                    )
+                    .collect::<Vec<_>>()
+            })?;
+            let mut err = None;
+            for x in r {
+                match x {
+                    Ok(inode) if inode.kind == Itype::File => removed.push(inode),
+                    Ok(_) => {}
+                    Err(e) => err = Some(e),
+                }
+            }
+            // it would be tried again and again
+            if let Some(e) = err {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// the first `limit` entries of `dir` and their inodes, a broken entry is left out
+    fn children(&self, dir: Ino, limit: usize) -> Result<Vec<(String, Inode)>, MetaError> {
+        let mut entries = self.inline_dentries(dir)?.unwrap_or_default();
+        if entries.len() < limit {
+            let rest = self
+                .meta
+                .scan_prefix_from(&Dentry::prefix(dir), "", limit - entries.len());
+            entries.extend(
+                rest.iter()
+                    .filter_map(|(k, v)| decode_record::<Dentry>(Tag::Dentry, k, v)),
+            );
+        }
+        entries.truncate(limit);
+        let mut r = Vec::with_capacity(entries.len());
+        for de in entries {
+            let inode = self.get_inode(de.ino).map_err(|_| {
+                log::error!("dentry {} of dir {} points to missing ino {}", de.name, dir, de.ino);
+                MetaError::Corrupt(format!("dentry {} of dir {}", de.name, dir))
+            })?;
+            r.push((de.name, inode));
+        }
+        Ok(r)
+    }
+
+    /// run `f` with the writes to the store held back, they are written at once afterwards along with the superblock,
+    /// the store writes all or none of them if it can, e.g. sled, if it fails the superblock is reloaded and the
+    /// decoded inodes are dropped, so nothing in memory is ahead of the store
+    fn batch<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> Result<T, MetaError> {
+        // it's what's reloaded
+        self.flush_sb()?;
+        let inner = std::mem::replace(&mut self.meta, Box::new(MemMetaStore::new()));
+        let batch = Rc::new(BatchStore::new(inner));
+        self.meta = Box::new(batch.clone());
+        let r = f(self);
         let _ = self.flush_sb();
+        self.meta = Box::new(MemMetaStore::new());
+        let batch = Rc::into_inner(batch).expect("batch store is shared");
+        let (inner, w) = batch.commit();
+        self.meta = inner;
+        if let Err(e) = w {
+            log::error!("can't write batch, error {}", e);
+            let cap = self.inodes.borrow().cap();
+            self.set_inode_cache(cap);
+            self.sb = load_sb(&*self.meta, &SuperBlock::key())?;
+            self.sb_dirty.set(0);
+            return Err(e.into());
+        }
+        Ok(r)
+    }
+
+    /// remove the entry and its inode, the inode of an open file is kept with no links until `drop_unlinked`, it's
+    /// what's returned then
+    fn remove(&mut self, parent: Ino, name: &str, mut inode: Inode) -> Result<Inode, MetaError> {
+        if inode.id == FS_ROOT_INODE {
+            return Err(MetaError::Busy);
+        }
+        self.remove_dentry(parent, name)?;
+        if self.open.contains(&inode.id) {
+            inode.links = 0;
+            inode.ctime = epoch_secs();
+            self.store_inode(&inode)?;
+        } else {
+            self.drop_inode(&inode)?;
+        }
+        self.resize_dir(parent, false, inode.kind == Itype::Dir);
+        Ok(inode)
+    }
+
+    /// whether `ino` is open, removing the last entry of an open file keeps its inode and blocks, so it can still be
+    /// read and written, until it's closed and `drop_unlinked`
+    pub fn set_open(&mut self, ino: Ino, open: bool) {
+        if open {
+            self.open.insert(ino);
+        } else {
+            self.open.remove(&ino);
+        }
+    }
+
+    /// remove the inode of a closed file whose entry was removed while it was open, return it so its data can be
+    /// dropped, a crash before it leaves an orphan
+    pub fn drop_unlinked(&mut self, ino: Ino) -> Result<Inode, MetaError> {
+        let inode = self.get_inode(ino)?;
+        if inode.links != 0 || self.open.contains(&ino) {
+            return Err(MetaError::Busy);
+        }
+        self.drop_inode(&inode)?;
         Ok(inode)
     }
 
-    pub fn load_inode(&self, inode: Ino) -> Option<Inode> {
+    /// remove `inode` and what's kept along with it and free its ino, its entry is gone already
+    fn drop_inode(&mut self, inode: &Inode) -> Result<(), MetaError> {
+        if inode.kind == Itype::Symlink {
+            let _ = self.delete_key(&Inode::link_key(inode.id));
+        }
+        self.atimes.borrow_mut().remove(&inode.id);
+        if inode.kind == Itype::File {
+            self.release_blocks(inode.id, inode.blocks());
+        }
+        for name in self.list_xattr(inode.id) {
+            let _ = self.delete_key(&Inode::xattr_key(inode.id, &name));
+        }
+        self.delete_key(&Inode::key(inode.id))?;
+        self.sb.free_ino(inode.id);
+        self.sb_changed();
+        Ok(())
+    }
+
+    /// the other inodes sharing block `blk` of `ino`
+    fn block_peers(&self, ino: Ino, blk: u64) -> Vec<Ino> {
+        let key = Inode::bref_key(ino, blk);
+        self.load(&key)
+            .and_then(|v| decode::<Vec<Ino>>(&key, &v))
+            .unwrap_or_default()
+    }
+
+    fn store_peers(&mut self, ino: Ino, blk: u64, peers: &[Ino]) -> Result<(), MetaError> {
+        let key = Inode::bref_key(ino, blk);
+        if peers.is_empty() {
+            return self.delete_key(&key);
+        }
+        let val = bincode::serialize(peers).expect("can't serialize block refs");
+        Ok(self.meta.insert(&key, &val)?)
+    }
+
+    /// number of files referencing block `blk` of `ino`, it's 1 unless the block is shared by `share_block`
+    pub fn block_refs(&self, ino: Ino, blk: u64) -> u64 {
+        1 + self.block_peers(ino, blk).len() as u64
+    }
+
+    /// whether block `blk` of `ino` must be copied before it's written, it's checked on every flush, so it's a
+    /// single key lookup
+    pub fn is_shared(&self, ino: Ino, blk: u64) -> bool {
+        self.meta.contains_key(&Inode::bref_key(ino, blk)).unwrap_or(true)
+    }
+
+    /// record that block `blk` of `dst` refers to the data of the same block of `src`, every file sharing the block
+    /// keeps the list of the others, so the count is the same whichever of them is asked
+    pub fn share_block(&mut self, src: Ino, dst: Ino, blk: u64) -> Result<(), MetaError> {
+        let mut group = self.block_peers(src, blk);
+        group.push(src);
+        for &ino in &group {
+            let mut peers: Vec<Ino> = group.iter().copied().filter(|&x| x != ino).collect();
+            peers.push(dst);
+            self.store_peers(ino, blk, &peers)?;
+        }
+        self.store_peers(dst, blk, &group)
+    }
+
+    /// drop the reference of `ino` to block `blk`, e.g. once it has a private copy, the data is freed by the store
+    /// once the count hits zero
+    pub fn unshare_block(&mut self, ino: Ino, blk: u64) -> Result<(), MetaError> {
+        let peers = self.block_peers(ino, blk);
+        for &p in &peers {
+            let rest: Vec<Ino> = self.block_peers(p, blk).into_iter().filter(|&x| x != ino).collect();
+            self.store_peers(p, blk, &rest)?;
+        }
+        if !peers.is_empty() {
+            self.store_peers(ino, blk, &[])?;
+        }
+        Ok(())
+    }
+
+    /// whether block `blk` of `ino` was written, blocks written before it was recorded are not
+    pub fn has_data(&self, ino: Ino, blk: u64) -> bool {
+        self.meta.contains_key(&Inode::data_key(ino, blk)).unwrap_or(false)
+    }
+
+    /// record that block `blk` of `ino` holds data, it's called on every flush, so the key is only written once
+    pub fn mark_data(&mut self, ino: Ino, blk: u64) -> Result<(), MetaError> {
+        if self.has_data(ino, blk) {
+            return Ok(());
+        }
+        Ok(self.meta.insert(&Inode::data_key(ino, blk), &[])?)
+    }
+
+    /// forget that block `blk` of `ino` holds data, e.g. it's cut off by a truncate
+    pub fn clear_data(&mut self, ino: Ino, blk: u64) {
+        if self.has_data(ino, blk) {
+            let _ = self.delete_key(&Inode::data_key(ino, blk));
+        }
+    }
+
+    /// drop the references of `ino` to its first `blocks` blocks, it's called when its data is removed
+    pub fn release_blocks(&mut self, ino: Ino, blocks: u64) {
+        for blk in 0..blocks {
+            self.clear_data(ino, blk);
+            if self.is_shared(ino, blk) {
+                if let Err(e) = self.unshare_block(ino, blk) {
+                    log::error!("can't release block {}_{} error {}", ino, blk, e);
+                }
+            }
+        }
+    }
+
+    pub fn get_xattr(&self, ino: Ino, name: &str) -> Result<Vec<u8>, MetaError> {
+        self.get_inode(ino)?;
+        self.load(&Inode::xattr_key(ino, name)).ok_or(MetaError::NoAttr)
+    }
+
+    /// `flags` is `XATTR_CREATE`, `XATTR_REPLACE` or 0 for either, the ctime of `ino` is updated like chmod does
+    pub fn set_xattr(&mut self, ino: Ino, name: &str, value: &[u8], flags: i32) -> Result<(), MetaError> {
+        if name.is_empty() || name.len() > XATTR_NAME_MAX {
+            return Err(MetaError::AttrName);
+        }
+        if value.len() > XATTR_SIZE_MAX {
+            return Err(MetaError::AttrValue);
+        }
+        let mut inode = self.get_inode(ino)?;
+        let key = Inode::xattr_key(ino, name);
+        let exists = self.meta.contains_key(&key)?;
+        if flags & libc::XATTR_CREATE != 0 && exists {
+            return Err(MetaError::Exists);
+        }
+        if flags & libc::XATTR_REPLACE != 0 && !exists {
+            return Err(MetaError::NoAttr);
+        }
+        self.meta
+            .insert(&key, value)
+            .inspect_err(|e| log::error!("can't store xattr {} error {}", key, e))?;
+        inode.ctime = epoch_secs();
+        self.store_inode(&inode)
+    }
+
+    /// names of the extended attributes of `ino` in key order
+    pub fn list_xattr(&self, ino: Ino) -> Vec<String> {
+        let prefix = Inode::xattr_prefix(ino);
+        self.meta
+            .scan_prefix_from(&prefix, "", usize::MAX)
+            .into_iter()
+            .map(|(k, _)| k[prefix.len()..].to_string())
+            .collect()
+    }
+
+    pub fn remove_xattr(&mut self, ino: Ino, name: &str) -> Result<(), MetaError> {
+        let mut inode = self.get_inode(ino)?;
+        let key = Inode::xattr_key(ino, name);
+        if !self.meta.contains_key(&key)? {
+            return Err(MetaError::NoAttr);
+        }
+        self.delete_key(&key)?;
+        inode.ctime = epoch_secs();
+        self.store_inode(&inode)
+    }
+
+    /// the inode with pending atime applied
+    pub fn load_inode(&self, ino: Ino) -> Option<Inode> {
+        let mut inode = self.load_stored_inode(ino)?;
+        if let Some(atime) = self.atimes.borrow().get(&ino) {
+            inode.atime = inode.atime.max(*atime);
+        }
+        Some(inode)
+    }
+
+    fn load_stored_inode(&self, inode: Ino) -> Option<Inode> {
         let key = Inode::key(inode);
+        if let Some(x) = self.inodes.borrow_mut().get(&key) {
+            return Some(*x);
+        }
         match self.meta.get(&key) {
             Err(e) => {
                 log::error!("load inode error {}", e.to_string());
@@ -209,80 +1601,1592 @@ impl Meta {
                     log::error!("can't find inode {}", key);
                     None
                 } else {
-                    let inode = bincode::deserialize::<Inode>(&tmp.unwrap());
-                    if inode.is_err() {
-                        log::error!("deserialize inode fail error {}", inode.err().unwrap().to_string());
-                        return None;
-                    }
-                    Some(inode.unwrap())
+                    let inode = decode_record::<Inode>(Tag::Inode, &key, &tmp.unwrap())?;
+                    self.cache_inode(&key, &inode);
+                    Some(inode)
                 }
             }
         }
     }
 
+    /// same as `load_inode`, but a missing inode is `ENOENT`
+    pub fn get_inode(&self, ino: Ino) -> Result<Inode, MetaError> {
+        self.load_inode(ino).ok_or(MetaError::NotFound)
+    }
+
     /// if `key` exist, we can overwrite it
-    pub fn store_inode(&mut self, inode: &Inode) -> Result<(), String> {
+    pub fn store_inode(&mut self, inode: &Inode) -> Result<(), MetaError> {
         let key = Inode::key(inode.id);
+        if fault::hit(Fault::StoreInode) {
+            self.uncache_inode(&key);
+            return Err(MetaError::Store("injected store_inode fault".to_string()));
+        }
         let r = self.meta.insert(&key, &inode.val());
         if r.is_err() {
-            return Err(r.err().unwrap().to_string());
+            self.uncache_inode(&key);
+            return Err(r.err().unwrap().into());
+        }
+        self.cache_inode(&key, inode);
+        // the pending atime is written along with the inode, unless it's newer
+        let mut atimes = self.atimes.borrow_mut();
+        if atimes.get(&inode.id).is_some_and(|a| *a <= inode.atime) {
+            atimes.remove(&inode.id);
         }
         Ok(())
     }
 
+    /// load the next batch of entries of `ino` into `handle`, the first batch starts with `.` and `..`
     pub fn load_dentry(&self, ino: Ino, handle: &Rc<RefCell<DirHandle>>) {
-        let key = Dentry::prefix(ino);
-        let mut iter = self.meta.scan_prefix(&key);
-
-        handle.borrow_mut().add(NameT {
-            name: ".".to_string(),
-            kind: Itype::Dir,
-        });
-        handle.borrow_mut().add(NameT {
-            name: "..".to_string(),
-            kind: Itype::Dir,
-        });
+        let prefix = Dentry::prefix(ino);
+        let mut h = handle.borrow_mut();
+        h.trim();
+        let cursor = match h.cursor.take() {
+            Some(x) => x,
+            None => {
+                for name in [".", ".."] {
+                    h.add(NameT {
+                        name: name.to_string(),
+                        kind: Itype::Dir,
+                        off: Dentry::cookie(name),
+                    });
+                }
+                String::new()
+            }
+        };
 
-        while let Some(i) = iter.next() {
-            let de = bincode::deserialize::<Dentry>(&i).expect("can't deserialize dentry");
-            let inode = self.load_inode(de.ino).expect("can't load inode");
-            handle.borrow_mut().add(NameT {
+        let mut batch = self.meta.scan_prefix_from(&prefix, &cursor, DENTRY_BATCH);
+        let full = batch.len() == DENTRY_BATCH;
+        // inline entries are merged in key order, those after a full batch are left to the next one
+        match self.inline_dentries(ino) {
+            Err(e) => log::error!("can't load entries of dir {}, error {}", ino, e),
+            Ok(inline) => {
+                let last = batch.last().map(|(k, _)| k.clone());
+                for de in inline.unwrap_or_default() {
+                    let key = de.key();
+                    if key <= cursor || full && last.as_ref().is_some_and(|l| key > *l) {
+                        continue;
+                    }
+                    if let Err(i) = batch.binary_search_by(|(k, _)| k.cmp(&key)) {
+                        batch.insert(i, (key, de.val()));
+                    }
+                }
+            }
+        }
+        h.eof = !full;
+        h.cursor = Some(batch.last().map_or(cursor, |(k, _)| k.clone()));
+        // broken entries are left out, they can't be looked up either
+        for (key, v) in batch {
+            let Some(de) = decode_record::<Dentry>(Tag::Dentry, &key, &v) else {
+                continue;
+            };
+            let Some(inode) = self.load_inode(de.ino) else {
+                log::error!("dentry {} of dir {} points to missing ino {}", de.name, ino, de.ino);
+                continue;
+            };
+            h.add(NameT {
+                off: Dentry::cookie(&de.name),
                 name: de.name,
                 kind: inode.kind,
             });
         }
     }
 
+    /// move `handle` right after the entry of `ino` whose cookie is `off`, it's usually the last one taken, which is
+    /// still loaded, otherwise, e.g. after `seekdir`, the entries are loaded from the first batch on until it's found,
+    /// the scan resumes after its key then, an entry removed meanwhile can't be found, the listing starts over
+    pub fn seek_dentry(&self, ino: Ino, handle: &Rc<RefCell<DirHandle>>, off: i64) {
+        if handle.borrow_mut().seek(off) {
+            return;
+        }
+        handle.borrow_mut().seek(0);
+        loop {
+            self.load_dentry(ino, handle);
+            let mut h = handle.borrow_mut();
+            if h.seek(off) {
+                return;
+            }
+            if h.eof {
+                break;
+            }
+            h.skip_batch();
+        }
+        log::warn!("dir {} has no entry of offset {}, list it from the start", ino, off);
+        handle.borrow_mut().seek(0);
+    }
+
     pub fn dentry_exist(&self, ino: Ino, name: impl AsRef<str>) -> bool {
-        let name = Dentry::key(ino, name.as_ref());
-        self.meta.contains_key(&name).expect("can't find key")
+        let key = Dentry::key(ino, name.as_ref());
+        // when in doubt, say yes, so it's never overwritten
+        if self.meta.contains_key(&key).unwrap_or(true) {
+            return true;
+        }
+        self.inline_dentries(ino)
+            .map_or(true, |v| v.is_some_and(|v| v.iter().any(|d| d.name == name.as_ref())))
     }
 
-    /// if `key` exist, we can overwrite it
-    pub fn store_dentry(&mut self, parent: Ino, name: impl AsRef<str>, ino: Ino) -> Result<(), String> {
+    /// if `key` exist, we can overwrite it, an entry of a directory of at most `INLINE_DIR_MAX` entries goes to its
+    /// inline key, which is split into a key per entry once it's exceeded
+    pub fn store_dentry(&mut self, parent: Ino, name: impl AsRef<str>, ino: Ino) -> Result<(), MetaError> {
         let key = Dentry::key(parent, name.as_ref());
         if self.meta.contains_key(&key).is_err() {
             log::error!("dentry existed {}", key);
-            return Err(format!("key {key} exists"));
+            return Err(MetaError::Store(format!("key {key} exists")));
+        }
+        if fault::hit(Fault::StoreDentry) {
+            return Err(MetaError::Store("injected store_dentry fault".to_string()));
         }
         log::info!("store_dentry {}", key);
         let de = Dentry::new(parent, ino, name.as_ref());
-        let r = self.meta.insert(&key, &de.val());
-        if r.is_err() {
-            log::error!("insert key {} vaule {} fail", key, ino);
-            return Err(r.err().unwrap().to_string());
+        let inline = self.inline_dentries(parent)?;
+        if inline.is_none() && self.has_dentry_keys(parent) {
+            let r = self.meta.insert(&key, &de.val());
+            if r.is_err() {
+                log::error!("insert key {} vaule {} fail", key, ino);
+                return Err(r.err().unwrap().into());
+            }
+            return Ok(());
         }
-        Ok(())
+        let mut entries = inline.unwrap_or_default();
+        entries.retain(|d| d.name != de.name);
+        let pos = entries.partition_point(|d| d.name < de.name);
+        entries.insert(pos, de);
+        if entries.len() <= INLINE_DIR_MAX {
+            return self.store_inline(parent, &entries);
+        }
+        // the entries get keys of their own before the inline key is gone, a crash in between leaves them twice
+        log::info!("split dir {} of {} entries", parent, entries.len());
+        for d in &entries {
+            self.meta.insert(&d.key(), &d.val())?;
+        }
+        self.store_inline(parent, &[])
     }
 
-    pub fn delete_key(&mut self, key: &String) -> Result<(), String> {
+    pub fn delete_key(&mut self, key: &String) -> Result<(), MetaError> {
+        self.uncache_inode(key);
         let r = self.meta.remove(key);
         match r {
             Err(e) => {
                 log::error!("can't remove {} error {}", key, e);
-                Err(e.to_string())
+                Err(e.into())
             }
             Ok(_) => Ok(()),
         }
     }
 }
+
+impl Drop for Meta {
+    fn drop(&mut self) {
+        let _ = self.flush_atime();
+        if self.sb_dirty.get() != 0 {
+            let _ = self.flush_sb();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{load_sb, Ino, DENTRY_BATCH, INLINE_DIR_MAX};
+    use crate::meta::dentry::Dentry;
+    use crate::meta::mem::MemMetaStore;
+    use crate::meta::meta_store::{MetaIter, MetaStore};
+    use crate::meta::record::{self, Tag};
+    use crate::meta::super_block::{DirLimits, Layout, SuperBlock, FS_VERSION};
+    use crate::meta::{DirHandle, Inode, Itype, Meta, MetaError, MetaKV};
+    use crate::utils::fault::{self, Fault};
+    use crate::utils::{FS_BLK_SIZE, FS_ROOT_INODE};
+    use libc::{PATH_MAX, S_IFDIR, S_IFREG};
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    fn new_meta(path: &str) -> Meta {
+        let meta = format!("{}/meta", path);
+        let data = format!("{}/data", path);
+        let _ = std::fs::remove_dir_all(path);
+        std::fs::create_dir_all(&data).unwrap();
+        Meta::format(&meta, &data, false).unwrap();
+        let mut m = Meta::load_fs(meta, true).unwrap();
+        m.mknod(0, "/", Itype::Dir, 0o755).unwrap();
+        m
+    }
+
+    /// for tests which don't reopen the store
+    fn mem_meta() -> Meta {
+        let mut m = Meta::in_memory("/tmp");
+        m.mknod(0, "/", Itype::Dir, 0o755).unwrap();
+        m
+    }
+
+    #[test]
+    fn test_unlink_rmdir() {
+        let mut m = mem_meta();
+        let dir = m.mknod(1, "dir", Itype::Dir, 0o755).unwrap();
+        m.mknod(1, "file", Itype::File, 0o644).unwrap();
+        m.mknod(dir.id, "child", Itype::File, 0o644).unwrap();
+
+        assert_eq!(m.rmdir(1, "file").err(), Some(MetaError::NotDir));
+        assert_eq!(m.unlink(1, "dir").err(), Some(MetaError::IsDir));
+        assert_eq!(m.rmdir(1, "dir").err(), Some(MetaError::NotEmpty));
+
+        m.unlink(dir.id, "child").unwrap();
+        m.rmdir(1, "dir").unwrap();
+        m.unlink(1, "file").unwrap();
+        assert_eq!(m.unlink(1, "file").err(), Some(MetaError::NotFound));
+        assert_eq!(m.rmdir(1, "dir").err(), Some(MetaError::NotFound));
+        assert!(m.lookup(1, "dir").is_none());
+        assert_eq!(m.get_inode(dir.id).err(), Some(MetaError::NotFound));
+        assert_eq!(m.get_inode(12345).err(), Some(MetaError::NotFound));
+        assert_eq!(m.get_inode(1).unwrap().kind, Itype::Dir);
+    }
+
+    /// a store counting the writes reaching it, a batch fails if `fail` is set
+    struct CountedStore {
+        inner: MemMetaStore,
+        writes: Rc<Cell<u64>>,
+        fail: Rc<Cell<bool>>,
+    }
+
+    impl MetaStore for CountedStore {
+        fn insert(&self, key: &str, val: &[u8]) -> Result<(), String> {
+            self.writes.set(self.writes.get() + 1);
+            self.inner.insert(key, val)
+        }
+
+        fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+            self.inner.get(key)
+        }
+
+        fn scan_prefix(&self, prefix: &str) -> MetaIter {
+            self.inner.scan_prefix(prefix)
+        }
+
+        fn scan_prefix_from(&self, prefix: &str, start: &str, limit: usize) -> Vec<(String, Vec<u8>)> {
+            self.inner.scan_prefix_from(prefix, start, limit)
+        }
+
+        fn remove(&self, key: &str) -> Result<(), String> {
+            self.writes.set(self.writes.get() + 1);
+            self.inner.remove(key)
+        }
+
+        fn apply(&self, ops: &[(String, Option<Vec<u8>>)]) -> Result<(), String> {
+            if self.fail.get() {
+                return Err("injected batch fault".to_string());
+            }
+            self.writes.set(self.writes.get() + 1);
+            self.inner.apply(ops)
+        }
+
+        fn contains_key(&self, key: &str) -> Result<bool, String> {
+            self.inner.contains_key(key)
+        }
+
+        fn flush(&self) {}
+
+        fn compact(&mut self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    fn counted_meta(writes: &Rc<Cell<u64>>, fail: &Rc<Cell<bool>>) -> Meta {
+        let sb = SuperBlock::new("/tmp", Layout::Block);
+        let store = MemMetaStore::new();
+        store.insert(&SuperBlock::key(), &sb.val()).unwrap();
+        let store = CountedStore {
+            inner: store,
+            writes: writes.clone(),
+            fail: fail.clone(),
+        };
+        let mut m = Meta::open(Box::new(store), sb);
+        m.mknod(0, "/", Itype::Dir, 0o755).unwrap();
+        m
+    }
+
+    #[test]
+    fn test_unlink_batch() {
+        let (writes, fail) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(false)));
+        let mut m = counted_meta(&writes, &fail);
+        let dir = m.mknod(1, "dir", Itype::Dir, 0o755).unwrap().id;
+        let names: Vec<String> = (0..40).map(|i| format!("f{}", i)).collect();
+        let inos: Vec<Ino> = names
+            .iter()
+            .map(|n| m.mknod(dir, n, Itype::File, 0o644).unwrap().id)
+            .collect();
+
+        let w = writes.get();
+        for n in &names[..20] {
+            m.unlink(dir, n).unwrap();
+        }
+        let single = writes.get() - w;
+        assert!(single >= 40, "{} writes", single);
+
+        // nothing is written if the batch fails, and what's in memory is rolled back
+        let rest: Vec<&str> = names[20..].iter().map(|s| s.as_str()).collect();
+        let used = m.sb.used_inodes();
+        fail.set(true);
+        assert!(m.unlink_batch(dir, &rest).is_err());
+        fail.set(false);
+        assert!(m.lookup(dir, "f20").is_some());
+        assert_eq!(m.get_inode(dir).unwrap().length, 20);
+        assert_eq!(m.sb.used_inodes(), used);
+
+        // the superblock written before the batch and the batch
+        let w = writes.get();
+        let mut with_missing = rest.clone();
+        with_missing.push("missing");
+        let r = m.unlink_batch(dir, &with_missing).unwrap();
+        assert_eq!(writes.get() - w, 2);
+        assert!(r[..20].iter().all(|x| x.is_ok()));
+        assert_eq!(r[20].as_ref().err(), Some(&MetaError::NotFound));
+        assert!(m.dir_empty(dir));
+        assert_eq!(m.get_inode(dir).unwrap().length, 0);
+        assert!(inos.iter().all(|&i| m.get_inode(i).is_err()));
+        // the freed inos are in the stored superblock
+        assert_eq!(m.sb.used_inodes(), used - 20);
+        assert_eq!(load_sb(&*m.meta, &SuperBlock::key()).unwrap().used_inodes(), used - 20);
+    }
+
+    #[test]
+    fn test_clear_dir() {
+        let mut m = mem_meta();
+        let top = m.mknod(1, "top", Itype::Dir, 0o755).unwrap().id;
+        let sub = m.mknod(top, "sub", Itype::Dir, 0o755).unwrap().id;
+        let deep = m.mknod(sub, "deep", Itype::Dir, 0o755).unwrap().id;
+        m.mknod(top, "empty", Itype::Dir, 0o755).unwrap();
+        let mut files = Vec::new();
+        for (dir, n) in [(top, 30), (sub, 3), (deep, 12)] {
+            for i in 0..n {
+                files.push(m.mknod(dir, format!("f{}", i), Itype::File, 0o644).unwrap().id);
+            }
+        }
+        m.symlink(deep, "link", b"f0").unwrap();
+        let keep = m.mknod(1, "keep", Itype::File, 0o644).unwrap().id;
+
+        let mut removed = Vec::new();
+        m.clear_dir(top, &mut removed).unwrap();
+        let mut ids: Vec<Ino> = removed.iter().map(|i| i.id).collect();
+        ids.sort();
+        assert_eq!(ids, files);
+        assert!(m.dir_empty(top));
+        let inode = m.get_inode(top).unwrap();
+        assert_eq!((inode.length, inode.links), (0, 2));
+        assert!(m.get_inode(sub).is_err() && m.get_inode(deep).is_err());
+        assert!(m.get_inode(keep).is_ok());
+        m.rmdir(1, "top").unwrap();
+
+        let mut removed = Vec::new();
+        assert_eq!(m.clear_dir(keep, &mut removed).err(), Some(MetaError::NotDir));
+        assert_eq!(m.clear_dir(top, &mut removed).err(), Some(MetaError::NotFound));
+    }
+
+    #[test]
+    fn test_reap_orphans() {
+        let mut m = mem_meta();
+        let keep = m.mknod(1, "keep", Itype::File, 0o644).unwrap().id;
+        let file = m.mknod(1, "file", Itype::File, 0o644).unwrap().id;
+        let dir = m.mknod(1, "dir", Itype::Dir, 0o755).unwrap().id;
+        let sub = m.mknod(dir, "sub", Itype::Dir, 0o755).unwrap().id;
+        let lost = m.mknod(sub, "lost", Itype::File, 0o644).unwrap().id;
+        assert!(m.orphans().unwrap().is_empty());
+
+        // a crash between removing the entries and the inodes
+        m.orphan(1, "file").unwrap();
+        m.orphan(1, "dir").unwrap();
+        let used = m.sb.used_inodes();
+        let mut ids: Vec<Ino> = m.orphans().unwrap().iter().map(|i| i.id).collect();
+        ids.sort();
+        assert_eq!(ids, [file, dir, sub, lost]);
+
+        assert_eq!(m.reap_orphans().unwrap().len(), 4);
+        assert_eq!(m.sb.used_inodes(), used - 4);
+        for ino in [file, dir, sub, lost] {
+            assert!(m.load_inode(ino).is_none());
+        }
+        assert!(m.meta.get(&Dentry::inline_key(dir)).unwrap().is_none());
+        assert!(m.get_inode(keep).is_ok());
+        assert!(m.orphans().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dir_limits() {
+        let mut m = mem_meta();
+        m.sb.set_limits(DirLimits {
+            max_links: 5,
+            max_entries: 6,
+        });
+        let links = |m: &Meta, ino| m.get_inode(ino).unwrap().links;
+        assert_eq!(links(&m, 1), 2);
+        let a = m.mknod(1, "a", Itype::Dir, 0o755).unwrap();
+        assert_eq!((links(&m, 1), a.links), (3, 2));
+        m.mknod(1, "b", Itype::Dir, 0o755).unwrap();
+        m.mknod(1, "c", Itype::Dir, 0o755).unwrap();
+        assert_eq!(links(&m, 1), 5);
+        assert_eq!(m.mknod(1, "d", Itype::Dir, 0o755).err(), Some(MetaError::TooManyLinks));
+        assert!(m.lookup(1, "d").is_none());
+
+        // files don't take links, only entries
+        m.mknod(1, "f", Itype::File, 0o644).unwrap();
+        m.symlink(1, "l", b"f").unwrap();
+        m.mknod(1, "g", Itype::File, 0o644).unwrap();
+        assert_eq!(m.get_inode(1).unwrap().length, 6);
+        assert_eq!(m.mknod(1, "h", Itype::File, 0o644).err(), Some(MetaError::DirFull));
+
+        // moving a directory moves its link, renaming within the directory adds nothing
+        m.rename(1, "c", a.id, "c", 0).unwrap();
+        assert_eq!((links(&m, 1), links(&m, a.id)), (4, 3));
+        m.rename(1, "f", 1, "f2", 0).unwrap();
+        m.mknod(1, "d", Itype::Dir, 0o755).unwrap();
+        assert_eq!(m.rename(a.id, "c", 1, "c", 0).err(), Some(MetaError::TooManyLinks));
+        m.mknod(a.id, "x", Itype::File, 0o644).unwrap();
+        assert_eq!(m.rename(a.id, "x", 1, "x", 0).err(), Some(MetaError::DirFull));
+        m.rmdir(1, "d").unwrap();
+        m.rmdir(1, "b").unwrap();
+        assert_eq!(links(&m, 1), 3);
+        m.rename(a.id, "c", 1, "c", 0).unwrap();
+        assert_eq!((links(&m, 1), links(&m, a.id)), (4, 2));
+    }
+
+    #[test]
+    fn test_mode() {
+        let mut m = mem_meta();
+        let mut inode = m.mknod(1, "file", Itype::File, S_IFREG | 0o644).unwrap();
+        assert_eq!(inode.mode, 0o644);
+
+        // chmod can't change file type
+        inode.set_mode(S_IFDIR | 0o4755);
+        m.store_inode(&inode).unwrap();
+        let inode = m.load_inode(inode.id).unwrap();
+        assert_eq!(inode.mode, 0o4755);
+        assert_eq!(inode.kind, Itype::File);
+    }
+
+    #[test]
+    fn test_foreign_store() {
+        let path = "/tmp/test_meta_foreign";
+        let _ = std::fs::remove_dir_all(path);
+        {
+            let db = sled::open(path).unwrap();
+            db.insert("foo", "bar").unwrap();
+            db.flush().unwrap();
+        }
+        assert_eq!(Meta::load_fs(path.to_string(), true).err().unwrap(), "not formated");
+
+        let _ = std::fs::remove_dir_all(path);
+        {
+            let db = sled::open(path).unwrap();
+            db.insert("sb", "definitely not a superblock").unwrap();
+            db.flush().unwrap();
+        }
+        assert_eq!(
+            Meta::load_fs(path.to_string(), true).err().unwrap(),
+            "not a junkfs filesystem"
+        );
+    }
+
+    #[test]
+    fn test_sb_backup() {
+        let path = "/tmp/test_meta_sb_backup";
+        let meta = format!("{}/meta", path);
+        let mut m = new_meta(path);
+        let mut files = Vec::new();
+        for i in 0..3 {
+            files.push(m.mknod(1, format!("f{}", i), Itype::File, 0o644).unwrap().id);
+        }
+        m.flush_sb().unwrap();
+        // the backup lags behind, it was written at format
+        assert_ne!(m.load("sb"), m.load("sb_backup"));
+        assert!(m.check().is_empty());
+
+        // a torn primary, the inode map of the backup is rebuilt, so live inos aren't handed out again
+        let mut sb = m.load("sb").unwrap();
+        let n = sb.len();
+        sb.truncate(n / 2);
+        m.store("sb", &sb);
+        drop(m);
+        let mut m = Meta::load_fs(meta.clone(), true).unwrap();
+        assert_eq!(m.sb.used_inodes(), 1 + files.len() as u64);
+        assert!(m.lookup(1, "f2").is_some());
+        let x = m.mknod(1, "x", Itype::File, 0o644).unwrap();
+        assert!(!files.contains(&x.id));
+        assert!(m.check().is_empty());
+        m.flush_sb().unwrap();
+
+        // a lost primary, and then a lost backup, which is written again on load
+        m.delete_key(&"sb".to_string()).unwrap();
+        drop(m);
+        let mut m = Meta::load_fs(meta.clone(), true).unwrap();
+        assert!(m.load("sb").is_some());
+        m.delete_key(&"sb_backup".to_string()).unwrap();
+        assert_eq!(m.check(), vec!["backup superblock is missing".to_string()]);
+        drop(m);
+        let mut m = Meta::load_fs(meta.clone(), true).unwrap();
+        assert!(m.check().is_empty());
+
+        // both gone
+        m.delete_key(&"sb".to_string()).unwrap();
+        m.store("sb_backup", b"garbage");
+        drop(m);
+        assert_eq!(Meta::load_fs(meta, true).err().unwrap(), "not formated");
+    }
+
+    #[test]
+    fn test_copy_store() {
+        let path = "/tmp/test_meta_copy_store";
+        let src = format!("{}/meta", path);
+        let dst = format!("{}/copy", path);
+        let mut m = new_meta(path);
+        let dir = m.mknod(1, "dir", Itype::Dir, 0o755).unwrap();
+        // more keys than a batch
+        for i in 0..600 {
+            m.mknod(dir.id, format!("f{}", i), Itype::File, 0o644).unwrap();
+        }
+        let l = m.symlink(1, "link", b"dir/f1").unwrap();
+        m.set_xattr(dir.id, "user.k", b"v", 0).unwrap();
+        m.close();
+        drop(m);
+
+        assert!(Meta::copy_store(&format!("{}/none", path), &dst, false).is_err());
+        assert!(Meta::copy_store(&src, &src, true).unwrap_err().contains("same"));
+        let n = Meta::copy_store(&src, &dst, false).unwrap();
+        assert!(n > 1200);
+        assert!(Meta::copy_store(&src, &dst, false).unwrap_err().contains("--force"));
+        assert_eq!(Meta::copy_store(&src, &dst, true).unwrap(), n);
+
+        // the copy mounts as the same filesystem and the source is left as it was
+        for p in [&dst, &src] {
+            let mut m = Meta::load_fs(p.clone(), true).unwrap();
+            assert!(m.check().is_empty());
+            assert_eq!(m.lookup(1, "dir").unwrap().id, dir.id);
+            assert!(m.lookup(dir.id, "f599").is_some());
+            assert_eq!(m.readlink(l.id).unwrap(), b"dir/f1");
+            assert_eq!(m.get_xattr(dir.id, "user.k").unwrap(), b"v");
+            assert!(m.mknod(1, "new", Itype::File, 0o644).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let path = "/tmp/test_meta_check";
+        let mut m = new_meta(path);
+        assert!(m.check().is_empty());
+
+        // root is reserved since format, a missing one only counts once other inodes exist
+        m.delete_key(&"i_1".to_string()).unwrap();
+        assert!(m.check().is_empty());
+        m.mknod(1, "f", Itype::File, 0o644).unwrap();
+        assert_eq!(m.check(), vec!["root inode is missing".to_string()]);
+
+        std::fs::remove_dir_all(format!("{}/data", path)).unwrap();
+        let issues = m.check();
+        assert_eq!(issues.len(), 2);
+        assert!(issues[1].contains("is inaccessible"));
+    }
+
+    fn dir_size(path: &str) -> u64 {
+        let mut sz = 0;
+        for e in std::fs::read_dir(path).unwrap().flatten() {
+            let m = e.metadata().unwrap();
+            sz += if m.is_dir() { dir_size(e.path().to_str().unwrap()) } else { m.len() };
+        }
+        sz
+    }
+
+    #[test]
+    fn test_compact() {
+        let path = "/tmp/test_meta_compact";
+        let meta = format!("{}/meta", path);
+        let mut m = new_meta(path);
+        m.mknod(1, "keep", Itype::File, 0o644).unwrap();
+        // plain keys, a mknod per entry rewrites the whole inode map
+        let val = vec![7u8; 256];
+        for i in 0..20000 {
+            m.store(&format!("x_{}", i), &val);
+        }
+        for i in 0..20000 {
+            m.delete_key(&format!("x_{}", i)).unwrap();
+        }
+        m.close();
+        let before = dir_size(&meta);
+
+        m.compact().unwrap();
+        let after = dir_size(&meta);
+        assert!(after < before, "before {} after {}", before, after);
+        assert!(!std::path::Path::new(&format!("{}.old", meta)).exists());
+
+        // still usable and data survived
+        assert!(m.lookup(1, "keep").is_some());
+        assert!(m.load("x_0").is_none());
+        m.mknod(1, "new", Itype::File, 0o644).unwrap();
+        drop(m);
+        let mut m = Meta::load_fs(meta, true).unwrap();
+        assert!(m.lookup(1, "new").is_some());
+        assert!(m.lookup(1, "keep").is_some());
+    }
+
+    #[test]
+    fn test_compact_fail() {
+        let path = "/tmp/test_meta_compact_fail";
+        let meta = format!("{}/meta", path);
+        let mut m = new_meta(path);
+        m.mknod(1, "keep", Itype::File, 0o644).unwrap();
+
+        // the live store was moved away, the copy can't be moved in
+        fault::arm(Fault::SwapStore, 0);
+        assert!(m.compact().is_err());
+        fault::disarm(Fault::SwapStore);
+        assert!(!std::path::Path::new(&format!("{}.old", meta)).exists());
+        assert!(m.lookup(1, "keep").is_some());
+        m.mknod(1, "after_swap", Itype::File, 0o644).unwrap();
+
+        // the copy can't be made
+        std::fs::write(format!("{}.compact", meta), b"").unwrap();
+        assert!(m.compact().is_err());
+        m.mknod(1, "after_copy", Itype::File, 0o644).unwrap();
+        let _ = std::fs::remove_file(format!("{}.compact", meta));
+
+        drop(m);
+        let mut m = Meta::load_fs(meta, true).unwrap();
+        for name in ["keep", "after_swap", "after_copy"] {
+            assert!(m.lookup(1, name).is_some(), "{}", name);
+        }
+    }
+
+    fn list(m: &Meta, ino: u64) -> Vec<String> {
+        let h = Rc::new(RefCell::new(DirHandle::new(0)));
+        let mut v = Vec::new();
+        while !h.borrow().done() {
+            m.load_dentry(ino, &h);
+            while let Some(e) = h.borrow_mut().next() {
+                v.push(e.name.clone());
+            }
+        }
+        v.sort();
+        v
+    }
+
+    #[test]
+    fn test_dentry_scheme() {
+        let path = "/tmp/test_meta_dentry";
+        let meta = format!("{}/meta", path);
+        let mut m = new_meta(path);
+        // make parents 1 and 12 and names sharing their digits
+        let mut dir = None;
+        for i in 0..11 {
+            dir = Some(m.mknod(1, format!("{}_", i), Itype::Dir, 0o755).unwrap());
+        }
+        let dir = dir.unwrap();
+        assert_eq!(dir.id, 12);
+        m.mknod(12, "x", Itype::File, 0o644).unwrap();
+        m.mknod(1, "2_x", Itype::File, 0o644).unwrap();
+        m.mknod(12, "_", Itype::File, 0o644).unwrap();
+
+        assert_eq!(list(&m, 12), vec![".", "..", "_", "x"]);
+        assert_eq!(list(&m, 1).len(), 2 + 11 + 1);
+        assert!(m.lookup(1, "2_x").is_some());
+        assert!(m.lookup(12, "x").is_some());
+        assert!(m.lookup(1, "2").is_none());
+        assert_eq!(m.rmdir(1, "10_").err(), Some(MetaError::NotEmpty));
+        m.unlink(12, "x").unwrap();
+        m.unlink(12, "_").unwrap();
+        m.rmdir(1, "10_").unwrap();
+        m.rmdir(1, "9_").unwrap();
+
+        for v0 in [false, true] {
+            // rewrite as version 1 with legacy keys and bare records, it's migrated on load, so is a superblock written
+            // before it had a magic and a version, whose records are the ones of version 1
+            let mut dentries = Vec::new();
+            let mut inodes = Vec::new();
+            {
+                // the iterator keeps the db open
+                let mut it = m.meta.scan_prefix(Dentry::all_prefix());
+                while let Some(v) = it.next() {
+                    dentries.push(record::decode::<Dentry>(Tag::Dentry, &v).unwrap());
+                }
+                let mut it = m.meta.scan_prefix(Inode::prefix());
+                while let Some(v) = it.next() {
+                    inodes.push(record::decode::<Inode>(Tag::Inode, &v).unwrap());
+                }
+            }
+            for de in &dentries {
+                m.delete_key(&de.key()).unwrap();
+                m.store(&de.legacy_key(), &bincode::serialize(de).unwrap());
+            }
+            for inode in &inodes {
+                m.store(&Inode::key(inode.id), &bincode::serialize(inode).unwrap());
+            }
+            m.flush_sb().unwrap();
+            let sb = if v0 {
+                m.sb.val_v0()
+            } else {
+                let mut sb = bincode::serialize(&m.sb).unwrap();
+                sb[8..12].copy_from_slice(&1u32.to_le_bytes());
+                sb
+            };
+            m.store("sb", &sb);
+            drop(m);
+
+            m = Meta::load_fs(meta.clone(), true).unwrap();
+            assert_eq!(m.sb.version(), FS_VERSION);
+            assert_eq!(list(&m, 1).len(), 2 + 9 + 1);
+            // links of directories are counted
+            assert_eq!(m.get_inode(1).unwrap().links, 2 + 9);
+            assert_eq!(m.get_inode(2).unwrap().links, 2);
+            assert!(m.lookup(1, "2_x").is_some());
+            assert!(m.lookup(0, "/").is_some());
+            assert!(m.load(&dentries[0].legacy_key()).is_none());
+            assert!(record::open(Tag::Inode, &m.load(&Inode::key(2)).unwrap()).is_ok());
+            assert!(record::open(Tag::Dentry, &m.load(&dentries[0].key()).unwrap()).is_ok());
+            assert!(record::open(Tag::SuperBlock, &m.load("sb").unwrap()).is_ok());
+        }
+        drop(m);
+        assert!(Meta::load_fs(meta, true).is_ok());
+    }
+
+    #[test]
+    fn test_repair() {
+        let path = "/tmp/test_meta_repair";
+        let meta = format!("{}/meta", path);
+        let mut m = new_meta(path);
+        let a = m.mknod(1, "a", Itype::File, 0o644).unwrap();
+        let b = m.mknod(1, "b", Itype::File, 0o644).unwrap();
+        assert_eq!(m.repair(false).unwrap(), (0, 0));
+
+        // crashed after the inode map was flushed but before the inodes, or the other way around
+        m.sb.free_ino(b.id);
+        m.sb.alloc_ino().unwrap();
+        m.sb.alloc_ino().unwrap();
+        m.flush_sb().unwrap();
+        assert_eq!(m.repair(false).unwrap(), (1, 0));
+        assert_eq!(m.repair(true).unwrap(), (1, 0));
+        assert_eq!(m.repair(false).unwrap(), (0, 0));
+
+        m.sb.free_ino(a.id);
+        m.flush_sb().unwrap();
+        assert_eq!(m.repair(false).unwrap(), (0, 1));
+        m.meta.remove(&SuperBlock::backup_key()).unwrap();
+        drop(m);
+
+        // a load for reading writes nothing, e.g. `fsck` without `--repair` still reports it
+        let mut m = Meta::load_fs(meta.clone(), false).unwrap();
+        assert_eq!(m.repair(false).unwrap(), (0, 1));
+        m.close();
+        drop(m);
+        let mut m = Meta::load_fs(meta.clone(), false).unwrap();
+        assert_eq!(m.repair(false).unwrap(), (0, 1));
+        assert!(m.backup_issue().is_some());
+        // as `fsck --repair` does
+        assert_eq!(m.repair(true).unwrap(), (0, 1));
+        assert!(m.backup_issue().is_none());
+        assert_eq!(m.repair(false).unwrap(), (0, 0));
+        drop(m);
+
+        // repaired on load, live inos are never handed out again
+        let mut m = Meta::load_fs(meta, true).unwrap();
+        assert_eq!(m.repair(false).unwrap(), (0, 0));
+        let c = m.mknod(1, "c", Itype::File, 0o644).unwrap();
+        assert!(c.id != a.id && c.id != b.id);
+    }
+
+    #[test]
+    fn test_symlink() {
+        let path = "/tmp/test_meta_symlink";
+        let meta = format!("{}/meta", path);
+        let mut m = new_meta(path);
+        let file = m.mknod(1, "file", Itype::File, 0o644).unwrap();
+
+        let long: Vec<u8> = (0..PATH_MAX as usize - 1).map(|i| b"abc/"[i % 4]).collect();
+        let abs = m.symlink(1, "abs", b"/tmp/x").unwrap();
+        let rel = m.symlink(1, "rel", &long).unwrap();
+        assert_eq!(rel.kind, Itype::Symlink);
+        assert_eq!(rel.length, long.len() as u64);
+
+        assert_eq!(m.symlink(1, "abs", b"y").err(), Some(MetaError::Exists));
+        assert_eq!(
+            m.symlink(1, "big", &vec![b'a'; PATH_MAX as usize]).err(),
+            Some(MetaError::NameTooLong)
+        );
+        assert_eq!(m.symlink(1, "empty", b"").err(), Some(MetaError::NotFound));
+        assert_eq!(m.readlink(file.id).err(), Some(MetaError::Invalid));
+        drop(m);
+
+        let mut m = Meta::load_fs(meta, true).unwrap();
+        assert_eq!(m.readlink(abs.id).unwrap(), b"/tmp/x");
+        assert_eq!(m.readlink(rel.id).unwrap(), long);
+        assert_eq!(m.get_inode(rel.id).unwrap().length, long.len() as u64);
+
+        m.unlink(1, "rel").unwrap();
+        assert_eq!(m.readlink(rel.id).err(), Some(MetaError::NotFound));
+        assert!(m.load(&Inode::link_key(rel.id)).is_none());
+    }
+
+    #[test]
+    fn test_dir_size() {
+        let mut m = mem_meta();
+        let size = |m: &Meta, ino| m.get_inode(ino).unwrap().length;
+        assert_eq!(size(&m, 1), 0);
+
+        let dir = m.mknod(1, "dir", Itype::Dir, 0o755).unwrap();
+        m.mknod(1, "file", Itype::File, 0o644).unwrap();
+        m.symlink(1, "link", b"file").unwrap();
+        assert_eq!(size(&m, 1), 3);
+        assert_eq!(size(&m, dir.id), 0);
+
+        m.mknod(dir.id, "a", Itype::File, 0o644).unwrap();
+        m.mknod(dir.id, "b", Itype::File, 0o644).unwrap();
+        assert_eq!(size(&m, dir.id), 2);
+        // failed operations leave the size alone
+        assert!(m.mknod(dir.id, "a", Itype::File, 0o644).is_err());
+        assert!(m.rmdir(1, "dir").is_err());
+        assert_eq!(size(&m, dir.id), 2);
+        assert_eq!(size(&m, 1), 3);
+
+        m.unlink(dir.id, "a").unwrap();
+        m.unlink(dir.id, "b").unwrap();
+        assert_eq!(size(&m, dir.id), 0);
+        m.rmdir(1, "dir").unwrap();
+        m.unlink(1, "link").unwrap();
+        assert_eq!(size(&m, 1), 1);
+    }
+
+    #[test]
+    fn test_lazy_sb() {
+        let path = "/tmp/test_meta_lazy_sb";
+        let meta = format!("{}/meta", path);
+        let mut m = new_meta(path);
+        m.flush_sb().unwrap();
+        let stale = m.load("sb").unwrap();
+
+        let mut files = Vec::new();
+        for i in 0..10 {
+            files.push(m.mknod(1, format!("f{}", i), Itype::File, 0o644).unwrap());
+        }
+        assert_eq!(m.sb_dirty.get(), 10);
+        assert_eq!(m.load("sb").unwrap(), stale);
+        for i in 10..super::SB_FLUSH_INTERVAL {
+            m.mknod(1, format!("f{}", i), Itype::File, 0o644).unwrap();
+        }
+        assert_eq!(m.sb_dirty.get(), 0);
+        assert_ne!(m.load("sb").unwrap(), stale);
+        m.sync().unwrap();
+
+        // crash before the superblock is written
+        m.store("sb", &stale);
+        m.sb_dirty.set(0);
+        drop(m);
+        let mut m = Meta::load_fs(meta, true).unwrap();
+        let x = m.mknod(1, "x", Itype::File, 0o644).unwrap();
+        assert!(files.iter().all(|f| f.id != x.id));
+    }
+
+    #[test]
+    fn test_atime_batch() {
+        let path = "/tmp/test_meta_atime_batch";
+        let meta = format!("{}/meta", path);
+        let mut m = new_meta(path);
+        let f = m.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let stored = |m: &Meta| {
+            record::decode::<Inode>(Tag::Inode, &m.load(&Inode::key(f.id)).unwrap())
+                .unwrap()
+                .atime
+        };
+
+        let before = crate::utils::stats::snapshot().atime_writes;
+        for i in 1..=100 {
+            m.touch_atime(f.id, f.atime + i);
+            assert_eq!(m.load_inode(f.id).unwrap().atime, f.atime + i);
+        }
+        assert_eq!(stored(&m), f.atime);
+        m.sync().unwrap();
+        assert_eq!(crate::utils::stats::snapshot().atime_writes - before, 1);
+        assert_eq!(stored(&m), f.atime + 100);
+
+        // pending atime survives an unrelated inode update and unmount
+        m.touch_atime(f.id, f.atime + 200);
+        let mut inode = m.load_inode(f.id).unwrap();
+        inode.mode = 0o600;
+        m.store_inode(&inode).unwrap();
+        assert!(m.atimes.borrow().is_empty());
+        m.touch_atime(f.id, f.atime + 300);
+        drop(m);
+        let m = Meta::load_fs(meta, true).unwrap();
+        let inode = m.load_inode(f.id).unwrap();
+        assert_eq!((inode.atime, inode.mode & 0o777), (f.atime + 300, 0o600));
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut m = mem_meta();
+        let a = m.mknod(1, "a", Itype::Dir, 0o755).unwrap();
+        let b = m.mknod(a.id, "b", Itype::Dir, 0o755).unwrap();
+        let f = m.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let g = m.mknod(1, "g", Itype::File, 0o644).unwrap();
+
+        assert_eq!(m.rename(1, "x", 1, "y", 0).unwrap_err(), MetaError::NotFound);
+        assert_eq!(m.rename(1, "a", b.id, "a", 0).unwrap_err(), MetaError::Invalid);
+        assert_eq!(m.rename(1, "a", a.id, "a", 0).unwrap_err(), MetaError::Invalid);
+        assert_eq!(m.rename(1, "f", f.id, "f", 0).unwrap_err(), MetaError::NotDir);
+        assert_eq!(m.rename(1, "f", a.id, "b", 0).unwrap_err(), MetaError::IsDir);
+        assert_eq!(m.rename(1, "a", 1, "f", 0).unwrap_err(), MetaError::NotDir);
+        assert_eq!(
+            m.rename(1, "f", 1, "g", libc::RENAME_NOREPLACE).unwrap_err(),
+            MetaError::Exists
+        );
+        assert_eq!(
+            m.rename(1, "f", 1, "g", libc::RENAME_EXCHANGE).unwrap_err(),
+            MetaError::Invalid
+        );
+
+        let age = |m: &mut Meta, ino: Ino| {
+            let mut inode = m.get_inode(ino).unwrap();
+            inode.mtime = 1;
+            inode.ctime = 1;
+            m.store_inode(&inode).unwrap();
+        };
+        for ino in [1, a.id, b.id, f.id] {
+            age(&mut m, ino);
+        }
+
+        // the ino, and so the data, moves along with the name
+        assert!(m.rename(1, "f", b.id, "f2", 0).unwrap().is_none());
+        // both directories are modified, the file is changed but not modified
+        for ino in [1, b.id] {
+            let dir = m.get_inode(ino).unwrap();
+            assert!(dir.mtime > 1 && dir.ctime > 1);
+        }
+        let moved = m.get_inode(f.id).unwrap();
+        assert_eq!((moved.mtime, moved.ctime > 1), (1, true));
+        assert_eq!(m.get_inode(a.id).unwrap().mtime, 1);
+
+        // within a directory too
+        age(&mut m, b.id);
+        age(&mut m, f.id);
+        m.rename(b.id, "f2", b.id, "f3", 0).unwrap();
+        m.rename(b.id, "f3", b.id, "f2", 0).unwrap();
+        assert!(m.get_inode(b.id).unwrap().mtime > 1);
+        let moved = m.get_inode(f.id).unwrap();
+        assert_eq!((moved.mtime, moved.ctime > 1), (1, true));
+        assert!(m.lookup(1, "f").is_none());
+        let moved = m.lookup(b.id, "f2").unwrap();
+        assert_eq!((moved.id, moved.parent), (f.id, b.id));
+        assert_eq!(m.load_inode(1).unwrap().length, 2);
+        assert_eq!(m.load_inode(b.id).unwrap().length, 1);
+
+        // replace an existing file
+        let old = m.rename(b.id, "f2", 1, "g", 0).unwrap().unwrap();
+        assert_eq!(old.id, g.id);
+        assert!(m.load_inode(g.id).is_none());
+        assert_eq!(m.lookup(1, "g").unwrap().id, f.id);
+        assert_eq!(m.load_inode(1).unwrap().length, 2);
+
+        // an empty directory can be replaced by a directory
+        let c = m.mknod(1, "c", Itype::Dir, 0o755).unwrap();
+        assert_eq!(m.rename(1, "c", 1, "a", 0).unwrap_err(), MetaError::NotEmpty);
+        m.rename(a.id, "b", 1, "b", 0).unwrap();
+        assert_eq!(m.rename(1, "c", 1, "a", 0).unwrap().unwrap().id, a.id);
+        assert_eq!(m.lookup(1, "a").unwrap().id, c.id);
+        assert!(m.rename(1, "a", 1, "a", 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_corrupted_value() {
+        let mut m = new_meta("/tmp/test_meta_corrupted_value");
+        let dir = m.mknod(1, "dir", Itype::Dir, 0o755).unwrap();
+        let f = m.mknod(dir.id, "f", Itype::File, 0o644).unwrap();
+        m.mknod(dir.id, "g", Itype::File, 0o644).unwrap();
+        m.mknod(dir.id, "h", Itype::File, 0o644).unwrap();
+        // entries have keys of their own
+        for i in 0..INLINE_DIR_MAX {
+            m.mknod(dir.id, format!("i{}", i), Itype::File, 0o644).unwrap();
+        }
+
+        // truncated inode, garbage dentry and a dentry to nowhere
+        let ival = m.load(&Inode::key(f.id)).unwrap();
+        m.store(&Inode::key(f.id), &ival[..ival.len() / 2]);
+        m.store(&Dentry::key(dir.id, "g"), b"garbage");
+        m.store(&Dentry::key(dir.id, "x"), &Dentry::new(dir.id, 4096, "x").val());
+
+        assert!(m.load_inode(f.id).is_none());
+        assert_eq!(m.get_inode(f.id).unwrap_err(), MetaError::NotFound);
+        assert!(m.lookup(dir.id, "f").is_none());
+        assert!(m.lookup(dir.id, "g").is_none());
+        assert!(m.lookup(dir.id, "x").is_none());
+        assert_eq!(m.unlink(dir.id, "f").unwrap_err(), MetaError::NotFound);
+
+        let mut expect = vec![".".to_string(), "..".to_string(), "h".to_string()];
+        expect.extend((0..INLINE_DIR_MAX).map(|i| format!("i{}", i)));
+        assert_eq!(list(&m, dir.id), expect);
+
+        // a garbage inline key loses the entries of a small directory, which is then never taken as empty
+        let small = m.mknod(1, "small", Itype::Dir, 0o755).unwrap();
+        m.mknod(small.id, "a", Itype::File, 0o644).unwrap();
+        m.store(&Dentry::inline_key(small.id), b"garbage");
+        assert!(m.lookup(small.id, "a").is_none());
+        assert_eq!(list(&m, small.id), vec![".", ".."]);
+        assert_eq!(m.rmdir(1, "small").unwrap_err(), MetaError::NotEmpty);
+    }
+
+    #[test]
+    fn test_protect_root() {
+        let mut m = mem_meta();
+        let dir = m.mknod(1, "dir", Itype::Dir, 0o755).unwrap();
+        m.mknod(dir.id, "f", Itype::File, 0o644).unwrap();
+
+        assert_eq!(m.rmdir(dir.id, ".").unwrap_err(), MetaError::Invalid);
+        assert_eq!(m.rmdir(dir.id, "..").unwrap_err(), MetaError::NotEmpty);
+        assert_eq!(m.unlink(dir.id, ".").unwrap_err(), MetaError::IsDir);
+        assert_eq!(m.unlink(1, "..").unwrap_err(), MetaError::IsDir);
+        assert_eq!(m.rename(dir.id, ".", 1, "x", 0).unwrap_err(), MetaError::Busy);
+        assert_eq!(m.rename(1, "dir", dir.id, "..", 0).unwrap_err(), MetaError::Busy);
+
+        // root is the entry "/" of the imaginary parent 0
+        assert_eq!(m.rmdir(0, "/").unwrap_err(), MetaError::Busy);
+        assert_eq!(m.unlink(0, "/").unwrap_err(), MetaError::IsDir);
+        assert!(m.load_inode(FS_ROOT_INODE).is_some());
+
+        // reserved slots are never freed nor handed out
+        m.sb.free_ino(0);
+        m.sb.free_ino(FS_ROOT_INODE);
+        for _ in 0..4 {
+            let ino = m.sb.alloc_ino().unwrap();
+            assert!(ino > FS_ROOT_INODE);
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut m = mem_meta();
+        let f = m.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let l = m.symlink(1, "l", b"f").unwrap();
+
+        assert_eq!(m.resolve(1, "f").unwrap().id, f.id);
+        assert_eq!(m.resolve(1, "x").unwrap_err(), MetaError::NotFound);
+        assert_eq!(m.resolve(f.id, "child").unwrap_err(), MetaError::NotDir);
+        assert_eq!(m.resolve(l.id, "child").unwrap_err(), MetaError::NotDir);
+        assert_eq!(m.resolve(4096, "child").unwrap_err(), MetaError::NotFound);
+    }
+
+    #[test]
+    fn test_prewarm() {
+        let path = "/tmp/test_meta_prewarm";
+        let meta = format!("{}/meta", path);
+        let mut m = new_meta(path);
+        let dir = m.mknod(1, "dir", Itype::Dir, 0o755).unwrap();
+        m.mknod(dir.id, "f", Itype::File, 0o644).unwrap();
+        m.mknod(1, "g", Itype::File, 0o644).unwrap();
+        drop(m);
+
+        // cold
+        let mut m = Meta::load_fs(meta.clone(), true).unwrap();
+        let (_, misses) = m.meta.cache_stats();
+        m.lookup(dir.id, "f").unwrap();
+        assert!(m.meta.cache_stats().1 > misses);
+        drop(m);
+
+        let mut m = Meta::load_fs(meta, true).unwrap();
+        // root, the inline keys of root and dir and the inodes of the entries
+        assert_eq!(m.prewarm(), 6);
+        let (hits, misses) = m.meta.cache_stats();
+        m.lookup(1, "dir").unwrap();
+        m.lookup(dir.id, "f").unwrap();
+        m.lookup(1, "g").unwrap();
+        // inodes are served by the decoded inode cache, dentries by the read cache of the store
+        assert_eq!(m.meta.cache_stats(), (hits + 3, misses));
+    }
+
+    #[test]
+    fn test_reformat() {
+        let path = "/tmp/test_meta_reformat";
+        let meta = format!("{}/meta", path);
+        let data = format!("{}/data", path);
+        let mut m = new_meta(path);
+        m.mknod(1, "file", Itype::File, 0o644).unwrap();
+        drop(m);
+
+        let e = Meta::format(&meta, &data, false).err().unwrap();
+        assert!(e.contains("a filesystem of 2 inodes"), "{}", e);
+        let mut m = Meta::load_fs(meta.clone(), true).unwrap();
+        assert!(m.lookup(1, "file").is_some());
+        drop(m);
+
+        Meta::format(&meta, &data, true).unwrap();
+        let mut m = Meta::load_fs(meta.clone(), true).unwrap();
+        assert!(m.load_inode(1).is_none());
+        assert!(m.lookup(1, "file").is_none());
+        assert_eq!(m.sb.used_inodes(), 1);
+        drop(m);
+
+        // any non-empty store is protected, not only a formatted one
+        let other = format!("{}/other", path);
+        {
+            let db = sled::open(&other).unwrap();
+            db.insert("foo", "bar").unwrap();
+            db.flush().unwrap();
+        }
+        let e = Meta::format(&other, &data, false).err().unwrap();
+        assert!(e.contains("1 unknown keys"), "{}", e);
+    }
+
+    #[test]
+    fn test_in_memory() {
+        let mut m = mem_meta();
+        assert!(m.check().is_empty());
+        let dir = m.mknod(1, "dir", Itype::Dir, 0o755).unwrap();
+        let f = m.mknod(dir.id, "f", Itype::File, 0o644).unwrap();
+        let l = m.symlink(1, "l", b"dir/f").unwrap();
+        assert_eq!(m.mknod(dir.id, "f", Itype::File, 0o644).err(), Some(MetaError::Exists));
+
+        assert_eq!(m.lookup(dir.id, "f").unwrap().id, f.id);
+        assert_eq!(m.resolve(f.id, "x").unwrap_err(), MetaError::NotDir);
+        assert_eq!(m.readlink(l.id).unwrap(), b"dir/f");
+        assert_eq!(list(&m, 1), vec![".", "..", "dir", "l"]);
+        assert_eq!(list(&m, dir.id), vec![".", "..", "f"]);
+
+        let mut inode = m.load_inode(f.id).unwrap();
+        inode.length = 4096;
+        m.store_inode(&inode).unwrap();
+        assert_eq!(m.get_inode(f.id).unwrap().length, 4096);
+
+        // a dentry of parent 1 must not show up under parent 12 or the other way round
+        let mut parent = dir.id;
+        while parent < 12 {
+            parent = m.mknod(1, format!("d{}", parent), Itype::Dir, 0o755).unwrap().id;
+        }
+        m.mknod(parent, "x", Itype::File, 0o644).unwrap();
+        assert_eq!(list(&m, parent), vec![".", "..", "x"]);
+        assert_eq!(list(&m, 1).len(), 4 + (parent - l.id) as usize);
+
+        m.rename(dir.id, "f", 1, "f", 0).unwrap();
+        assert_eq!(list(&m, dir.id), vec![".", ".."]);
+        m.rmdir(1, "dir").unwrap();
+        m.unlink(1, "f").unwrap();
+        m.unlink(1, "l").unwrap();
+        assert!(m.lookup(1, "f").is_none());
+        assert!(m.load(&Inode::link_key(l.id)).is_none());
+
+        // nothing leaked, the inode map matches the inodes
+        assert_eq!(m.repair(false).unwrap(), (0, 0));
+        m.sync().unwrap();
+        m.compact().unwrap();
+        assert!(m.check().is_empty());
+        assert_eq!(m.lookup(parent, "x").unwrap().parent, parent);
+    }
+
+    #[test]
+    fn test_scan_prefix_from() {
+        let mut m = new_meta("/tmp/test_meta_scan_prefix_from");
+        let dir = m.mknod(1, "dir", Itype::Dir, 0o755).unwrap();
+        for i in 0..100 {
+            m.mknod(dir.id, format!("f{:03}", i), Itype::File, 0o644).unwrap();
+        }
+        m.mknod(1, "g", Itype::File, 0o644).unwrap();
+
+        let prefix = Dentry::prefix(dir.id);
+        let mut start = String::new();
+        let mut names = Vec::new();
+        loop {
+            let page = m.meta.scan_prefix_from(&prefix, &start, 7);
+            assert!(page.len() <= 7);
+            let Some((last, _)) = page.last() else {
+                break;
+            };
+            start = last.clone();
+            for (k, v) in page {
+                assert!(k.starts_with(&prefix));
+                names.push(record::decode::<Dentry>(Tag::Dentry, &v).unwrap().name);
+            }
+        }
+        let expect: Vec<String> = (0..100).map(|i| format!("f{:03}", i)).collect();
+        assert_eq!(names, expect);
+        assert!(m.meta.scan_prefix_from(&Dentry::prefix(4096), "", 7).is_empty());
+    }
+
+    #[test]
+    fn test_inline_dir() {
+        let mut m = mem_meta();
+        let d = m.mknod(1, "d", Itype::Dir, 0o755).unwrap();
+        let other = m.mknod(1, "other", Itype::Dir, 0o755).unwrap();
+        let names: Vec<String> = (0..INLINE_DIR_MAX + 1).map(|i| format!("f{}", i)).collect();
+        let inline = |m: &Meta| m.inline_dentries(d.id).unwrap().map(|v| v.len());
+        let expect = |n: usize| {
+            let mut v = vec![".".to_string(), "..".to_string()];
+            v.extend(names[names.len() - n..].iter().cloned());
+            v
+        };
+
+        for name in &names[..INLINE_DIR_MAX] {
+            m.mknod(d.id, name, Itype::File, 0o644).unwrap();
+        }
+        assert_eq!(inline(&m), Some(INLINE_DIR_MAX));
+        assert!(!m.has_dentry_keys(d.id));
+        assert!(m.dentry_exist(d.id, "f0") && !m.dentry_exist(d.id, "f8"));
+        assert_eq!(m.mknod(d.id, "f0", Itype::File, 0o644).unwrap_err(), MetaError::Exists);
+
+        // split
+        m.mknod(d.id, &names[INLINE_DIR_MAX], Itype::File, 0o644).unwrap();
+        assert_eq!(inline(&m), None);
+        assert_eq!(
+            m.meta.scan_prefix_from(&Dentry::prefix(d.id), "", 100).len(),
+            INLINE_DIR_MAX + 1
+        );
+        assert_eq!(list(&m, d.id), expect(INLINE_DIR_MAX + 1));
+        assert!(names.iter().all(|x| m.lookup(d.id, x).is_some()));
+
+        // merged once it's down to half
+        let mut left = names.len();
+        while left > INLINE_DIR_MAX / 2 + 1 {
+            m.unlink(d.id, &names[names.len() - left]).unwrap();
+            left -= 1;
+            assert_eq!(inline(&m), None);
+        }
+        m.unlink(d.id, &names[names.len() - left]).unwrap();
+        left -= 1;
+        assert_eq!(inline(&m), Some(INLINE_DIR_MAX / 2));
+        assert!(!m.has_dentry_keys(d.id));
+        assert_eq!(list(&m, d.id), expect(left));
+        assert!(m.lookup(d.id, &names[0]).is_none());
+        assert!(names[names.len() - left..].iter().all(|x| m.lookup(d.id, x).is_some()));
+        assert_eq!(m.get_inode(d.id).unwrap().length, left as u64);
+
+        // an entry left twice by a crash is listed once
+        let last = names.last().unwrap();
+        let de = Dentry::new(d.id, m.lookup(d.id, last).unwrap().id, last);
+        m.store(&de.key(), &de.val());
+        assert_eq!(list(&m, d.id), expect(left));
+
+        // moved out and removed, both forms are gone
+        m.rename(d.id, last, other.id, "x", 0).unwrap();
+        assert!(m.lookup(d.id, last).is_none());
+        assert!(m.lookup(other.id, "x").is_some());
+        assert_eq!(m.rmdir(1, "d").unwrap_err(), MetaError::NotEmpty);
+        for name in &names[names.len() - left..names.len() - 1] {
+            m.unlink(d.id, name).unwrap();
+        }
+        assert_eq!(inline(&m), None);
+        assert!(!m.has_dentry_keys(d.id));
+        m.rmdir(1, "d").unwrap();
+    }
+
+    #[test]
+    fn test_dentry_batch() {
+        let mut m = mem_meta();
+        let n = DENTRY_BATCH * 2 + 10;
+        for i in 0..n {
+            m.mknod(1, format!("f{:05}", i), Itype::File, 0o644).unwrap();
+        }
+        let names = list(&m, 1);
+        assert_eq!(names.len(), n + 2);
+        assert_eq!(names[2], "f00000");
+        assert_eq!(names[n + 1], format!("f{:05}", n - 1));
+
+        // a handle never holds more than a batch, going back reloads from the start
+        let h = Rc::new(RefCell::new(DirHandle::new(0)));
+        m.load_dentry(1, &h);
+        assert_eq!(h.borrow().entry.len(), DENTRY_BATCH + 2);
+        let last = h.borrow().entry.last().unwrap().off;
+        assert!(h.borrow_mut().seek(last));
+        assert!(h.borrow().need_load());
+        m.load_dentry(1, &h);
+        assert_eq!(h.borrow().entry.len(), DENTRY_BATCH);
+        assert_eq!(h.borrow().peek().unwrap().name, format!("f{:05}", DENTRY_BATCH));
+        assert!(!h.borrow_mut().seek(Dentry::cookie(".")));
+        m.seek_dentry(1, &h, Dentry::cookie("."));
+        assert_eq!(h.borrow().entry.len(), DENTRY_BATCH + 2);
+        assert_eq!(h.borrow().peek().unwrap().name, "..");
+    }
+
+    #[test]
+    fn test_dentry_cookie() {
+        let mut m = mem_meta();
+        let n = DENTRY_BATCH * 2 + 10;
+        for i in 0..n {
+            m.mknod(1, format!("f{:05}", i), Itype::File, 0o644).unwrap();
+        }
+        let name = |i: usize| format!("f{:05}", i);
+        // what `readdir` does, take `count` entries after offset `off`
+        let read = |m: &Meta, h: &Rc<RefCell<DirHandle>>, off: i64, count: usize| {
+            m.seek_dentry(1, h, off);
+            let mut v = Vec::new();
+            while v.len() < count {
+                if h.borrow().need_load() {
+                    m.load_dentry(1, h);
+                }
+                let mut h = h.borrow_mut();
+                let Some(e) = h.next() else {
+                    break;
+                };
+                v.push((e.name.clone(), e.off));
+            }
+            v
+        };
+
+        let h = Rc::new(RefCell::new(DirHandle::new(0)));
+        let first = read(&m, &h, 0, 12);
+        assert_eq!(first[2].0, name(0));
+        let off = first.last().unwrap().1;
+
+        // entries before the offset are removed, including the last one taken, the listing goes on after it
+        for i in 0..10 {
+            m.unlink(1, &name(i)).unwrap();
+        }
+        let next = read(&m, &h, off, 3);
+        assert_eq!(next[0].0, name(10));
+
+        // a fresh handle, as after `seekdir`, finds the entry by its cookie in a later batch
+        let off = Dentry::cookie(&name(DENTRY_BATCH + 5));
+        let h = Rc::new(RefCell::new(DirHandle::new(0)));
+        let rest = read(&m, &h, off, n);
+        let expect: Vec<_> = (DENTRY_BATCH + 6..n).map(name).collect();
+        assert_eq!(rest.iter().map(|x| x.0.clone()).collect::<Vec<_>>(), expect);
+        assert!(h.borrow().done());
+
+        // the entry of the offset is gone, the listing starts over
+        m.unlink(1, &name(DENTRY_BATCH + 5)).unwrap();
+        let h = Rc::new(RefCell::new(DirHandle::new(0)));
+        assert_eq!(read(&m, &h, off, 1)[0].0, ".");
+    }
+
+    #[test]
+    fn test_dentry_modify_listing() {
+        let mut m = mem_meta();
+        let n = DENTRY_BATCH + 100;
+        for i in 0..n {
+            m.mknod(1, format!("f{:05}", i), Itype::File, 0o644).unwrap();
+        }
+        let h = Rc::new(RefCell::new(DirHandle::new(0)));
+        let mut seen = Vec::new();
+        m.load_dentry(1, &h);
+        for _ in 0..DENTRY_BATCH / 2 {
+            seen.push(h.borrow_mut().next().unwrap().name.clone());
+        }
+
+        // remove listed and unlisted entries, add some before and after the cursor, and rename across it
+        m.unlink(1, "f00000").unwrap();
+        m.unlink(1, &format!("f{:05}", n - 1)).unwrap();
+        m.mknod(1, "a", Itype::File, 0o644).unwrap();
+        m.mknod(1, "z", Itype::File, 0o644).unwrap();
+        m.rename(1, "f00001", 1, "y", 0).unwrap();
+
+        while !h.borrow().done() {
+            if h.borrow().need_load() {
+                m.load_dentry(1, &h);
+            }
+            while let Some(e) = h.borrow_mut().next() {
+                seen.push(e.name.clone());
+            }
+        }
+
+        let mut sorted = seen.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), seen.len(), "duplicated entries");
+        // in name order after the dots
+        assert!(seen[2..].windows(2).all(|x| x[0] < x[1]));
+        for i in 2..n - 1 {
+            assert!(sorted.binary_search(&format!("f{:05}", i)).is_ok(), "f{:05} skipped", i);
+        }
+        assert!(!seen.contains(&"a".to_string()));
+        assert!(!seen.contains(&format!("f{:05}", n - 1)));
+        assert!(seen.contains(&"z".to_string()));
+    }
+
+    #[test]
+    fn test_inode_cache() {
+        let mut m = mem_meta();
+        let f = m.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let key = Inode::key(f.id);
+        assert!(m.inodes.borrow_mut().get(&key).is_some());
+
+        // written through, never stale
+        let mut inode = m.load_inode(f.id).unwrap();
+        inode.length = 100;
+        m.store_inode(&inode).unwrap();
+        assert_eq!(m.inodes.borrow_mut().get(&key).unwrap().length, 100);
+        assert_eq!(m.get_inode(f.id).unwrap().length, 100);
+
+        // a raw write drops the entry
+        m.store(&key, b"garbage");
+        assert!(m.inodes.borrow_mut().get(&key).is_none());
+        assert!(m.load_inode(f.id).is_none());
+        m.store_inode(&inode).unwrap();
+
+        m.unlink(1, "f").unwrap();
+        assert!(m.inodes.borrow_mut().get(&key).is_none());
+        assert!(m.load_inode(f.id).is_none());
+
+        // disabled
+        m.set_inode_cache(0);
+        let g = m.mknod(1, "g", Itype::File, 0o644).unwrap();
+        assert_eq!(m.get_inode(g.id).unwrap().id, g.id);
+        assert_eq!(m.inodes.borrow().len(), 0);
+    }
+
+    #[test]
+    fn test_fault_injection() {
+        let mut m = mem_meta();
+        // every load has to decode
+        m.set_inode_cache(0);
+        let f = m.mknod(1, "f", Itype::File, 0o644).unwrap();
+
+        // a failed mknod leaves neither the inode nor the dentry behind
+        for point in [Fault::StoreInode, Fault::StoreDentry] {
+            fault::arm(point, 0);
+            let e = m.mknod(1, "x", Itype::File, 0o644).unwrap_err();
+            assert!(matches!(&e, MetaError::Store(x) if x.contains("injected")), "{:?}", e);
+            assert_eq!(e.errno(), libc::EIO);
+            fault::disarm(point);
+            assert!(m.lookup(1, "x").is_none());
+            assert_eq!(m.repair(false).unwrap(), (0, 0));
+        }
+
+        // an entry which can't be decoded is missing, not fatal
+        fault::arm(Fault::Deserialize, 0);
+        assert_eq!(m.unlink(1, "f").err(), Some(MetaError::NotFound));
+        assert_eq!(list(&m, 1), vec![".", ".."]);
+        fault::disarm(Fault::Deserialize);
+        assert_eq!(list(&m, 1), vec![".", "..", "f"]);
+        assert_eq!(m.unlink(1, "f").unwrap().id, f.id);
+
+        fault::arm(Fault::Flush, 0);
+        assert!(m.sync().is_err());
+        fault::disarm(Fault::Flush);
+        m.sync().unwrap();
+
+        // the nth hit fails, the listing loads the dentry and then the inode of each entry
+        m.mknod(1, "a", Itype::File, 0o644).unwrap();
+        m.mknod(1, "b", Itype::File, 0o644).unwrap();
+        fault::arm(Fault::Deserialize, 2);
+        assert_eq!(list(&m, 1), vec![".", "..", "a"]);
+        fault::disarm(Fault::Deserialize);
+        assert_eq!(m.get_inode(1).unwrap().length, 2);
+    }
+
+    #[test]
+    fn test_corrupt_record() {
+        let mut m = mem_meta();
+        m.set_inode_cache(0);
+        let f = m.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let g = m.mknod(1, "g", Itype::File, 0o644).unwrap();
+
+        // a flipped bit in an inode, an inode stored under the inline key of dentries and a truncated dentry
+        let mut v = m.load(&Inode::key(f.id)).unwrap();
+        let n = v.len();
+        v[n - 3] ^= 0x10;
+        m.store(&Inode::key(f.id), &v);
+        assert_eq!(m.get_inode(f.id).err(), Some(MetaError::NotFound));
+        assert!(m.lookup(1, "f").is_none());
+        assert_eq!(m.repair(false).err().unwrap(), "corrupt record, checksum mismatch");
+        let inode = m.load(&Inode::key(g.id)).unwrap();
+        m.store(&Dentry::inline_key(1), &inode);
+        assert!(m.lookup(1, "g").is_none());
+        m.store(&Dentry::key(1, "h"), &[Tag::Dentry as u8, 1, 2]);
+        assert_eq!(list(&m, 1), vec![".", ".."]);
+
+        // others are untouched
+        assert_eq!(m.get_inode(g.id).unwrap().id, g.id);
+        assert_eq!(m.get_inode(1).unwrap().kind, Itype::Dir);
+    }
+
+    #[test]
+    fn test_block_refs() {
+        let mut m = mem_meta();
+        let mut files = Vec::new();
+        for name in ["a", "b", "c"] {
+            let mut f = m.mknod(1, name, Itype::File, 0o644).unwrap();
+            f.length = 2 * FS_BLK_SIZE;
+            m.store_inode(&f).unwrap();
+            files.push(f.id);
+        }
+        let (a, b, c) = (files[0], files[1], files[2]);
+        assert_eq!(m.block_refs(a, 1), 1);
+        assert!(!m.is_shared(a, 1));
+
+        m.share_block(a, b, 1).unwrap();
+        m.share_block(b, c, 1).unwrap();
+        for &ino in &files {
+            assert_eq!(m.block_refs(ino, 1), 3);
+            assert_eq!(m.block_refs(ino, 0), 1);
+        }
+
+        // a private copy leaves the others sharing
+        m.unshare_block(b, 1).unwrap();
+        assert!(!m.is_shared(b, 1));
+        assert_eq!((m.block_refs(a, 1), m.block_refs(c, 1)), (2, 2));
+
+        // removing a file drops its references, the last one isn't shared anymore
+        m.unlink(1, "c").unwrap();
+        assert_eq!(m.block_refs(a, 1), 1);
+        assert!(!m.is_shared(a, 1) && !m.is_shared(c, 1));
+    }
+
+    #[test]
+    fn test_xattr() {
+        let mut m = mem_meta();
+        let f = m.mknod(1, "f", Itype::File, 0o644).unwrap();
+        let g = m.mknod(1, "g", Itype::File, 0o644).unwrap();
+        assert_eq!(m.get_xattr(f.id, "user.a"), Err(MetaError::NoAttr));
+        m.set_xattr(f.id, "user.a", b"1", 0).unwrap();
+        m.set_xattr(f.id, "user.b", b"", 0).unwrap();
+        m.set_xattr(g.id, "user.c", b"3", 0).unwrap();
+        assert_eq!(m.get_xattr(f.id, "user.a").unwrap(), b"1");
+        assert_eq!(m.get_xattr(f.id, "user.b").unwrap(), b"");
+        assert_eq!(m.list_xattr(f.id), vec!["user.a", "user.b"]);
+
+        assert_eq!(
+            m.set_xattr(f.id, "user.a", b"2", libc::XATTR_CREATE),
+            Err(MetaError::Exists)
+        );
+        assert_eq!(
+            m.set_xattr(f.id, "user.z", b"2", libc::XATTR_REPLACE),
+            Err(MetaError::NoAttr)
+        );
+        m.set_xattr(f.id, "user.a", b"2", libc::XATTR_REPLACE).unwrap();
+        assert_eq!(m.get_xattr(f.id, "user.a").unwrap(), b"2");
+        assert_eq!(m.set_xattr(f.id, &"n".repeat(256), b"", 0), Err(MetaError::AttrName));
+        assert_eq!(
+            m.set_xattr(f.id, "user.big", &vec![0u8; 65537], 0),
+            Err(MetaError::AttrValue)
+        );
+        assert_eq!(m.set_xattr(1000, "user.a", b"", 0), Err(MetaError::NotFound));
+
+        m.remove_xattr(f.id, "user.a").unwrap();
+        assert_eq!(m.remove_xattr(f.id, "user.a"), Err(MetaError::NoAttr));
+        assert_eq!(m.list_xattr(f.id), vec!["user.b"]);
+
+        // gone with the file, the others are kept
+        m.unlink(1, "f").unwrap();
+        assert!(m.load(&Inode::xattr_key(f.id, "user.b")).is_none());
+        assert_eq!(m.list_xattr(g.id), vec!["user.c"]);
+    }
+
+    #[test]
+    fn test_verify_format() {
+        let path = "/tmp/test_verify_format";
+        let (meta, data) = (format!("{}/meta", path), format!("{}/data", path));
+        let _ = std::fs::remove_dir_all(path);
+
+        // nothing is created, the data path doesn't exist yet
+        let c = Meta::verify_format(&meta, &data, Layout::Packed, false);
+        assert!(!std::path::Path::new(&meta).exists());
+        assert!(c.existing.is_none());
+        assert_eq!(c.issues.len(), 1);
+        assert!(c.issues[0].contains("inaccessible"));
+        assert_eq!(
+            (c.version, c.layout, c.block_size),
+            (FS_VERSION, Layout::Packed, FS_BLK_SIZE)
+        );
+        assert_eq!(c.data_path, data);
+
+        // an existing filesystem is reported and left alone
+        let mut m = new_meta(path);
+        let f = m.mknod(1, "f", Itype::File, 0o644).unwrap();
+        drop(m);
+        let c = Meta::verify_format(&meta, &data, Layout::Block, false);
+        assert!(c.existing.as_ref().unwrap().contains("2 inodes"));
+        assert_eq!(c.issues.len(), 1);
+        assert!(c.issues[0].contains("--force"));
+        assert!(Meta::verify_format(&meta, &data, Layout::Block, true).issues.is_empty());
+
+        let mut m = Meta::load_fs(meta, true).unwrap();
+        assert_eq!(m.lookup(1, "f").unwrap().id, f.id);
+        assert_eq!(m.sb.used_inodes(), 2);
+    }
+}
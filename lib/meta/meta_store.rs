@@ -1,3 +1,8 @@
+use std::rc::Rc;
+
+/// `scan_prefix_from` of the whole store run by another thread, e.g. the worker of a `MirrorStore`
+pub type Scanner = Box<dyn Fn(&str, usize) -> Vec<(String, Vec<u8>)> + Send>;
+
 pub struct MetaIter {
     pub iter: Box<dyn Iterator<Item = Option<Vec<u8>>>>,
 }
@@ -9,11 +14,87 @@ pub trait MetaStore {
 
     fn scan_prefix(&self, prefix: &str) -> MetaIter;
 
+    /// at most `limit` keys and values with `prefix` in key order, after `start` or from the first one if `start` is
+    /// empty, so a big range can be walked in pages by passing the last key of the previous page
+    fn scan_prefix_from(&self, prefix: &str, start: &str, limit: usize) -> Vec<(String, Vec<u8>)>;
+
     fn remove(&self, key: &str) -> Result<(), String>;
 
+    /// insert or remove (a value of `None`) all of `ops`, a store that can do it atomically writes all or none
+    fn apply(&self, ops: &[(String, Option<Vec<u8>>)]) -> Result<(), String> {
+        for (k, v) in ops {
+            match v {
+                Some(v) => self.insert(k, v)?,
+                None => self.remove(k)?,
+            }
+        }
+        Ok(())
+    }
+
     fn contains_key(&self, key: &str) -> Result<bool, String>;
 
     fn flush(&self);
+
+    /// rewrite live data to reclaim space left by deleted keys
+    fn compact(&mut self) -> Result<(), String>;
+
+    /// hits and misses of the read cache in front of the store, if any
+    fn cache_stats(&self) -> (u64, u64) {
+        (0, 0)
+    }
+
+    /// a `Scanner` of the store, which sees the writes made after it's taken, a store only one thread can read has
+    /// none
+    fn scanner(&self) -> Option<Scanner> {
+        None
+    }
+}
+
+/// a store shared with whoever gets it back afterwards, e.g. the `BatchStore` of `Meta::batch`
+impl<T: MetaStore> MetaStore for Rc<T> {
+    fn insert(&self, key: &str, val: &[u8]) -> Result<(), String> {
+        (**self).insert(key, val)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        (**self).get(key)
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> MetaIter {
+        (**self).scan_prefix(prefix)
+    }
+
+    fn scan_prefix_from(&self, prefix: &str, start: &str, limit: usize) -> Vec<(String, Vec<u8>)> {
+        (**self).scan_prefix_from(prefix, start, limit)
+    }
+
+    fn remove(&self, key: &str) -> Result<(), String> {
+        (**self).remove(key)
+    }
+
+    fn apply(&self, ops: &[(String, Option<Vec<u8>>)]) -> Result<(), String> {
+        (**self).apply(ops)
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool, String> {
+        (**self).contains_key(key)
+    }
+
+    fn flush(&self) {
+        (**self).flush()
+    }
+
+    fn compact(&mut self) -> Result<(), String> {
+        Rc::get_mut(self).ok_or("store is shared".to_string())?.compact()
+    }
+
+    fn scanner(&self) -> Option<Scanner> {
+        (**self).scanner()
+    }
+
+    fn cache_stats(&self) -> (u64, u64) {
+        (**self).cache_stats()
+    }
 }
 
 impl MetaIter {
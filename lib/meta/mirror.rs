@@ -0,0 +1,319 @@
+use crate::meta::meta::COPY_BATCH;
+use crate::meta::meta_store::{MetaIter, MetaStore, Scanner};
+use crate::meta::sled::SledStore;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// writes waiting for the mirror, once it's full the mirror is dropped rather than waited for
+const MIRROR_QUEUE: usize = 4096;
+
+/// what the worker of a `MirrorStore` does to the mirror
+enum Op {
+    /// insert or remove (a value of `None`) all of them in a single batch
+    Apply(Vec<(String, Option<Vec<u8>>)>),
+    Flush,
+    /// the wrapped store was compacted, the first copy, if not done, goes on from the start with this scanner
+    Rescan(Scanner),
+}
+
+fn apply(db: &sled::Db, ops: &[(String, Option<Vec<u8>>)]) -> sled::Result<()> {
+    let mut batch = sled::Batch::default();
+    for (k, v) in ops {
+        match v {
+            Some(v) => batch.insert(k.as_str(), v.as_slice()),
+            None => batch.remove(k.as_str()),
+        }
+    }
+    db.apply_batch(batch)
+}
+
+/// copy the wrapped store with `scan` a batch at a time while applying the writes queued meanwhile, a key copied
+/// holds its latest value and a write queued is applied after it reached the wrapped store, so once the copy is done
+/// the mirror matches the wrapped store, stop once the queue is closed and the copy done, or as soon as `stale` is set
+///
+/// a mirror missing writes is cleared, so it's never taken for a good copy
+fn mirror(path: &str, rx: Receiver<Op>, mut scan: Scanner, stale: &AtomicBool) {
+    let db = match SledStore::open(path).and_then(|db| db.clear().map(|_| db)) {
+        Err(e) => {
+            log::error!("can't open mirror {} error {}, metadata is not mirrored", path, e);
+            return;
+        }
+        Ok(db) => db,
+    };
+    // the last key copied, `None` once the copy is done
+    let mut cursor = Some(String::new());
+    while !stale.load(Ordering::Relaxed) {
+        let op = match cursor {
+            // queued writes first, a closed queue doesn't stop the copy
+            Some(_) => rx.try_recv().ok(),
+            None => match rx.recv() {
+                Ok(op) => Some(op),
+                Err(_) => break,
+            },
+        };
+        let r = match op {
+            Some(Op::Apply(ops)) => apply(&db, &ops),
+            Some(Op::Flush) => db.flush().map(|_| ()),
+            Some(Op::Rescan(x)) => {
+                scan = x;
+                if cursor.is_some() {
+                    cursor = Some(String::new());
+                }
+                Ok(())
+            }
+            None => {
+                let batch = scan(&cursor.take().unwrap_or_default(), COPY_BATCH);
+                if batch.len() == COPY_BATCH {
+                    cursor = batch.last().map(|(k, _)| k.clone());
+                } else {
+                    log::info!("mirror {} has a copy of the metadata", path);
+                }
+                apply(&db, &batch.into_iter().map(|(k, v)| (k, Some(v))).collect::<Vec<_>>())
+            }
+        };
+        if let Err(e) = r {
+            log::error!("can't write mirror {} error {}", path, e);
+        }
+    }
+    if stale.load(Ordering::Relaxed) {
+        log::error!("mirror {} missed writes, it's cleared", path);
+        if let Err(e) = db.clear() {
+            log::error!("can't clear mirror {} error {}", path, e);
+        }
+    }
+    if let Err(e) = db.flush() {
+        log::error!("can't flush mirror {} error {}", path, e);
+    }
+}
+
+/// a store writing to the wrapped one, and in background to a sled store at another path, e.g. on another disk, so
+/// it can be recovered from with `migrate` if the wrapped one is lost
+///
+/// a write is mirrored once the wrapped store took it, the mirror is never waited for and its failures are only
+/// logged, reads are served by the wrapped store alone, the mirror starts as a copy made in background, if it falls
+/// `MIRROR_QUEUE` writes behind it's dropped for the rest of the mount
+pub struct MirrorStore {
+    inner: Box<dyn MetaStore>,
+    tx: Option<SyncSender<Op>>,
+    stale: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl MirrorStore {
+    /// mirror `inner` to `path`, whatever it holds is replaced by a copy of `inner`
+    pub fn new(inner: Box<dyn MetaStore>, path: &str) -> Self {
+        match inner.scanner() {
+            None => {
+                log::error!(
+                    "can't read meta store from another thread, metadata is not mirrored to {}",
+                    path
+                );
+                Self {
+                    inner,
+                    tx: None,
+                    stale: Arc::new(AtomicBool::new(true)),
+                    worker: None,
+                }
+            }
+            Some(scan) => Self::spawn(inner, path, scan, MIRROR_QUEUE),
+        }
+    }
+
+    fn spawn(inner: Box<dyn MetaStore>, path: &str, scan: Scanner, queue: usize) -> Self {
+        let (tx, rx) = sync_channel::<Op>(queue);
+        let stale = Arc::new(AtomicBool::new(false));
+        let path = path.to_string();
+        let s = stale.clone();
+        let worker = std::thread::spawn(move || mirror(&path, rx, scan, &s));
+        Self {
+            inner,
+            tx: Some(tx),
+            stale,
+            worker: Some(worker),
+        }
+    }
+
+    /// a worker gone, e.g. the mirror can't be opened, is not an error of the wrapped store, a write which can't be
+    /// queued drops the mirror, a flush is only skipped
+    fn send(&self, op: Op) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        if self.stale.load(Ordering::Relaxed) {
+            return;
+        }
+        match tx.try_send(op) {
+            Err(TrySendError::Full(Op::Flush)) | Ok(_) | Err(TrySendError::Disconnected(_)) => {}
+            Err(TrySendError::Full(_)) => {
+                log::error!("mirror is {} writes behind, it's dropped", MIRROR_QUEUE);
+                self.stale.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl MetaStore for MirrorStore {
+    fn insert(&self, key: &str, val: &[u8]) -> Result<(), String> {
+        self.inner.insert(key, val)?;
+        self.send(Op::Apply(vec![(key.to_string(), Some(val.to_vec()))]));
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        self.inner.get(key)
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> MetaIter {
+        self.inner.scan_prefix(prefix)
+    }
+
+    fn scan_prefix_from(&self, prefix: &str, start: &str, limit: usize) -> Vec<(String, Vec<u8>)> {
+        self.inner.scan_prefix_from(prefix, start, limit)
+    }
+
+    fn remove(&self, key: &str) -> Result<(), String> {
+        self.inner.remove(key)?;
+        self.send(Op::Apply(vec![(key.to_string(), None)]));
+        Ok(())
+    }
+
+    fn apply(&self, ops: &[(String, Option<Vec<u8>>)]) -> Result<(), String> {
+        self.inner.apply(ops)?;
+        self.send(Op::Apply(ops.to_vec()));
+        Ok(())
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool, String> {
+        self.inner.contains_key(key)
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+        self.send(Op::Flush);
+    }
+
+    fn compact(&mut self) -> Result<(), String> {
+        // even a failed one may have replaced the store
+        let r = self.inner.compact();
+        match self.inner.scanner() {
+            Some(scan) => self.send(Op::Rescan(scan)),
+            None => self.stale.store(true, Ordering::Relaxed),
+        }
+        r
+    }
+
+    fn cache_stats(&self) -> (u64, u64) {
+        self.inner.cache_stats()
+    }
+}
+
+impl Drop for MirrorStore {
+    fn drop(&mut self) {
+        // close the queue and wait for the mirror to apply what's left of it, at most `MIRROR_QUEUE` writes, and to
+        // finish the first copy
+        drop(self.tx.take());
+        if let Some(w) = self.worker.take() {
+            let _ = w.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MirrorStore;
+    use crate::meta::mem::MemMetaStore;
+    use crate::meta::meta_store::MetaStore;
+    use crate::meta::sled::SledStore;
+    use std::rc::Rc;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_mirror_store() {
+        let path = "/tmp/test_mirror_store";
+        let _ = std::fs::remove_dir_all(path);
+        let db = SledStore::open(path).unwrap();
+        db.insert("stale", b"x").unwrap();
+        drop(db);
+
+        let inner = Rc::new(MemMetaStore::new());
+        for k in ["a", "b"] {
+            inner.insert(k, k.as_bytes()).unwrap();
+        }
+        // copied in several batches
+        for i in 0..3000 {
+            inner.insert(&format!("z{:04}", i), b"z").unwrap();
+        }
+        let m = MirrorStore::new(Box::new(inner.clone()), path);
+        m.insert("c", b"c").unwrap();
+        m.remove("a").unwrap();
+        m.apply(&[("d".to_string(), Some(b"d".to_vec())), ("b".to_string(), None)])
+            .unwrap();
+        m.flush();
+        assert_eq!(m.get("c").unwrap(), Some(b"c".to_vec()));
+        drop(m);
+
+        let keys = |v: Vec<(String, Vec<u8>)>| v.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+        let expect = keys(inner.scan_prefix_from("", "", 10000));
+        assert_eq!(expect.len(), 3002);
+        assert_eq!(expect[..2], ["c", "d"]);
+        let db = SledStore::open(path).unwrap();
+        let mirrored: Vec<_> = db
+            .iter()
+            .keys()
+            .map(|k| String::from_utf8(k.unwrap().to_vec()).unwrap())
+            .collect();
+        assert_eq!(mirrored, expect);
+    }
+
+    #[test]
+    fn test_mirror_behind() {
+        let path = "/tmp/test_mirror_behind";
+        let _ = std::fs::remove_dir_all(path);
+        let inner = Rc::new(MemMetaStore::new());
+        inner.insert("a", b"a").unwrap();
+
+        // the first copy is held until the writes overflow a queue of 1
+        let (entered, wait_entered) = channel::<()>();
+        let (go, wait_go) = channel::<()>();
+        let scan = inner.scanner().unwrap();
+        let gated = move |start: &str, limit| {
+            let _ = entered.send(());
+            let _ = wait_go.recv();
+            scan(start, limit)
+        };
+        let m = MirrorStore::spawn(Box::new(inner.clone()), path, Box::new(gated), 1);
+        wait_entered.recv().unwrap();
+        for k in ["b", "c", "d"] {
+            m.insert(k, k.as_bytes()).unwrap();
+        }
+        assert!(m.stale.load(Ordering::Relaxed));
+        m.flush();
+        go.send(()).unwrap();
+        drop(m);
+
+        // the primary has everything, the mirror missed writes so it's cleared
+        assert_eq!(inner.scan_prefix_from("", "", 10).len(), 4);
+        let db = SledStore::open(path).unwrap();
+        assert_eq!(db.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_mirror_fail() {
+        // a file where the mirror directory should be
+        let path = "/tmp/test_mirror_fail";
+        let _ = std::fs::remove_dir_all(path);
+        std::fs::write(path, b"not a store").unwrap();
+
+        let m = MirrorStore::new(Box::new(MemMetaStore::new()), path);
+        m.insert("k", b"v").unwrap();
+        m.apply(&[("j".to_string(), Some(b"w".to_vec()))]).unwrap();
+        m.flush();
+        assert_eq!(m.get("k").unwrap(), Some(b"v".to_vec()));
+        assert!(m.contains_key("j").unwrap());
+        drop(m);
+        assert_eq!(std::fs::read(path).unwrap(), b"not a store");
+    }
+}
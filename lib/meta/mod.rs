@@ -1,15 +1,22 @@
+mod batch;
 mod dentry;
+mod error;
 mod inode;
+mod mem;
 mod meta;
 mod meta_store;
+mod mirror;
+mod record;
 mod sled;
 mod super_block;
 
 use crate::meta::meta::NameT;
-use crate::store::CacheStore;
+use crate::store::{CacheStore, SharedStore};
+pub use error::MetaError;
 pub use inode::{Inode, Itype};
-pub use meta::{Ino, Meta};
+pub use meta::{FormatCheck, Ino, Meta};
 use meta_store::MetaStore;
+pub use super_block::{DirLimits, KeyParams, Layout, LABEL_MAX};
 
 pub trait MetaKV {
     fn key(&self) -> String;
@@ -20,29 +27,48 @@ pub trait MetaKV {
 pub struct FileHandle {
     ino: Ino,
     pub fh: u64,
+    /// opened with `O_APPEND`, every write goes to the end of file
+    pub append: bool,
+    /// every write is flushed to the data store before it returns
+    pub write_through: bool,
     cache: CacheStore,
 }
 
 impl FileHandle {
-    pub fn new(ino: Ino, fh: u64) -> Self {
+    pub fn new(ino: Ino, fh: u64, store: SharedStore) -> Self {
         Self {
             ino,
             fh,
-            cache: CacheStore::new(ino), // TODO: we can pass config here to change store backend
+            append: false,
+            write_through: false,
+            cache: CacheStore::new(ino, store),
         }
     }
 
     pub fn write(&mut self, meta: &mut Meta, off: u64, data: &[u8]) -> usize {
-        self.cache.write(meta, off, data)
+        let n = self.cache.write(meta, off, data);
+        if self.write_through {
+            self.cache.flush(meta);
+        }
+        n
     }
 
     pub fn flush(&mut self, meta: &mut Meta) {
         self.cache.flush(meta);
     }
 
-    pub fn read(&mut self, meta: &mut Meta, off: u64, size: usize) -> Option<Vec<u8>> {
+    /// whether a write back of the handle failed since the last call
+    pub fn take_error(&mut self) -> bool {
+        self.cache.take_error()
+    }
+
+    pub fn read(&mut self, meta: &mut Meta, off: u64, size: usize) -> Result<Vec<u8>, libc::c_int> {
         self.cache.read(meta, off, size)
     }
+
+    pub fn dirty(&self) -> u64 {
+        self.cache.dirty()
+    }
 }
 
 impl Drop for FileHandle {
@@ -51,10 +77,19 @@ impl Drop for FileHandle {
     }
 }
 
+/// entries of a directory are loaded in batches as the listing goes, `pos` and `base` count from the first entry
+/// `.`, `cursor` is the key of the last loaded entry, the next batch starts right after it, so an entry that stays in
+/// the directory is listed exactly once however the directory changes between batches, the kernel tells where to go
+/// on by the cookie of an entry, see `Meta::seek_dentry`
 pub struct DirHandle {
     pub fh: u64,
     pos: usize,
+    base: usize,
     entry: Vec<NameT>,
+    cursor: Option<String>,
+    eof: bool,
+    /// cookie of the last entry taken by `next`, 0 if none
+    last: i64,
 }
 
 impl DirHandle {
@@ -62,7 +97,11 @@ impl DirHandle {
         Self {
             fh,
             pos: 0,
+            base: 0,
             entry: Vec::new(),
+            cursor: None,
+            eof: false,
+            last: 0,
         }
     }
 
@@ -70,24 +109,64 @@ impl DirHandle {
         self.entry.push(e);
     }
 
-    pub fn off(&self) -> usize {
-        self.pos
+    /// whether the loaded entries are used up and another batch must be loaded
+    pub fn need_load(&self) -> bool {
+        !self.eof && self.pos == self.base + self.entry.len()
     }
 
     pub fn done(&self) -> bool {
-        if self.entry.len() > 0 {
-            return self.pos == self.entry.len();
+        self.eof && self.pos == self.base + self.entry.len()
+    }
+
+    /// move right after the entry whose cookie is `off`, 0 goes back to the start, return false if it's not among
+    /// the loaded entries
+    pub fn seek(&mut self, off: i64) -> bool {
+        if off == 0 {
+            self.pos = 0;
+            self.base = 0;
+            self.entry.clear();
+            self.cursor = None;
+            self.eof = false;
+            self.last = 0;
+            return true;
+        }
+        if off == self.last {
+            return true;
         }
-        return true;
+        match self.entry.iter().position(|e| e.off == off) {
+            None => false,
+            Some(i) => {
+                self.pos = self.base + i + 1;
+                self.last = off;
+                true
+            }
+        }
+    }
+
+    /// pass over the loaded entries, so the next batch is loaded
+    fn skip_batch(&mut self) {
+        self.pos = self.base + self.entry.len();
+    }
+
+    /// drop the consumed entries before a new batch is loaded
+    fn trim(&mut self) {
+        let n = self.pos - self.base;
+        self.entry.drain(..n);
+        self.base = self.pos;
+    }
+
+    pub fn peek(&self) -> Option<&NameT> {
+        self.entry.get(self.pos - self.base)
     }
 
     pub fn next(&mut self) -> Option<&NameT> {
-        if self.pos == self.entry.len() {
+        let i = self.pos - self.base;
+        if i == self.entry.len() {
             None
         } else {
-            let tmp = &self.entry[self.pos];
             self.pos += 1;
-            Some(tmp)
+            self.last = self.entry[i].off;
+            Some(&self.entry[i])
         }
     }
 }
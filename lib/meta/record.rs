@@ -0,0 +1,87 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// what a stored value holds, so a value decoded as another type is refused rather than misread, the tags are not
+/// ASCII, a superblock written before records were sealed starts with the magic `junkfs`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tag {
+    SuperBlock = 0xa1,
+    Inode = 0xa2,
+    Dentry = 0xa3,
+    Dir = 0xa4,
+}
+
+// tag and checksum
+const HEADER_LEN: usize = 5;
+
+fn checksum(tag: Tag, body: &[u8]) -> u32 {
+    let mut h = crc32fast::Hasher::new();
+    h.update(&[tag as u8]);
+    h.update(body);
+    h.finalize()
+}
+
+/// a record is `tag | crc32 of tag and body | body`, the body is the bincode of `val`
+pub fn seal<T: Serialize>(tag: Tag, val: &T) -> Vec<u8> {
+    let body = bincode::serialize(val).expect("can't serialize record");
+    let mut buf = Vec::with_capacity(HEADER_LEN + body.len());
+    buf.push(tag as u8);
+    buf.extend_from_slice(&checksum(tag, &body).to_le_bytes());
+    buf.extend_from_slice(&body);
+    buf
+}
+
+/// the body of a record of type `tag`, verified against its checksum
+pub fn open(tag: Tag, data: &[u8]) -> Result<&[u8], String> {
+    if data.len() < HEADER_LEN {
+        return Err(format!("corrupt record, {} bytes", data.len()));
+    }
+    if data[0] != tag as u8 {
+        return Err(format!("corrupt record, tag {:#x} expect {:#x}", data[0], tag as u8));
+    }
+    let body = &data[HEADER_LEN..];
+    let sum = u32::from_le_bytes(data[1..HEADER_LEN].try_into().unwrap());
+    if sum != checksum(tag, body) {
+        return Err("corrupt record, checksum mismatch".to_string());
+    }
+    Ok(body)
+}
+
+/// decode a record of type `tag`, a corrupt one is an error rather than garbage fed to bincode
+pub fn decode<T: DeserializeOwned>(tag: Tag, data: &[u8]) -> Result<T, String> {
+    let body = open(tag, data)?;
+    bincode::deserialize::<T>(body).map_err(|e| format!("corrupt record, error {}", e))
+}
+
+/// the body of a record sealed or written before records were sealed, only for migration
+pub fn open_legacy(tag: Tag, data: &[u8]) -> &[u8] {
+    open(tag, data).unwrap_or(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, open, open_legacy, seal, Tag};
+
+    #[test]
+    fn test_record() {
+        let v = seal(Tag::Inode, &(42u64, "name".to_string()));
+        assert_eq!(
+            decode::<(u64, String)>(Tag::Inode, &v).unwrap(),
+            (42, "name".to_string())
+        );
+
+        // a schema mixup, a flipped bit anywhere and a truncated value
+        assert!(decode::<(u64, String)>(Tag::Dentry, &v).unwrap_err().contains("tag"));
+        for i in 0..v.len() {
+            let mut bad = v.clone();
+            bad[i] ^= 1 << (i % 8);
+            assert!(decode::<(u64, String)>(Tag::Inode, &bad).is_err());
+        }
+        assert!(open(Tag::Inode, &v[..3]).is_err());
+        assert!(open(Tag::Inode, &v[..v.len() - 1]).is_err());
+
+        let raw = bincode::serialize(&7u64).unwrap();
+        assert_eq!(open_legacy(Tag::Inode, &raw), &raw[..]);
+        assert_eq!(open_legacy(Tag::Inode, &v), &v[5..]);
+    }
+}
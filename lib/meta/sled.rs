@@ -1,12 +1,20 @@
 use crate::cache::{Flusher, LRUCache};
-use crate::meta::meta_store::{MetaIter, MetaStore};
+use crate::meta::meta_store::{MetaIter, MetaStore, Scanner};
+use crate::utils::fault::{self, Fault};
+use crate::utils::stats;
 use sled::IVec;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::ops::Bound;
 
 pub struct SledStore {
     /// read cache
     cache: RefCell<LRUCache<String, Vec<u8>>>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
     db: sled::Db,
+    path: String,
+    /// the live db couldn't be reopened after a failed compaction, `db` is a throwaway one, so every request fails
+    poisoned: bool,
 }
 
 fn transform_iter<T: Iterator<Item = Result<(IVec, IVec), sled::Error>>>(
@@ -21,6 +29,27 @@ fn transform_iter<T: Iterator<Item = Result<(IVec, IVec), sled::Error>>>(
     })
 }
 
+/// see `MetaStore::scan_prefix_from`
+fn scan_from(db: &sled::Db, prefix: &str, start: &str, limit: usize) -> Vec<(String, Vec<u8>)> {
+    let from = if start.is_empty() { Bound::Included(prefix) } else { Bound::Excluded(start) };
+    let mut r = Vec::new();
+    for x in db.range::<&str, _>((from, Bound::Unbounded)) {
+        match x {
+            Err(e) => {
+                log::warn!("scan {} from {} error {}", prefix, start, e);
+                break;
+            }
+            Ok((k, v)) => {
+                if r.len() == limit || !k.starts_with(prefix.as_bytes()) {
+                    break;
+                }
+                r.push((String::from_utf8_lossy(&k).into_owned(), v.to_vec()));
+            }
+        }
+    }
+    r
+}
+
 impl Flusher<String, Vec<u8>> for SledStore {
     fn flush(&mut self, key: String, data: Vec<u8>) {
         match self.db.insert(&key, data.as_slice()) {
@@ -36,7 +65,11 @@ impl SledStore {
     pub fn new(meta_path: &str, cache_cap: usize) -> Self {
         let s = Self {
             cache: RefCell::new(LRUCache::new(cache_cap)),
-            db: sled::open(meta_path).unwrap(),
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+            db: Self::open(meta_path).unwrap(),
+            path: meta_path.to_string(),
+            poisoned: false,
         };
         // unnecessary to flush, use default dummy backend
         // let p = std::ptr::addr_of_mut!(s);
@@ -45,14 +78,99 @@ impl SledStore {
     }
 }
 
+impl SledStore {
+    /// sled's background threads may hold the file lock for a moment after the previous `Db` was dropped (e.g.
+    /// right after format or a quick remount), retry a few times before giving up
+    pub(crate) fn open(meta_path: &str) -> sled::Result<sled::Db> {
+        // crashed between the two renames of `compact`
+        let old = Self::old_path(meta_path);
+        if !std::path::Path::new(meta_path).exists() && std::path::Path::new(&old).exists() {
+            log::warn!("restore {} from {}", meta_path, old);
+            std::fs::rename(&old, meta_path)?;
+        }
+        let mut retry = 0;
+        loop {
+            match sled::open(meta_path) {
+                Err(sled::Error::Io(e)) if e.kind() == std::io::ErrorKind::Other && retry < 50 => {
+                    log::warn!("open {} fail, error {}, retry", meta_path, e);
+                    retry += 1;
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                }
+                r => return r,
+            }
+        }
+    }
+
+    fn old_path(meta_path: &str) -> String {
+        format!("{}.old", meta_path)
+    }
+
+    /// sled 0.34 has no way to force its segment cleaner, so copy the live keys into a fresh db and swap the
+    /// directories, the old one is kept as `{path}.old` until the new one is opened
+    fn rebuild(&mut self) -> sled::Result<()> {
+        let tmp = format!("{}.compact", self.path);
+        let old = Self::old_path(&self.path);
+        let _ = std::fs::remove_dir_all(&tmp);
+        let _ = std::fs::remove_dir_all(&old);
+
+        self.db.flush()?;
+        let db = sled::open(&tmp)?;
+        db.import(self.db.export());
+        db.flush()?;
+        drop(db);
+
+        // release the current db before its directory is moved
+        let placeholder = sled::Config::new().temporary(true).open()?;
+        drop(std::mem::replace(&mut self.db, placeholder));
+        let r = Self::swap(&self.path, &tmp, &old).and_then(|_| Self::open(&self.path));
+        match r {
+            Ok(db) => {
+                self.db = db;
+                let _ = std::fs::remove_dir_all(&old);
+                Ok(())
+            }
+            Err(e) => {
+                // writes must not go to the placeholder, `open` moves `.old` back if the live store was moved away
+                let _ = std::fs::remove_dir_all(&tmp);
+                match Self::open(&self.path) {
+                    Ok(db) => self.db = db,
+                    Err(e) => {
+                        log::error!("can't reopen {} after a failed compaction, error {}", self.path, e);
+                        self.poisoned = true;
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.poisoned {
+            return Err(format!("{} is lost after a failed compaction", self.path));
+        }
+        Ok(())
+    }
+
+    fn swap(path: &str, tmp: &str, old: &str) -> sled::Result<()> {
+        std::fs::rename(path, old)?;
+        if fault::hit(Fault::SwapStore) {
+            return Err(sled::Error::Io(std::io::Error::other("injected fault")));
+        }
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
 impl MetaStore for SledStore {
     fn insert(&self, key: &str, val: &[u8]) -> Result<(), String> {
+        self.check()?;
         match self.db.insert(key, val) {
             Err(e) => {
                 log::error!("insert {} fail, error {}", key, e);
                 Err(e.to_string())
             }
             Ok(_) => {
+                stats::record_meta_write(1, (key.len() + val.len()) as u64);
                 self.cache.borrow_mut().add(key.to_string(), val.to_vec());
                 Ok(())
             }
@@ -60,9 +178,12 @@ impl MetaStore for SledStore {
     }
 
     fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        self.check()?;
         if let Some(v) = self.cache.borrow_mut().get(&key.to_string()) {
+            self.hits.set(self.hits.get() + 1);
             return Ok(Some(v.clone()));
         }
+        self.misses.set(self.misses.get() + 1);
         match self.db.get(key) {
             Err(e) => {
                 log::error!("get {} fail, error {}", key, e);
@@ -86,18 +207,57 @@ impl MetaStore for SledStore {
         }
     }
 
+    fn scan_prefix_from(&self, prefix: &str, start: &str, limit: usize) -> Vec<(String, Vec<u8>)> {
+        scan_from(&self.db, prefix, start, limit)
+    }
+
     fn remove(&self, key: &str) -> Result<(), String> {
+        self.check()?;
         self.cache.borrow_mut().del(&key.to_string());
         match self.db.remove(key) {
             Err(e) => {
                 log::error!("remove {} fail, error {}", key, e);
                 Err(e.to_string())
             }
-            Ok(_) => Ok(()),
+            Ok(_) => {
+                stats::record_meta_write(1, key.len() as u64);
+                Ok(())
+            }
         }
     }
 
+    fn apply(&self, ops: &[(String, Option<Vec<u8>>)]) -> Result<(), String> {
+        self.check()?;
+        let mut batch = sled::Batch::default();
+        for (k, v) in ops {
+            match v {
+                Some(v) => batch.insert(k.as_str(), v.as_slice()),
+                None => batch.remove(k.as_str()),
+            }
+        }
+        if let Err(e) = self.db.apply_batch(batch) {
+            log::error!("apply batch of {} keys fail, error {}", ops.len(), e);
+            return Err(e.to_string());
+        }
+        let bytes = ops
+            .iter()
+            .map(|(k, v)| k.len() + v.as_ref().map_or(0, |v| v.len()))
+            .sum::<usize>();
+        stats::record_meta_write(ops.len() as u64, bytes as u64);
+        let mut cache = self.cache.borrow_mut();
+        for (k, v) in ops {
+            match v {
+                Some(v) => {
+                    cache.add(k.clone(), v.clone());
+                }
+                None => cache.del(k),
+            }
+        }
+        Ok(())
+    }
+
     fn contains_key(&self, key: &str) -> Result<bool, String> {
+        self.check()?;
         if let Some(_) = self.cache.borrow_mut().get(&key.to_string()) {
             return Ok(true);
         }
@@ -115,6 +275,27 @@ impl MetaStore for SledStore {
         // self.cache.borrow_mut().flush();
         let _r = self.db.flush();
     }
+
+    fn compact(&mut self) -> Result<(), String> {
+        self.check()?;
+        self.rebuild().map_err(|e| {
+            log::error!("compact {} fail, error {}", self.path, e);
+            e.to_string()
+        })
+    }
+
+    fn cache_stats(&self) -> (u64, u64) {
+        (self.hits.get(), self.misses.get())
+    }
+
+    /// it reads the db it's taken from, a compaction replaces the db, so take another one after it
+    fn scanner(&self) -> Option<Scanner> {
+        if self.poisoned {
+            return None;
+        }
+        let db = self.db.clone();
+        Some(Box::new(move |start, limit| scan_from(&db, "", start, limit)))
+    }
 }
 
 impl Drop for SledStore {
@@ -1,24 +1,174 @@
-use crate::meta::{Ino, MetaKV};
+use crate::meta::record::{self, Tag};
+use crate::meta::{Ino, MetaError, MetaKV};
+use crate::store::{CHECK_LEN, SALT_LEN};
 use crate::utils::{BitMap, FS_ROOT_INODE, FS_TOTAL_INODES};
 use serde::{Deserialize, Serialize};
 
+pub const FS_MAGIC: u64 = 0x6a756e6b6673; // "junkfs"
+/// 2: fixed width parent in dentry keys
+/// 3: data layout in superblock
+/// 4: directory limits in superblock, a directory has 2 links plus one per subdirectory
+/// 5: superblock, inodes and dentries are sealed with a checksum, see `record`
+/// 6: entries of a small directory are kept in a single key, see `Meta::store_dentry`
+/// 7: a label in superblock
+/// 8: key parameters in superblock if file data is encrypted
+/// 9: whether the last writable mount was unmounted cleanly in superblock
+pub const FS_VERSION: u32 = 9;
+/// a label is at most this many bytes, as the one of ext4
+pub const LABEL_MAX: usize = 16;
+
+// `magic` and `version` must be the leading fields of `SuperBlock`
+#[derive(Deserialize)]
+struct Header {
+    magic: u64,
+    version: u32,
+}
+
+/// how file data is laid out under the data path, it's chosen by `mkfs` and never changes
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum Layout {
+    /// a directory per file and a file per block, see `FileStore`
+    #[default]
+    Block,
+    /// a sparse file per file holding all of its blocks, see `PackedStore`
+    Packed,
+}
+
+/// caps on the entries of a single directory, they're chosen by `mkfs` and checked when an entry is added
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct DirLimits {
+    /// links of a directory, which are 2 plus its subdirectories, another subdirectory gets `EMLINK`
+    pub max_links: u32,
+    /// entries of a directory, another one gets `ENOSPC`, 0 is unlimited
+    pub max_entries: u64,
+}
+
+impl Default for DirLimits {
+    /// the link limit of ext4
+    fn default() -> Self {
+        Self {
+            max_links: 65000,
+            max_entries: 0,
+        }
+    }
+}
+
+// superblock written before it had a magic and a version, version 1 only added them
+#[derive(Deserialize)]
+struct SuperBlockV0 {
+    ino: Ino,
+    uri: String,
+    imap: BitMap,
+}
+
+// superblock before version 3
+#[derive(Deserialize)]
+struct SuperBlockV2 {
+    magic: u64,
+    version: u32,
+    ino: Ino,
+    uri: String,
+    imap: BitMap,
+}
+
+// superblock before version 4
+#[derive(Deserialize)]
+struct SuperBlockV3 {
+    magic: u64,
+    version: u32,
+    ino: Ino,
+    uri: String,
+    imap: BitMap,
+    layout: Layout,
+}
+
+/// what tells the key of encrypted file data, the key is derived from a passphrase and `salt`, it's never stored,
+/// `check` tells a wrong passphrase, see `Xts`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct KeyParams {
+    pub salt: [u8; SALT_LEN],
+    pub check: [u8; CHECK_LEN],
+}
+
+// superblock before version 7
+#[derive(Deserialize)]
+struct SuperBlockV6 {
+    magic: u64,
+    version: u32,
+    ino: Ino,
+    uri: String,
+    imap: BitMap,
+    layout: Layout,
+    limits: DirLimits,
+}
+
+// superblock before version 8
+#[derive(Deserialize)]
+struct SuperBlockV7 {
+    magic: u64,
+    version: u32,
+    ino: Ino,
+    uri: String,
+    imap: BitMap,
+    layout: Layout,
+    limits: DirLimits,
+    label: String,
+}
+
+// superblock before version 9
+#[derive(Deserialize)]
+struct SuperBlockV8 {
+    magic: u64,
+    version: u32,
+    ino: Ino,
+    uri: String,
+    imap: BitMap,
+    layout: Layout,
+    limits: DirLimits,
+    label: String,
+    key: Option<KeyParams>,
+}
+
 // NOTE: we use a key-value database to store metadata of filesystem, so it's unnecessary to store
 // inode map, data map and inode table in metadata, we only limit the total number of data blocks
 // and inode count is enough
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SuperBlock {
+    magic: u64,
+    version: u32,
     ino: Ino,
     uri: String, // currently the `uri` is a path to store file blocks
     imap: BitMap,
+    layout: Layout,
+    limits: DirLimits,
+    label: String,          // empty unless it's given by `mkfs --label` or `set_label`
+    key: Option<KeyParams>, // file data is encrypted if it's set by `mkfs --key-file`
+    clean: bool,            // cleared while mounted writable, set again by `Meta::close`
 }
 
 impl SuperBlock {
-    pub fn new(uri: &str) -> Self {
-        SuperBlock {
+    pub fn new(uri: &str, layout: Layout) -> Self {
+        let mut sb = SuperBlock {
+            magic: FS_MAGIC,
+            version: FS_VERSION,
             ino: FS_ROOT_INODE,
             uri: uri.to_string(),
             imap: BitMap::new(FS_TOTAL_INODES),
-        }
+            layout,
+            limits: DirLimits::default(),
+            label: String::new(),
+            key: None,
+            clean: true,
+        };
+        sb.reserve();
+        sb
+    }
+
+    /// slot 0 is not an ino and root is always `FS_ROOT_INODE`, neither is ever handed out by `alloc_ino`, it's
+    /// idempotent, so stores formatted before the reservation are fixed up when their root is created
+    pub fn reserve(&mut self) {
+        self.imap.add(0);
+        self.imap.add(FS_ROOT_INODE);
     }
 
     pub fn alloc_ino(&mut self) -> Option<Ino> {
@@ -29,20 +179,126 @@ impl SuperBlock {
         &self.uri
     }
 
-    pub fn check(&self) {
-        assert_eq!(self.imap.cap(), FS_TOTAL_INODES);
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn limits(&self) -> DirLimits {
+        self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: DirLimits) {
+        self.limits = limits;
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// a label is at most `LABEL_MAX` bytes of printable ASCII other than `,` and space, so it can name the mount in
+    /// the mount options, an empty one clears it
+    pub fn set_label(&mut self, label: &str) -> Result<(), MetaError> {
+        if label.len() > LABEL_MAX {
+            return Err(MetaError::NameTooLong);
+        }
+        if !label.bytes().all(|c| c.is_ascii_graphic() && c != b',') {
+            return Err(MetaError::Invalid);
+        }
+        self.label = label.to_string();
+        Ok(())
+    }
+
+    pub fn key_params(&self) -> Option<KeyParams> {
+        self.key
+    }
+
+    /// file data is encrypted with a key `key` tells, it's only set by format
+    pub fn set_key_params(&mut self, key: Option<KeyParams>) {
+        self.key = key;
+    }
+
+    /// the last writable mount was unmounted cleanly, so it left no orphans, a superblock older than the flag is
+    /// taken as not clean
+    pub fn clean(&self) -> bool {
+        self.clean
+    }
+
+    pub fn set_clean(&mut self, clean: bool) {
+        self.clean = clean;
+    }
+
+    /// verify the inode map summary against its bits, return the found issues
+    pub fn check(&self) -> Vec<String> {
+        let mut issues = Vec::new();
+        if self.imap.cap() != FS_TOTAL_INODES {
+            issues.push(format!(
+                "inode map capacity {} expect {}",
+                self.imap.cap(),
+                FS_TOTAL_INODES
+            ));
+        }
         let mut cnt = 0;
 
-        for i in 0..FS_TOTAL_INODES {
+        for i in 0..self.imap.cap() {
             if self.imap.test(i) {
                 cnt += 1;
             }
         }
-        assert_eq!(cnt, self.imap.len());
+        if cnt != self.imap.len() {
+            issues.push(format!("inode map count {} but {} bits set", self.imap.len(), cnt));
+        }
+        issues
+    }
+
+    /// build the inode map from `live` inos, return the number of inos leaked (allocated without an inode) and
+    /// lost (an inode marked free), the map is replaced only if `apply` is true
+    pub fn rebuild_imap(&mut self, live: &[Ino], apply: bool) -> (u64, u64) {
+        let mut imap = BitMap::new(FS_TOTAL_INODES);
+        imap.add(0);
+        imap.add(FS_ROOT_INODE);
+        for &ino in live {
+            if ino >= imap.cap() || (!imap.add(ino) && ino != FS_ROOT_INODE) {
+                log::error!("invalid or duplicated ino {}", ino);
+            }
+        }
+
+        let (mut leaked, mut lost) = (0, 0);
+        for i in 0..imap.cap() {
+            match (self.imap.test(i), imap.test(i)) {
+                (true, false) => leaked += 1,
+                (false, true) => lost += 1,
+                _ => {}
+            }
+        }
+        if apply {
+            self.imap = imap;
+        }
+        (leaked, lost)
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// mark the superblock as current after its store was migrated
+    pub fn upgrade(&mut self) {
+        self.version = FS_VERSION;
+    }
+
+    /// inos the filesystem can hold including root, slot 0 is not counted
+    pub fn total_inodes(&self) -> u64 {
+        self.imap.cap() - 1
+    }
+
+    /// allocated inos including root, slot 0 is not counted
+    pub fn used_inodes(&self) -> u64 {
+        self.imap.len() - self.imap.test(0) as u64
     }
 
     pub fn free_ino(&mut self, ino: Ino) {
-        if self.imap.test(ino) {
+        if ino <= FS_ROOT_INODE {
+            log::error!("can't free reserved ino {}", ino);
+        } else if self.imap.test(ino) {
             self.imap.del(ino);
         } else {
             log::error!("non existed ino {}", ino);
@@ -53,8 +309,149 @@ impl SuperBlock {
         "sb".to_string()
     }
 
+    /// key of the copy of the superblock used when `sb` is lost or corrupted, it's written less often, see
+    /// `Meta::flush_sb`
+    pub fn backup_key() -> String {
+        "sb_backup".to_string()
+    }
+
+    /// whether `other` is a copy of this superblock, maybe with an older inode map, which is rebuilt on load anyway
+    pub fn same_fs(&self, other: &SuperBlock) -> bool {
+        self.magic == other.magic
+            && self.version == other.version
+            && self.uri == other.uri
+            && self.layout == other.layout
+            && self.limits == other.limits
+            && self.key == other.key
+    }
+
+    /// check magic and version before decoding the whole superblock, so a foreign or incompatible store is
+    /// reported clearly instead of a confusing deserialize error
+    pub fn decode(data: &[u8]) -> Result<Self, String> {
+        let sealed = data.first() == Some(&(Tag::SuperBlock as u8));
+        let data = if sealed { record::open(Tag::SuperBlock, data)? } else { data };
+        let hdr = bincode::deserialize::<Header>(data);
+        match hdr {
+            Ok(hdr) if hdr.magic == FS_MAGIC => {
+                if hdr.version == 0 || hdr.version > FS_VERSION {
+                    return Err(format!("unsupported version {}, expect {}", hdr.version, FS_VERSION));
+                }
+                if !sealed && hdr.version >= 5 {
+                    return Err("corrupt record, superblock is not sealed".to_string());
+                }
+                let e = |e: bincode::Error| format!("corrupted superblock, error {}", e);
+                if hdr.version < 3 {
+                    let sb = bincode::deserialize::<SuperBlockV2>(data).map_err(e)?;
+                    return Ok(SuperBlock {
+                        magic: sb.magic,
+                        version: sb.version,
+                        ino: sb.ino,
+                        uri: sb.uri,
+                        imap: sb.imap,
+                        layout: Layout::Block,
+                        limits: DirLimits::default(),
+                        label: String::new(),
+                        key: None,
+                        clean: false,
+                    });
+                }
+                if hdr.version < 4 {
+                    let sb = bincode::deserialize::<SuperBlockV3>(data).map_err(e)?;
+                    return Ok(SuperBlock {
+                        magic: sb.magic,
+                        version: sb.version,
+                        ino: sb.ino,
+                        uri: sb.uri,
+                        imap: sb.imap,
+                        layout: sb.layout,
+                        limits: DirLimits::default(),
+                        label: String::new(),
+                        key: None,
+                        clean: false,
+                    });
+                }
+                if hdr.version < 7 {
+                    let sb = bincode::deserialize::<SuperBlockV6>(data).map_err(e)?;
+                    return Ok(SuperBlock {
+                        magic: sb.magic,
+                        version: sb.version,
+                        ino: sb.ino,
+                        uri: sb.uri,
+                        imap: sb.imap,
+                        layout: sb.layout,
+                        limits: sb.limits,
+                        label: String::new(),
+                        key: None,
+                        clean: false,
+                    });
+                }
+                if hdr.version < 8 {
+                    let sb = bincode::deserialize::<SuperBlockV7>(data).map_err(e)?;
+                    return Ok(SuperBlock {
+                        magic: sb.magic,
+                        version: sb.version,
+                        ino: sb.ino,
+                        uri: sb.uri,
+                        imap: sb.imap,
+                        layout: sb.layout,
+                        limits: sb.limits,
+                        label: sb.label,
+                        key: None,
+                        clean: false,
+                    });
+                }
+                if hdr.version < 9 {
+                    let sb = bincode::deserialize::<SuperBlockV8>(data).map_err(e)?;
+                    return Ok(SuperBlock {
+                        magic: sb.magic,
+                        version: sb.version,
+                        ino: sb.ino,
+                        uri: sb.uri,
+                        imap: sb.imap,
+                        layout: sb.layout,
+                        limits: sb.limits,
+                        label: sb.label,
+                        key: sb.key,
+                        clean: false,
+                    });
+                }
+                bincode::deserialize::<SuperBlock>(data).map_err(e)
+            }
+            _ if !sealed => Self::decode_v0(data).ok_or_else(|| "not a junkfs filesystem".to_string()),
+            _ => Err("not a junkfs filesystem".to_string()),
+        }
+    }
+
+    /// a superblock without magic is told by root being its `ino`, the inode map having the size of ours and nothing
+    /// left after it, it's taken for version 1, which records it was written in, so the store is migrated on load
+    fn decode_v0(data: &[u8]) -> Option<Self> {
+        let sb = bincode::deserialize::<SuperBlockV0>(data).ok()?;
+        let len = bincode::serialized_size(&(sb.ino, &sb.uri, &sb.imap)).ok()?;
+        if sb.ino != FS_ROOT_INODE || sb.imap.cap() != FS_TOTAL_INODES || len != data.len() as u64 {
+            return None;
+        }
+        Some(SuperBlock {
+            magic: FS_MAGIC,
+            version: 1,
+            ino: sb.ino,
+            uri: sb.uri,
+            imap: sb.imap,
+            layout: Layout::Block,
+            limits: DirLimits::default(),
+            label: String::new(),
+            key: None,
+            clean: false,
+        })
+    }
+
+    /// the superblock as it was written before it had a magic and a version
+    #[cfg(test)]
+    pub(crate) fn val_v0(&self) -> Vec<u8> {
+        bincode::serialize(&(self.ino, &self.uri, &self.imap)).unwrap()
+    }
+
     pub fn val(this: &Self) -> Vec<u8> {
-        bincode::serialize(this).expect("can't serialize superblock")
+        record::seal(Tag::SuperBlock, this)
     }
 }
 
@@ -70,25 +467,26 @@ impl MetaKV for SuperBlock {
 
 #[cfg(test)]
 mod test {
-    use crate::meta::super_block::SuperBlock;
-    use crate::meta::MetaKV;
+    use crate::meta::record::{self, Tag};
+    use crate::meta::super_block::{DirLimits, KeyParams, Layout, SuperBlock, FS_VERSION};
+    use crate::meta::{MetaError, MetaKV};
 
     #[test]
     fn test_superblock() {
-        let mut sb = SuperBlock::new("tmp");
+        let mut sb = SuperBlock::new("tmp", Layout::Block);
 
-        sb.alloc_ino();
+        assert_eq!(sb.alloc_ino(), Some(2));
         sb.alloc_ino();
         sb.alloc_ino();
 
-        assert_eq!(sb.imap.len(), 3);
+        assert_eq!(sb.imap.len(), 5);
 
         // let tmp = SuperBlock::val(&sb);
         let tmp = sb.val();
 
-        let bs = bincode::deserialize::<SuperBlock>(tmp.as_slice()).unwrap();
+        let bs = SuperBlock::decode(tmp.as_slice()).unwrap();
 
-        assert_eq!(bs.imap.len(), 3);
+        assert_eq!(bs.imap.len(), 5);
 
         let path = "/tmp/test_sb";
         let _ = std::fs::remove_dir_all(path);
@@ -98,11 +496,243 @@ mod test {
         let _ = db.insert("sb", tmp);
 
         let tmp = db.get("sb").unwrap().unwrap();
-        let bs = bincode::deserialize::<SuperBlock>(tmp.as_ref()).unwrap();
+        let bs = SuperBlock::decode(tmp.as_ref()).unwrap();
 
         assert_eq!(bs.imap.len(), sb.imap.len());
         assert!(bs.imap.test(0));
         assert!(bs.imap.test(1));
         assert!(bs.imap.test(2));
     }
+
+    #[test]
+    fn test_superblock_decode() {
+        let mut sb = SuperBlock::new("tmp", Layout::Block);
+        sb.alloc_ino();
+        let sb = SuperBlock::decode(&sb.val()).unwrap();
+        assert_eq!(sb.imap.len(), 3);
+
+        assert_eq!(SuperBlock::decode(b"garbage").err().unwrap(), "not a junkfs filesystem");
+        assert_eq!(SuperBlock::decode(&[0u8; 64]).err().unwrap(), "not a junkfs filesystem");
+
+        let sb = SuperBlock::new("tmp", Layout::Packed);
+        assert_eq!(SuperBlock::decode(&sb.val()).unwrap().layout(), Layout::Packed);
+
+        // written before the magic and version, only if nothing is left over
+        let v0 = sb.val_v0();
+        let old = SuperBlock::decode(&v0).unwrap();
+        assert_eq!((old.version(), old.layout(), old.uri()), (1, Layout::Block, "tmp"));
+        assert_eq!(old.imap.len(), sb.imap.len());
+        let mut long = v0.clone();
+        long.push(0);
+        assert!(SuperBlock::decode(&long).is_err());
+        assert!(SuperBlock::decode(&v0[..v0.len() - 1]).is_err());
+
+        // written before the layout was recorded
+        #[derive(serde::Serialize)]
+        struct V2<'a> {
+            magic: u64,
+            version: u32,
+            ino: u64,
+            uri: &'a str,
+            imap: &'a crate::utils::BitMap,
+        }
+        let v2 = bincode::serialize(&V2 {
+            magic: sb.magic,
+            version: 2,
+            ino: sb.ino,
+            uri: "tmp",
+            imap: &sb.imap,
+        })
+        .unwrap();
+        let old = SuperBlock::decode(&v2).unwrap();
+        assert_eq!((old.version(), old.layout(), old.uri()), (2, Layout::Block, "tmp"));
+
+        // written before the directory limits were recorded
+        #[derive(serde::Serialize)]
+        struct V3<'a> {
+            magic: u64,
+            version: u32,
+            ino: u64,
+            uri: &'a str,
+            imap: &'a crate::utils::BitMap,
+            layout: Layout,
+        }
+        let v3 = bincode::serialize(&V3 {
+            magic: sb.magic,
+            version: 3,
+            ino: sb.ino,
+            uri: "tmp",
+            imap: &sb.imap,
+            layout: Layout::Packed,
+        })
+        .unwrap();
+        let old = SuperBlock::decode(&v3).unwrap();
+        assert_eq!(
+            (old.version(), old.layout(), old.limits()),
+            (3, Layout::Packed, DirLimits::default())
+        );
+
+        let mut sb = SuperBlock::new("tmp", Layout::Block);
+        let limits = DirLimits {
+            max_links: 10,
+            max_entries: 100,
+        };
+        sb.set_limits(limits);
+        assert_eq!(SuperBlock::decode(&sb.val()).unwrap().limits(), limits);
+
+        // written before the label was recorded
+        #[derive(serde::Serialize)]
+        struct V6<'a> {
+            magic: u64,
+            version: u32,
+            ino: u64,
+            uri: &'a str,
+            imap: &'a crate::utils::BitMap,
+            layout: Layout,
+            limits: DirLimits,
+        }
+        let v6 = record::seal(
+            Tag::SuperBlock,
+            &V6 {
+                magic: sb.magic,
+                version: 6,
+                ino: sb.ino,
+                uri: "tmp",
+                imap: &sb.imap,
+                layout: Layout::Packed,
+                limits,
+            },
+        );
+        let old = SuperBlock::decode(&v6).unwrap();
+        assert_eq!(
+            (old.version(), old.layout(), old.limits(), old.label()),
+            (6, Layout::Packed, limits, "")
+        );
+
+        sb.set_label("backup-01").unwrap();
+        assert_eq!(SuperBlock::decode(&sb.val()).unwrap().label(), "backup-01");
+        assert_eq!(sb.set_label("a-label-too-long!"), Err(MetaError::NameTooLong));
+        for bad in ["with space", "a,b", "tab\t", "nul\0", "naïve"] {
+            assert_eq!(sb.set_label(bad), Err(MetaError::Invalid), "{}", bad);
+        }
+        assert_eq!(sb.label(), "backup-01");
+        sb.set_label("").unwrap();
+        assert_eq!(sb.label(), "");
+
+        // written before data could be encrypted
+        #[derive(serde::Serialize)]
+        struct V7<'a> {
+            magic: u64,
+            version: u32,
+            ino: u64,
+            uri: &'a str,
+            imap: &'a crate::utils::BitMap,
+            layout: Layout,
+            limits: DirLimits,
+            label: &'a str,
+        }
+        let v7 = record::seal(
+            Tag::SuperBlock,
+            &V7 {
+                magic: sb.magic,
+                version: 7,
+                ino: sb.ino,
+                uri: "tmp",
+                imap: &sb.imap,
+                layout: Layout::Block,
+                limits,
+                label: "photos",
+            },
+        );
+        let old = SuperBlock::decode(&v7).unwrap();
+        assert_eq!((old.version(), old.label(), old.key_params()), (7, "photos", None));
+
+        let key = KeyParams {
+            salt: [1; 16],
+            check: [2; 16],
+        };
+        sb.set_key_params(Some(key));
+        let new = SuperBlock::decode(&sb.val()).unwrap();
+        assert_eq!(new.key_params(), Some(key));
+        assert!(new.same_fs(&sb) && !old.same_fs(&sb));
+
+        // written before the clean unmount was recorded
+        #[derive(serde::Serialize)]
+        struct V8<'a> {
+            magic: u64,
+            version: u32,
+            ino: u64,
+            uri: &'a str,
+            imap: &'a crate::utils::BitMap,
+            layout: Layout,
+            limits: DirLimits,
+            label: &'a str,
+            key: Option<KeyParams>,
+        }
+        let v8 = record::seal(
+            Tag::SuperBlock,
+            &V8 {
+                magic: sb.magic,
+                version: 8,
+                ino: sb.ino,
+                uri: "tmp",
+                imap: &sb.imap,
+                layout: Layout::Block,
+                limits,
+                label: "photos",
+                key: Some(key),
+            },
+        );
+        let old = SuperBlock::decode(&v8).unwrap();
+        assert_eq!((old.version(), old.key_params(), old.clean()), (8, Some(key), false));
+        assert!(new.clean());
+        sb.set_clean(false);
+        let unclean = SuperBlock::decode(&sb.val()).unwrap();
+        // a backup written at another time may differ by the flag only
+        assert!(!unclean.clean() && unclean.same_fs(&new));
+
+        let mut sb = SuperBlock::new("tmp", Layout::Block);
+        sb.version = FS_VERSION + 1;
+        let e = SuperBlock::decode(&sb.val()).err().unwrap();
+        assert!(e.starts_with("unsupported version"));
+    }
+
+    #[test]
+    fn test_superblock_check() {
+        let sb = SuperBlock::new("tmp", Layout::Block);
+        assert!(sb.check().is_empty());
+
+        // set a bit behind the count's back: magic, version, ino, uri (len + bytes), imap.data (len)
+        let mut buf = bincode::serialize(&sb).unwrap();
+        let pos = 8 + 4 + 8 + 8 + sb.uri.len() + 8;
+        buf[pos] |= 1 << 2;
+        let bad = bincode::deserialize::<SuperBlock>(&buf).unwrap();
+        assert!(bad.imap.test(2));
+        let issues = bad.check();
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].starts_with("inode map count 2"));
+
+        // the same bit flipped in a stored superblock is caught by its checksum, and so is a bare one
+        let mut buf = sb.val();
+        buf[5 + pos] |= 1 << 2;
+        assert!(SuperBlock::decode(&buf).err().unwrap().starts_with("corrupt record"));
+        let bare = bincode::serialize(&sb).unwrap();
+        assert!(SuperBlock::decode(&bare).err().unwrap().starts_with("corrupt record"));
+    }
+
+    #[test]
+    fn test_reserved() {
+        let mut sb = SuperBlock::new("tmp", Layout::Block);
+        assert_eq!(sb.used_inodes(), 1);
+        sb.free_ino(0);
+        sb.free_ino(crate::utils::FS_ROOT_INODE);
+        assert!(sb.imap.test(0) && sb.imap.test(crate::utils::FS_ROOT_INODE));
+        assert_eq!(sb.used_inodes(), 1);
+        assert!(sb.check().is_empty());
+
+        // the first allocation after format never hands out a reserved slot
+        assert_eq!(sb.alloc_ino(), Some(2));
+        sb.free_ino(2);
+        assert_eq!(sb.alloc_ino(), Some(2));
+    }
 }
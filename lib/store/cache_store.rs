@@ -1,23 +1,78 @@
 use crate::cache::MemPool;
 use crate::meta::{Ino, Meta};
-use crate::store::{Entry, FileStore, Store};
-use crate::utils::{FS_BLK_SIZE, FS_PAGE_SIZE};
-use std::cmp::min;
+use crate::store::{Entry, SharedStore};
+use crate::utils::{epoch_secs, stats, FS_BLK_SIZE, FS_FUSE_MAX_IO_SIZE};
+use std::cmp::{max, min};
+use std::time::Instant;
 
 const CACHE_LIMIT: usize = 32; // 128K
 
+/// copy the head of `data` into the page of the last entry if it starts inside or right after that entry, so that
+/// small sequential or overlapping writes share one page and one `pwrite`, return the bytes absorbed, pages are
+/// `page` bytes
+///
+/// NOTE: only the last entry is considered, merging into an earlier one would reorder overlapping writes
+fn coalesce(bufs: &mut [Entry], page: u64, blk_id: u64, blk_off: u64, data: &[u8]) -> usize {
+    let last = match bufs.last_mut() {
+        None => return 0,
+        Some(x) => x,
+    };
+    if last.blk_id != blk_id || blk_off < last.blk_off || blk_off > last.blk_off + last.size {
+        return 0;
+    }
+    let pos = blk_off - last.blk_off;
+    if pos >= page {
+        return 0;
+    }
+    let n = min(page - pos, data.len() as u64);
+    unsafe {
+        std::ptr::copy(data.as_ptr(), last.data.add(pos as usize), n as usize);
+    }
+    last.size = max(last.size, pos + n);
+    n as usize
+}
+
+// linux UIO_MAXIOV
+const MAX_IOVS: usize = 1024;
+
+/// number of leading entries which are in the same block and back to back, so they can be written at once, e.g. by
+/// one `pwritev`
+fn contiguous(buf: &[Entry]) -> usize {
+    let mut n = 1;
+    while n < buf.len() && n < MAX_IOVS {
+        let (prev, cur) = (&buf[n - 1], &buf[n]);
+        if cur.blk_id != prev.blk_id || cur.blk_off != prev.blk_off + prev.size {
+            break;
+        }
+        n += 1;
+    }
+    n
+}
+
+/// never read beyond the end of file, whatever the backing block holds
+fn clamp_read(length: u64, off: u64, size: usize) -> usize {
+    if off >= length {
+        0
+    } else {
+        min(size as u64, length - off) as usize
+    }
+}
+
 pub struct CacheStore {
     ino: Ino,
     bufs: Vec<Entry>,
-    store: Box<dyn Store>,
+    store: SharedStore,
+    /// a write back failed since the last `take_error`, the buffered data of it is lost
+    failed: bool,
 }
 
 impl CacheStore {
-    pub fn new(ino: Ino) -> Self {
+    pub fn new(ino: Ino, store: SharedStore) -> Self {
         Self {
             ino,
             bufs: Vec::new(),
-            store: Box::new(FileStore),
+            store,
+            failed: false,
         }
     }
 
@@ -38,8 +93,9 @@ impl CacheStore {
             let blk_off1 = pos;
             let off1 = off;
             let n = self.write_block(meta, blk1, blk_off1, off1, data1);
+            nbytes += n;
             if n != data1.len() {
-                nbytes += n;
+                stats::record_write(nbytes as u64);
                 return nbytes;
             }
 
@@ -47,21 +103,66 @@ impl CacheStore {
             let blk2 = blk1 + 1;
             let blk_off2 = 0;
             let off2 = blk2 * FS_BLK_SIZE;
-            assert_eq!(blk_off2 * FS_BLK_SIZE % FS_BLK_SIZE, off2);
-            let n = self.write_block(meta, blk2, blk_off2, off2, data2);
-            if n != data2.len() {
-                nbytes += n;
-                return nbytes;
-            }
+            assert_eq!(off2 % FS_BLK_SIZE, blk_off2);
+            nbytes += self.write_block(meta, blk2, blk_off2, off2, data2);
         } else {
             nbytes += self.write_block(meta, blk, pos, off, data);
         }
+        stats::record_write(nbytes as u64);
         nbytes
     }
 
-    pub fn read(&mut self, meta: &mut Meta, off: u64, size: usize) -> Option<Vec<u8>> {
+    /// a block short of the read is a hole and reads as zeros, unless it's missing from the store though it was
+    /// written, e.g. removed behind our back or lost to a crash of the host, which is `EIO`
+    pub fn read(&mut self, meta: &mut Meta, off: u64, size: usize) -> Result<Vec<u8>, libc::c_int> {
         self.flush(meta);
-        self.store.read(self.ino, off, size)
+        let length = meta.get_inode(self.ino)?.length;
+        let mut sz = match clamp_read(length, off, size) {
+            0 => return Ok(Vec::new()),
+            x => min(FS_FUSE_MAX_IO_SIZE, x as u64),
+        };
+        let blk_id = off / FS_BLK_SIZE;
+        // check off + sz is cross chunk, if so, read at most rest bytes in current block
+        if (off + sz) / FS_BLK_SIZE == (blk_id + 1) {
+            sz = (blk_id + 1) * FS_BLK_SIZE - off;
+        }
+        let mut v = vec![0u8; sz as usize];
+        let r = self
+            .store
+            .borrow_mut()
+            .read(self.ino, blk_id, off % FS_BLK_SIZE, &mut v);
+        match r {
+            Err(e) => {
+                log::error!(
+                    "can't read data blk_id {} off {} size {} error {}",
+                    blk_id,
+                    off % FS_BLK_SIZE,
+                    sz,
+                    e
+                );
+                Err(e.raw_os_error().unwrap_or(libc::EIO))
+            }
+            Ok(n) => {
+                if n < v.len() && meta.has_data(self.ino, blk_id) && !self.store.borrow().exists(self.ino, blk_id) {
+                    log::error!(
+                        "block {}_{} is lost, it was written but the store doesn't have it",
+                        self.ino,
+                        blk_id
+                    );
+                    return Err(libc::EIO);
+                }
+                if n < v.len() {
+                    log::info!(
+                        "block {}_{} is short, {} of {} bytes are hole",
+                        self.ino,
+                        blk_id,
+                        v.len() - n,
+                        v.len()
+                    );
+                }
+                Ok(v)
+            }
+        }
     }
 
     fn copy_data(&mut self, src: *const u8, dst: *mut u8, size: usize, blk_id: u64, blk_off: u64, off: u64) {
@@ -78,25 +179,30 @@ impl CacheStore {
         self.bufs.push(e);
     }
 
+    /// the data is copied in pages of the memory pool
     fn write_block(&mut self, meta: &mut Meta, blk_id: u64, blk_off: u64, off: u64, data: &[u8]) -> usize {
-        let mut ptr = data.as_ptr();
-        let end = unsafe { ptr.add(data.len()) };
+        let page = MemPool::get().page_size();
+        let end = unsafe { data.as_ptr().add(data.len()) };
         let len = data.len();
         let mut i = 0;
         let mut nbytes = 0;
 
         while i < len {
-            let sz = min(len - i, FS_PAGE_SIZE as usize);
+            let n = coalesce(&mut self.bufs, page, blk_id, blk_off + i as u64, &data[i..]);
+            if n > 0 {
+                i += n;
+                nbytes += n;
+                continue;
+            }
+            let sz = min(len - i, page as usize);
             let mem = self.alloc(meta);
             if mem.is_null() {
                 return nbytes;
             }
-            unsafe {
-                ptr = ptr.add(i as usize);
-                assert!(ptr < end);
-            }
-            assert!(sz <= FS_PAGE_SIZE as usize);
-            self.copy_data(ptr, mem, sz, blk_id, blk_off + i as u64, off);
+            let ptr = unsafe { data.as_ptr().add(i) };
+            assert!(ptr < end);
+            assert!(sz <= page as usize);
+            self.copy_data(ptr, mem, sz, blk_id, blk_off + i as u64, off + i as u64);
             i += sz;
             nbytes += sz;
         }
@@ -111,12 +217,289 @@ impl CacheStore {
         return MemPool::get().alloc();
     }
 
+    /// bytes buffered and not flushed yet
+    pub fn dirty(&self) -> u64 {
+        self.bufs.iter().map(|e| e.size).sum()
+    }
+
     // NOTE: the entry's order is mattered in bufs, do NOT reorder them
     pub fn flush(&mut self, meta: &mut Meta) {
-        self.store.write(meta, self.ino, &self.bufs);
+        if self.bufs.is_empty() {
+            return;
+        }
+        let bytes = self.dirty();
+        let start = Instant::now();
+        let ok = self.write_back(meta);
+        stats::record_flush(bytes, start.elapsed().as_nanos() as u64, ok);
+        if !ok {
+            log::error!("write back of ino {} fail, {} bytes lost", self.ino, bytes);
+            self.failed = true;
+        }
         for i in &self.bufs {
             MemPool::get().free(i.data);
         }
         self.bufs.clear();
     }
+
+    /// whether a write back failed since the last call, the error is sticky until it's taken
+    pub fn take_error(&mut self) -> bool {
+        std::mem::take(&mut self.failed)
+    }
+
+    /// copy on write, a block shared with other files is copied before it's written, the copy is made before the
+    /// reference is dropped, so a crash in between costs a needless copy at worst
+    fn unshare(&self, meta: &mut Meta, blk: u64) -> bool {
+        if !meta.is_shared(self.ino, blk) {
+            return true;
+        }
+        if let Err(e) = self.store.borrow_mut().unshare(self.ino, blk) {
+            log::error!("can't unshare block {}_{} error {}", self.ino, blk, e);
+            return false;
+        }
+        if let Err(e) = meta.unshare_block(self.ino, blk) {
+            log::error!("can't drop reference of block {}_{} error {}", self.ino, blk, e);
+            return false;
+        }
+        true
+    }
+
+    /// write groups of contiguous entries to the store and grow the file, return false on the first failure
+    fn write_back(&mut self, meta: &mut Meta) -> bool {
+        let mut inode = match meta.load_inode(self.ino) {
+            None => return false,
+            Some(x) => x,
+        };
+        let mut sz = 0;
+        let mut i = 0;
+        while i < self.bufs.len() {
+            let n = contiguous(&self.bufs[i..]);
+            let group = &self.bufs[i..i + n];
+            for e in group {
+                sz = max(sz, e.off + e.size);
+            }
+            log::info!(
+                "write off {} entries {} inode.length {} size {}",
+                group[0].off,
+                n,
+                inode.length,
+                sz
+            );
+            if !self.unshare(meta, group[0].blk_id) {
+                return false;
+            }
+            if self.store.borrow_mut().write(self.ino, group).is_err() {
+                log::warn!("write {}_{} fail", self.ino, group[0].blk_id);
+                return false;
+            }
+            if let Err(e) = meta.mark_data(self.ino, group[0].blk_id) {
+                log::error!(
+                    "can't record data of block {}_{} error {}",
+                    self.ino,
+                    group[0].blk_id,
+                    e
+                );
+                return false;
+            }
+            i += n;
+        }
+
+        // a write modifies the data, and the length if it grows
+        if inode.length < sz {
+            log::info!("trying to update inode.length {} to {}", inode.length, sz);
+            inode.length = sz;
+        }
+        let now = epoch_secs();
+        inode.mtime = now;
+        inode.ctime = now;
+        if let Err(e) = meta.store_inode(&inode) {
+            log::error!("can't update ino {} error {}", self.ino, e);
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{clamp_read, coalesce, contiguous, CacheStore};
+    use crate::cache::{MemPool, POOL_LOCK};
+    use crate::meta::{Ino, Itype, Meta};
+    use crate::store::{DataStore, Entry, MemStore, SharedStore};
+    use crate::utils::{stats, FS_BLK_SIZE, FS_PAGE_SIZE};
+    use libc::{SEEK_DATA, SEEK_HOLE};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// every write fails
+    struct BrokenStore;
+
+    impl DataStore for BrokenStore {
+        fn write(&mut self, _ino: Ino, _group: &[Entry]) -> std::io::Result<()> {
+            Err(std::io::Error::from_raw_os_error(libc::EIO))
+        }
+
+        fn read(&mut self, _ino: Ino, _blk: u64, _off: u64, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Ok(0)
+        }
+
+        fn set_len(&mut self, _ino: Ino, _blk: u64, _len: u64) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn fsync(&mut self, _ino: Ino, _datasync: bool) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn unlink(&mut self, _ino: Ino, _blocks: u64) {}
+
+        fn exists(&self, _ino: Ino, _blk: u64) -> bool {
+            false
+        }
+    }
+
+    /// a meta store with a file of ino 2 and a fresh memory pool
+    fn setup(path: &str) -> Meta {
+        let (meta, data) = (format!("{}/meta", path), format!("{}/data", path));
+        let _ = std::fs::remove_dir_all(path);
+        std::fs::create_dir_all(&data).unwrap();
+        Meta::format(&meta, &data, false).unwrap();
+        let mut m = Meta::load_fs(meta, true).unwrap();
+        m.mknod(0, "/", Itype::Dir, 0o755).unwrap();
+        assert_eq!(m.mknod(1, "f", Itype::File, 0o644).unwrap().id, 2);
+        MemPool::init(1 << 20, FS_PAGE_SIZE);
+        m
+    }
+
+    fn entry(page: &mut [u8], blk_id: u64, blk_off: u64, data: &[u8]) -> Entry {
+        page[..data.len()].copy_from_slice(data);
+        Entry {
+            blk_id,
+            blk_off,
+            off: blk_off,
+            size: data.len() as u64,
+            data: page.as_mut_ptr(),
+        }
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let mut page = vec![0u8; FS_PAGE_SIZE as usize];
+        let mut bufs = vec![entry(&mut page, 0, 100, &[0])];
+
+        // 1 byte at a time until the page is full
+        for i in 1..FS_PAGE_SIZE {
+            assert_eq!(coalesce(&mut bufs, FS_PAGE_SIZE, 0, 100 + i, &[(i % 251) as u8]), 1);
+        }
+        assert_eq!(coalesce(&mut bufs, FS_PAGE_SIZE, 0, 100 + FS_PAGE_SIZE, &[1]), 0);
+        assert_eq!(bufs.len(), 1);
+        assert_eq!(bufs[0].size, FS_PAGE_SIZE);
+        assert_eq!(page[4000], (4000 % 251) as u8);
+
+        // overwrite inside the last entry
+        assert_eq!(coalesce(&mut bufs, FS_PAGE_SIZE, 0, 110, &[7, 7]), 2);
+        assert_eq!(bufs[0].size, FS_PAGE_SIZE);
+        assert_eq!(&page[10..12], &[7, 7]);
+
+        // a gap, an earlier offset or another block need a new entry
+        let mut page = vec![0u8; FS_PAGE_SIZE as usize];
+        let mut bufs = vec![entry(&mut page, 0, 0, &[1, 2, 3])];
+        assert_eq!(coalesce(&mut bufs, FS_PAGE_SIZE, 0, 4, &[4]), 0);
+        assert_eq!(coalesce(&mut bufs, FS_PAGE_SIZE, 1, 3, &[4]), 0);
+        assert_eq!(
+            coalesce(&mut bufs, FS_PAGE_SIZE, 0, 3, &[4; 8192]),
+            FS_PAGE_SIZE as usize - 3
+        );
+        assert_eq!(bufs[0].size, FS_PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_contiguous() {
+        let mut pages: Vec<Vec<u8>> = (0..8).map(|_| vec![0u8; FS_PAGE_SIZE as usize]).collect();
+        let mut entries: Vec<Entry> = pages
+            .iter_mut()
+            .enumerate()
+            .map(|(i, p)| entry(p, 0, i as u64 * FS_PAGE_SIZE, &[0u8; FS_PAGE_SIZE as usize]))
+            .collect();
+        // a gap splits the group
+        entries[6].blk_off += 1;
+        assert_eq!(contiguous(&entries), 6);
+        assert_eq!(contiguous(&entries[6..]), 1);
+        entries[6].blk_off -= 1;
+        assert_eq!(contiguous(&entries), 8);
+        entries[4].blk_id = 1;
+        assert_eq!(contiguous(&entries), 4);
+    }
+
+    #[test]
+    fn test_clamp_read() {
+        assert_eq!(clamp_read(100, 0, 4096), 100);
+        assert_eq!(clamp_read(100, 60, 4096), 40);
+        assert_eq!(clamp_read(100, 60, 10), 10);
+        assert_eq!(clamp_read(100, 100, 10), 0);
+        assert_eq!(clamp_read(100, 200, 10), 0);
+        assert_eq!(clamp_read(0, 0, 10), 0);
+    }
+
+    #[test]
+    fn test_flush_stats() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut m = setup("/tmp/test_cache_store_flush_stats");
+
+        let mut cs = CacheStore::new(2, Rc::new(RefCell::new(MemStore::new())));
+        let before = stats::snapshot();
+        assert_eq!(cs.write(&mut m, 0, &[1u8; 10000]), 10000);
+        let s = stats::snapshot();
+        assert_eq!(s.write_bytes - before.write_bytes, 10000);
+        assert_eq!(s.flush_calls, before.flush_calls);
+        assert_eq!(s.dirty_bytes() - before.dirty_bytes(), 10000);
+
+        cs.flush(&mut m);
+        // nothing to flush, nothing recorded
+        cs.flush(&mut m);
+        let s = stats::snapshot();
+        assert_eq!(s.flush_calls - before.flush_calls, 1);
+        assert_eq!(s.flush_bytes - before.flush_bytes, 10000);
+        assert_eq!(s.flush_errors, before.flush_errors);
+        assert_eq!(s.dirty_bytes(), before.dirty_bytes());
+
+        cs.store = Rc::new(RefCell::new(BrokenStore));
+        cs.write(&mut m, 20000, &[2u8; 100]);
+        cs.flush(&mut m);
+        assert!(cs.take_error());
+        assert!(!cs.take_error());
+        let s = stats::snapshot();
+        assert_eq!(s.flush_calls - before.flush_calls, 2);
+        assert_eq!(s.flush_errors - before.flush_errors, 1);
+        assert_eq!(s.dirty_bytes(), before.dirty_bytes());
+        MemPool::destroy();
+    }
+
+    #[test]
+    fn test_mem_store() {
+        let _guard = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut m = setup("/tmp/test_cache_store_mem_store");
+        let store: SharedStore = Rc::new(RefCell::new(MemStore::new()));
+
+        let mut cs = CacheStore::new(2, store.clone());
+        cs.write(&mut m, 0, b"hello");
+        cs.write(&mut m, FS_BLK_SIZE + 10, b"world");
+        // read flushes first, the hole in between reads as zero
+        assert_eq!(cs.read(&mut m, 0, 5).unwrap(), b"hello");
+        assert_eq!(m.load_inode(2).unwrap().length, FS_BLK_SIZE + 15);
+        assert_eq!(cs.read(&mut m, 3, 4).unwrap(), b"lo\0\0");
+        assert_eq!(cs.read(&mut m, FS_BLK_SIZE + 8, 100).unwrap(), b"\0\0world");
+
+        // another handle of the same file shares the store
+        let mut other = CacheStore::new(2, store.clone());
+        assert_eq!(other.read(&mut m, FS_BLK_SIZE + 10, 5).unwrap(), b"world");
+
+        let s = store.borrow();
+        assert!(s.exists(2, 0) && s.exists(2, 1) && !s.exists(2, 2));
+        assert_eq!(s.seek(2, FS_BLK_SIZE + 15, 5, SEEK_DATA), Ok(5));
+        assert_eq!(s.seek(2, FS_BLK_SIZE + 15, 5, SEEK_HOLE), Ok(FS_BLK_SIZE + 15));
+        drop(s);
+        store.borrow_mut().unlink(2, 2);
+        assert!(!store.borrow().exists(2, 0));
+        MemPool::destroy();
+    }
 }
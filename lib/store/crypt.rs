@@ -0,0 +1,173 @@
+use crate::utils::FS_BLK_SIZE;
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes256;
+use sha2::{Digest, Sha256};
+
+/// data is encrypted in sectors of this many bytes, the unit of XTS, block files are always whole sectors
+pub const SECTOR: u64 = 512;
+/// bytes of the salt kept in superblock
+pub const SALT_LEN: usize = 16;
+/// bytes of the value telling a key from another, kept in superblock
+pub const CHECK_LEN: usize = 16;
+/// rounds of PBKDF2 turning a passphrase into a key
+const KDF_ROUNDS: u32 = 100_000;
+
+/// multiply the tweak by the primitive element of GF(2^128), little endian as IEEE 1619 does
+fn next_tweak(t: &mut [u8; 16]) {
+    let mut carry = 0;
+    for b in t.iter_mut() {
+        let c = *b >> 7;
+        *b = (*b << 1) | carry;
+        carry = c;
+    }
+    if carry != 0 {
+        t[0] ^= 0x87;
+    }
+}
+
+/// AES-256 in XTS mode, the tweak of a sector is its position in the file, not the ino, so a block shared by
+/// `DataStore::share` reads the same from every file sharing it
+pub struct Xts {
+    data: Aes256,
+    tweak: Aes256,
+    check: [u8; CHECK_LEN],
+}
+
+impl Xts {
+    /// derive the key from `passphrase` and the salt of the filesystem, only `check` is ever stored
+    pub fn derive(passphrase: &[u8], salt: &[u8; SALT_LEN]) -> Self {
+        let mut key = [0u8; 64];
+        pbkdf2::pbkdf2_hmac::<Sha256>(passphrase, salt, KDF_ROUNDS, &mut key);
+        let mut h = Sha256::new();
+        h.update(b"junkfs key check");
+        h.update(key);
+        let mut check = [0u8; CHECK_LEN];
+        check.copy_from_slice(&h.finalize()[..CHECK_LEN]);
+        Self {
+            data: Aes256::new(GenericArray::from_slice(&key[..32])),
+            tweak: Aes256::new(GenericArray::from_slice(&key[32..])),
+            check,
+        }
+    }
+
+    /// tells whether a passphrase gives the key the filesystem was made with
+    pub fn check(&self) -> [u8; CHECK_LEN] {
+        self.check
+    }
+
+    fn first_tweak(&self, blk: u64, off: u64) -> [u8; 16] {
+        let sector = blk * (FS_BLK_SIZE / SECTOR) + off / SECTOR;
+        let mut t = [0u8; 16];
+        t[..8].copy_from_slice(&sector.to_le_bytes());
+        self.tweak.encrypt_block(GenericArray::from_mut_slice(&mut t));
+        t
+    }
+
+    /// encrypt the whole sectors in `buf` which starts at `off` of block `blk`
+    pub fn encrypt(&self, blk: u64, off: u64, buf: &mut [u8]) {
+        self.apply(blk, off, buf, true);
+    }
+
+    /// decrypt the whole sectors in `buf` which starts at `off` of block `blk`, a sector of zeros is a hole and
+    /// stays zero
+    pub fn decrypt(&self, blk: u64, off: u64, buf: &mut [u8]) {
+        self.apply(blk, off, buf, false);
+    }
+
+    fn apply(&self, blk: u64, off: u64, buf: &mut [u8], enc: bool) {
+        assert_eq!((off % SECTOR, buf.len() as u64 % SECTOR), (0, 0));
+        for (i, sector) in buf.chunks_exact_mut(SECTOR as usize).enumerate() {
+            if !enc && sector.iter().all(|&x| x == 0) {
+                continue;
+            }
+            let mut t = self.first_tweak(blk, off + i as u64 * SECTOR);
+            for b in sector.chunks_exact_mut(16) {
+                b.iter_mut().zip(t.iter()).for_each(|(x, y)| *x ^= y);
+                let b = GenericArray::from_mut_slice(b);
+                if enc {
+                    self.data.encrypt_block(b);
+                } else {
+                    self.data.decrypt_block(b);
+                }
+                b.iter_mut().zip(t.iter()).for_each(|(x, y)| *x ^= y);
+                next_tweak(&mut t);
+            }
+        }
+    }
+}
+
+/// a fresh salt for `mkfs`
+pub fn salt() -> Result<[u8; SALT_LEN], String> {
+    use std::io::Read;
+    let mut salt = [0u8; SALT_LEN];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut salt))
+        .map_err(|e| format!("can't generate salt, error {}", e))?;
+    Ok(salt)
+}
+
+/// the passphrase is the content of `path` without the trailing newline
+pub fn read_passphrase(path: &str) -> Result<Vec<u8>, String> {
+    let mut v = std::fs::read(path).map_err(|e| format!("can't read key file {}, error {}", path, e))?;
+    while v.last().is_some_and(|&c| c == b'\n' || c == b'\r') {
+        v.pop();
+    }
+    if v.is_empty() {
+        return Err(format!("key file {} is empty", path));
+    }
+    Ok(v)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{next_tweak, read_passphrase, Xts, SECTOR};
+
+    #[test]
+    fn test_xts() {
+        let salt = [7u8; 16];
+        let x = Xts::derive(b"secret", &salt);
+        assert_eq!(x.check(), Xts::derive(b"secret", &salt).check());
+        assert_ne!(x.check(), Xts::derive(b"secret", &[8u8; 16]).check());
+        let y = Xts::derive(b"Secret", &salt);
+        assert_ne!(x.check(), y.check());
+
+        let plain: Vec<u8> = (0..4 * SECTOR as usize).map(|i| (i % 7) as u8).collect();
+        let mut buf = plain.clone();
+        x.encrypt(3, SECTOR, &mut buf);
+        assert_ne!(buf, plain);
+        // the same data in every sector is stored differently, so is the same sector of another block
+        let n = SECTOR as usize;
+        assert_ne!(buf[..n], buf[n..2 * n]);
+        let mut other = plain.clone();
+        x.encrypt(4, SECTOR, &mut other);
+        assert_ne!(buf, other);
+
+        let mut back = buf.clone();
+        x.decrypt(3, SECTOR, &mut back);
+        assert_eq!(back, plain);
+        let mut wrong = buf.clone();
+        y.decrypt(3, SECTOR, &mut wrong);
+        assert_ne!(wrong, plain);
+
+        let mut hole = vec![0u8; n];
+        x.decrypt(0, 0, &mut hole);
+        assert_eq!(hole, vec![0u8; n]);
+
+        let mut t = [0u8; 16];
+        t[15] = 0x80;
+        next_tweak(&mut t);
+        assert_eq!(t[0], 0x87);
+        assert!(t[1..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_read_passphrase() {
+        let path = "/tmp/test_read_passphrase";
+        std::fs::write(path, b"pass word\r\n").unwrap();
+        assert_eq!(read_passphrase(path).unwrap(), b"pass word");
+        std::fs::write(path, b"\n").unwrap();
+        assert!(read_passphrase(path).unwrap_err().contains("empty"));
+        assert!(read_passphrase("/tmp/test_read_passphrase_none").is_err());
+    }
+}
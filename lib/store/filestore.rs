@@ -1,11 +1,16 @@
 use crate::cache::{Flusher, LRUCache};
-use crate::meta::{Ino, Meta};
-use crate::store::{Entry, Store};
-use crate::utils::{get_data_path, FS_BLK_SIZE, FS_FUSE_MAX_IO_SIZE};
+use crate::meta::Ino;
+use crate::store::crypt::{Xts, SECTOR};
+use crate::store::{DataStore, Entry, Reaper};
+use crate::utils::{align_up, get_data_path, stats, FS_BLK_SIZE};
 use once_cell::sync::Lazy;
-use std::cmp::{max, min};
+use std::cmp::max;
+use std::collections::{BTreeSet, HashMap};
 use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
 use std::os::unix::prelude::FileExt;
+use std::sync::{Mutex, MutexGuard};
 const MAX_CACHE_ITEMS: usize = 256;
 
 struct FileFlusher;
@@ -36,7 +41,152 @@ fn cache_get_mut<'a>(key: &String) -> Option<&'a mut std::fs::File> {
     unsafe { G_FILE_CACHE.get_mut(key) }
 }
 
-pub struct FileStore;
+fn cache_del(key: &String) {
+    unsafe { G_FILE_CACHE.del(key) }
+}
+
+// blocks written since the last fsync, kept per ino rather than per handle, since fsync must cover data written
+// through every handle, including the ones released already
+static DIRTY_BLOCKS: Lazy<Mutex<HashMap<Ino, BTreeSet<u64>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn dirty_blocks() -> MutexGuard<'static, HashMap<Ino, BTreeSet<u64>>> {
+    DIRTY_BLOCKS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// call `sync` on every dirty block of `ino`, blocks not synced stay dirty, return the number of synced blocks
+fn sync_dirty<F: FnMut(u64) -> std::io::Result<()>>(ino: Ino, mut sync: F) -> std::io::Result<usize> {
+    let blocks = dirty_blocks().remove(&ino).unwrap_or_default();
+    let mut n = 0;
+    for &blk in &blocks {
+        if let Err(e) = sync(blk) {
+            dirty_blocks().entry(ino).or_default().extend(blocks.range(blk..));
+            return Err(e);
+        }
+        stats::record_block_sync();
+        n += 1;
+    }
+    Ok(n)
+}
+
+pub(super) fn seek_block<F: Fn(u64) -> bool>(
+    length: u64,
+    off: u64,
+    whence: i32,
+    exists: F,
+) -> Result<u64, libc::c_int> {
+    if off >= length {
+        return Err(libc::ENXIO);
+    }
+    let last = (length - 1) / FS_BLK_SIZE;
+    let want = match whence {
+        libc::SEEK_DATA => true,
+        libc::SEEK_HOLE => false,
+        _ => return Err(libc::EINVAL),
+    };
+
+    for blk in off / FS_BLK_SIZE..=last {
+        if exists(blk) == want {
+            return Ok(max(off, blk * FS_BLK_SIZE));
+        }
+    }
+    // no data after `off`, or the implicit hole at the end of file
+    if want {
+        Err(libc::ENXIO)
+    } else {
+        Ok(length)
+    }
+}
+
+/// write contiguous entries at `base` plus the block offset of the first one with `pwritev`, a short write or
+/// `EINTR` is retried with the rest, only a real error fails
+pub(super) fn write_vectored_at(fp: &std::fs::File, group: &[Entry], base: u64) -> std::io::Result<()> {
+    write_all_vectored(group, base, |iov, off| {
+        let r = unsafe {
+            libc::pwritev(
+                fp.as_raw_fd(),
+                iov.as_ptr(),
+                iov.len() as libc::c_int,
+                off as libc::off_t,
+            )
+        };
+        if r < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stats::record_pwritev(iov.len(), r as u64);
+        Ok(r as usize)
+    })
+}
+
+/// call `writev` with the iovecs not written yet and their file offset until all of `group` is written, it may
+/// write less than asked, e.g. interrupted by a signal
+fn write_all_vectored<F>(group: &[Entry], base: u64, mut writev: F) -> std::io::Result<()>
+where
+    F: FnMut(&[libc::iovec], u64) -> std::io::Result<usize>,
+{
+    let mut iov: Vec<libc::iovec> = group
+        .iter()
+        .map(|e| libc::iovec {
+            iov_base: e.data as *mut libc::c_void,
+            iov_len: e.size as usize,
+        })
+        .collect();
+    let mut off = base + group[0].blk_off;
+    let mut first = 0;
+    loop {
+        while first < iov.len() && iov[first].iov_len == 0 {
+            first += 1;
+        }
+        if first == iov.len() {
+            return Ok(());
+        }
+        let mut n = match writev(&iov[first..], off) {
+            Ok(0) => return Err(std::io::Error::from(std::io::ErrorKind::WriteZero)),
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        off += n as u64;
+        while n > 0 && first < iov.len() {
+            let cur = &mut iov[first];
+            if n >= cur.iov_len {
+                n -= cur.iov_len;
+                first += 1;
+            } else {
+                cur.iov_base = unsafe { (cur.iov_base as *mut u8).add(n) } as *mut libc::c_void;
+                cur.iov_len -= n;
+                n = 0;
+            }
+        }
+    }
+}
+
+/// fill `buf` from `off` until it's full or the end of `fp`, return the bytes read, the rest of `buf` is left
+/// untouched, which is zero for a fresh buffer, since a block file shorter than the file size is a hole
+pub(super) fn read_full(fp: &std::fs::File, buf: &mut [u8], off: u64) -> std::io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match fp.read_at(&mut buf[n..], off + n as u64) {
+            Ok(x) => {
+                stats::record_pread(x as u64);
+                if x == 0 {
+                    break;
+                }
+                n += x;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+pub struct FileStore {
+    reaper: Option<Reaper>,
+    /// block files are opened with `O_DSYNC`
+    dsync: bool,
+    /// data is encrypted in the block files, see `set_key`
+    xts: Option<Xts>,
+}
 
 impl Flusher<u64, std::fs::File> for FileStore {
     fn flush(&mut self, key: u64, data: std::fs::File) {
@@ -45,7 +195,33 @@ impl Flusher<u64, std::fs::File> for FileStore {
     }
 }
 
+impl Default for FileStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FileStore {
+    pub fn new() -> Self {
+        Self {
+            reaper: None,
+            dsync: false,
+            xts: None,
+        }
+    }
+
+    /// encrypt data written to block files and decrypt what's read with `xts`, a block file is then whole sectors
+    /// and a sector written in part is read, decrypted and written whole
+    pub fn set_key(&mut self, xts: Xts) {
+        self.xts = Some(xts);
+    }
+
+    /// open block files with `O_DSYNC`, so a write is on disk when it returns, it must be set before any block is
+    /// opened since the fds are cached
+    pub fn set_dsync(&mut self, on: bool) {
+        self.dsync = on;
+    }
+
     fn read_key(ino: Ino, blk: u64) -> String {
         format!("{}r{}", ino, blk)
     }
@@ -62,123 +238,660 @@ impl FileStore {
         format!("{}/{}", get_data_path(), ino)
     }
 
-    pub fn unlink(ino: Ino, blk_id: u64) {
-        let p = Self::build_path(ino, blk_id);
-        match std::fs::remove_file(&p) {
-            Err(e) => {
-                log::error!("can't remove {} error {}", p, e);
-            }
-            Ok(_) => {
-                log::info!("remove file {}", p);
+    /// directory holding data of unlinked files waiting for removal, see `Reaper`
+    pub fn trash_dir() -> String {
+        format!("{}/.trash", get_data_path())
+    }
+
+    /// ids of the block files of `ino` on disk, whatever its length says, e.g. a block past the length persisted
+    /// before a crash
+    fn block_files(ino: Ino) -> Vec<u64> {
+        std::fs::read_dir(Self::build_dir(ino))
+            .map(|d| {
+                d.flatten()
+                    .filter_map(|e| e.file_name().to_str()?.parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// close cached fds of the first `blocks` blocks of `ino` and of every block file it has, must be called before
+    /// its data is removed, or else the fds are reused when the ino is reallocated
+    fn evict(ino: Ino, blocks: u64) {
+        dirty_blocks().remove(&ino);
+        for blk in (0..blocks.max(1)).chain(Self::block_files(ino)) {
+            cache_del(&Self::read_key(ino, blk));
+            cache_del(&Self::write_key(ino, blk));
+        }
+    }
+
+    /// write `group` encrypted, the sectors it covers in part are completed with what they hold
+    fn write_sealed(fp: &std::fs::File, xts: &Xts, group: &[Entry]) -> std::io::Result<()> {
+        let blk = group[0].blk_id;
+        let from = group[0].blk_off;
+        let to = from + group.iter().map(|e| e.size).sum::<u64>();
+        let start = from - from % SECTOR;
+        let end = align_up(to, SECTOR);
+        let mut buf = vec![0u8; (end - start) as usize];
+        let last = end - start - SECTOR;
+        let head = from != start;
+        // a single sector is read once
+        let tail = to != end && !(head && last == 0);
+        for (pos, partial) in [(0, head), (last, tail)] {
+            if partial {
+                let sector = &mut buf[pos as usize..(pos + SECTOR) as usize];
+                read_full(fp, sector, start + pos)?;
+                xts.decrypt(blk, start + pos, sector);
             }
         }
+        let mut pos = (from - start) as usize;
+        for e in group {
+            buf[pos..pos + e.size as usize].copy_from_slice(e.data());
+            pos += e.size as usize;
+        }
+        xts.encrypt(blk, start, &mut buf);
+        fp.write_all_at(&buf, start)?;
+        stats::record_pwritev(1, buf.len() as u64);
+        Ok(())
     }
 
-    fn get_fp<'a, 'b>(key: String, ino: Ino, blk: u64) -> Option<&'b mut std::fs::File>
+    /// read the sectors covering `buf` and decrypt them
+    fn read_sealed(fp: &std::fs::File, xts: &Xts, blk: u64, off: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start = off - off % SECTOR;
+        let mut tmp = vec![0u8; (align_up(off + buf.len() as u64, SECTOR) - start) as usize];
+        let n = read_full(fp, &mut tmp, start)?;
+        let whole = align_up(n as u64, SECTOR) as usize;
+        xts.decrypt(blk, start, &mut tmp[..whole]);
+        let skip = (off - start) as usize;
+        let n = n.saturating_sub(skip).min(buf.len());
+        buf[..n].copy_from_slice(&tmp[skip..skip + n]);
+        Ok(n)
+    }
+
+    /// cut or extend an encrypted block file to the sector holding `len`, what's beyond `len` in that sector is
+    /// zeroed, so it reads as zeros when the file grows again
+    fn set_len_sealed(fp: &std::fs::File, xts: &Xts, blk: u64, len: u64) -> std::io::Result<()> {
+        fp.set_len(align_up(len, SECTOR))?;
+        let tail = (len % SECTOR) as usize;
+        if tail == 0 {
+            return Ok(());
+        }
+        let start = len - tail as u64;
+        let mut sector = [0u8; SECTOR as usize];
+        read_full(fp, &mut sector, start)?;
+        if sector.iter().all(|&x| x == 0) {
+            return Ok(());
+        }
+        xts.decrypt(blk, start, &mut sector);
+        sector[tail..].fill(0);
+        xts.encrypt(blk, start, &mut sector);
+        fp.write_all_at(&sector, start)
+    }
+
+    fn get_fp<'a, 'b>(key: String, ino: Ino, blk: u64, dsync: bool) -> std::io::Result<&'b mut std::fs::File>
     where
         'a: 'b,
     {
         if let Some(tmp) = cache_get_mut(&key) {
-            Some(tmp)
+            Ok(tmp)
         } else {
-            let _ = std::fs::create_dir_all(&Self::build_dir(ino));
+            let _ = std::fs::create_dir_all(Self::build_dir(ino));
             let fpath = Self::build_path(ino, blk);
             // NOTE: do NOT use append, see `File::write_at` doc `pwrite64` bug
             let f = std::fs::File::options()
                 .create(true)
+                .truncate(false)
                 .read(true)
                 .write(true)
-                .open(&fpath);
-            if f.is_err() {
-                log::error!("can't create {}", fpath);
-                return None;
-            }
-            cache_add(key, f.unwrap())
+                .custom_flags(if dsync { libc::O_DSYNC } else { 0 })
+                .open(&fpath)
+                .inspect_err(|e| log::error!("can't create {} error {}", fpath, e))?;
+            cache_add(key, f).ok_or_else(|| std::io::Error::from_raw_os_error(libc::EMFILE))
+        }
+    }
+}
+
+impl DataStore for FileStore {
+    /// the reaper purges what's left in trash by a crash, so it's started once the data path is known
+    fn init(&mut self) -> Result<(), String> {
+        self.reaper = Some(Reaper::new(Self::trash_dir())?);
+        Ok(())
+    }
+
+    fn write(&mut self, ino: Ino, group: &[Entry]) -> std::io::Result<()> {
+        let blk_id = group[0].blk_id;
+        let fp = Self::get_fp(Self::write_key(ino, blk_id), ino, blk_id, self.dsync)?;
+        let r = match &self.xts {
+            Some(xts) => Self::write_sealed(fp, xts, group),
+            None => write_vectored_at(fp, group, 0),
+        };
+        if let Err(e) = r {
+            log::error!("can't write entries {:?} error {}", group, e);
+            return Err(e);
         }
+        dirty_blocks().entry(ino).or_default().insert(blk_id);
+        Ok(())
     }
-    fn write_impl(&mut self, ino: Ino, e: &Entry) -> bool {
-        let key = Self::write_key(ino, e.blk_id);
-        let fp = Self::get_fp(key, ino, e.blk_id);
 
-        if fp.is_none() {
-            log::error!("can't open file {}_{}", ino, e.blk_id);
-            return false;
+    /// a block file missing is a hole, it's not created by a read
+    fn read(&mut self, ino: Ino, blk: u64, off: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        if cache_get_mut(&Self::read_key(ino, blk)).is_none() && !self.exists(ino, blk) {
+            return Ok(0);
+        }
+        let fp = Self::get_fp(Self::read_key(ino, blk), ino, blk, self.dsync)?;
+        match &self.xts {
+            Some(xts) => Self::read_sealed(fp, xts, blk, off, buf),
+            None => read_full(fp, buf, off),
         }
+    }
 
-        let fp = fp.unwrap();
-        unsafe {
-            let s = std::slice::from_raw_parts(e.data, e.size as usize);
-            let r = fp.write_at(s, e.blk_off);
-            if r.is_err() {
-                log::error!("can't write entry {:?}", e);
-                return false;
+    fn set_len(&mut self, ino: Ino, blk: u64, len: u64) -> std::io::Result<()> {
+        let fp = Self::get_fp(Self::write_key(ino, blk), ino, blk, self.dsync)?;
+        match &self.xts {
+            Some(xts) => Self::set_len_sealed(fp, xts, blk, len)?,
+            None => fp.set_len(len)?,
+        }
+        dirty_blocks().entry(ino).or_default().insert(blk);
+        Ok(())
+    }
+
+    /// create the block file and its directory now and extend it sparsely, so a later write finds it open
+    fn preallocate(&mut self, ino: Ino, blk: u64, len: u64) -> std::io::Result<()> {
+        let fp = Self::get_fp(Self::write_key(ino, blk), ino, blk, self.dsync)?;
+        let len = if self.xts.is_some() { align_up(len, SECTOR) } else { len };
+        if fp.metadata()?.len() < len {
+            fp.set_len(len)?;
+        }
+        dirty_blocks().entry(ino).or_default().insert(blk);
+        Ok(())
+    }
+
+    /// unless `datasync` the data directory is synced too, so newly created blocks are reachable after a crash
+    fn fsync(&mut self, ino: Ino, datasync: bool) -> std::io::Result<()> {
+        let n = sync_dirty(ino, |blk| {
+            let r = match cache_get_mut(&Self::write_key(ino, blk)) {
+                Some(fp) => fp.sync_data(),
+                None => match std::fs::File::open(Self::build_path(ino, blk)) {
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                    Err(e) => Err(e),
+                    Ok(fp) => fp.sync_data(),
+                },
+            };
+            if let Err(e) = &r {
+                log::error!("can't sync block {}_{} error {}", ino, blk, e);
             }
+            r
+        })?;
+        if !datasync && n > 0 {
+            if let Ok(dir) = std::fs::File::open(Self::build_dir(ino)) {
+                dir.sync_all()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// the data directory is handed to the reaper, so it returns at once however large the file is
+    fn unlink(&mut self, ino: Ino, blocks: u64) {
+        Self::evict(ino, blocks);
+        let dir = Self::build_dir(ino);
+        match self.reaper.as_mut() {
+            Some(r) => {
+                r.submit(&dir);
+            }
+            None => {
+                let _ = std::fs::remove_dir_all(&dir);
+            }
+        }
+    }
+
+    /// the block file of `dst` is a hard link to the one of `src`, the host filesystem frees the data with its last
+    /// link
+    fn share(&mut self, src: Ino, dst: Ino, blk: u64) -> std::io::Result<()> {
+        let from = Self::build_path(src, blk);
+        if !std::path::Path::new(&from).exists() {
+            return Ok(());
+        }
+        let to = Self::build_path(dst, blk);
+        std::fs::create_dir_all(Self::build_dir(dst))?;
+        cache_del(&Self::read_key(dst, blk));
+        cache_del(&Self::write_key(dst, blk));
+        let _ = std::fs::remove_file(&to);
+        std::fs::hard_link(&from, &to).inspect_err(|e| log::error!("can't link {} to {} error {}", from, to, e))?;
+        // the linked data may not be synced yet
+        dirty_blocks().entry(dst).or_default().insert(blk);
+        Ok(())
+    }
+
+    /// copy the block file aside and rename it over the link, cached fds of the block refer to the shared one, so
+    /// they're closed
+    fn unshare(&mut self, ino: Ino, blk: u64) -> std::io::Result<()> {
+        let path = Self::build_path(ino, blk);
+        match std::fs::metadata(&path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+            // the others are gone already
+            Ok(m) if m.nlink() == 1 => return Ok(()),
+            Ok(_) => {}
         }
-        return true;
-    }
-
-    fn read_impl(&mut self, ino: Ino, off: u64, size: usize) -> Option<Vec<u8>> {
-        let blk_id = off / FS_BLK_SIZE;
-        let key = Self::read_key(ino, blk_id);
-        let fp = Self::get_fp(key, ino, blk_id);
-        if fp.is_none() {
-            log::error!("can't open file for read {}_{}", ino, blk_id);
-            return None;
-        }
-        let fp = fp.unwrap();
-        let mut sz = min(FS_FUSE_MAX_IO_SIZE, size as u64);
-        // check off + sz is cross chunk, if so, read at most rest bytes in current block
-        if (off + sz) / FS_BLK_SIZE == (blk_id + 1) {
-            sz = (blk_id + 1) * FS_BLK_SIZE - off;
-        }
-        let mut v = vec![0u8; sz as usize];
-        let buf = v.as_mut_slice();
-        let r = fp.read_at(buf, off % FS_BLK_SIZE);
-        if r.is_err() {
-            log::error!(
-                "can't read data blk_id {} off {} size {}",
-                blk_id,
-                off % FS_BLK_SIZE,
-                sz
-            );
-            return None;
-        }
-        Some(v)
-    }
-}
-
-impl Store for FileStore {
-    fn write(&mut self, meta: &mut Meta, ino: Ino, buf: &Vec<Entry>) {
-        if buf.is_empty() {
-            return;
-        }
-        let mut sz = 0;
-        let mut inode = meta.load_inode(ino).unwrap();
-
-        for e in buf {
-            sz = max(sz, e.off + e.size);
-            log::info!(
-                "write off {} size {} inode.length {} size {}",
-                e.off,
-                e.size,
-                inode.length,
-                sz
-            );
-            if !self.write_impl(ino, e) {
-                log::warn!("write {}_{} fail", ino, e.blk_id);
-                return;
+        let tmp = format!("{}.cow", path);
+        std::fs::copy(&path, &tmp).inspect_err(|e| log::error!("can't copy {} error {}", path, e))?;
+        cache_del(&Self::read_key(ino, blk));
+        cache_del(&Self::write_key(ino, blk));
+        std::fs::rename(&tmp, &path)?;
+        dirty_blocks().entry(ino).or_default().insert(blk);
+        Ok(())
+    }
+
+    /// what the host filesystem allocated for the block files, so holes inside a block file are excluded too
+    fn allocated(&self, ino: Ino, blocks: u64) -> u64 {
+        (0..blocks)
+            .filter_map(|blk| std::fs::metadata(Self::build_path(ino, blk)).ok())
+            .map(|m| m.blocks() * 512)
+            .sum()
+    }
+
+    /// a block is data if its file exists, holes inside a block file are not reported
+    fn exists(&self, ino: Ino, blk: u64) -> bool {
+        std::path::Path::new(&Self::build_path(ino, blk)).exists()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        cache_get_mut, dirty_blocks, read_full, seek_block, sync_dirty, write_all_vectored, write_vectored_at,
+        FileStore,
+    };
+    use crate::store::{DataStore, Entry, Xts};
+    use crate::utils::{init_data_path, stats, FS_BLK_SIZE, FS_PAGE_SIZE};
+    use libc::{EINVAL, ENXIO, SEEK_DATA, SEEK_HOLE, SEEK_SET};
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::prelude::FileExt;
+
+    #[test]
+    fn test_seek() {
+        // blocks 1 and 3 are written, the file ends in the middle of block 4
+        let exists = |blk: u64| blk == 1 || blk == 3;
+        let len = 4 * FS_BLK_SIZE + 100;
+        let seek = |off: u64, whence: i32| seek_block(len, off, whence, exists);
+
+        assert_eq!(seek(0, SEEK_DATA), Ok(FS_BLK_SIZE));
+        assert_eq!(seek(FS_BLK_SIZE + 10, SEEK_DATA), Ok(FS_BLK_SIZE + 10));
+        assert_eq!(seek(2 * FS_BLK_SIZE, SEEK_DATA), Ok(3 * FS_BLK_SIZE));
+        assert_eq!(seek(4 * FS_BLK_SIZE, SEEK_DATA), Err(ENXIO));
+
+        assert_eq!(seek(0, SEEK_HOLE), Ok(0));
+        assert_eq!(seek(FS_BLK_SIZE + 10, SEEK_HOLE), Ok(2 * FS_BLK_SIZE));
+        assert_eq!(seek(3 * FS_BLK_SIZE, SEEK_HOLE), Ok(4 * FS_BLK_SIZE));
+        assert_eq!(seek(4 * FS_BLK_SIZE + 1, SEEK_HOLE), Ok(4 * FS_BLK_SIZE + 1));
+
+        // fully written file only has the implicit hole at the end
+        assert_eq!(seek_block(len, 0, SEEK_HOLE, |_| true), Ok(len));
+        assert_eq!(seek(len, SEEK_DATA), Err(ENXIO));
+        assert_eq!(seek(len, SEEK_HOLE), Err(ENXIO));
+        assert_eq!(seek(0, SEEK_SET), Err(EINVAL));
+    }
+
+    #[test]
+    fn test_pwritev() {
+        let ps = FS_PAGE_SIZE as usize;
+        let mut pages: Vec<Vec<u8>> = (0..8).map(|i| vec![i as u8; ps]).collect();
+        let entries: Vec<Entry> = pages
+            .iter_mut()
+            .enumerate()
+            .map(|(i, p)| Entry {
+                blk_id: 0,
+                blk_off: (i * ps) as u64,
+                off: (i * ps) as u64,
+                size: FS_PAGE_SIZE,
+                data: p.as_mut_ptr(),
+            })
+            .collect();
+        let path = "/tmp/test_pwritev";
+        let _ = std::fs::remove_file(path);
+        let f = std::fs::File::options()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        let before = stats::snapshot();
+        write_vectored_at(&f, &entries, 0).unwrap();
+        let after = stats::snapshot();
+        assert_eq!(after.pwritev_calls - before.pwritev_calls, 1);
+        assert_eq!(after.pwritev_iovs - before.pwritev_iovs, 8);
+
+        let mut buf = vec![0u8; 8 * ps];
+        f.read_exact_at(&mut buf, 0).unwrap();
+        for (i, c) in buf.chunks(ps).enumerate() {
+            assert!(c.iter().all(|&x| x == i as u8));
+        }
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_read_short_block() {
+        let path = "/tmp/test_read_short_block";
+        std::fs::write(path, vec![1u8; 100]).unwrap();
+        let f = std::fs::File::open(path).unwrap();
+
+        let mut buf = vec![0u8; 4096];
+        assert_eq!(read_full(&f, &mut buf, 60).unwrap(), 40);
+        assert!(buf[..40].iter().all(|&x| x == 1));
+        assert!(buf[40..].iter().all(|&x| x == 0));
+
+        let mut buf = vec![0u8; 16];
+        assert_eq!(read_full(&f, &mut buf, 200).unwrap(), 0);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_sync_dirty() {
+        // an ino no other test writes to
+        let ino = u64::MAX - 140;
+        let mark = |blks: &[u64]| dirty_blocks().entry(ino).or_default().extend(blks);
+        let sync = || {
+            let mut v = Vec::new();
+            let n = sync_dirty(ino, |blk| {
+                v.push(blk);
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(n, v.len());
+            v
+        };
+
+        mark(&(0..64).collect::<Vec<u64>>());
+        assert_eq!(sync().len(), 64);
+        // touch one block of a large file, only that one is synced
+        mark(&[33]);
+        assert_eq!(sync(), vec![33]);
+        assert!(sync().is_empty());
+
+        // the failed block and the ones after it stay dirty
+        mark(&[4, 5, 6]);
+        let r = sync_dirty(ino, |blk| {
+            if blk == 5 {
+                Err(std::io::Error::from_raw_os_error(libc::EIO))
+            } else {
+                Ok(())
             }
+        });
+        assert!(r.is_err());
+        assert_eq!(sync(), vec![5, 6]);
+    }
+
+    #[test]
+    fn test_share() {
+        let data = "/tmp/test_filestore_share";
+        let _ = std::fs::remove_dir_all(data);
+        std::fs::create_dir_all(data).unwrap();
+        let mut s = FileStore::new();
+        // inos no other test writes to, the fd cache is process wide
+        let (a, b) = (u64::MAX - 1700, u64::MAX - 1701);
+        let mut buf = *b"hello";
+        let e = Entry {
+            blk_id: 0,
+            blk_off: 0,
+            off: 0,
+            size: 5,
+            data: buf.as_mut_ptr(),
+        };
+
+        // the data path is process wide, other tests may change it, so set it right before use
+        init_data_path(data);
+        s.write(a, &[e]).unwrap();
+        s.share(a, b, 0).unwrap();
+        let path = |ino: u64| format!("{}/{}/0", data, ino);
+        assert_eq!(std::fs::metadata(path(b)).unwrap().nlink(), 2);
+
+        init_data_path(data);
+        s.unshare(b, 0).unwrap();
+        assert_eq!(std::fs::metadata(path(a)).unwrap().nlink(), 1);
+        assert_eq!(std::fs::read(path(b)).unwrap(), b"hello");
+        // a hole is nothing to share
+        s.share(a, b, 3).unwrap();
+        assert!(!s.exists(b, 3));
+        init_data_path(data);
+        s.unlink(a, 1);
+        s.unlink(b, 1);
+    }
+
+    #[test]
+    fn test_preallocate() {
+        let data = "/tmp/test_filestore_preallocate";
+        let _ = std::fs::remove_dir_all(data);
+        std::fs::create_dir_all(data).unwrap();
+        let mut s = FileStore::new();
+        // an ino no other test writes to, the fd cache is process wide
+        let ino = u64::MAX - 1800;
+        let blocks = |data: &str| std::fs::read_dir(format!("{}/{}", data, ino)).map_or(0, |d| d.count());
+
+        // the data path is process wide, other tests may change it, so set it right before use
+        init_data_path(data);
+        for blk in 0..4 {
+            s.preallocate(ino, blk, if blk == 3 { 100 } else { FS_BLK_SIZE })
+                .unwrap();
         }
+        assert_eq!(blocks(data), 4);
+        let path = format!("{}/{}/1", data, ino);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), FS_BLK_SIZE);
+        // sparse, nothing is allocated yet
+        assert_eq!(std::fs::metadata(&path).unwrap().blocks(), 0);
 
-        // try update inode.length
-        if inode.length < sz {
-            log::info!("trying to update inode.length {} to {}", inode.length, sz);
-            inode.length = sz;
-            meta.store_inode(&inode).unwrap()
+        // scattered writes land in the files created up front
+        for (blk, off) in [(2, 4096), (0, 77), (3, 50), (1, FS_BLK_SIZE - 5)] {
+            let mut buf = *b"hello";
+            let e = Entry {
+                blk_id: blk,
+                blk_off: off,
+                off: blk * FS_BLK_SIZE + off,
+                size: 5,
+                data: buf.as_mut_ptr(),
+            };
+            init_data_path(data);
+            s.write(ino, &[e]).unwrap();
         }
+        assert_eq!(blocks(data), 4);
+        let mut buf = [0u8; 5];
+        init_data_path(data);
+        assert_eq!(s.read(ino, 1, FS_BLK_SIZE - 5, &mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"hello");
+        // a shorter hint doesn't cut it
+        s.preallocate(ino, 3, 10).unwrap();
+        assert_eq!(std::fs::metadata(format!("{}/{}/3", data, ino)).unwrap().len(), 100);
+        // a block never written is a hole, reading it doesn't create it
+        init_data_path(data);
+        assert_eq!(s.read(ino, 5, 0, &mut buf).unwrap(), 0);
+        assert!(!s.exists(ino, 5));
+        init_data_path(data);
+        s.unlink(ino, 4);
     }
 
-    fn read(&mut self, ino: Ino, off: u64, size: usize) -> Option<Vec<u8>> {
-        self.read_impl(ino, off, size)
+    #[test]
+    fn test_unlink_sparse() {
+        let data = "/tmp/test_filestore_unlink_sparse";
+        let _ = std::fs::remove_dir_all(data);
+        std::fs::create_dir_all(data).unwrap();
+        let mut s = FileStore::new();
+        // an ino no other test writes to, the fd cache is process wide
+        let ino = u64::MAX - 1920;
+        let mut buf = *b"sparse";
+        for blk in [0, 5] {
+            let e = Entry {
+                blk_id: blk,
+                blk_off: 10,
+                off: blk * FS_BLK_SIZE + 10,
+                size: 6,
+                data: buf.as_mut_ptr(),
+            };
+            // the data path is process wide, other tests may change it, so set it right before use
+            init_data_path(data);
+            s.write(ino, &[e]).unwrap();
+        }
+        init_data_path(data);
+        s.preallocate(ino, 3, 100).unwrap();
+        let mut tmp = [0u8; 6];
+        s.read(ino, 5, 10, &mut tmp).unwrap();
+        assert_eq!(&tmp, b"sparse");
+
+        // the length persisted covers the first block only
+        init_data_path(data);
+        s.unlink(ino, 1);
+        assert!(!std::path::Path::new(&format!("{}/{}", data, ino)).exists());
+        for blk in [0, 3, 5] {
+            assert!(cache_get_mut(&FileStore::write_key(ino, blk)).is_none());
+        }
+        assert!(cache_get_mut(&FileStore::read_key(ino, 5)).is_none());
+    }
+
+    #[test]
+    fn test_dsync() {
+        let data = "/tmp/test_filestore_dsync";
+        let _ = std::fs::remove_dir_all(data);
+        std::fs::create_dir_all(data).unwrap();
+        // inos no other test writes to, the fd cache is process wide
+        let (a, b) = (u64::MAX - 1900, u64::MAX - 1901);
+        let mut buf = *b"durable";
+        let p = buf.as_mut_ptr();
+        let entry = || Entry {
+            blk_id: 0,
+            blk_off: 0,
+            off: 0,
+            size: 7,
+            data: p,
+        };
+        let flags = |ino: u64| {
+            let fp = FileStore::get_fp(FileStore::write_key(ino, 0), ino, 0, false).unwrap();
+            unsafe { libc::fcntl(fp.as_raw_fd(), libc::F_GETFL) }
+        };
+
+        // the data path is process wide, other tests may change it, so set it right before use
+        init_data_path(data);
+        let mut s = FileStore::new();
+        s.set_dsync(true);
+        s.write(a, &[entry()]).unwrap();
+        assert_ne!(flags(a) & libc::O_DSYNC, 0);
+        // on disk without an fsync, it's read back by another fd once the cached one is closed
+        FileStore::evict(a, 1);
+        assert_eq!(std::fs::read(format!("{}/{}/0", data, a)).unwrap(), b"durable");
+
+        init_data_path(data);
+        let mut s = FileStore::new();
+        s.write(b, &[entry()]).unwrap();
+        assert_eq!(flags(b) & libc::O_DSYNC, 0);
+        init_data_path(data);
+        s.unlink(a, 1);
+        s.unlink(b, 1);
+    }
+
+    #[test]
+    fn test_sealed() {
+        let data = "/tmp/test_filestore_sealed";
+        let _ = std::fs::remove_dir_all(data);
+        std::fs::create_dir_all(data).unwrap();
+        // an ino no other test writes to, the fd cache is process wide
+        let ino = u64::MAX - 1910;
+        let salt = [3u8; 16];
+        let mut s = FileStore::new();
+        s.set_key(Xts::derive(b"right", &salt));
+        let w = |s: &mut FileStore, off: u64, buf: &mut [u8]| {
+            let e = Entry {
+                blk_id: 0,
+                blk_off: off,
+                off,
+                size: buf.len() as u64,
+                data: buf.as_mut_ptr(),
+            };
+            init_data_path(data);
+            s.write(ino, &[e]).unwrap();
+        };
+        let plain: Vec<u8> = (0..1500).map(|i| (i % 251) as u8).collect();
+
+        // the data path is process wide, other tests may change it, so set it right before use
+        w(&mut s, 100, &mut plain.clone());
+        // within a sector written before
+        w(&mut s, 700, &mut b"patch".to_vec());
+        let mut expect = vec![0u8; 100];
+        expect.extend_from_slice(&plain);
+        expect[700..705].copy_from_slice(b"patch");
+
+        let raw = std::fs::read(format!("{}/{}/0", data, ino)).unwrap();
+        assert_eq!(raw.len(), 2048);
+        assert_ne!(raw[100..1600], plain[..]);
+        assert!(!raw.windows(5).any(|x| x == b"patch"));
+
+        let mut buf = vec![0u8; expect.len()];
+        init_data_path(data);
+        assert_eq!(s.read(ino, 0, 0, &mut buf).unwrap(), expect.len());
+        assert_eq!(buf, expect);
+        let mut buf = vec![0u8; 10];
+        init_data_path(data);
+        s.read(ino, 0, 695, &mut buf).unwrap();
+        assert_eq!(&buf, &expect[695..705]);
+
+        let mut wrong = FileStore::new();
+        wrong.set_key(Xts::derive(b"wrong", &salt));
+        let mut buf = vec![0u8; expect.len()];
+        init_data_path(data);
+        wrong.read(ino, 0, 0, &mut buf).unwrap();
+        assert_ne!(buf, expect);
+
+        // a shrink in the middle of a sector leaves zeros behind when the file grows again
+        init_data_path(data);
+        s.set_len(ino, 0, 600).unwrap();
+        s.set_len(ino, 0, 2000).unwrap();
+        let mut buf = vec![0u8; 1000];
+        init_data_path(data);
+        s.read(ino, 0, 0, &mut buf).unwrap();
+        assert_eq!(buf[..600], expect[..600]);
+        assert!(buf[600..].iter().all(|&x| x == 0));
+        init_data_path(data);
+        s.unlink(ino, 1);
+    }
+
+    #[test]
+    fn test_short_write() {
+        let mut pages: Vec<Vec<u8>> = vec![b"hello".to_vec(), Vec::new(), b" junk".to_vec(), b"fs".to_vec()];
+        let mut off = 10;
+        let entries: Vec<Entry> = pages
+            .iter_mut()
+            .map(|p| {
+                let e = Entry {
+                    blk_id: 0,
+                    blk_off: off,
+                    off,
+                    size: p.len() as u64,
+                    data: p.as_mut_ptr(),
+                };
+                off += p.len() as u64;
+                e
+            })
+            .collect();
+
+        // at most 3 bytes a call, and every other call is interrupted
+        let mut file = vec![0u8; 32];
+        let mut calls = 0;
+        write_all_vectored(&entries, 100, |iov, off| {
+            calls += 1;
+            if calls % 2 == 1 {
+                return Err(std::io::Error::from_raw_os_error(libc::EINTR));
+            }
+            let iov = &iov[0];
+            let n = iov.iov_len.min(3);
+            let src = unsafe { std::slice::from_raw_parts(iov.iov_base as *const u8, n) };
+            let off = off as usize - 100;
+            file[off..off + n].copy_from_slice(src);
+            Ok(n)
+        })
+        .unwrap();
+        assert_eq!(&file[10..22], b"hello junkfs");
+        assert!(file[..10].iter().chain(&file[22..]).all(|&x| x == 0));
+
+        let r = write_all_vectored(&entries, 0, |_, _| Err(std::io::Error::from_raw_os_error(libc::EIO)));
+        assert_eq!(r.unwrap_err().raw_os_error(), Some(libc::EIO));
+        let r = write_all_vectored(&entries, 0, |_, _| Ok(0));
+        assert_eq!(r.unwrap_err().kind(), std::io::ErrorKind::WriteZero);
     }
 }
@@ -0,0 +1,97 @@
+use crate::meta::Ino;
+use crate::store::{DataStore, Entry};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// keep all blocks in memory, nothing survives the process, it's meant for tests, a shared block is the same buffer
+/// under several keys, it's freed with the last of them
+#[derive(Default)]
+pub struct MemStore {
+    blocks: HashMap<(Ino, u64), Rc<RefCell<Vec<u8>>>>,
+}
+
+impl MemStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl DataStore for MemStore {
+    fn write(&mut self, ino: Ino, group: &[Entry]) -> std::io::Result<()> {
+        let mut blk = self.blocks.entry((ino, group[0].blk_id())).or_default().borrow_mut();
+        let mut off = group[0].blk_off() as usize;
+        for e in group {
+            let data = e.data();
+            if blk.len() < off + data.len() {
+                blk.resize(off + data.len(), 0);
+            }
+            blk[off..off + data.len()].copy_from_slice(data);
+            off += data.len();
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, ino: Ino, blk: u64, off: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let data = match self.blocks.get(&(ino, blk)) {
+            None => return Ok(0),
+            Some(x) => x.borrow(),
+        };
+        let off = off as usize;
+        if off >= data.len() {
+            return Ok(0);
+        }
+        let n = buf.len().min(data.len() - off);
+        buf[..n].copy_from_slice(&data[off..off + n]);
+        Ok(n)
+    }
+
+    fn set_len(&mut self, ino: Ino, blk: u64, len: u64) -> std::io::Result<()> {
+        self.blocks
+            .entry((ino, blk))
+            .or_default()
+            .borrow_mut()
+            .resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn fsync(&mut self, _ino: Ino, _datasync: bool) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn unlink(&mut self, ino: Ino, _blocks: u64) {
+        self.blocks.retain(|k, _| k.0 != ino);
+    }
+
+    fn share(&mut self, src: Ino, dst: Ino, blk: u64) -> std::io::Result<()> {
+        if let Some(x) = self.blocks.get(&(src, blk)).cloned() {
+            self.blocks.insert((dst, blk), x);
+        }
+        Ok(())
+    }
+
+    fn unshare(&mut self, ino: Ino, blk: u64) -> std::io::Result<()> {
+        if let Some(x) = self.blocks.get_mut(&(ino, blk)) {
+            if Rc::strong_count(x) > 1 {
+                let copy = x.borrow().clone();
+                *x = Rc::new(RefCell::new(copy));
+            }
+        }
+        Ok(())
+    }
+
+    /// rounded up to 512 bytes like a host filesystem, a buffer shared by several files is counted by each of them
+    fn allocated(&self, ino: Ino, _blocks: u64) -> u64 {
+        let bytes: u64 = self
+            .blocks
+            .iter()
+            .filter(|(k, _)| k.0 == ino)
+            .map(|(_, v)| v.borrow().len() as u64)
+            .sum();
+        bytes.div_ceil(512) * 512
+    }
+
+    fn exists(&self, ino: Ino, blk: u64) -> bool {
+        self.blocks.contains_key(&(ino, blk))
+    }
+}
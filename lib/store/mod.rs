@@ -1,12 +1,28 @@
 mod cache_store;
+mod crypt;
 mod filestore;
+mod mem_store;
+mod packed_store;
+mod reaper;
 
-use crate::meta::{Ino, Meta};
+use crate::meta::Ino;
+use crate::utils::FS_BLK_SIZE;
 pub use cache_store::CacheStore;
+pub use crypt::{read_passphrase, salt, Xts, CHECK_LEN, SALT_LEN};
+use filestore::seek_block;
 pub use filestore::FileStore;
+pub use mem_store::MemStore;
+pub use packed_store::PackedStore;
+pub use reaper::Reaper;
+use std::cell::RefCell;
+use std::rc::Rc;
 
+// bytes copied at a time by the default `share`
+const SHARE_CHUNK: usize = 1 << 20;
+
+/// a buffered page of a file waiting to be written
 #[derive(Debug)]
-struct Entry {
+pub struct Entry {
     blk_id: u64,   // block id
     blk_off: u64,  // offset in block
     off: u64,      // global offset in file
@@ -14,8 +30,114 @@ struct Entry {
     data: *mut u8, // data buffer
 }
 
-trait Store {
-    fn write(&mut self, meta: &mut Meta, ino: Ino, buf: &Vec<Entry>);
+impl Entry {
+    pub fn blk_id(&self) -> u64 {
+        self.blk_id
+    }
+
+    pub fn blk_off(&self) -> u64 {
+        self.blk_off
+    }
 
-    fn read(&mut self, ino: Ino, off: u64, size: usize) -> Option<Vec<u8>>;
+    pub fn data(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.data, self.size as usize) }
+    }
 }
+
+/// the backend holding file data, a file is split into blocks of `FS_BLK_SIZE` bytes which are addressed by ino
+/// and block id, the inode length is maintained by the caller
+pub trait DataStore {
+    /// called once the metadata is loaded, before any other method
+    fn init(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// write a group of back to back entries of one block, starting at the block offset of the first one
+    fn write(&mut self, ino: Ino, group: &[Entry]) -> std::io::Result<()>;
+
+    /// fill `buf` from `off` of block `blk`, return the bytes read, the rest of `buf` is a hole and left untouched
+    fn read(&mut self, ino: Ino, blk: u64, off: u64, buf: &mut [u8]) -> std::io::Result<usize>;
+
+    /// truncate or extend block `blk` of `ino` to `len` bytes
+    fn set_len(&mut self, ino: Ino, blk: u64, len: u64) -> std::io::Result<()>;
+
+    /// get block `blk` of `ino` ready for writes up to `len` bytes, it reads as before, zeros past its end, it's a
+    /// hint for a file grown at once, nothing to do for a store creating blocks cheaply on write
+    fn preallocate(&mut self, _ino: Ino, _blk: u64, _len: u64) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// write zeros over `len` bytes from `off` of block `blk`, which is created if it doesn't exist, so the range reads
+    /// as zeros whatever it held and is allocated rather than a hole
+    fn zero(&mut self, ino: Ino, blk: u64, off: u64, len: u64) -> std::io::Result<()> {
+        let mut buf = vec![0u8; SHARE_CHUNK.min(len as usize)];
+        let mut done = 0;
+        while done < len {
+            let e = Entry {
+                blk_id: blk,
+                blk_off: off + done,
+                off: blk * FS_BLK_SIZE + off + done,
+                size: (len - done).min(buf.len() as u64),
+                data: buf.as_mut_ptr(),
+            };
+            self.write(ino, &[e])?;
+            done += buf.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// persist the data of `ino` written since the last fsync
+    fn fsync(&mut self, ino: Ino, datasync: bool) -> std::io::Result<()>;
+
+    /// remove all data of `ino`, which has `blocks` blocks
+    fn unlink(&mut self, ino: Ino, blocks: u64);
+
+    /// whether block `blk` of `ino` was ever written
+    fn exists(&self, ino: Ino, blk: u64) -> bool;
+
+    /// make block `blk` of `dst` refer to the data of the same block of `src`, which the caller records in meta, a
+    /// store that can't share blocks copies it, a block never written stays a hole
+    fn share(&mut self, src: Ino, dst: Ino, blk: u64) -> std::io::Result<()> {
+        let mut buf = vec![0u8; SHARE_CHUNK];
+        let mut off = 0;
+        while off < FS_BLK_SIZE {
+            let n = self.read(src, blk, off, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+            // keep holes, e.g. a middle block of a sparse file reads as zeros until the next block
+            if buf[..n].iter().any(|&x| x != 0) {
+                let e = Entry {
+                    blk_id: blk,
+                    blk_off: off,
+                    off: blk * FS_BLK_SIZE + off,
+                    size: n as u64,
+                    data: buf.as_mut_ptr(),
+                };
+                self.write(dst, &[e])?;
+            }
+            off += n as u64;
+        }
+        Ok(())
+    }
+
+    /// give `ino` a private copy of block `blk` before it's modified, so the others sharing it don't see the change,
+    /// nothing to do for a store copying blocks in `share`
+    fn unshare(&mut self, _ino: Ino, _blk: u64) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// bytes of storage backing the first `blocks` blocks of `ino`, holes excluded, it's what `st_blocks` reports,
+    /// by default a block is either fully allocated or not at all
+    fn allocated(&self, ino: Ino, blocks: u64) -> u64 {
+        (0..blocks).filter(|&blk| self.exists(ino, blk)).count() as u64 * FS_BLK_SIZE
+    }
+
+    /// find the next data or hole at or after `off` for `SEEK_DATA`/`SEEK_HOLE`, at block granularity
+    fn seek(&self, ino: Ino, length: u64, off: u64, whence: i32) -> Result<u64, libc::c_int> {
+        seek_block(length, off, whence, |blk| self.exists(ino, blk))
+    }
+}
+
+/// a data store shared by the filesystem and all of its open files
+pub type SharedStore = Rc<RefCell<dyn DataStore>>;
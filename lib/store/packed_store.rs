@@ -0,0 +1,260 @@
+use crate::cache::LRUCache;
+use crate::meta::Ino;
+use crate::store::filestore::{read_full, write_vectored_at};
+use crate::store::{DataStore, Entry, Reaper};
+use crate::utils::{stats, FS_BLK_SIZE};
+use std::collections::HashSet;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+
+const MAX_CACHE_ITEMS: usize = 256;
+
+/// keep all blocks of a file in one sparse file `{root}/{ino}`, block `n` starts at `n * FS_BLK_SIZE`, blocks never
+/// written are holes, so a small file costs a single inode of the host filesystem instead of a directory and a file
+/// per block
+pub struct PackedStore {
+    root: String,
+    files: LRUCache<Ino, std::fs::File>,
+    /// files written since the last fsync
+    dirty: HashSet<Ino>,
+    reaper: Option<Reaper>,
+    /// files are opened with `O_DSYNC`
+    dsync: bool,
+}
+
+impl PackedStore {
+    pub fn new(root: &str) -> Self {
+        Self {
+            root: root.to_string(),
+            files: LRUCache::new(MAX_CACHE_ITEMS),
+            dirty: HashSet::new(),
+            reaper: None,
+            dsync: false,
+        }
+    }
+
+    /// open files with `O_DSYNC`, so a write is on disk when it returns, it applies to files opened afterwards
+    pub fn set_dsync(&mut self, on: bool) {
+        self.dsync = on;
+    }
+
+    fn build_path(&self, ino: Ino) -> String {
+        format!("{}/{}", self.root, ino)
+    }
+
+    fn trash_dir(&self) -> String {
+        format!("{}/.trash", self.root)
+    }
+
+    fn get_fp(&mut self, ino: Ino) -> std::io::Result<&mut std::fs::File> {
+        if self.files.get(&ino).is_none() {
+            let fpath = self.build_path(ino);
+            let f = std::fs::File::options()
+                .create(true)
+                .truncate(false)
+                .read(true)
+                .write(true)
+                .custom_flags(if self.dsync { libc::O_DSYNC } else { 0 })
+                .open(&fpath)
+                .inspect_err(|e| log::error!("can't create {} error {}", fpath, e))?;
+            self.files.add(ino, f);
+        }
+        self.files
+            .get_mut(&ino)
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EMFILE))
+    }
+}
+
+impl DataStore for PackedStore {
+    fn init(&mut self) -> Result<(), String> {
+        self.reaper = Some(Reaper::new(self.trash_dir())?);
+        Ok(())
+    }
+
+    fn write(&mut self, ino: Ino, group: &[Entry]) -> std::io::Result<()> {
+        let fp = self.get_fp(ino)?;
+        if let Err(e) = write_vectored_at(fp, group, group[0].blk_id * FS_BLK_SIZE) {
+            log::error!("can't write entries {:?} error {}", group, e);
+            return Err(e);
+        }
+        self.dirty.insert(ino);
+        Ok(())
+    }
+
+    fn read(&mut self, ino: Ino, blk: u64, off: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        // reading a file never written must not create it
+        if self.files.get(&ino).is_none() && !std::path::Path::new(&self.build_path(ino)).exists() {
+            return Ok(0);
+        }
+        let fp = self.get_fp(ino)?;
+        read_full(fp, buf, blk * FS_BLK_SIZE + off)
+    }
+
+    /// the file is cut at the new end of `blk` if it's the last block, or else the cut off part becomes a hole
+    fn set_len(&mut self, ino: Ino, blk: u64, len: u64) -> std::io::Result<()> {
+        let fp = self.get_fp(ino)?;
+        let (end, blk_end) = (blk * FS_BLK_SIZE + len, (blk + 1) * FS_BLK_SIZE);
+        if fp.metadata()?.len() <= blk_end {
+            fp.set_len(end)?;
+        } else if len < FS_BLK_SIZE {
+            let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+            let r =
+                unsafe { libc::fallocate(fp.as_raw_fd(), mode, end as libc::off_t, (blk_end - end) as libc::off_t) };
+            if r < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        self.dirty.insert(ino);
+        Ok(())
+    }
+
+    /// unless `datasync` the data directory is synced too, so a new file is reachable after a crash
+    fn fsync(&mut self, ino: Ino, datasync: bool) -> std::io::Result<()> {
+        if !self.dirty.remove(&ino) {
+            return Ok(());
+        }
+        let r = match self.files.get(&ino) {
+            Some(fp) => fp.sync_data(),
+            None => std::fs::File::open(self.build_path(ino)).and_then(|fp| fp.sync_data()),
+        };
+        if let Err(e) = r {
+            log::error!("can't sync file {} error {}", ino, e);
+            self.dirty.insert(ino);
+            return Err(e);
+        }
+        stats::record_block_sync();
+        if !datasync {
+            std::fs::File::open(&self.root)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn unlink(&mut self, ino: Ino, _blocks: u64) {
+        self.files.del(&ino);
+        self.dirty.remove(&ino);
+        let path = self.build_path(ino);
+        match self.reaper.as_mut() {
+            Some(r) => {
+                r.submit(&path);
+            }
+            None => {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+    }
+
+    fn allocated(&self, ino: Ino, _blocks: u64) -> u64 {
+        std::fs::metadata(self.build_path(ino)).map_or(0, |m| m.blocks() * 512)
+    }
+
+    /// a block is data if the file has data anywhere inside it
+    fn exists(&self, ino: Ino, blk: u64) -> bool {
+        let Ok(fp) = std::fs::File::open(self.build_path(ino)) else {
+            return false;
+        };
+        let off = unsafe { libc::lseek(fp.as_raw_fd(), (blk * FS_BLK_SIZE) as libc::off_t, libc::SEEK_DATA) };
+        off >= 0 && (off as u64) < (blk + 1) * FS_BLK_SIZE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PackedStore;
+    use crate::store::{DataStore, Entry, FileStore};
+    use crate::utils::{init_data_path, FS_BLK_SIZE};
+
+    fn entry(blk_id: u64, blk_off: u64, data: &mut [u8]) -> Entry {
+        Entry {
+            blk_id,
+            blk_off,
+            off: blk_id * FS_BLK_SIZE + blk_off,
+            size: data.len() as u64,
+            data: data.as_mut_ptr(),
+        }
+    }
+
+    /// files and directories under `path`, excluding trash
+    fn count_inodes(path: &str) -> usize {
+        let mut n = 0;
+        for e in std::fs::read_dir(path).unwrap().flatten() {
+            if e.file_name() == ".trash" {
+                continue;
+            }
+            n += 1;
+            if e.file_type().unwrap().is_dir() {
+                n += count_inodes(&e.path().to_string_lossy());
+            }
+        }
+        n
+    }
+
+    #[test]
+    fn test_packed_store() {
+        let root = "/tmp/test_packed_store";
+        let _ = std::fs::remove_dir_all(root);
+        std::fs::create_dir_all(root).unwrap();
+        let mut s = PackedStore::new(root);
+        s.init().unwrap();
+
+        let mut a = *b"hello";
+        let mut b = *b"world";
+        s.write(2, &[entry(0, 0, &mut a)]).unwrap();
+        s.write(2, &[entry(2, 10, &mut b)]).unwrap();
+        s.fsync(2, false).unwrap();
+        assert!(s.dirty.is_empty());
+
+        let mut buf = vec![0u8; 20];
+        assert_eq!(s.read(2, 2, 5, &mut buf).unwrap(), 10);
+        assert_eq!(&buf[..10], b"\0\0\0\0\0world");
+        let mut buf = vec![0u8; 5];
+        assert_eq!(s.read(2, 0, 0, &mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"hello");
+        // a hole in the middle and a file never written
+        assert_eq!(s.read(2, 1, 0, &mut buf).unwrap(), 5);
+        assert_eq!(&buf, &[0u8; 5]);
+        assert_eq!(s.read(3, 0, 0, &mut buf).unwrap(), 0);
+        assert!(!std::path::Path::new(&format!("{}/3", root)).exists());
+
+        assert!(s.exists(2, 0) && !s.exists(2, 1) && s.exists(2, 2) && !s.exists(3, 0));
+        assert_eq!(s.seek(2, 2 * FS_BLK_SIZE + 15, 5, libc::SEEK_HOLE), Ok(FS_BLK_SIZE));
+
+        // cutting a middle block leaves a hole, cutting the last one shrinks the file
+        s.set_len(2, 0, 2).unwrap();
+        assert_eq!(s.read(2, 0, 0, &mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"he\0\0\0");
+        s.set_len(2, 2, 12).unwrap();
+        let path = format!("{}/2", root);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 2 * FS_BLK_SIZE + 12);
+
+        s.unlink(2, 3);
+        assert!(!std::path::Path::new(&path).exists());
+        assert!(!s.exists(2, 0));
+    }
+
+    #[test]
+    fn test_host_inodes() {
+        let (packed, block) = ("/tmp/test_host_inodes_packed", "/tmp/test_host_inodes_block");
+        for p in [packed, block] {
+            let _ = std::fs::remove_dir_all(p);
+            std::fs::create_dir_all(p).unwrap();
+        }
+        let mut ps = PackedStore::new(packed);
+        let mut fs = FileStore::new();
+        // inos no other test writes to, the fd cache of `FileStore` is process wide
+        let inos: Vec<u64> = (0..32).map(|i| u64::MAX - 1540 - i).collect();
+
+        // the data path is process wide, other tests may change it, so set it right before use
+        init_data_path(block);
+        for &ino in &inos {
+            let mut data = vec![ino as u8; 100];
+            fs.write(ino, &[entry(0, 0, &mut data)]).unwrap();
+            ps.write(ino, &[entry(0, 0, &mut data)]).unwrap();
+        }
+        assert_eq!(count_inodes(block), 2 * inos.len());
+        assert_eq!(count_inodes(packed), inos.len());
+        init_data_path(block);
+        for &ino in &inos {
+            fs.unlink(ino, 1);
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::JoinHandle;
+
+/// remove data of unlinked files in background
+///
+/// the data directory is renamed into `trash` first, which is cheap, so `unlink` returns immediately, anything left
+/// in `trash` by a crash is purged when the next `Reaper` starts
+pub struct Reaper {
+    trash: PathBuf,
+    seq: u64,
+    tx: Option<Sender<PathBuf>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Reaper {
+    pub fn new(trash: impl AsRef<Path>) -> Result<Self, String> {
+        let trash = trash.as_ref().to_path_buf();
+        std::fs::create_dir_all(&trash).map_err(|e| format!("can't create {}, error {}", trash.display(), e))?;
+
+        let (tx, rx) = channel::<PathBuf>();
+        let worker = std::thread::spawn(move || {
+            while let Ok(p) = rx.recv() {
+                let r = if p.is_dir() { std::fs::remove_dir_all(&p) } else { std::fs::remove_file(&p) };
+                match r {
+                    Err(e) => log::error!("can't remove {} error {}", p.display(), e),
+                    Ok(_) => log::info!("reaped {}", p.display()),
+                }
+            }
+        });
+
+        let leftover = std::fs::read_dir(&trash).map_err(|e| e.to_string())?;
+        for e in leftover.flatten() {
+            log::warn!("purge leftover {}", e.path().display());
+            let _ = tx.send(e.path());
+        }
+
+        Ok(Self {
+            trash,
+            seq: 0,
+            tx: Some(tx),
+            worker: Some(worker),
+        })
+    }
+
+    /// move `path` into trash and schedule its removal, return false if `path` doesn't exist or can't be moved
+    pub fn submit(&mut self, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+        let name = match path.file_name() {
+            None => return false,
+            Some(x) => x.to_string_lossy(),
+        };
+        // the name may be reused (e.g. ino reuse) before the previous one is reaped
+        let dst = self.trash.join(format!("{}.{}.{}", name, std::process::id(), self.seq));
+        self.seq += 1;
+
+        if let Err(e) = std::fs::rename(path, &dst) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log::error!("can't move {} to {} error {}", path.display(), dst.display(), e);
+            }
+            return false;
+        }
+        self.tx.as_ref().unwrap().send(dst).is_ok()
+    }
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        // close the channel and wait for pending removals
+        drop(self.tx.take());
+        if let Some(w) = self.worker.take() {
+            let _ = w.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Reaper;
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    fn wait_empty(dir: &str) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if std::fs::read_dir(dir).unwrap().next().is_none() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn test_reaper() {
+        let root = "/tmp/test_reaper";
+        let trash = format!("{}/.trash", root);
+        let _ = std::fs::remove_dir_all(root);
+        std::fs::create_dir_all(format!("{}/leftover", trash)).unwrap();
+        for i in 0..64 {
+            let dir = format!("{}/{}", root, i % 4 + 2);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(format!("{}/{}", dir, i), vec![0u8; 4096]).unwrap();
+        }
+
+        let mut r = Reaper::new(&trash).unwrap();
+        for ino in 2..6 {
+            let dir = format!("{}/{}", root, ino);
+            assert!(r.submit(&dir));
+            // gone from the data path as soon as submit returns
+            assert!(!Path::new(&dir).exists());
+        }
+        assert!(!r.submit(format!("{}/100", root)));
+        assert!(wait_empty(&trash));
+    }
+}
@@ -6,6 +6,9 @@ pub struct BitMap {
     data: Vec<u8>,
     size: u64,
     count: u64,
+    /// where the next `alloc_next` starts, it's not stored, a loaded map starts from 0
+    #[serde(skip)]
+    cursor: u64,
 }
 
 const fn roundup_align8(size: u64) -> u64 {
@@ -22,6 +25,7 @@ impl BitMap {
             data: vec![0u8; bytes as usize],
             size: cnt,
             count: 0,
+            cursor: 0,
         }
     }
 
@@ -60,6 +64,7 @@ impl BitMap {
         self.count == self.size
     }
 
+    /// the lowest clear bit, so a freed one is reused right away
     pub fn alloc(&mut self) -> Option<u64> {
         if self.full() {
             None
@@ -74,6 +79,35 @@ impl BitMap {
         }
     }
 
+    /// the first clear bit after the last one handed out by it, wrapping around, so it doesn't walk over the bits
+    /// still in use from 0 every time, and a freed bit is reused only after the others
+    pub fn alloc_next(&mut self) -> Option<u64> {
+        if self.full() {
+            return None;
+        }
+        let size = self.cap();
+        let mut i = self.cursor % size;
+        let mut scanned = 0;
+        while scanned < size {
+            if i & 7 == 0 && i + 8 <= size && self.data[(i >> 3) as usize] == 0xff {
+                i += 8;
+                scanned += 8;
+            } else {
+                if !self.test(i) {
+                    self.add(i);
+                    self.cursor = i + 1;
+                    return Some(i);
+                }
+                i += 1;
+                scanned += 1;
+            }
+            if i == size {
+                i = 0;
+            }
+        }
+        None
+    }
+
     pub fn free(&mut self, bit: u64) -> bool {
         if !self.test(bit) {
             return false;
@@ -86,6 +120,7 @@ impl BitMap {
 #[cfg(test)]
 mod test {
     use super::BitMap;
+    use proptest::prelude::*;
 
     #[test]
     fn test_bitmap() {
@@ -133,4 +168,86 @@ mod test {
 
         assert_eq!(bm_cnt, d_cnt);
     }
+
+    #[test]
+    fn test_bitmap_edge() {
+        let mut bm = BitMap::new(0);
+        assert!(bm.full());
+        assert_eq!(bm.alloc(), None);
+
+        for size in [8, 64, 1024] {
+            let mut bm = BitMap::new(size);
+            for i in 0..size {
+                assert_eq!(bm.alloc(), Some(i));
+            }
+            assert!(bm.full());
+            assert_eq!(bm.alloc(), None);
+            assert!(bm.free(size - 1));
+            assert_eq!(bm.alloc(), Some(size - 1));
+        }
+    }
+
+    #[test]
+    fn test_bitmap_rotate() {
+        let mut bm = BitMap::new(1024);
+        for i in 0..512 {
+            assert_eq!(bm.alloc_next(), Some(i));
+        }
+        // open and close one at a time, it goes on from the last one rather than rescanning the ones in use
+        for i in 512..1024 {
+            assert_eq!(bm.alloc_next(), Some(i));
+            assert!(bm.free(i));
+        }
+        // then wraps around to reuse the freed ones
+        assert!(bm.free(3));
+        assert_eq!(bm.alloc_next(), Some(3));
+        assert_eq!(bm.alloc_next(), Some(512));
+        // `alloc` still takes the lowest
+        assert!(bm.free(100));
+        assert_eq!(bm.alloc(), Some(100));
+    }
+
+    proptest! {
+        // compare with a naive model, sizes around byte boundaries exercise the tail byte
+        #[test]
+        fn prop_bitmap(size in prop_oneof![0u64..=17, 60u64..=68, Just(1024u64)],
+                       ops in prop::collection::vec((0u8..4, any::<u64>()), 0..512)) {
+            let mut bm = BitMap::new(size);
+            let mut model = vec![false; size as usize];
+            let mut cursor = 0;
+
+            for (op, bit) in ops {
+                match op {
+                    0 => {
+                        let expect = model.iter().position(|x| !*x).map(|x| x as u64);
+                        prop_assert_eq!(bm.alloc(), expect);
+                        if let Some(x) = expect {
+                            model[x as usize] = true;
+                        }
+                    }
+                    3 => {
+                        let expect = (0..size).map(|k| (cursor + k) % size).find(|&x| !model[x as usize]);
+                        prop_assert_eq!(bm.alloc_next(), expect);
+                        if let Some(x) = expect {
+                            model[x as usize] = true;
+                            cursor = x + 1;
+                        }
+                    }
+                    1 if size > 0 => {
+                        let bit = bit % size;
+                        prop_assert_eq!(bm.free(bit), model[bit as usize]);
+                        model[bit as usize] = false;
+                    }
+                    _ if size > 0 => {
+                        let bit = bit % size;
+                        prop_assert_eq!(bm.test(bit), model[bit as usize]);
+                    }
+                    _ => {}
+                }
+                let cnt = model.iter().filter(|x| **x).count() as u64;
+                prop_assert_eq!(bm.len(), cnt);
+                prop_assert_eq!(bm.full(), cnt == size);
+            }
+        }
+    }
 }
@@ -0,0 +1,65 @@
+//! failures injected at chosen points of the metadata path, so tests can check a failing store is reported as an
+//! errno instead of a panic, and that a panic in a handler still leaves meta flushed, it's compiled in for tests and with the `fault-injection` feature, a no-op otherwise
+
+/// where a failure can be injected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// decoding a stored inode or dentry
+    Deserialize,
+    StoreInode,
+    StoreDentry,
+    /// flushing the metadata store
+    Flush,
+    /// entering a request handler, it panics rather than failing
+    Panic,
+    /// entering a request handler, it's delayed past the slow request threshold rather than failing
+    Slow,
+    /// moving the compacted copy of a sled store in place of the live one
+    SwapStore,
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+mod imp {
+    use super::Fault;
+    use std::cell::RefCell;
+
+    thread_local! {
+        // point => hits left before it fails, armed per thread so tests running in parallel don't see each other's
+        static ARMED: RefCell<Vec<(Fault, u64)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// fail the `after + 1`th hit of `point` on this thread, and every one after it until `disarm`
+    pub fn arm(point: Fault, after: u64) {
+        ARMED.with_borrow_mut(|a| {
+            a.retain(|(p, _)| *p != point);
+            a.push((point, after));
+        });
+    }
+
+    pub fn disarm(point: Fault) {
+        ARMED.with_borrow_mut(|a| a.retain(|(p, _)| *p != point));
+    }
+
+    pub fn hit(point: Fault) -> bool {
+        ARMED.with_borrow_mut(|a| match a.iter_mut().find(|(p, _)| *p == point) {
+            None => false,
+            Some((_, 0)) => {
+                log::warn!("injected fault {:?}", point);
+                true
+            }
+            Some((_, n)) => {
+                *n -= 1;
+                false
+            }
+        })
+    }
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+pub use imp::{arm, disarm, hit};
+
+#[cfg(not(any(test, feature = "fault-injection")))]
+#[inline(always)]
+pub fn hit(_point: Fault) -> bool {
+    false
+}
@@ -1,4 +1,6 @@
 mod bitmap;
+pub mod fault;
+pub mod stats;
 
 use crate::meta::{Inode, Itype};
 pub use bitmap::BitMap;
@@ -18,6 +20,9 @@ pub const FS_PAGE_SIZE: u64 = 4096;
 
 pub const FS_ROOT_INODE: u64 = 1;
 
+// permission bits (including suid/sgid/sticky) of mode
+pub const FS_MODE_MASK: u32 = 0o7777;
+
 static mut DATA_PATH: Lazy<String> = Lazy::new(|| "".to_string());
 pub const fn is_power_of2(size: u64) -> bool {
     (size > 0) && (size & (size - 1)) == 0
@@ -37,14 +42,28 @@ pub fn get_data_path() -> &'static String {
     unsafe { &*DATA_PATH }
 }
 
+/// seconds since unix epoch
+pub fn epoch_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("can't get unix timestamp")
+        .as_secs()
+}
+
 pub fn to_systime(s: u64) -> SystemTime {
     UNIX_EPOCH + time::Duration::from_secs(s)
 }
 
+/// seconds since unix epoch of `t`, a time before the epoch is 0
+pub fn from_systime(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
 pub fn to_filetype(s: Itype) -> FileType {
     match s {
         Itype::File => FileType::RegularFile,
         Itype::Dir => FileType::Directory,
+        Itype::Symlink => FileType::Symlink,
     }
 }
 
@@ -52,7 +71,8 @@ pub fn to_attr(inode: &Inode) -> FileAttr {
     FileAttr {
         ino: inode.id,
         size: inode.length,
-        blocks: inode.blocks(),
+        // in units of 512 bytes, the size rounded up, `Fs` reports what's actually allocated for a file
+        blocks: inode.length.div_ceil(512),
         atime: to_systime(inode.atime),
         mtime: to_systime(inode.mtime),
         ctime: to_systime(inode.ctime),
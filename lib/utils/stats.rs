@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+static PWRITEV_CALLS: AtomicU64 = AtomicU64::new(0);
+static PWRITEV_IOVS: AtomicU64 = AtomicU64::new(0);
+static PWRITEV_BYTES: AtomicU64 = AtomicU64::new(0);
+static SB_FLUSHES: AtomicU64 = AtomicU64::new(0);
+static SB_BYTES: AtomicU64 = AtomicU64::new(0);
+static META_WRITES: AtomicU64 = AtomicU64::new(0);
+static META_BYTES: AtomicU64 = AtomicU64::new(0);
+static ATIME_WRITES: AtomicU64 = AtomicU64::new(0);
+static BLOCK_SYNCS: AtomicU64 = AtomicU64::new(0);
+static WRITE_BYTES: AtomicU64 = AtomicU64::new(0);
+static FLUSH_CALLS: AtomicU64 = AtomicU64::new(0);
+static FLUSH_BYTES: AtomicU64 = AtomicU64::new(0);
+static FLUSH_ERRORS: AtomicU64 = AtomicU64::new(0);
+static FLUSH_NS: AtomicU64 = AtomicU64::new(0);
+static LAST_FLUSH: AtomicU64 = AtomicU64::new(0);
+static PREAD_CALLS: AtomicU64 = AtomicU64::new(0);
+static PREAD_BYTES: AtomicU64 = AtomicU64::new(0);
+static FILE_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static SLOW_OPS: AtomicU64 = AtomicU64::new(0);
+static OPS: Mutex<BTreeMap<&'static str, OpStats>> = Mutex::new(BTreeMap::new());
+
+/// latency of the requests of one kind, e.g. `read`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct OpStats {
+    pub calls: u64,
+    pub ns: u64,
+    pub max_ns: u64,
+    /// calls reaching the slow request threshold
+    pub slow: u64,
+}
+
+/// a point-in-time copy of the io counters
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub pwritev_calls: u64,
+    pub pwritev_iovs: u64,
+    pub pwritev_bytes: u64,
+    pub sb_flushes: u64,
+    pub sb_bytes: u64,
+    /// keys inserted or removed in the metadata store, a batch counts each of its keys
+    pub meta_writes: u64,
+    /// bytes of the keys and values written to the metadata store, a removed key counts its key
+    pub meta_bytes: u64,
+    pub atime_writes: u64,
+    pub block_syncs: u64,
+    /// bytes buffered by writes
+    pub write_bytes: u64,
+    pub flush_calls: u64,
+    /// bytes leaving the write buffer, including the ones of failed flushes, which are dropped
+    pub flush_bytes: u64,
+    pub flush_errors: u64,
+    pub flush_ns: u64,
+    /// unix seconds of the last flush, 0 if there's none yet
+    pub last_flush: u64,
+    pub pread_calls: u64,
+    pub pread_bytes: u64,
+    /// reads served by the small file cache without reaching the data store
+    pub file_cache_hits: u64,
+    /// requests of any kind reaching the slow request threshold
+    pub slow_ops: u64,
+}
+
+impl Stats {
+    /// bytes buffered but not flushed yet
+    pub fn dirty_bytes(&self) -> u64 {
+        self.write_bytes.saturating_sub(self.flush_bytes)
+    }
+}
+
+pub fn record_pwritev(iovs: usize, bytes: u64) {
+    PWRITEV_CALLS.fetch_add(1, Ordering::Relaxed);
+    PWRITEV_IOVS.fetch_add(iovs as u64, Ordering::Relaxed);
+    PWRITEV_BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn record_sb_flush(bytes: u64) {
+    SB_FLUSHES.fetch_add(1, Ordering::Relaxed);
+    SB_BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn record_meta_write(keys: u64, bytes: u64) {
+    META_WRITES.fetch_add(keys, Ordering::Relaxed);
+    META_BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn record_atime_write() {
+    ATIME_WRITES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_block_sync() {
+    BLOCK_SYNCS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_pread(bytes: u64) {
+    PREAD_CALLS.fetch_add(1, Ordering::Relaxed);
+    PREAD_BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn record_file_cache_hit() {
+    FILE_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_write(bytes: u64) {
+    WRITE_BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn record_flush(bytes: u64, ns: u64, ok: bool) {
+    FLUSH_CALLS.fetch_add(1, Ordering::Relaxed);
+    FLUSH_BYTES.fetch_add(bytes, Ordering::Relaxed);
+    FLUSH_NS.fetch_add(ns, Ordering::Relaxed);
+    LAST_FLUSH.store(super::epoch_secs(), Ordering::Relaxed);
+    if !ok {
+        FLUSH_ERRORS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn record_op(op: &'static str, ns: u64, slow: bool) {
+    let mut ops = OPS.lock().unwrap_or_else(|e| e.into_inner());
+    let s = ops.entry(op).or_default();
+    s.calls += 1;
+    s.ns += ns;
+    s.max_ns = s.max_ns.max(ns);
+    if slow {
+        s.slow += 1;
+        SLOW_OPS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// latency of every kind of request served so far, ordered by name
+pub fn ops() -> Vec<(&'static str, OpStats)> {
+    let ops = OPS.lock().unwrap_or_else(|e| e.into_inner());
+    ops.iter().map(|(k, v)| (*k, *v)).collect()
+}
+
+pub fn snapshot() -> Stats {
+    Stats {
+        pwritev_calls: PWRITEV_CALLS.load(Ordering::Relaxed),
+        pwritev_iovs: PWRITEV_IOVS.load(Ordering::Relaxed),
+        pwritev_bytes: PWRITEV_BYTES.load(Ordering::Relaxed),
+        sb_flushes: SB_FLUSHES.load(Ordering::Relaxed),
+        sb_bytes: SB_BYTES.load(Ordering::Relaxed),
+        meta_writes: META_WRITES.load(Ordering::Relaxed),
+        meta_bytes: META_BYTES.load(Ordering::Relaxed),
+        atime_writes: ATIME_WRITES.load(Ordering::Relaxed),
+        block_syncs: BLOCK_SYNCS.load(Ordering::Relaxed),
+        write_bytes: WRITE_BYTES.load(Ordering::Relaxed),
+        flush_calls: FLUSH_CALLS.load(Ordering::Relaxed),
+        flush_bytes: FLUSH_BYTES.load(Ordering::Relaxed),
+        flush_errors: FLUSH_ERRORS.load(Ordering::Relaxed),
+        flush_ns: FLUSH_NS.load(Ordering::Relaxed),
+        last_flush: LAST_FLUSH.load(Ordering::Relaxed),
+        pread_calls: PREAD_CALLS.load(Ordering::Relaxed),
+        pread_bytes: PREAD_BYTES.load(Ordering::Relaxed),
+        file_cache_hits: FILE_CACHE_HITS.load(Ordering::Relaxed),
+        slow_ops: SLOW_OPS.load(Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,56 @@
+use junkfs::meta::Meta;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let repair = args.len() == 3 && args[2] == "--repair";
+    if args.len() != 2 && !repair {
+        eprintln!("{} meta_path [--repair]", args[0]);
+        std::process::exit(1);
+    }
+
+    let mut meta = match Meta::load_fs(args[1].clone(), false) {
+        Err(e) => {
+            eprintln!("can't load {}, error {}", args[1], e);
+            std::process::exit(1);
+        }
+        Ok(m) => m,
+    };
+
+    let mut issues = meta.check();
+    match meta.orphans() {
+        Err(e) => issues.push(format!("can't scan for orphans, error {}", e)),
+        Ok(v) if !v.is_empty() => {
+            // a mount only looks for them after an unclean one
+            if repair && !meta.unclean() {
+                if let Err(e) = meta.mark_unclean() {
+                    eprintln!("can't mark for reaping orphans, error {}", e);
+                    std::process::exit(1);
+                }
+            }
+            let when = if meta.unclean() { "on next mount" } else { "on the mount after --repair" };
+            issues.push(format!("{} orphan inode(s), reclaimed {}", v.len(), when));
+        }
+        Ok(_) => {}
+    }
+    for i in &issues {
+        println!("{}", i);
+    }
+
+    match meta.repair(repair) {
+        Err(e) => {
+            eprintln!("can't repair, error {}", e);
+            std::process::exit(1);
+        }
+        Ok((leaked, lost)) => {
+            println!(
+                "inode map: {} leaked {} lost{}",
+                leaked,
+                lost,
+                if repair { ", repaired" } else { "" }
+            );
+            if !repair && (leaked != 0 || lost != 0 || !issues.is_empty()) {
+                std::process::exit(2);
+            }
+        }
+    }
+}
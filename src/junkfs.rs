@@ -1,6 +1,6 @@
-use junkfs::fs::Fs;
+use junkfs::fs::{install_panic_hook, Config, Fs};
 use junkfs::logger::Logger;
-use libc::{sighandler_t, SIGINT, SIGTERM};
+use libc::{sighandler_t, SIGHUP, SIGINT, SIGTERM, SIGUSR1};
 use std::str::FromStr;
 
 fn main() {
@@ -8,43 +8,39 @@ fn main() {
         .or::<String>(Ok("WARN".to_string()))
         .unwrap();
     let log_path = "/tmp/junkfs.log";
-    Logger::init().add_file(&log_path, true);
+    let max_mb = std::env::var("JUNK_LOG_MAX_MB")
+        .map(|x| u64::from_str(&x).expect("invalid JUNK_LOG_MAX_MB"))
+        .unwrap_or(0);
+    let keep = std::env::var("JUNK_LOG_KEEP")
+        .map(|x| usize::from_str(&x).expect("invalid JUNK_LOG_KEEP"))
+        .unwrap_or(4);
+    Logger::init().add_rolling_file(&log_path, true, max_mb << 20, keep);
     log::set_max_level(log::LevelFilter::from_str(&level).unwrap());
-    if std::env::args().len() != 3 {
-        eprintln!("{} meta_path mount_point", std::env::args().nth(0).unwrap());
+    let cfg = match Config::parse(std::env::args().skip(1)) {
+        Err(e) => {
+            eprintln!("{}\n{}", e, Config::usage(&std::env::args().nth(0).unwrap()));
+            std::process::exit(1);
+        }
+        Ok(cfg) => cfg,
+    };
+    for opt in &cfg.unknown {
+        eprintln!("ignore unknown option {}", opt);
+    }
+    if let Err(e) = cfg.check() {
+        eprintln!("{}", e);
         std::process::exit(1);
     }
 
     println!("log write to {} level {}", log_path, level);
-    let meta_path = std::env::args().nth(1).unwrap();
-    let mount_point = std::env::args().nth(2).unwrap();
 
     setup_signal_handler();
+    install_panic_hook();
 
-    let junkfs = Fs::new(meta_path);
-    match junkfs {
-        Err(e) => {
-            log::error!("load filesystem fail, error {e}");
-            std::process::exit(1);
-        }
-        Ok(junkfs) => {
-            let options = [
-                fuser::MountOption::FSName("jfs".to_string()),
-                fuser::MountOption::Subtype("jfs".to_string()),
-            ];
-            // let session = fuser::spawn_mount2(junkfs, &mount_point, &options).expect("can't mount");
-            // wait_signal();
-            // session.join();
-
-            let r = fuser::mount2(junkfs, &mount_point, &options);
-            match r {
-                Err(e) => {
-                    log::error!("mount fail, error {}", e.to_string());
-                    std::process::exit(1);
-                }
-                Ok(()) => {}
-            }
-        }
+    // serve until unmounted
+    let r = junkfs::mount(&cfg).and_then(|m| m.join());
+    if let Err(e) = r {
+        log::error!("{}", e);
+        std::process::exit(1);
     }
 }
 
@@ -62,6 +58,45 @@ fn setup_signal_handler() {
         libc::signal(SIGTERM, handler);
         libc::signal(SIGINT, handler);
     }
+    setup_reload_handler();
+}
+
+/// `kill -HUP` reloads the log level from `JUNK_LEVEL_FILE` (default /tmp/junkfs.level), or falls back to
+/// `JUNK_LEVEL` when the file is absent. `kill -USR1` requests a metadata compaction. the signals are blocked in
+/// every thread and consumed by a dedicated thread, so nothing is done from signal context
+fn setup_reload_handler() {
+    let level_file = std::env::var("JUNK_LEVEL_FILE").unwrap_or("/tmp/junkfs.level".to_string());
+    let set = unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, SIGHUP);
+        libc::sigaddset(&mut set, SIGUSR1);
+        // threads spawned later (including fuse workers) inherit the mask
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        set
+    };
+
+    std::thread::spawn(move || loop {
+        let mut sig = 0;
+        if unsafe { libc::sigwait(&set, &mut sig) } != 0 {
+            continue;
+        }
+        if sig == SIGUSR1 {
+            log::warn!("metadata compaction requested");
+            Fs::request_compact();
+            continue;
+        }
+        match Logger::reload_level(&level_file) {
+            Some(level) => log::warn!("log level changed to {} by {}", level, level_file),
+            None => {
+                let level = std::env::var("JUNK_LEVEL").unwrap_or("WARN".to_string());
+                if let Ok(level) = log::LevelFilter::from_str(&level) {
+                    log::set_max_level(level);
+                    log::warn!("log level reset to {}", level);
+                }
+            }
+        }
+    });
 }
 
 #[allow(dead_code)]
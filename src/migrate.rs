@@ -0,0 +1,19 @@
+use junkfs::meta::Meta;
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+    let force = args.iter().any(|x| x == "--force");
+    args.retain(|x| x != "--force");
+    if args.len() != 3 {
+        eprintln!("{} [--force] src_meta_path dst_meta_path", args[0]);
+        std::process::exit(1);
+    }
+
+    match Meta::copy_store(&args[1], &args[2], force) {
+        Err(e) => {
+            eprintln!("can't copy {} to {}, error {}", args[1], args[2], e);
+            std::process::exit(1);
+        }
+        Ok(n) => println!("copied {} keys from {} to {}", n, args[1], args[2]),
+    }
+}
@@ -1,19 +1,115 @@
-use junkfs::meta::Meta;
+use junkfs::meta::{DirLimits, FormatCheck, KeyParams, Layout, Meta};
+use junkfs::store::{read_passphrase, salt, Xts};
+
+fn print_check(meta_path: &str, c: &FormatCheck) {
+    println!("meta_path  {}", meta_path);
+    println!("store_path {}", c.data_path);
+    println!("version    {}", c.version);
+    println!("inodes     {}", c.inodes);
+    println!("block_size {}", c.block_size);
+    println!("layout     {:?}", c.layout);
+    match &c.existing {
+        None => println!("existing   none"),
+        Some(x) => println!("existing   {}", x),
+    }
+    for i in &c.issues {
+        println!("issue: {}", i);
+    }
+}
+
+/// a fresh salt and what tells the key derived from the passphrase in `path` with it
+fn key_params(path: &str) -> Result<KeyParams, String> {
+    let passphrase = read_passphrase(path)?;
+    let salt = salt()?;
+    Ok(KeyParams {
+        salt,
+        check: Xts::derive(&passphrase, &salt).check(),
+    })
+}
+
+/// take `--name value` out of `args`
+fn take_value<T: std::str::FromStr>(args: &mut Vec<String>, name: &str) -> Result<Option<T>, String> {
+    let Some(i) = args.iter().position(|x| x == name) else {
+        return Ok(None);
+    };
+    if i + 1 >= args.len() {
+        return Err(format!("{} requires an argument", name));
+    }
+    let val = args.remove(i + 1);
+    args.remove(i);
+    val.parse::<T>()
+        .map(Some)
+        .map_err(|_| format!("invalid value of {}: {}", name, val))
+}
 
 fn main() {
-    if std::env::args().len() != 3 {
-        eprintln!("{} meta_path store_path", std::env::args().nth(0).unwrap());
+    let mut args: Vec<String> = std::env::args().collect();
+    let force = args.iter().any(|x| x == "--force");
+    let verify = args.iter().any(|x| x == "--verify");
+    let layout = if args.iter().any(|x| x == "--packed") { Layout::Packed } else { Layout::Block };
+    args.retain(|x| x != "--force" && x != "--packed" && x != "--verify");
+    let mut limits = DirLimits::default();
+    match (
+        take_value(&mut args, "--max-links"),
+        take_value(&mut args, "--max-entries"),
+    ) {
+        (Ok(links), Ok(entries)) => {
+            limits.max_links = links.unwrap_or(limits.max_links);
+            limits.max_entries = entries.unwrap_or(limits.max_entries);
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    let label = match take_value::<String>(&mut args, "--label") {
+        Ok(x) => x.unwrap_or_default(),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let key_file = match take_value::<String>(&mut args, "--key-file") {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if args.len() != 3 || limits.max_links < 2 {
+        eprintln!(
+            "{} [--force] [--packed] [--verify] [--max-links n] [--max-entries n] [--label name] [--key-file path] \
+             meta_path store_path",
+            args[0]
+        );
         std::process::exit(1);
     }
 
-    let meta_path = std::env::args().nth(1).unwrap();
-    let mut store_path = std::env::args().nth(2).unwrap();
+    let meta_path = args[1].clone();
+    let mut store_path = args[2].clone();
 
     while store_path.ends_with('/') {
         store_path.remove(store_path.len() - 1);
     }
 
-    let r = Meta::format(&meta_path, &store_path);
+    // print what would be formatted, exit with 2 if it would fail
+    if verify {
+        let c = Meta::verify_format(&meta_path, &store_path, layout, force);
+        print_check(&meta_path, &c);
+        if !c.issues.is_empty() {
+            std::process::exit(2);
+        }
+        return;
+    }
+
+    let key = match key_file.as_deref().map(key_params).transpose() {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let r = Meta::format_with(&meta_path, &store_path, layout, limits, &label, key, force);
 
     match r {
         Err(e) => {
@@ -0,0 +1,29 @@
+use junkfs::meta::Meta;
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+    let force = args.iter().any(|x| x == "--force");
+    args.retain(|x| x != "--force");
+    if args.len() != 3 {
+        eprintln!("{} [--force] meta_path snapshot_meta_path", args[0]);
+        std::process::exit(1);
+    }
+
+    let meta = match Meta::load_fs(args[1].clone(), false) {
+        Err(e) => {
+            eprintln!("can't load {}, error {}", args[1], e);
+            std::process::exit(1);
+        }
+        Ok(m) => m,
+    };
+    match meta.snapshot(&args[2], force) {
+        Err(e) => {
+            eprintln!("can't snapshot {} to {}, error {}", args[1], args[2], e);
+            std::process::exit(1);
+        }
+        Ok(n) => println!(
+            "snapshot {} keys of {} to {}, its data is shared, mount it with -o ro",
+            n, args[1], args[2]
+        ),
+    }
+}
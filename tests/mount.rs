@@ -0,0 +1,473 @@
+//! end-to-end tests against a real mount, they need FUSE and are skipped unless `JUNK_FUSE_TEST` is set, e.g.
+//! `JUNK_FUSE_TEST=1 cargo test --test mount`
+
+use junkfs::fs::Config;
+use junkfs::meta::Meta;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// memory pool and data path are process wide, so only one mount at a time
+static MOUNT_LOCK: Mutex<()> = Mutex::new(());
+
+struct Mount {
+    root: String,
+    mnt: String,
+    session: Option<junkfs::MountHandle>,
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl Mount {
+    fn new(name: &str) -> Option<Self> {
+        Self::with_args(name, &[])
+    }
+
+    /// mount with extra command line options
+    fn with_args(name: &str, args: &[&str]) -> Option<Self> {
+        if std::env::var("JUNK_FUSE_TEST").is_err() {
+            eprintln!("skip {}: set JUNK_FUSE_TEST=1 to run tests which mount junkfs", name);
+            return None;
+        }
+        if !Path::new("/dev/fuse").exists() {
+            eprintln!("skip {}: /dev/fuse is not available", name);
+            return None;
+        }
+
+        let guard = MOUNT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let root = format!("/tmp/junkfs_it_{}_{}", name, std::process::id());
+        let (meta, data, mnt) = (
+            format!("{}/meta", root),
+            format!("{}/data", root),
+            format!("{}/mnt", root),
+        );
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&data).unwrap();
+        std::fs::create_dir_all(&mnt).unwrap();
+        Meta::format(&meta, &data, false).unwrap();
+
+        let args = args.iter().map(|x| x.to_string()).chain([meta, mnt.clone()]);
+        let cfg = Config::parse(args).unwrap();
+        let session = match junkfs::mount(&cfg) {
+            Err(e) => {
+                eprintln!("skip {}: can't mount, error {}", name, e);
+                return None;
+            }
+            Ok(s) => s,
+        };
+        let m = Mount {
+            root,
+            mnt,
+            session: Some(session),
+            _guard: guard,
+        };
+        m.wait_mounted();
+        Some(m)
+    }
+
+    fn wait_mounted(&self) {
+        let parent = std::fs::metadata(&self.root).unwrap().dev();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if std::fs::metadata(&self.mnt).map(|m| m.dev() != parent).unwrap_or(false) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("{} is not mounted in time", self.mnt);
+    }
+
+    fn path(&self, name: &str) -> String {
+        format!("{}/{}", self.mnt, name)
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        // unmount and wait for the session to exit
+        drop(self.session.take());
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+fn copy_dir(from: &str, to: &str) {
+    std::fs::create_dir_all(to).unwrap();
+    for e in std::fs::read_dir(from).unwrap() {
+        let e = e.unwrap();
+        let dst = format!("{}/{}", to, e.file_name().to_string_lossy());
+        if e.file_type().unwrap().is_dir() {
+            copy_dir(&e.path().to_string_lossy(), &dst);
+        } else {
+            std::fs::copy(e.path(), &dst).unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_mount_api() {
+    let mut m = match Mount::new("mount_api") {
+        None => return,
+        Some(m) => m,
+    };
+    std::fs::write(m.path("f"), b"hello").unwrap();
+
+    // the mount point is a plain directory again once unmounted
+    m.session.take().unwrap().unmount().unwrap();
+    let parent = std::fs::metadata(&m.root).unwrap().dev();
+    assert_eq!(std::fs::metadata(&m.mnt).unwrap().dev(), parent);
+    assert!(!Path::new(&m.path("f")).exists());
+
+    // mounted again, what was written is there, dropping the handle unmounts too
+    let cfg = Config::parse([format!("{}/meta", m.root), m.mnt.clone()]).unwrap();
+    let h = junkfs::mount(&cfg).unwrap();
+    assert_eq!(h.mount_point(), m.mnt);
+    m.wait_mounted();
+    assert_eq!(std::fs::read(m.path("f")).unwrap(), b"hello");
+    drop(h);
+    assert_eq!(std::fs::metadata(&m.mnt).unwrap().dev(), parent);
+}
+
+#[test]
+fn test_file_ops() {
+    let m = match Mount::new("file_ops") {
+        None => return,
+        Some(m) => m,
+    };
+
+    let data: Vec<u8> = (0..(1 << 20) + 123).map(|i| (i % 251) as u8).collect();
+    let mut f = std::fs::File::create(m.path("file")).unwrap();
+    f.write_all(&data).unwrap();
+    f.sync_all().unwrap();
+    drop(f);
+    assert_eq!(std::fs::metadata(m.path("file")).unwrap().len(), data.len() as u64);
+    assert_eq!(std::fs::read(m.path("file")).unwrap(), data);
+
+    // overwrite in the middle and read it back through another handle
+    let mut f = std::fs::OpenOptions::new().write(true).open(m.path("file")).unwrap();
+    f.seek(SeekFrom::Start(4000)).unwrap();
+    f.write_all(b"hello").unwrap();
+    drop(f);
+    let mut buf = [0u8; 5];
+    let mut f = std::fs::File::open(m.path("file")).unwrap();
+    f.seek(SeekFrom::Start(4000)).unwrap();
+    f.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    std::fs::create_dir(m.path("dir")).unwrap();
+    std::fs::write(m.path("dir/a"), b"a").unwrap();
+    std::os::unix::fs::symlink("../file", m.path("dir/link")).unwrap();
+    assert_eq!(std::fs::read_link(m.path("dir/link")).unwrap(), Path::new("../file"));
+    assert_eq!(std::fs::read(m.path("dir/link")).unwrap().len(), data.len());
+
+    let mut names: Vec<String> = std::fs::read_dir(m.path("dir"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["a", "link"]);
+
+    // only the entry moves, the data stays where it is
+    std::fs::rename(m.path("file"), m.path("dir/moved")).unwrap();
+    assert_eq!(std::fs::read(m.path("dir/moved")).unwrap()[4000..4005], *b"hello");
+    std::fs::rename(m.path("dir/moved"), m.path("file")).unwrap();
+
+    assert!(std::fs::remove_dir(m.path("dir")).is_err());
+    // O_CREAT without O_EXCL opens an existing file
+    for _ in 0..2 {
+        let f = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(m.path("dir/a"))
+            .unwrap();
+        assert_eq!(f.metadata().unwrap().len(), 1);
+    }
+    let e = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(m.path("dir/a"))
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists);
+
+    let e = std::fs::metadata(m.path("file/child")).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::ENOTDIR));
+
+    // O_TRUNC empties an existing file, O_APPEND writes at the end whatever the offset
+    std::fs::write(m.path("dir/a"), b"abc").unwrap();
+    assert_eq!(std::fs::read(m.path("dir/a")).unwrap(), b"abc");
+    let mut f = std::fs::OpenOptions::new().append(true).open(m.path("dir/a")).unwrap();
+    f.write_all(b"de").unwrap();
+    drop(f);
+    assert_eq!(std::fs::read(m.path("dir/a")).unwrap(), b"abcde");
+    drop(
+        std::fs::OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(m.path("dir/a"))
+            .unwrap(),
+    );
+    assert_eq!(std::fs::metadata(m.path("dir/a")).unwrap().len(), 0);
+    assert!(std::fs::read(m.path("dir/a")).unwrap().is_empty());
+
+    std::fs::remove_file(m.path("file")).unwrap();
+    assert_eq!(
+        std::fs::metadata(m.path("file")).unwrap_err().kind(),
+        std::io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn test_rm_rf() {
+    let m = match Mount::new("rm_rf") {
+        None => return,
+        Some(m) => m,
+    };
+
+    for i in 0..8 {
+        let dir = m.path(&format!("d{}", i));
+        std::fs::create_dir(&dir).unwrap();
+        for j in 0..16 {
+            std::fs::write(format!("{}/f{}", dir, j), vec![j as u8; 4096 * j]).unwrap();
+        }
+        std::fs::create_dir(format!("{}/sub", dir)).unwrap();
+        std::fs::write(format!("{}/sub/x", dir), b"x").unwrap();
+    }
+    for i in 0..8 {
+        std::fs::remove_dir_all(m.path(&format!("d{}", i))).unwrap();
+    }
+    assert_eq!(std::fs::read_dir(&m.mnt).unwrap().count(), 0);
+
+    // inos are reused after the removal
+    std::fs::write(m.path("again"), b"again").unwrap();
+    assert_eq!(std::fs::read(m.path("again")).unwrap(), b"again");
+}
+
+#[test]
+fn test_fsync() {
+    let m = match Mount::new("fsync") {
+        None => return,
+        Some(m) => m,
+    };
+
+    let data: Vec<u8> = (0..3 * 4096 + 5).map(|i| (i % 253) as u8).collect();
+    std::fs::create_dir(m.path("dir")).unwrap();
+    let mut f = std::fs::File::create(m.path("dir/file")).unwrap();
+    f.write_all(&data).unwrap();
+    f.sync_all().unwrap();
+    std::fs::File::open(m.path("dir")).unwrap().sync_all().unwrap();
+
+    // what's on disk now is what a crash leaves behind, the file is still open so nothing is flushed by release
+    let crash = format!("{}/crash", m.root);
+    copy_dir(&format!("{}/meta", m.root), &format!("{}/meta", crash));
+    copy_dir(&format!("{}/data", m.root), &format!("{}/data", crash));
+    drop(f);
+
+    let mut meta = Meta::load_fs(format!("{}/meta", crash), true).unwrap();
+    let dir = meta.lookup(1, "dir").unwrap();
+    let file = meta.lookup(dir.id, "file").unwrap();
+    assert_eq!(file.length, data.len() as u64);
+    let blk = std::fs::read(format!("{}/data/{}/0", crash, file.id)).unwrap();
+    assert_eq!(blk, data);
+}
+
+#[test]
+fn test_sync_on_close() {
+    let m = match Mount::with_args("sync_on_close", &["--sync-on-close"]) {
+        None => return,
+        Some(m) => m,
+    };
+
+    let data: Vec<u8> = (0..2 * 4096 + 7).map(|i| (i % 251) as u8).collect();
+    std::fs::write(m.path("file"), &data).unwrap();
+
+    // no fsync, closing it is enough
+    let crash = format!("{}/crash", m.root);
+    copy_dir(&format!("{}/meta", m.root), &format!("{}/meta", crash));
+    copy_dir(&format!("{}/data", m.root), &format!("{}/data", crash));
+
+    let mut meta = Meta::load_fs(format!("{}/meta", crash), true).unwrap();
+    let file = meta.lookup(1, "file").unwrap();
+    assert_eq!(file.length, data.len() as u64);
+    let blk = std::fs::read(format!("{}/data/{}/0", crash, file.id)).unwrap();
+    assert_eq!(blk, data);
+}
+
+#[test]
+fn test_xattr() {
+    let m = match Mount::new("xattr") {
+        None => return,
+        Some(m) => m,
+    };
+
+    let file = m.path("file");
+    std::fs::write(&file, b"x").unwrap();
+    let run = |cmd: &str, args: &[&str]| std::process::Command::new(cmd).args(args).output();
+    match run("setfattr", &["-n", "user.color", "-v", "blue", &file]) {
+        Err(e) => eprintln!("skip setfattr/getfattr: {}", e),
+        Ok(out) => {
+            assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+            let out = run("getfattr", &["--only-values", "-n", "user.color", &file]).unwrap();
+            assert_eq!(out.stdout, b"blue");
+            let out = run("setfattr", &["-x", "user.color", &file]).unwrap();
+            assert!(out.status.success());
+            let out = run("getfattr", &["-n", "user.color", &file]).unwrap();
+            assert!(!out.status.success());
+        }
+    }
+
+    // the size probe and a buffer too small
+    let path = std::ffi::CString::new(file).unwrap();
+    let name = c"user.k";
+    let r = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), b"value".as_ptr().cast(), 5, 0) };
+    assert_eq!(r, 0);
+    let r = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+    assert_eq!(r, 5);
+    let mut buf = [0u8; 2];
+    let r = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), buf.as_mut_ptr().cast(), buf.len()) };
+    assert_eq!(r, -1);
+    assert_eq!(std::io::Error::last_os_error().raw_os_error(), Some(libc::ERANGE));
+    let mut buf = [0u8; 64];
+    let r = unsafe { libc::listxattr(path.as_ptr(), buf.as_mut_ptr().cast(), buf.len()) };
+    assert_eq!(&buf[..r as usize], b"user.k\0");
+}
+
+#[test]
+fn test_prealloc() {
+    let m = match Mount::with_args("prealloc", &["--prealloc"]) {
+        None => return,
+        Some(m) => m,
+    };
+
+    // sized up front like a database file, then written at random offsets
+    let f = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .read(true)
+        .write(true)
+        .open(m.path("db"))
+        .unwrap();
+    let blk = 128u64 << 20;
+    f.set_len(4 * blk).unwrap();
+    let ino = std::fs::metadata(m.path("db")).unwrap().ino();
+    let dir = format!("{}/data/{}", m.root, ino);
+    let blocks = || std::fs::read_dir(&dir).unwrap().count();
+    assert_eq!(blocks(), 4);
+    for off in [3 * blk + 17, 5, 2 * blk - 1, blk + 4096] {
+        f.write_all_at(b"x", off).unwrap();
+    }
+    f.sync_all().unwrap();
+    assert_eq!(blocks(), 4);
+    let mut buf = [0u8; 1];
+    f.read_exact_at(&mut buf, 2 * blk - 1).unwrap();
+    assert_eq!(&buf, b"x");
+}
+
+#[test]
+fn test_sparse_du() {
+    let m = match Mount::new("sparse_du") {
+        None => return,
+        Some(m) => m,
+    };
+
+    // one byte after a 1GiB hole
+    let mut f = std::fs::File::create(m.path("sparse")).unwrap();
+    f.seek(SeekFrom::Start(1 << 30)).unwrap();
+    f.write_all(b"x").unwrap();
+    drop(f);
+
+    let md = std::fs::metadata(m.path("sparse")).unwrap();
+    assert_eq!(md.len(), (1 << 30) + 1);
+    assert!(md.blocks() * 512 < 1 << 20, "{} blocks allocated", md.blocks());
+}
+
+#[test]
+fn test_health() {
+    let m = match Mount::new("health") {
+        None => return,
+        Some(m) => m,
+    };
+
+    let _f = std::fs::File::create(m.path("file")).unwrap();
+    let s = std::fs::read_to_string(m.path(".junkfs/health")).unwrap();
+    let h = junkfs::fs::Health::parse(&s).unwrap();
+    assert!(h.open_handles >= 1);
+
+    // hidden and reserved
+    assert!(std::fs::read_dir(&m.mnt)
+        .unwrap()
+        .all(|e| e.unwrap().file_name() != ".junkfs"));
+    assert!(std::fs::create_dir(m.path(".junkfs")).is_err());
+    assert!(std::fs::write(m.path(".junkfs/health"), b"x").is_err());
+}
+
+#[test]
+fn test_handles() {
+    let m = match Mount::new("handles") {
+        None => return,
+        Some(m) => m,
+    };
+
+    // the kernel may hold on to a released handle for a moment, so only the ones opened here are counted
+    let count = |m: &Mount| {
+        std::fs::read_to_string(m.path(".junkfs/handles"))
+            .unwrap()
+            .lines()
+            .filter(|l| l.split(' ').nth(2) == Some("file"))
+            .count()
+    };
+    let files: Vec<_> = (0..4)
+        .map(|i| std::fs::File::create(m.path(&format!("f{}", i))).unwrap())
+        .collect();
+    assert_eq!(count(&m), files.len());
+    drop(files);
+    for _ in 0..50 {
+        if count(&m) == 0 {
+            return;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    panic!("handles not released");
+}
+
+#[test]
+fn test_mmap_shared() {
+    let mut m = match Mount::new("mmap_shared") {
+        None => return,
+        Some(m) => m,
+    };
+
+    let len = 2 * 4096 + 100;
+    let f = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(m.path("file"))
+        .unwrap();
+    f.set_len(len as u64).unwrap();
+    let data: Vec<u8> = (0..len).map(|i| (i % 253) as u8).collect();
+    unsafe {
+        use std::os::fd::AsRawFd;
+        let p = libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            f.as_raw_fd(),
+            0,
+        );
+        assert_ne!(p, libc::MAP_FAILED, "{}", std::io::Error::last_os_error());
+        std::ptr::copy_nonoverlapping(data.as_ptr(), p.cast::<u8>(), len);
+        assert_eq!(libc::msync(p, len, libc::MS_SYNC), 0);
+        assert_eq!(libc::munmap(p, len), 0);
+    }
+    drop(f);
+
+    // read(2) on a new fd sees what was written through the mapping, so does the next mount
+    assert_eq!(std::fs::read(m.path("file")).unwrap(), data);
+    m.session.take().unwrap().unmount().unwrap();
+    let cfg = Config::parse([format!("{}/meta", m.root), m.mnt.clone()]).unwrap();
+    let _h = junkfs::mount(&cfg).unwrap();
+    m.wait_mounted();
+    assert_eq!(std::fs::read(m.path("file")).unwrap(), data);
+}